@@ -0,0 +1,182 @@
+use anyhow::{bail, Result};
+use colored::Colorize;
+use std::collections::HashMap;
+use std::io::{self, IsTerminal, Write};
+use std::sync::{mpsc, Mutex};
+use std::time::Duration;
+
+use crate::config::StepResult;
+
+use super::utils::*;
+
+/// One prompt on the terminal at a time — concurrent `foreach` instances of
+/// an approval step would otherwise race each other for the same stdin.
+static PROMPT: Mutex<()> = Mutex::new(());
+
+/// The outputs every granted approval records.
+pub const OUTPUTS: &[&str] = &["approved_by", "approved_at", "approved_via"];
+
+/// How an approval gate is going to be satisfied.
+#[derive(Debug, PartialEq)]
+enum Gate {
+    /// Named with `--approve` on the command line.
+    PreApproved,
+    /// Ask on the terminal.
+    Prompt,
+}
+
+/// Wait for an approval gate, returning who approved, when and how as the
+/// step's outputs (`approved_by`, `approved_at` in epoch seconds,
+/// `approved_via`) so they land in the flow's run state.
+pub fn run(
+    step_id: &str,
+    message: &str,
+    approvers: &[String],
+    timeout: u64,
+    pre_approved: bool,
+) -> Result<StepResult> {
+    let user = current_user();
+    let via = match gate(
+        step_id,
+        pre_approved,
+        io::stdin().is_terminal(),
+        &user,
+        approvers,
+    )? {
+        Gate::PreApproved => {
+            log_info(&format!(
+                "Approval '{}' pre-approved via --approve",
+                step_id
+            ));
+            "--approve"
+        }
+        Gate::Prompt => {
+            let _guard = PROMPT.lock().unwrap_or_else(|e| e.into_inner());
+            if !prompt(message, timeout)? {
+                bail!("Approval '{}' declined by {}", step_id, user);
+            }
+            "tty"
+        }
+    };
+
+    log_success(&format!("Approval '{}' granted by {}", step_id, user));
+    let approved_at = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let outputs = HashMap::from([
+        ("approved_by".to_string(), serde_json::json!(user)),
+        ("approved_at".to_string(), serde_json::json!(approved_at)),
+        ("approved_via".to_string(), serde_json::json!(via)),
+    ]);
+    Ok(StepResult { outputs })
+}
+
+/// Decide how the gate can be satisfied, or why it cannot. An approver list
+/// applies to `--approve` too: pre-approving is approving.
+fn gate(
+    step_id: &str,
+    pre_approved: bool,
+    interactive: bool,
+    user: &str,
+    approvers: &[String],
+) -> Result<Gate> {
+    if !approvers.is_empty() && !approvers.iter().any(|a| a == user) {
+        bail!(
+            "User '{}' may not approve '{}' (approvers: {})",
+            user,
+            step_id,
+            approvers.join(", ")
+        );
+    }
+    if pre_approved {
+        return Ok(Gate::PreApproved);
+    }
+    if !interactive {
+        bail!(
+            "Approval '{}' needs an answer but stdin is not a terminal — \
+             pass `--approve {}` to approve it up front",
+            step_id,
+            step_id
+        );
+    }
+    Ok(Gate::Prompt)
+}
+
+/// Ask on the terminal, failing if nobody answers within `timeout` seconds
+/// (0 waits indefinitely). Anything but y/yes is a refusal.
+fn prompt(message: &str, timeout: u64) -> Result<bool> {
+    print!("{} {} (y/N) ", "[?]".yellow().bold(), message);
+    io::stdout().flush()?;
+
+    // The read happens on its own thread so the wait can be bounded; on a
+    // timeout that thread stays parked on stdin until the process exits.
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        let mut input = String::new();
+        let _ = tx.send(io::stdin().read_line(&mut input).map(|_| input));
+    });
+    let answer = if timeout == 0 {
+        rx.recv().ok()
+    } else {
+        rx.recv_timeout(Duration::from_secs(timeout)).ok()
+    };
+    let Some(answer) = answer else {
+        println!();
+        bail!("No answer within {}s", timeout);
+    };
+
+    let answer = answer?.trim().to_lowercase();
+    Ok(answer == "y" || answer == "yes")
+}
+
+/// The operator answering, as the shell knows them.
+fn current_user() -> String {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("LOGNAME"))
+        .unwrap_or_else(|_| {
+            // SAFETY: getuid() takes no arguments, cannot fail, and has no side effects.
+            format!("uid {}", unsafe { libc::getuid() })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pre_approval_skips_the_prompt_even_without_a_tty() {
+        assert_eq!(
+            gate("confirm", true, false, "ops", &[]).unwrap(),
+            Gate::PreApproved
+        );
+    }
+
+    #[test]
+    fn no_tty_fails_with_the_flag_to_pass() {
+        let err = gate("confirm", false, false, "ops", &[])
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("not a terminal"), "{err}");
+        assert!(err.contains("--approve confirm"), "{err}");
+    }
+
+    #[test]
+    fn a_tty_prompts() {
+        assert_eq!(
+            gate("confirm", false, true, "ops", &[]).unwrap(),
+            Gate::Prompt
+        );
+    }
+
+    #[test]
+    fn approvers_bind_prompt_and_flag_alike() {
+        let approvers = vec!["alice".to_string(), "bob".to_string()];
+        assert!(gate("confirm", true, false, "bob", &approvers).is_ok());
+        for (pre, tty) in [(true, false), (false, true)] {
+            let err = gate("confirm", pre, tty, "mallory", &approvers)
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains("approvers: alice, bob"), "{err}");
+        }
+    }
+}
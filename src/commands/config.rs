@@ -0,0 +1,440 @@
+use anyhow::Result;
+use colored::Colorize;
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::utils::{log_success, log_warning};
+use crate::config::{FleetConfig, Layer, Setting};
+use crate::hooks::HOOKED_COMMANDS;
+use crate::registry::NodeRegistry;
+use crate::secrets::{self, PROVISIONING_COMMANDS};
+
+/// `fleet config check`: by the time this runs, main has loaded the config
+/// strictly — any error already failed the command with its file and line.
+/// What is left is to say what was loaded, file by file.
+pub fn check(config: &FleetConfig) -> Result<()> {
+    let root = config.config_dir.join("fleet.yaml");
+    if !root.exists() {
+        log_warning(&format!(
+            "No fleet.yaml in {} — every setting is a default",
+            config.config_dir.display()
+        ));
+        return Ok(());
+    }
+
+    // label -> section -> entries defined there
+    let mut files: BTreeMap<&str, BTreeMap<&str, usize>> = BTreeMap::new();
+    for (key, origin) in &config.origins {
+        let section = key.split_once('.').map_or(key.as_str(), |(s, _)| s);
+        *files
+            .entry(origin.label.as_str())
+            .or_default()
+            .entry(section)
+            .or_default() += 1;
+    }
+    // The root first, then includes by path
+    let mut labels: Vec<&str> = files.keys().copied().collect();
+    labels.sort_by_key(|l| (*l != "fleet.yaml", *l));
+    let width = labels.iter().map(|l| l.len()).max().unwrap_or(0);
+    for label in &labels {
+        let parts: Vec<String> = files[label]
+            .iter()
+            .map(|(section, n)| describe_section(section, *n))
+            .collect();
+        println!("  {:<width$}  {}", label, parts.join(", "), width = width);
+    }
+
+    log_success(&format!(
+        "{} is valid: {}, {}, {}, {}, {}",
+        root.display(),
+        plural(labels.len().max(1), "file"),
+        plural(config.flows.len(), "flow"),
+        plural(config.hooks.len(), "hook"),
+        plural(config.secrets.len(), "secret"),
+        plural(config.nodes.len(), "node override"),
+    ));
+    Ok(())
+}
+
+/// `fleet config show [node]`: the settings each node ends up with, every
+/// one labelled with the layer it came from, then the hooks and secrets
+/// each command would use. Nodes are the registry's plus any with
+/// overrides, or just `node`.
+pub fn show(config: &FleetConfig, registry: &NodeRegistry, node: Option<&str>) -> Result<()> {
+    let profile = config.active_profile();
+    match profile {
+        Some((name, _)) => println!(
+            "{} {}{}\n",
+            "profile".bold(),
+            name,
+            config
+                .origins
+                .get(&format!("profiles.{}", name))
+                .map(|o| format!(" ({})", o))
+                .unwrap_or_default()
+        ),
+        None if !config.profiles.is_empty() => {
+            let mut names: Vec<&str> = config.profiles.keys().map(String::as_str).collect();
+            names.sort();
+            println!(
+                "{} none (--profile or FLEET_PROFILE selects one of: {})\n",
+                "profile".bold(),
+                names.join(", ")
+            );
+        }
+        None => {}
+    }
+    let profile_nodes = profile.map(|(_, p)| &p.nodes);
+    let names: Vec<&str> = match node {
+        Some(name) => {
+            let overridden = config.nodes.contains_key(name)
+                || profile_nodes.is_some_and(|nodes| nodes.contains_key(name));
+            if !registry.contains_key(name) && !overridden {
+                log_warning(&format!(
+                    "'{}' is not in the registry and has no overrides — showing the global settings",
+                    name
+                ));
+            }
+            vec![name]
+        }
+        None => {
+            let all: BTreeSet<&str> = registry
+                .keys()
+                .chain(config.nodes.keys())
+                .chain(profile_nodes.into_iter().flat_map(|nodes| nodes.keys()))
+                .map(String::as_str)
+                .collect();
+            all.into_iter().collect()
+        }
+    };
+    if names.is_empty() {
+        println!("No nodes in the registry and no node overrides");
+    }
+
+    for name in names {
+        let tags: &[String] = match registry.get(name) {
+            Some(n) => {
+                let tags = if n.tags.is_empty() {
+                    String::new()
+                } else {
+                    format!(" tags: {}", n.tags.join(", "))
+                };
+                println!("{} ({}@{}){}", name.bold(), n.ssh_user, n.hostname, tags);
+                &n.tags
+            }
+            None => {
+                println!("{} (not in the registry)", name.bold());
+                &[]
+            }
+        };
+        print_settings(config, "ssh", &config.explain_ssh(name, tags));
+        print_settings(config, "deploy", &config.explain_deploy(name, tags));
+        println!();
+    }
+
+    println!("{}", "hooks".bold());
+    let mut any = false;
+    for command in HOOKED_COMMANDS {
+        let Some((hook, layers)) = config.explain_hook(command) else {
+            continue;
+        };
+        let timeout = hook.timeout.map(|t| format!("{}s", t));
+        for (when, script, layer) in [
+            ("pre", hook.pre, layers.pre),
+            ("post", hook.post, layers.post),
+            ("pre_all", hook.pre_all, layers.pre_all),
+            ("post_all", hook.post_all, layers.post_all),
+            ("remote_pre", hook.remote_pre, layers.remote_pre),
+            ("remote_post", hook.remote_post, layers.remote_post),
+            ("on_failure", hook.on_failure, layers.on_failure),
+            ("timeout", timeout, layers.timeout),
+        ] {
+            if let (Some(script), Some(layer)) = (script, layer) {
+                println!(
+                    "  {:<20} {:<40} {}",
+                    format!("{}-{}", when, command),
+                    script,
+                    layer_label(config, &layer).dimmed()
+                );
+                any = true;
+            }
+        }
+    }
+    if !any {
+        println!("  {}", "(none)".dimmed());
+    }
+    let profile_hooks = profile.into_iter().flat_map(|(name, p)| {
+        p.hooks
+            .keys()
+            .map(move |c| (format!("profiles.{}.", name), c))
+    });
+    let hooks = config.hooks.keys().map(|c| (String::new(), c));
+    for (prefix, command) in hooks.chain(profile_hooks) {
+        if !HOOKED_COMMANDS.contains(&command.as_str()) {
+            log_warning(&format!(
+                "{}hooks.{} never fires — hooks run for: {}",
+                prefix,
+                command,
+                HOOKED_COMMANDS.join(", ")
+            ));
+        }
+    }
+    let rebuild = config.hook("rebuild");
+    if rebuild.remote_pre.is_some() || rebuild.remote_post.is_some() {
+        log_warning(
+            "hooks.rebuild remote_pre/remote_post never fire — rebuild acts on this machine",
+        );
+    }
+
+    println!("{}", "secrets".bold());
+    let mut any = false;
+    for command in PROVISIONING_COMMANDS {
+        let mut names: Vec<&String> = config
+            .secrets
+            .iter()
+            .filter(|(_, s)| s.provision_before.iter().any(|c| c == command))
+            .map(|(name, _)| name)
+            .collect();
+        names.sort();
+        for name in names {
+            let secret = &config.secrets[name];
+            println!(
+                "  {:<20} {:<40} {}",
+                format!("before {}", command),
+                format!(
+                    "{} -> {}",
+                    name,
+                    secrets::expand_home_pub(&secret.path).display()
+                ),
+                origin_of(config, &format!("secrets.{}", name)).dimmed()
+            );
+            any = true;
+        }
+    }
+    if !any {
+        println!("  {}", "(none)".dimmed());
+    }
+    for (name, secret) in &config.secrets {
+        for command in &secret.provision_before {
+            if !PROVISIONING_COMMANDS.contains(&command.as_str()) {
+                log_warning(&format!(
+                    "secrets.{} is never provisioned before '{}' — secrets are provisioned before: {}",
+                    name,
+                    command,
+                    PROVISIONING_COMMANDS.join(", ")
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn print_settings(config: &FleetConfig, section: &str, settings: &[Setting]) {
+    println!("  {}", section);
+    for s in settings {
+        let from = layer_label(config, &s.layer);
+        let from = match s.layer {
+            Layer::Default => from.dimmed(),
+            _ => from.normal(),
+        };
+        println!("    {:<28} {:<20} {}", s.name, s.value, from);
+    }
+}
+
+/// `layer (file:line)`, or just `layer` for a default or a config not
+/// read from disk.
+fn layer_label(config: &FleetConfig, layer: &Layer) -> String {
+    match config.layer_origin(layer) {
+        Some(origin) => format!("{} ({})", layer, origin),
+        None => layer.to_string(),
+    }
+}
+
+/// `key (file:line)`, or just `key` for a config not read from disk.
+fn origin_of(config: &FleetConfig, key: &str) -> String {
+    match config.origins.get(key) {
+        Some(origin) => format!("{} ({})", key, origin),
+        None => key.to_string(),
+    }
+}
+
+/// What a file contributes to one section: `3 flows`, or just `ssh` for a
+/// section of settings rather than named entries.
+fn describe_section(section: &str, n: usize) -> String {
+    match section {
+        "flows" => plural(n, "flow"),
+        "hooks" => plural(n, "hook"),
+        "secrets" => plural(n, "secret"),
+        "nodes" => plural(n, "node override"),
+        "tags" => plural(n, "tag override"),
+        "step_templates" => plural(n, "step template"),
+        "profiles" => plural(n, "profile"),
+        other => other.to_string(),
+    }
+}
+
+fn plural(n: usize, what: &str) -> String {
+    if n == 1 {
+        format!("1 {}", what)
+    } else {
+        format!("{} {}s", n, what)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_setting_names_the_layer_that_won() {
+        let config = FleetConfig::from_yaml(
+            "ssh:\n  connect_timeout: 9\n  options:\n    ServerAliveInterval: '30'\n    User: root\n\
+             nodes:\n  web1:\n    ssh:\n      options:\n        User: deploy\n    deploy:\n      show_trace: true\n",
+        )
+        .unwrap();
+        let layer = |settings: &[Setting], name: &str| {
+            let s = settings.iter().find(|s| s.name == name).unwrap();
+            (s.value.clone(), s.layer.clone())
+        };
+        let global = |key: &str| Layer::Global(key.to_string());
+        let web1 = Layer::Node("web1".to_string());
+
+        let ssh = config.explain_ssh("web1", &[]);
+        assert_eq!(
+            layer(&ssh, "connect_timeout"),
+            ("9".into(), global("ssh.connect_timeout"))
+        );
+        assert_eq!(
+            layer(&ssh, "strict_host_key"),
+            ("accept-new".into(), Layer::Default)
+        );
+        assert_eq!(
+            layer(&ssh, "options.ServerAliveInterval"),
+            ("30".into(), global("ssh.options"))
+        );
+        assert_eq!(layer(&ssh, "options.User"), ("deploy".into(), web1.clone()));
+        let deploy = config.explain_deploy("web1", &[]);
+        assert_eq!(layer(&deploy, "show_trace"), ("true".into(), web1));
+        assert_eq!(
+            layer(&deploy, "magic_rollback"),
+            ("true".into(), Layer::Default)
+        );
+
+        // The explanation and what commands actually use must agree
+        check_agreement(&config, &[("web1", &[]), ("db1", &[])]);
+    }
+
+    #[test]
+    fn a_profile_sits_between_its_layers() {
+        let mut config = FleetConfig::from_yaml(
+            "ssh:\n  connect_timeout: 9\nnodes:\n  web1:\n    ssh:\n      connect_timeout: 20\n\
+             profiles:\n  ci:\n    ssh:\n      connect_timeout: 30\n      strict_host_key: 'no'\n\
+             \x20   nodes:\n      web1:\n        deploy:\n          show_trace: true\n",
+        )
+        .unwrap();
+        config.profile = Some("ci".to_string());
+        let layer = |settings: Vec<Setting>, name: &str| {
+            let s = settings.into_iter().find(|s| s.name == name).unwrap();
+            (s.value, s.layer.to_string())
+        };
+        // The node's own override beats the profile's global settings...
+        assert_eq!(
+            layer(config.explain_ssh("web1", &[]), "connect_timeout"),
+            ("20".into(), "nodes.web1".into())
+        );
+        assert_eq!(
+            layer(config.explain_ssh("db1", &[]), "connect_timeout"),
+            ("30".into(), "profiles.ci.ssh.connect_timeout".into())
+        );
+        assert_eq!(
+            layer(config.explain_ssh("web1", &[]), "strict_host_key"),
+            ("no".into(), "profiles.ci.ssh.strict_host_key".into())
+        );
+        // ...and the profile's override for the node beats both
+        assert_eq!(
+            layer(config.explain_deploy("web1", &[]), "show_trace"),
+            ("true".into(), "profiles.ci.nodes.web1".into())
+        );
+        check_agreement(&config, &[("web1", &[]), ("db1", &[])]);
+    }
+
+    #[test]
+    fn tags_sit_between_global_and_node_and_later_tags_win() {
+        let mut config = FleetConfig::from_yaml(
+            "ssh:\n  options:\n    ProxyJump: jump.hq\n\
+             tags:\n  site-ams:\n    ssh:\n      options:\n        ProxyJump: jump.ams\n\
+             \x20 slow-link:\n    ssh:\n      connect_timeout: 20\n      options:\n        ProxyJump: jump.sat\n\
+             nodes:\n  ams3:\n    ssh:\n      connect_timeout: 9\n\
+             profiles:\n  ci:\n    tags:\n      site-ams:\n        ssh:\n          options:\n            ProxyJump: jump.ci\n",
+        )
+        .unwrap();
+        let tags = |ts: &[&str]| ts.iter().map(|t| t.to_string()).collect::<Vec<_>>();
+        let (ams, both) = (tags(&["site-ams"]), tags(&["site-ams", "slow-link"]));
+        let layer = |settings: Vec<Setting>, name: &str| {
+            let s = settings.into_iter().find(|s| s.name == name).unwrap();
+            (s.value, s.layer.to_string())
+        };
+
+        assert_eq!(
+            layer(config.explain_ssh("ams1", &ams), "options.ProxyJump"),
+            ("jump.ams".into(), "tags.site-ams".into())
+        );
+        // Of two tags setting a key, the one the node lists later wins
+        assert_eq!(
+            layer(config.explain_ssh("ams2", &both), "options.ProxyJump"),
+            ("jump.sat".into(), "tags.slow-link".into())
+        );
+        // A node's own override beats its tags
+        assert_eq!(
+            layer(config.explain_ssh("ams3", &both), "connect_timeout"),
+            ("9".into(), "nodes.ams3".into())
+        );
+        config.profile = Some("ci".to_string());
+        assert_eq!(
+            layer(config.explain_ssh("ams1", &ams), "options.ProxyJump"),
+            ("jump.ci".into(), "profiles.ci.tags.site-ams".into())
+        );
+        check_agreement(
+            &config,
+            &[
+                ("ams1", &ams),
+                ("ams2", &both),
+                ("ams3", &both),
+                ("hq1", &[]),
+            ],
+        );
+    }
+
+    fn check_agreement(config: &FleetConfig, nodes: &[(&str, &[String])]) {
+        let layer = |settings: &[Setting], name: &str| {
+            settings
+                .iter()
+                .find(|s| s.name == name)
+                .unwrap()
+                .value
+                .clone()
+        };
+        for &(node, tags) in nodes {
+            let resolved = config.resolve_ssh(node, tags);
+            let ssh = config.explain_ssh(node, tags);
+            assert_eq!(
+                layer(&ssh, "connect_timeout"),
+                resolved.connect_timeout.to_string()
+            );
+            assert_eq!(layer(&ssh, "strict_host_key"), resolved.strict_host_key);
+            for (k, v) in &resolved.options {
+                assert_eq!(&layer(&ssh, &format!("options.{}", k)), v);
+            }
+            assert_eq!(ssh.len(), 2 + resolved.options.len());
+            let resolved = config.resolve_deploy(node, tags);
+            let deploy = config.explain_deploy(node, tags);
+            assert_eq!(
+                layer(&deploy, "show_trace"),
+                resolved.show_trace.to_string()
+            );
+            assert_eq!(
+                layer(&deploy, "magic_rollback"),
+                resolved.magic_rollback.to_string()
+            );
+        }
+    }
+}
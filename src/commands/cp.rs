@@ -0,0 +1,299 @@
+use anyhow::{bail, Context, Result};
+use colored::Colorize;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::utils::*;
+use crate::config::FleetConfig;
+use crate::registry::Node;
+use crate::targeting::ResolvedTargets;
+use crate::transport::{shell_quote, Target, Transport};
+
+/// What `fleet cp <from> <to>` copies: one side is `<selector>:<path>`.
+#[derive(Debug, PartialEq)]
+pub enum Transfer {
+    /// A local file to every selected node.
+    Push {
+        local: PathBuf,
+        selector: String,
+        remote: String,
+    },
+    /// A file from every selected node into `<dir>/<node>/`.
+    Pull {
+        selector: String,
+        remote: String,
+        dir: PathBuf,
+    },
+}
+
+impl Transfer {
+    pub fn parse(from: &str, to: &str) -> Result<Self> {
+        match (remote_spec(from), remote_spec(to)) {
+            (None, Some((selector, remote))) => Ok(Transfer::Push {
+                local: PathBuf::from(from),
+                selector: selector.to_string(),
+                // scp puts a file sent to `web1:` in the home directory
+                remote: if remote.is_empty() { "~" } else { remote }.to_string(),
+            }),
+            (Some((selector, remote)), None) => {
+                if remote.is_empty() || remote.ends_with('/') {
+                    bail!("`{}` names a directory; fleet cp copies files", from);
+                }
+                Ok(Transfer::Pull {
+                    selector: selector.to_string(),
+                    remote: remote.to_string(),
+                    dir: PathBuf::from(to),
+                })
+            }
+            (Some(_), Some(_)) => {
+                bail!("copies go between this machine and nodes, not node to node")
+            }
+            (None, None) => bail!("one side must be <node or @tag>:<path>"),
+        }
+    }
+
+    pub fn selector(&self) -> &str {
+        match self {
+            Transfer::Push { selector, .. } | Transfer::Pull { selector, .. } => selector,
+        }
+    }
+}
+
+/// `web1:/etc/hosts` as its selector and path. A colon after a slash is
+/// part of a local path.
+fn remote_spec(arg: &str) -> Option<(&str, &str)> {
+    let (selector, path) = arg.split_once(':')?;
+    (!selector.is_empty() && !selector.contains('/')).then_some((selector, path))
+}
+
+/// Copy to or from every node at once, then report each node's SHA-256 —
+/// checked to be the same on both ends — or why it failed.
+pub fn run(
+    transfer: &Transfer,
+    targets: &ResolvedTargets,
+    config: &FleetConfig,
+    transport: &dyn Transport,
+) -> Result<()> {
+    let local_sum = match transfer {
+        Transfer::Push { local, remote, .. } => {
+            if !local.is_file() {
+                bail!("{} is not a file", local.display());
+            }
+            log_info(&format!(
+                "Copying {} to {}:{}",
+                local.display(),
+                transfer.selector(),
+                remote
+            ));
+            Some(local_sha256(local)?)
+        }
+        Transfer::Pull { remote, dir, .. } => {
+            log_info(&format!(
+                "Copying {}:{} into {}/<node>/",
+                transfer.selector(),
+                remote,
+                dir.display()
+            ));
+            None
+        }
+    };
+
+    let results: Vec<Result<(String, Option<PathBuf>)>> = std::thread::scope(|s| {
+        let handles: Vec<_> = targets
+            .nodes
+            .iter()
+            .map(|(name, node)| {
+                let local_sum = local_sum.as_deref();
+                s.spawn(move || copy_one(transfer, local_sum, name, node, config, transport))
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| {
+                h.join()
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("copy thread panicked")))
+            })
+            .collect()
+    });
+
+    let mut failed = 0;
+    for ((name, _), result) in targets.nodes.iter().zip(results) {
+        match result {
+            Ok((sum, Some(path))) => {
+                println!(
+                    "{} {} {}  {}",
+                    node_label(name),
+                    "✓".green(),
+                    sum,
+                    path.display()
+                )
+            }
+            Ok((sum, None)) => println!("{} {} {}", node_label(name), "✓".green(), sum),
+            Err(e) => {
+                println!("{} {} {:#}", node_label(name), "✗".red(), e);
+                failed += 1;
+            }
+        }
+    }
+    println!(
+        "\n{}/{} nodes copied",
+        targets.nodes.len() - failed,
+        targets.nodes.len()
+    );
+
+    if failed > 0 {
+        bail!("{} node(s) failed", failed);
+    }
+    Ok(())
+}
+
+/// One node's copy: its checksum, and where a pulled file landed.
+fn copy_one(
+    transfer: &Transfer,
+    local_sum: Option<&str>,
+    name: &str,
+    node: &Node,
+    config: &FleetConfig,
+    transport: &dyn Transport,
+) -> Result<(String, Option<PathBuf>)> {
+    let target = Target::node(config, name, node);
+    match transfer {
+        Transfer::Push { local, remote, .. } => {
+            let output = transport.push(&target, local, remote)?;
+            if !output.success() {
+                bail!("copy failed: {}", output.stderr.trim());
+            }
+            // Into a directory, the file keeps its name
+            let base = local.file_name().unwrap_or_default().to_string_lossy();
+            let remote_sum = remote_sha256(
+                transport,
+                &target,
+                &format!(
+                    "f={}; [ -d \"$f\" ] && f=\"$f\"/{}; sha256sum -- \"$f\"",
+                    remote_word(remote),
+                    shell_quote(&base)
+                ),
+            )?;
+            let local_sum = local_sum.unwrap_or_default();
+            if remote_sum != local_sum {
+                bail!(
+                    "checksum mismatch: sent {}, node has {}",
+                    local_sum,
+                    remote_sum
+                );
+            }
+            Ok((remote_sum, None))
+        }
+        Transfer::Pull { remote, dir, .. } => {
+            let dir = dir.join(name);
+            fs::create_dir_all(&dir)
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+            let base = Path::new(remote).file_name().unwrap_or_default();
+            let path = dir.join(base);
+            let output = transport.pull(&target, remote, &path)?;
+            if !output.success() {
+                bail!("copy failed: {}", output.stderr.trim());
+            }
+            let remote_sum = remote_sha256(
+                transport,
+                &target,
+                &format!("sha256sum -- {}", remote_word(remote)),
+            )?;
+            let local_sum = local_sha256(&path)?;
+            if remote_sum != local_sum {
+                bail!(
+                    "checksum mismatch: node has {}, received {}",
+                    remote_sum,
+                    local_sum
+                );
+            }
+            Ok((remote_sum, Some(path)))
+        }
+    }
+}
+
+/// A remote path as a word for the node's shell: quoted, but with a leading
+/// `~` still meaning the home directory, as it does to scp.
+fn remote_word(path: &str) -> String {
+    match path.strip_prefix('~') {
+        Some("") => "\"$HOME\"".to_string(),
+        Some(rest) if rest.starts_with('/') => format!("\"$HOME\"/{}", shell_quote(&rest[1..])),
+        _ => shell_quote(path),
+    }
+}
+
+/// Hashed here rather than with `sha256sum`, which not every operator's
+/// machine has — macOS ships `shasum` instead.
+fn local_sha256(path: &Path) -> Result<String> {
+    let mut file =
+        fs::File::open(path).with_context(|| format!("failed to read {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn remote_sha256(transport: &dyn Transport, target: &Target, command: &str) -> Result<String> {
+    let output = transport.run(target, command)?;
+    if !output.success() {
+        bail!("could not checksum the copy: {}", output.stderr.trim());
+    }
+    first_word(&output.stdout)
+}
+
+/// The hash in `sha256sum` output.
+fn first_word(output: &str) -> Result<String> {
+    match output.split_whitespace().next() {
+        Some(sum) => Ok(sum.to_string()),
+        None => bail!("sha256sum printed nothing"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_side_with_a_selector_is_the_remote_one() {
+        assert_eq!(
+            Transfer::parse("app.conf", "@web:/etc/app/").unwrap(),
+            Transfer::Push {
+                local: PathBuf::from("app.conf"),
+                selector: "@web".to_string(),
+                remote: "/etc/app/".to_string(),
+            }
+        );
+        assert_eq!(
+            Transfer::parse("web1:/var/log/app.log", "./logs").unwrap(),
+            Transfer::Pull {
+                selector: "web1".to_string(),
+                remote: "/var/log/app.log".to_string(),
+                dir: PathBuf::from("./logs"),
+            }
+        );
+        // A colon further into a path is just a character
+        assert!(Transfer::parse("./a:b", "./c").is_err());
+        assert!(Transfer::parse("web1:/a", "db1:/b").is_err());
+        assert!(Transfer::parse("web1:/etc/", "out").is_err());
+        // No path at all is the home directory, as it is to scp
+        assert_eq!(
+            Transfer::parse("app.conf", "web1:").unwrap(),
+            Transfer::Push {
+                local: PathBuf::from("app.conf"),
+                selector: "web1".to_string(),
+                remote: "~".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn a_leading_tilde_still_means_home_on_the_node() {
+        assert_eq!(remote_word("~/app.conf"), "\"$HOME\"/app.conf");
+        assert_eq!(remote_word("~/my app/"), "\"$HOME\"/'my app/'");
+        assert_eq!(remote_word("~"), "\"$HOME\"");
+        assert_eq!(remote_word("~bob/app.conf"), "'~bob/app.conf'");
+        assert_eq!(remote_word("/etc/~/x"), "'/etc/~/x'");
+    }
+}
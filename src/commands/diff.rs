@@ -1,11 +1,15 @@
 use anyhow::Result;
-use std::process::Command;
 
 use super::utils::*;
 use crate::config::FleetConfig;
 use crate::targeting::ResolvedTargets;
+use crate::transport::{Target, Transport};
 
-pub fn run(targets: &ResolvedTargets, config: &FleetConfig) -> Result<()> {
+pub fn run(
+    targets: &ResolvedTargets,
+    config: &FleetConfig,
+    transport: &dyn Transport,
+) -> Result<()> {
     let flake = flake_dir();
 
     for (name, node) in &targets.nodes {
@@ -13,22 +17,15 @@ pub fn run(targets: &ResolvedTargets, config: &FleetConfig) -> Result<()> {
 
         // Build the new closure locally
         let drv = format!("{flake}#nixosConfigurations.{name}.config.system.build.toplevel");
-        let new_path = run_command_output(
-            Command::new("nix")
-                .arg("build")
-                .arg(&drv)
-                .arg("--print-out-paths")
-                .arg("--no-link"),
-        )?;
+        let build = transport.local("nix", &["build", &drv, "--print-out-paths", "--no-link"])?;
+        if !build.success() {
+            anyhow::bail!("Command failed: {}", build.stderr.trim());
+        }
+        let new_path = build.stdout.trim().to_string();
 
         // Get current system path from remote
-        let ssh = config.resolve_ssh(name);
-        let current_path = match ssh_run_with_config(
-            &node.ssh_user,
-            &node.hostname,
-            &ssh,
-            "readlink /run/current-system",
-        ) {
+        let target = Target::node(config, name, node);
+        let current_path = match transport.run_ok(&target, "readlink /run/current-system") {
             Ok(path) => path,
             Err(e) => {
                 log_warning(&format!(
@@ -42,13 +39,18 @@ pub fn run(targets: &ResolvedTargets, config: &FleetConfig) -> Result<()> {
 
         // Show diff
         println!("{}", node_label(name));
-        let mut cmd = Command::new("nix");
-        cmd.arg("store")
-            .arg("diff-closures")
-            .arg(current_path.trim())
-            .arg(new_path.trim());
-        if let Err(e) = run_command(&mut cmd) {
-            log_warning(&format!("{} diff failed: {}", node_label(name), e));
+        let diff = transport.local(
+            "nix",
+            &["store", "diff-closures", current_path.trim(), &new_path],
+        );
+        match diff {
+            Ok(out) if out.success() => print!("{}", out.stdout),
+            Ok(out) => log_warning(&format!(
+                "{} diff failed: {}",
+                node_label(name),
+                out.stderr.trim()
+            )),
+            Err(e) => log_warning(&format!("{} diff failed: {}", node_label(name), e)),
         }
         println!();
     }
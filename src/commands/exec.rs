@@ -3,16 +3,22 @@ use anyhow::Result;
 use super::utils::*;
 use crate::config::FleetConfig;
 use crate::targeting::ResolvedTargets;
+use crate::transport::{self, Target, Transport};
 
-pub fn run(targets: &ResolvedTargets, cmd: &[String], config: &FleetConfig) -> Result<()> {
-    let remote_cmd = cmd.join(" ");
+pub fn run(
+    targets: &ResolvedTargets,
+    cmd: &[String],
+    config: &FleetConfig,
+    transport: &dyn Transport,
+) -> Result<()> {
+    let remote_cmd = transport::exec_command_line(cmd);
     log_info(&format!("Executing: {}", remote_cmd));
 
     let mut had_error = false;
 
     for (name, node) in &targets.nodes {
-        let ssh = config.resolve_ssh(name);
-        match ssh_run_with_config(&node.ssh_user, &node.hostname, &ssh, &remote_cmd) {
+        let target = Target::node(config, name, node);
+        match transport.run_ok(&target, &remote_cmd) {
             Ok(output) => {
                 for line in output.lines() {
                     println!("{} {}", node_label(name), line);
@@ -1,14 +1,25 @@
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
 use colored::Colorize;
+use std::borrow::Cow;
 use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
 use std::process::Command;
+use std::sync::Mutex;
 
-use crate::config::{ActionDef, FleetConfig, FlowSecret, StepDef, StepResult};
+use crate::condition::{self, Expr, StepStatus};
+use crate::config::{
+    ActionDef, ConditionDef, FailureMode, FleetConfig, FlowDef, FlowSecret, StepDef, StepResult,
+};
 use crate::dag;
-use crate::flow;
+use crate::flow::{self, Reference, Segment, TemplateRef};
+use crate::hooks::{self, HookContext};
+use crate::lock::Locks;
 use crate::registry::NodeRegistry;
+use crate::runs::{self, Recorder, RunRecord, RunStatus, StepRecord};
 use crate::secrets;
 use crate::targeting;
+use crate::transport::Transport;
 
 use super::utils::*;
 
@@ -31,20 +42,160 @@ pub fn list(config: &FleetConfig) -> Result<()> {
     Ok(())
 }
 
+/// Outputs captured during a run, keyed by step ID then output name.
+type FlowOutputs = HashMap<String, HashMap<String, serde_json::Value>>;
+
+/// Command-line options for `fleet flow run`.
+pub struct RunOptions {
+    /// Targets for steps without their own
+    pub targets: Vec<String>,
+    pub all: bool,
+    /// `name=value` params
+    pub params: Vec<String>,
+    /// IDs of approval steps approved up front
+    pub approve: Vec<String>,
+    pub dry_run: bool,
+}
+
+/// What every step of a run shares — including the steps of sub-flows.
+struct RunContext<'a> {
+    /// The flow being run — a sub-flow's own name inside one.
+    flow: &'a str,
+    config: &'a FleetConfig,
+    registry: &'a NodeRegistry,
+    cli_all: bool,
+    approved: &'a [String],
+    /// Keeps the run's record on disk, if one is being kept.
+    recorder: Option<&'a Recorder>,
+    /// Prepended to step IDs in the record: `<flow step>.` in a sub-flow.
+    record_prefix: String,
+    /// Flow and node locks, taken by sub-flows and node-changing steps.
+    locks: &'a Locks,
+    /// Sub-flow locks this run holds, by flow name. Taken the first time a
+    /// step runs the sub-flow and kept until the run ends: flock locks are
+    /// per open file, so two steps of one run locking the same sub-flow
+    /// each would wait on each other.
+    sub_flow_locks: &'a Mutex<HashMap<String, File>>,
+    /// How steps reach nodes.
+    transport: &'a dyn Transport,
+    /// What the running flow's relative `file:` paths resolve against.
+    flow_dir: &'a Path,
+}
+
 pub fn run(
     config: &FleetConfig,
     registry: &NodeRegistry,
+    locks: &Locks,
+    transport: &dyn Transport,
+    runs_dir: &Path,
     name: &str,
-    cli_targets: &[String],
-    cli_all: bool,
-    dry_run: bool,
+    opts: &RunOptions,
 ) -> Result<()> {
-    let flow_def = config
-        .flows
-        .get(name)
-        .ok_or_else(|| anyhow::anyhow!("Unknown flow: '{}'", name))?;
+    let (flow_def, levels) = plan(config, registry, name)?;
+    let params = bind_params(name, &flow_def, parse_params(&opts.params)?)?;
+    check_approvals(config, name, &opts.approve)?;
+
+    let sub_flow_locks = Mutex::new(HashMap::new());
+    let mut ctx = RunContext {
+        flow: name,
+        config,
+        registry,
+        cli_all: opts.all,
+        approved: &opts.approve,
+        recorder: None,
+        record_prefix: String::new(),
+        locks,
+        sub_flow_locks: &sub_flow_locks,
+        transport,
+        flow_dir: config.flow_dir(&flow_def),
+    };
+    if opts.dry_run {
+        print_execution_plan(&ctx, &flow_def, &levels, &opts.targets, &params);
+        return Ok(());
+    }
+    // Held until the run, cleanup included, is recorded.
+    let _flow_lock = locks.flow(name, &format!("fleet flow run {}", name))?;
+
+    let (git_rev, git_dirty) = runs::git_state(&config.config_dir);
+    let started_at = runs::now_epoch();
+    let recorder = Recorder::start(
+        runs_dir.to_path_buf(),
+        RunRecord {
+            id: format!("{}-{}", started_at, std::process::id()),
+            flow: name.to_string(),
+            params: params.clone().into_iter().collect(),
+            targets: opts.targets.clone(),
+            all: opts.all,
+            user: std::env::var("USER").unwrap_or_else(|_| "unknown".to_owned()),
+            git_rev,
+            git_dirty,
+            started_at,
+            finished_at: None,
+            duration_ms: None,
+            status: RunStatus::Running,
+            error: None,
+            steps: Vec::new(),
+        },
+    );
+    ctx.recorder = Some(&recorder);
+
+    let result = execute_flow(&ctx, name, &flow_def, &levels, &opts.targets, &params);
+    recorder.finish(result.as_ref().err().map(|e| format!("{:#}", e)));
+    log_info(&format!("Run recorded: fleet flow show {}", recorder.id()));
+    if let Err(e) = runs::prune(runs_dir, &config.runs) {
+        log_warning(&format!("Could not prune old run records: {:#}", e));
+    }
+    result.map(|_| ())
+}
 
-    let validated = flow::validate(flow_def)?;
+/// Reject `--approve` names that match no approval step in the flow or the
+/// sub-flows it runs — a typo there would otherwise surface only as a
+/// prompt, or a failed gate, partway through the run.
+fn check_approvals(config: &FleetConfig, name: &str, approve: &[String]) -> Result<()> {
+    fn collect<'a>(config: &'a FleetConfig, name: &str, out: &mut Vec<&'a str>) {
+        for step in config.flows[name].all_steps() {
+            match &step.action {
+                ActionDef::Approval { .. } => out.push(&step.id),
+                ActionDef::Flow { name: sub, .. } => collect(config, sub, out),
+                _ => {}
+            }
+        }
+    }
+
+    if approve.is_empty() {
+        return Ok(());
+    }
+    let mut gates = Vec::new();
+    collect(config, name, &mut gates);
+    for id in approve {
+        if !gates.contains(&id.as_str()) {
+            gates.sort();
+            bail!(
+                "--approve '{}' names no approval step in flow '{}' (approval steps: {})",
+                id,
+                name,
+                if gates.is_empty() {
+                    "none".to_string()
+                } else {
+                    gates.join(", ")
+                }
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Look up and validate a flow, expand its `foreach` steps against the
+/// registry, and order the result into execution levels.
+fn plan<'a>(
+    config: &'a FleetConfig,
+    registry: &NodeRegistry,
+    name: &str,
+) -> Result<(Cow<'a, FlowDef>, Vec<Vec<usize>>)> {
+    flow::validate(&config.flows, name)?;
+    let flow_def = flow::expand_foreach(&config.flows[name], registry)?;
+    let validated = flow::validate_steps(&flow_def)
+        .with_context(|| format!("Flow '{}' after foreach expansion", name))?;
     let levels = dag::topo_levels(flow_def.steps.len(), &validated.deps);
 
     // Check all steps were scheduled (if not, there's an undetected cycle)
@@ -53,11 +204,69 @@ pub fn run(
         bail!("Flow '{}' has a dependency cycle", name);
     }
 
-    if dry_run {
-        print_execution_plan(flow_def, &levels);
-        return Ok(());
+    Ok((flow_def, levels))
+}
+
+/// Parse `--param name=value` arguments.
+fn parse_params(raw: &[String]) -> Result<HashMap<String, String>> {
+    raw.iter()
+        .map(|p| match p.split_once('=') {
+            Some((k, v)) if !k.is_empty() => Ok((k.to_string(), v.to_string())),
+            _ => bail!("Invalid --param '{}' (expected name=value)", p),
+        })
+        .collect()
+}
+
+/// Check supplied params against the flow's declarations and fill in defaults.
+fn bind_params(
+    name: &str,
+    flow_def: &FlowDef,
+    mut given: HashMap<String, String>,
+) -> Result<HashMap<String, String>> {
+    let mut declared: Vec<_> = flow_def.params.keys().map(String::as_str).collect();
+    declared.sort();
+    for param in given.keys() {
+        if !flow_def.params.contains_key(param) {
+            bail!(
+                "Flow '{}' has no param '{}' (declared: {})",
+                name,
+                param,
+                if declared.is_empty() {
+                    "none".to_string()
+                } else {
+                    declared.join(", ")
+                }
+            );
+        }
+    }
+    for (param, def) in &flow_def.params {
+        if given.contains_key(param) {
+            continue;
+        }
+        match &def.default {
+            Some(default) => {
+                given.insert(param.clone(), default.clone());
+            }
+            None => bail!(
+                "Flow '{}' requires param '{}' (pass --param {}=...)",
+                name,
+                param,
+                param
+            ),
+        }
     }
+    Ok(given)
+}
 
+/// Run a validated flow level by level, returning every step's outputs.
+fn execute_flow(
+    ctx: &RunContext,
+    name: &str,
+    flow_def: &FlowDef,
+    levels: &[Vec<usize>],
+    cli_targets: &[String],
+    params: &HashMap<String, String>,
+) -> Result<FlowOutputs> {
     log_info(&format!(
         "Running flow: {} — {}",
         name, flow_def.description
@@ -73,79 +282,613 @@ pub fn run(
             "Resolving {} secret(s)...",
             flow_def.secrets.len()
         ));
-        resolve_flow_secrets(&flow_def.secrets, &config.config_dir)?
+        resolve_flow_secrets(&flow_def.secrets, ctx.flow_dir)?
     };
 
-    // Accumulate outputs from all completed steps, keyed by step ID
-    let mut all_outputs: HashMap<String, HashMap<String, serde_json::Value>> = HashMap::new();
+    let mut state = FlowState::default();
+    let main = run_levels(
+        ctx,
+        flow_def,
+        levels,
+        cli_targets,
+        &resolved_secrets,
+        params,
+        &mut state,
+    );
+    if main.is_err() {
+        print_failure_report(flow_def, &state);
+    }
+    if flow_def.on_failure.is_empty() && flow_def.finally.is_empty() {
+        main?;
+    } else {
+        run_cleanup(
+            ctx,
+            flow_def,
+            cli_targets,
+            &resolved_secrets,
+            params,
+            &mut state,
+            main,
+        )?;
+    }
 
-    for (level_idx, level) in levels.iter().enumerate() {
-        for &step_idx in level {
-            let step = &flow_def.steps[step_idx];
+    log_success(&format!("Flow '{}' complete", name));
+    Ok(state.outputs)
+}
 
-            println!(
-                "{} Step {}/{}: {}",
-                ">>>".blue().bold(),
-                level_idx + 1,
-                level.len(),
-                step.id.bold()
-            );
+/// Outputs and statuses of a flow's finished steps, what failed, and
+/// what a failure kept from running.
+#[derive(Default)]
+struct FlowState<'a> {
+    /// Outputs of completed steps, keyed by step ID
+    outputs: FlowOutputs,
+    /// How each finished step ended, for later steps' conditions
+    statuses: HashMap<String, StepStatus>,
+    /// Failed steps and their errors, in the order they failed
+    failed: Vec<(&'a StepDef, String)>,
+    /// Steps skipped because of failures upstream, with those failed steps
+    blocked: Vec<(String, Vec<String>)>,
+}
 
-            // Evaluate condition
-            if let Some(ref cond) = step.condition {
-                let status = Command::new("sh").arg("-c").arg(&cond.command).status();
-                match status {
-                    Ok(s) if s.success() => {}
-                    _ => {
-                        log_info(&format!("Condition not met, skipping step '{}'", step.id));
-                        continue;
-                    }
+/// Run the main steps level by level. After a failure, the flow's
+/// `failure_mode` decides what else runs: nothing (`fail-fast`), the rest
+/// of the level (`finish-level`), or every step not downstream of a
+/// failure (`best-effort`).
+fn run_levels<'a>(
+    ctx: &RunContext,
+    flow_def: &'a FlowDef,
+    levels: &[Vec<usize>],
+    cli_targets: &[String],
+    secrets: &HashMap<String, String>,
+    params: &HashMap<String, String>,
+    state: &mut FlowState<'a>,
+) -> Result<()> {
+    let mut errors = Vec::new();
+    'levels: for (level_idx, level) in levels.iter().enumerate() {
+        for batch in batches(flow_def, level) {
+            let mut runnable = Vec::with_capacity(batch.len());
+            for idx in batch {
+                let step = &flow_def.steps[idx];
+                let blocked_by = blockers(step, state);
+                if blocked_by.is_empty() {
+                    runnable.push(step);
+                    continue;
                 }
+                print_step_header(level_idx, level.len(), step);
+                log_warning(&format!(
+                    "Skipping step '{}': upstream {} failed",
+                    step.id,
+                    quoted(&blocked_by)
+                ));
+                skip_blocked(ctx, step, blocked_by, state);
+                println!();
             }
+            if runnable.is_empty() {
+                continue;
+            }
+
+            let scope = TemplateScope {
+                outputs: &state.outputs,
+                secrets,
+                params,
+                strict: flow_def.strict_templates,
+                failure: None,
+            };
+            let statuses = &state.statuses;
 
-            // Resolve targets for this step
-            let step_targets = if step.targets.is_empty() {
-                cli_targets.to_vec()
+            let results: Vec<(&StepDef, Timed<Result<Option<StepResult>>>)> = if runnable.len() == 1
+            {
+                let step = runnable[0];
+                print_step_header(level_idx, level.len(), step);
+                vec![(
+                    step,
+                    timed(|| run_step(ctx, step, cli_targets, &scope, statuses)),
+                )]
             } else {
-                step.targets.clone()
+                // Instances of one concurrent fan-out: start them all, then
+                // wait for every one before reporting failures.
+                std::thread::scope(|s| {
+                    let handles: Vec<_> = runnable
+                        .iter()
+                        .map(|&step| {
+                            print_step_header(level_idx, level.len(), step);
+                            let scope = &scope;
+                            (
+                                step,
+                                s.spawn(move || {
+                                    timed(|| run_step(ctx, step, cli_targets, scope, statuses))
+                                }),
+                            )
+                        })
+                        .collect();
+                    handles
+                        .into_iter()
+                        .map(|(step, h)| {
+                            let result = h.join().unwrap_or_else(|_| Timed {
+                                value: Err(anyhow::anyhow!("step thread panicked")),
+                                started_at: runs::now_epoch(),
+                                elapsed: std::time::Duration::ZERO,
+                            });
+                            (step, result)
+                        })
+                        .collect()
+                })
             };
 
-            let result = dispatch_action(
-                config,
-                registry,
-                step,
-                &step_targets,
-                cli_all,
-                &all_outputs,
-                &resolved_secrets,
-            )?;
+            for (step, timed) in results {
+                if let Err(e) = finish_step(ctx, step, timed, state) {
+                    state.failed.push((step, format!("{:#}", e)));
+                    errors.push(e);
+                }
+            }
+
+            println!();
+            if !errors.is_empty() && flow_def.failure_mode == FailureMode::FailFast {
+                break 'levels;
+            }
+        }
+        if !errors.is_empty() && flow_def.failure_mode == FailureMode::FinishLevel {
+            break;
+        }
+    }
+
+    let mut errors = errors.into_iter();
+    match errors.next() {
+        None => Ok(()),
+        Some(e) if errors.len() == 0 => Err(e),
+        Some(_) => {
+            let ids: Vec<_> = state.failed.iter().map(|(s, _)| s.id.clone()).collect();
+            bail!("{} steps failed: {}", ids.len(), ids.join(", "))
+        }
+    }
+}
 
-            // Store outputs for downstream interpolation
-            if !result.outputs.is_empty() {
-                all_outputs.insert(step.id.clone(), result.outputs);
+/// The failed steps that keep `step` from running. A dependency that failed,
+/// or was itself skipped for a failure, blocks the step — unless the step's
+/// condition checks that dependency's status, since then it was written to
+/// react to the failure.
+fn blockers(step: &StepDef, state: &FlowState) -> Vec<String> {
+    let checked = match &step.condition {
+        Some(ConditionDef::Expr(src)) => Expr::parse(src)
+            .map(|expr| expr.status_steps())
+            .unwrap_or_default(),
+        _ => Vec::new(),
+    };
+    let mut out: Vec<String> = Vec::new();
+    for dep in &step.depends_on {
+        let base = dep
+            .strip_suffix(']')
+            .and_then(|id| id.split_once('['))
+            .map_or(dep.as_str(), |(base, _)| base);
+        if checked.iter().any(|&c| c == dep || c == base) {
+            continue;
+        }
+        let roots = if state.statuses.get(dep) == Some(&StepStatus::Failed) {
+            vec![dep.clone()]
+        } else {
+            state
+                .blocked
+                .iter()
+                .find(|(id, _)| id == dep)
+                .map(|(_, roots)| roots.clone())
+                .unwrap_or_default()
+        };
+        for root in roots {
+            if !out.contains(&root) {
+                out.push(root);
             }
+        }
+    }
+    out
+}
+
+/// Mark a step skipped because of the failures in `blocked_by`.
+fn skip_blocked(ctx: &RunContext, step: &StepDef, blocked_by: Vec<String>, state: &mut FlowState) {
+    state.statuses.insert(step.id.clone(), StepStatus::Skipped);
+    if let Some(recorder) = ctx.recorder {
+        recorder.step(StepRecord {
+            id: format!("{}{}", ctx.record_prefix, step.id),
+            action: step.action.type_name().to_string(),
+            status: StepStatus::Skipped,
+            started_at: runs::now_epoch(),
+            duration_ms: 0,
+            error: None,
+            outputs: Vec::new(),
+            approval: None,
+            blocked_by: blocked_by.clone(),
+        });
+    }
+    state.blocked.push((step.id.clone(), blocked_by));
+}
+
+/// After a failure: what failed, what was skipped because of which failure,
+/// and what the failure mode stopped from starting.
+fn print_failure_report(flow_def: &FlowDef, state: &FlowState) {
+    let not_run: Vec<&str> = flow_def
+        .steps
+        .iter()
+        .filter(|s| !state.statuses.contains_key(&s.id))
+        .map(|s| s.id.as_str())
+        .collect();
+    if state.blocked.is_empty() && not_run.is_empty() && state.failed.len() < 2 {
+        return;
+    }
 
+    println!("{}", "Failure report:".bold());
+    for (step, error) in &state.failed {
+        println!(
+            "  {}  {} — {}",
+            "failed ".red(),
+            step.id.bold(),
+            error.lines().next().unwrap_or_default()
+        );
+    }
+    for (id, blocked_by) in &state.blocked {
+        println!(
+            "  {}  {} — upstream {} failed",
+            "skipped".yellow(),
+            id,
+            quoted(blocked_by)
+        );
+    }
+    if !not_run.is_empty() {
+        println!(
+            "  {}  {} (stopped by failure_mode: {})",
+            "not run".dimmed(),
+            not_run.join(", "),
+            flow_def.failure_mode.name()
+        );
+    }
+    println!();
+}
+
+/// `'a', 'b'` for a message.
+fn quoted(ids: &[String]) -> String {
+    ids.iter()
+        .map(|id| format!("'{}'", id))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Run the `on_failure` steps (if the main steps failed) and then the
+/// `finally` steps, one at a time. A failed cleanup step does not stop the
+/// ones after it — teardown should get as far as it can.
+fn run_cleanup<'a>(
+    ctx: &RunContext,
+    flow_def: &'a FlowDef,
+    cli_targets: &[String],
+    secrets: &HashMap<String, String>,
+    params: &HashMap<String, String>,
+    state: &mut FlowState<'a>,
+    main: Result<()>,
+) -> Result<()> {
+    // With several failures, the first one is reported
+    let failure = match state.failed.first() {
+        None => Failure::default(),
+        Some((step, error)) => Failure {
+            step: step.id.clone(),
+            action: step.action.type_name().to_string(),
+            error: error.clone(),
+        },
+    };
+    let mut lists = vec![("finally", &flow_def.finally)];
+    if main.is_err() {
+        lists.insert(0, ("on_failure", &flow_def.on_failure));
+    }
+
+    let mut failed = Vec::new();
+    for (list, steps) in lists {
+        for (i, step) in steps.iter().enumerate() {
+            println!(
+                "{} {} {}/{}: {}",
+                ">>>".blue().bold(),
+                list,
+                i + 1,
+                steps.len(),
+                step.id.bold()
+            );
+            let scope = TemplateScope {
+                outputs: &state.outputs,
+                secrets,
+                params,
+                strict: flow_def.strict_templates,
+                failure: Some(&failure),
+            };
+            let timed = timed(|| run_step(ctx, step, cli_targets, &scope, &state.statuses));
+            if let Err(e) = finish_step(ctx, step, timed, state) {
+                log_error(&format!("Cleanup step '{}' failed: {:#}", step.id, e));
+                failed.push(step.id.as_str());
+            }
             println!();
         }
     }
 
-    log_success(&format!("Flow '{}' complete", name));
+    match (main, failed.is_empty()) {
+        (main, true) => main,
+        (Err(e), false) => Err(anyhow::anyhow!(
+            "{:#}; cleanup also failed in: {}",
+            e,
+            failed.join(", ")
+        )),
+        (Ok(()), false) => bail!("cleanup failed in: {}", failed.join(", ")),
+    }
+}
+
+/// Record how a step ended and keep its outputs for later steps; returns
+/// the step's error, if it failed.
+fn finish_step(
+    ctx: &RunContext,
+    step: &StepDef,
+    timed: Timed<Result<Option<StepResult>>>,
+    state: &mut FlowState,
+) -> Result<()> {
+    let result = timed.value;
+    let status = match &result {
+        Ok(Some(_)) => StepStatus::Succeeded,
+        Ok(None) => StepStatus::Skipped,
+        Err(_) => StepStatus::Failed,
+    };
+    state.statuses.insert(step.id.clone(), status);
+    if let Some(recorder) = ctx.recorder {
+        let outputs = result.as_ref().ok().and_then(Option::as_ref);
+        recorder.step(StepRecord {
+            id: format!("{}{}", ctx.record_prefix, step.id),
+            action: step.action.type_name().to_string(),
+            status,
+            started_at: timed.started_at,
+            duration_ms: timed.elapsed.as_millis() as u64,
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+            outputs: outputs
+                .map(|r| {
+                    let mut names: Vec<_> = r.outputs.keys().cloned().collect();
+                    names.sort();
+                    names
+                })
+                .unwrap_or_default(),
+            approval: outputs
+                .filter(|_| matches!(step.action, ActionDef::Approval { .. }))
+                .map(|r| r.outputs.clone().into_iter().collect()),
+            blocked_by: Vec::new(),
+        });
+    }
+    // Store outputs for downstream interpolation
+    if let Some(result) = result? {
+        if !result.outputs.is_empty() {
+            state.outputs.insert(step.id.clone(), result.outputs);
+        }
+    }
+    Ok(())
+}
+
+/// What made a flow's main steps fail, read by its cleanup steps as
+/// `${failure.<field>}`. Empty when they succeeded.
+#[derive(Default)]
+struct Failure {
+    step: String,
+    action: String,
+    error: String,
+}
+
+impl Failure {
+    fn field(&self, name: &str) -> Option<&str> {
+        match name {
+            "step" => Some(&self.step),
+            "action" => Some(&self.action),
+            "error" => Some(&self.error),
+            _ => None,
+        }
+    }
+}
+
+/// A value with when its computation started (epoch seconds) and how long
+/// it took, for the run record.
+struct Timed<T> {
+    value: T,
+    started_at: u64,
+    elapsed: std::time::Duration,
+}
+
+fn timed<T>(f: impl FnOnce() -> T) -> Timed<T> {
+    let started_at = runs::now_epoch();
+    let start = std::time::Instant::now();
+    let value = f();
+    Timed {
+        value,
+        started_at,
+        elapsed: start.elapsed(),
+    }
+}
+
+/// Split a level into the groups that run together: the instances of one
+/// non-serial `foreach` step form a single batch, every other step runs on
+/// its own, in level order.
+fn batches(flow_def: &FlowDef, level: &[usize]) -> Vec<Vec<usize>> {
+    let mut batches: Vec<Vec<usize>> = Vec::new();
+    for &idx in level {
+        let step = &flow_def.steps[idx];
+        let joins = match (batches.last(), &step.instance_of) {
+            (Some(last), Some(base)) if !step.serial => {
+                flow_def.steps[last[0]].instance_of.as_ref() == Some(base)
+            }
+            _ => false,
+        };
+        match batches.last_mut() {
+            Some(last) if joins => last.push(idx),
+            _ => batches.push(vec![idx]),
+        }
+    }
+    batches
+}
+
+fn print_step_header(level_idx: usize, level_len: usize, step: &StepDef) {
+    println!(
+        "{} Step {}/{}: {}",
+        ">>>".blue().bold(),
+        level_idx + 1,
+        level_len,
+        step.id.bold()
+    );
+}
+
+/// Evaluate a step's condition and run its action. `None` means the
+/// condition skipped it.
+fn run_step(
+    ctx: &RunContext,
+    step: &StepDef,
+    cli_targets: &[String],
+    scope: &TemplateScope,
+    statuses: &HashMap<String, StepStatus>,
+) -> Result<Option<StepResult>> {
+    // Resolve targets for this step
+    let step_targets = if step.targets.is_empty() {
+        cli_targets.to_vec()
+    } else {
+        step.targets.clone()
+    };
+
+    // Evaluate condition
+    let met = match &step.condition {
+        None => true,
+        Some(ConditionDef::Command { command }) => Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .status()
+            .is_ok_and(|s| s.success()),
+        Some(ConditionDef::Expr(src)) => {
+            let facts = StepFacts {
+                ctx,
+                scope,
+                statuses: Some(statuses),
+                targets: &step_targets,
+            };
+            Expr::parse(src)
+                .and_then(|expr| expr.eval(&facts))
+                .with_context(|| format!("step '{}' condition `{}`", step.id, src))?
+                // Every input is known at run time
+                .unwrap_or(false)
+        }
+    };
+    if !met {
+        log_info(&format!("Condition not met, skipping step '{}'", step.id));
+        return Ok(None);
+    }
+
+    dispatch_action(ctx, step, &step_targets, scope).map(Some)
+}
+
+/// What a declarative condition observes at one step. Without `statuses`
+/// this is plan time (`--dry-run`): params and the registry are known,
+/// step outputs, statuses and secrets are not.
+struct StepFacts<'a> {
+    ctx: &'a RunContext<'a>,
+    scope: &'a TemplateScope<'a>,
+    statuses: Option<&'a HashMap<String, StepStatus>>,
+    /// The step's own targets, before resolution
+    targets: &'a [String],
+}
+
+impl condition::Facts for StepFacts<'_> {
+    fn value(&self, r: &TemplateRef) -> Result<Option<String>> {
+        if self.statuses.is_none() && !matches!(r.reference, Reference::Param(_)) {
+            return Ok(None);
+        }
+        resolve_ref(r, self.scope).map(Some)
+    }
+
+    /// A `foreach` step's status summarises its instances: failed if any
+    /// failed, skipped if all were skipped, succeeded otherwise.
+    fn status(&self, step: &str) -> Option<StepStatus> {
+        let statuses = self.statuses?;
+        if let Some(&status) = statuses.get(step) {
+            return Some(status);
+        }
+        let instances: Vec<StepStatus> = statuses
+            .iter()
+            .filter(|(id, _)| {
+                id.strip_suffix(']')
+                    .and_then(|id| id.split_once('['))
+                    .is_some_and(|(base, _)| base == step)
+            })
+            .map(|(_, &status)| status)
+            .collect();
+        if instances.is_empty() {
+            None
+        } else if instances.contains(&StepStatus::Failed) {
+            Some(StepStatus::Failed)
+        } else if instances.iter().all(|&s| s == StepStatus::Skipped) {
+            Some(StepStatus::Skipped)
+        } else {
+            Some(StepStatus::Succeeded)
+        }
+    }
+
+    fn target_count(&self, selector: Option<&str>) -> Result<Option<usize>> {
+        let registry = self.ctx.registry;
+        let count = match selector {
+            None => resolve_step_targets(registry, self.targets, self.ctx.cli_all)?
+                .nodes
+                .len(),
+            // An unmatched tag counts zero rather than failing
+            Some(sel) => match sel.strip_prefix('@') {
+                Some(tag) => registry
+                    .values()
+                    .filter(|node| node.tags.iter().any(|t| t == tag))
+                    .count(),
+                None => targeting::resolve(registry, &[sel.to_string()], false)?
+                    .nodes
+                    .len(),
+            },
+        };
+        Ok(Some(count))
+    }
+}
+
+/// What a step's node locks say it is doing, for whoever waits on them.
+fn step_purpose(step: &StepDef) -> String {
+    format!("flow step '{}'", step.id)
+}
+
+/// Make sure this run holds the lock on sub-flow `name`, taking it for
+/// `step` if no step before it has.
+fn lock_sub_flow(ctx: &RunContext, name: &str, step: &StepDef) -> Result<()> {
+    // Held while waiting, so concurrent steps take each lock only once
+    let mut held = ctx.sub_flow_locks.lock().unwrap();
+    if !held.contains_key(name) {
+        let lock = ctx
+            .locks
+            .flow(name, &format!("sub-flow of step '{}'", step.id))?;
+        held.insert(name.to_string(), lock);
+    }
     Ok(())
 }
 
+/// Run a step's action inside the hooks of its type — `hooks.exec` for an
+/// `exec` step — for the step's nodes, as the command itself would.
+fn with_hooks<T>(
+    ctx: &RunContext,
+    step: &StepDef,
+    resolved: &targeting::ResolvedTargets,
+    op: impl FnOnce() -> Result<T>,
+) -> Result<T> {
+    let hook_ctx = HookContext::new(step.action.type_name(), resolved)
+        .in_flow(ctx.flow, &step.id)
+        .over(ctx.transport);
+    hooks::around(ctx.config, &hook_ctx, op)
+}
+
 fn dispatch_action(
-    config: &FleetConfig,
-    registry: &NodeRegistry,
+    ctx: &RunContext,
     step: &StepDef,
     targets: &[String],
-    cli_all: bool,
-    all_outputs: &HashMap<String, HashMap<String, serde_json::Value>>,
-    resolved_secrets: &HashMap<String, String>,
+    scope: &TemplateScope,
 ) -> Result<StepResult> {
+    let (config, registry, cli_all) = (ctx.config, ctx.registry, ctx.cli_all);
     match &step.action {
         ActionDef::Build { show_trace } => {
             let resolved = resolve_step_targets(registry, targets, cli_all)?;
-            super::build::run(&resolved, *show_trace)?;
+            with_hooks(ctx, step, &resolved, || {
+                super::build::run(&resolved, *show_trace)
+            })?;
             Ok(StepResult::default())
         }
         ActionDef::Deploy {
@@ -153,43 +896,67 @@ fn dispatch_action(
             dry_run,
         } => {
             let resolved = resolve_step_targets(registry, targets, cli_all)?;
-            super::deploy::run(&resolved, *dry_run, *show_trace, false)?;
+            let _locks = if *dry_run {
+                Vec::new()
+            } else {
+                ctx.locks.nodes(&resolved.names(), &step_purpose(step))?
+            };
+            with_hooks(ctx, step, &resolved, || {
+                super::deploy::run(&resolved, *dry_run, *show_trace, false)
+            })?;
             Ok(StepResult::default())
         }
         ActionDef::Diff => {
             let resolved = resolve_step_targets(registry, targets, cli_all)?;
-            super::diff::run(&resolved, config)?;
+            with_hooks(ctx, step, &resolved, || {
+                super::diff::run(&resolved, config, ctx.transport)
+            })?;
             Ok(StepResult::default())
         }
         ActionDef::Status => {
             let resolved = resolve_step_targets(registry, targets, cli_all)?;
-            super::status::run(&resolved, config)?;
+            with_hooks(ctx, step, &resolved, || {
+                super::status::run(&resolved, config, ctx.transport)
+            })?;
             Ok(StepResult::default())
         }
         ActionDef::Ping => {
             let resolved = resolve_step_targets(registry, targets, cli_all)?;
-            super::ping::run(&resolved, config)?;
+            with_hooks(ctx, step, &resolved, || {
+                super::ping::run(&resolved, config, ctx.transport)
+            })?;
             Ok(StepResult::default())
         }
         ActionDef::Rollback => {
             let resolved = resolve_step_targets(registry, targets, cli_all)?;
-            super::rollback::run(&resolved, config)?;
+            let _locks = ctx.locks.nodes(&resolved.names(), &step_purpose(step))?;
+            // Auto-confirm in flows, as for reboot
+            with_hooks(ctx, step, &resolved, || {
+                super::rollback::run(&resolved, true, config, ctx.transport)
+            })?;
             Ok(StepResult::default())
         }
         ActionDef::Reboot => {
             // Auto-confirm in flows
             let resolved = resolve_step_targets(registry, targets, cli_all)?;
-            super::reboot::run(&resolved, true, config)?;
+            let _locks = ctx.locks.nodes(&resolved.names(), &step_purpose(step))?;
+            with_hooks(ctx, step, &resolved, || {
+                super::reboot::run(&resolved, true, config, ctx.transport)
+            })?;
             Ok(StepResult::default())
         }
         ActionDef::Exec { command } => {
             let resolved = resolve_step_targets(registry, targets, cli_all)?;
-            super::exec::run(&resolved, command, config)?;
+            let _locks = ctx.locks.nodes(&resolved.names(), &step_purpose(step))?;
+            with_hooks(ctx, step, &resolved, || {
+                super::exec::run(&resolved, command, config, ctx.transport)
+            })?;
             Ok(StepResult::default())
         }
         ActionDef::Shell { command, env } => {
             log_info(&format!("Running: {}", command));
-            let resolved_env = resolve_step_env(env, all_outputs, resolved_secrets);
+            let resolved_env =
+                resolve_step_env(env, scope).with_context(|| format!("step '{}'", step.id))?;
             let mut cmd = Command::new("sh");
             cmd.arg("-c").arg(command);
             for (k, v) in &resolved_env {
@@ -244,19 +1011,67 @@ fn dispatch_action(
             skip_teardown,
             env,
         } => {
-            let resolved_env = resolve_step_env(env, all_outputs, resolved_secrets);
+            let resolved_env =
+                resolve_step_env(env, scope).with_context(|| format!("step '{}'", step.id))?;
             super::pitr_forge::run(
                 command,
                 tenant.as_deref(),
                 environment.as_deref(),
                 restore_time.as_deref(),
                 app_version.as_deref(),
-                cfg.as_deref(),
+                cfg.as_ref()
+                    .map(|c| ctx.flow_dir.join(c).display().to_string())
+                    .as_deref(),
                 output_json.as_deref(),
                 *skip_teardown,
                 &resolved_env,
             )
         }
+        ActionDef::Approval {
+            message,
+            approvers,
+            timeout,
+        } => {
+            let pre_approved = ctx
+                .approved
+                .iter()
+                .any(|a| *a == step.id || step.instance_of.as_deref() == Some(a.as_str()));
+            super::approval::run(&step.id, message, approvers, *timeout, pre_approved)
+        }
+        ActionDef::Flow { name, params } => {
+            let params =
+                resolve_step_env(params, scope).with_context(|| format!("step '{}'", step.id))?;
+            let (sub_def, levels) = plan(config, registry, name)?;
+            let params = bind_params(name, &sub_def, params)?;
+            let sub_ctx = RunContext {
+                flow: name,
+                config,
+                registry,
+                cli_all,
+                approved: ctx.approved,
+                recorder: ctx.recorder,
+                record_prefix: format!("{}{}.", ctx.record_prefix, step.id),
+                locks: ctx.locks,
+                sub_flow_locks: ctx.sub_flow_locks,
+                transport: ctx.transport,
+                flow_dir: config.flow_dir(&sub_def),
+            };
+            lock_sub_flow(ctx, name, step)?;
+            let outputs = execute_flow(&sub_ctx, name, &sub_def, &levels, targets, &params)
+                .with_context(|| format!("sub-flow '{}' (step '{}')", name, step.id))?;
+
+            // Flatten to `<sub_step>.<output>` so the parent reads them as
+            // `${step_id.<sub_step>.<output>}`.
+            let outputs = outputs
+                .into_iter()
+                .flat_map(|(sub_step, outputs)| {
+                    outputs
+                        .into_iter()
+                        .map(move |(k, v)| (format!("{}.{}", sub_step, k), v))
+                })
+                .collect();
+            Ok(StepResult { outputs })
+        }
         ActionDef::Pangea {
             file,
             template,
@@ -265,9 +1080,10 @@ fn dispatch_action(
             env,
         } => {
             // Resolve ${step_id.output_name} and ${secrets.<name>} references
-            let resolved_env = resolve_step_env(env, all_outputs, resolved_secrets);
+            let resolved_env =
+                resolve_step_env(env, scope).with_context(|| format!("step '{}'", step.id))?;
             super::pangea::run(
-                file,
+                &ctx.flow_dir.join(file).display().to_string(),
                 template.as_deref(),
                 namespace,
                 operation,
@@ -277,23 +1093,34 @@ fn dispatch_action(
     }
 }
 
+/// Everything a `${...}` reference can resolve against at a given step.
+struct TemplateScope<'a> {
+    /// Outputs of completed steps, keyed by step ID.
+    outputs: &'a FlowOutputs,
+    /// Flow-level secrets resolved at flow start.
+    secrets: &'a HashMap<String, String>,
+    /// The running flow's params, defaults applied.
+    params: &'a HashMap<String, String>,
+    /// Fail on an unresolved reference instead of substituting "".
+    strict: bool,
+    /// Set in cleanup steps.
+    failure: Option<&'a Failure>,
+}
+
 /// Resolve `${step_id.output_name}` references in environment variable values.
 ///
 /// Pattern: `${permissions.node_role_arn}` looks up step "permissions", output "node_role_arn".
-/// Values that don't match the pattern are passed through unchanged.
+/// Values without references are passed through unchanged.
 fn resolve_step_env(
     env: &HashMap<String, String>,
-    all_outputs: &HashMap<String, HashMap<String, serde_json::Value>>,
-    resolved_secrets: &HashMap<String, String>,
-) -> HashMap<String, String> {
+    scope: &TemplateScope,
+) -> Result<HashMap<String, String>> {
     let mut resolved = HashMap::new();
     for (key, value) in env {
-        resolved.insert(
-            key.clone(),
-            resolve_template(value, all_outputs, resolved_secrets),
-        );
+        let value = resolve_template(value, scope).with_context(|| format!("env {}", key))?;
+        resolved.insert(key.clone(), value);
     }
-    resolved
+    Ok(resolved)
 }
 
 /// Decrypt every secret declared on the flow before any step runs.
@@ -316,36 +1143,95 @@ fn resolve_flow_secrets(
 /// Resolve a single template string. Two reference forms are supported:
 ///   ${step_id.output_name}   — output produced by an earlier step
 ///   ${secrets.<name>}        — flow-level secret resolved at flow start
-/// Anything else evaluates to the empty string (matches existing behavior).
-fn resolve_template(
-    template: &str,
-    all_outputs: &HashMap<String, HashMap<String, serde_json::Value>>,
-    resolved_secrets: &HashMap<String, String>,
-) -> String {
-    let mut result = template.to_string();
-    while let Some(start) = result.find("${") {
-        let rest = &result[start + 2..];
-        let Some(end) = rest.find('}') else {
-            break;
-        };
-        let reference = &rest[..end];
-        let replacement = if let Some(rest) = reference.strip_prefix("secrets.") {
-            resolved_secrets.get(rest).cloned().unwrap_or_default()
-        } else if let Some((step_id, output_name)) = reference.split_once('.') {
-            all_outputs
-                .get(step_id)
-                .and_then(|outputs| outputs.get(output_name))
-                .map(|v| match v {
-                    serde_json::Value::String(s) => s.clone(),
-                    other => other.to_string(),
-                })
-                .unwrap_or_default()
-        } else {
-            String::new()
+/// Either may carry a fallback (`${ref:-default}`), and `$${` is a literal
+/// `${`. An unresolved reference without a fallback is an error naming what
+/// WAS available, unless the flow opted out with `strict_templates: false`,
+/// in which case it — or any other `${...}` — evaluates to the empty string.
+fn resolve_template(template: &str, scope: &TemplateScope) -> Result<String> {
+    let mut result = String::with_capacity(template.len());
+    for segment in flow::parse_template(template, scope.strict)? {
+        match segment {
+            Segment::Literal(text) => result.push_str(text),
+            Segment::Ref(r) => result.push_str(&resolve_ref(&r, scope)?),
+        }
+    }
+    Ok(result)
+}
+
+/// Resolve one `${...}` reference, applying its fallback and the flow's
+/// strictness.
+fn resolve_ref(r: &TemplateRef, scope: &TemplateScope) -> Result<String> {
+    let value = match r.reference {
+        Reference::Secret(name) => scope.secrets.get(name).cloned(),
+        Reference::Param(name) => scope.params.get(name).cloned(),
+        Reference::Failure(field) => scope
+            .failure
+            .and_then(|f| f.field(field))
+            .map(str::to_string),
+        Reference::Output { step, name } => scope
+            .outputs
+            .get(step)
+            .and_then(|outputs| outputs.get(name))
+            .map(|v| match v {
+                serde_json::Value::String(s) => s.clone(),
+                other => other.to_string(),
+            }),
+        Reference::Other(_) => None,
+    };
+    match (value, r.default) {
+        (Some(v), _) => Ok(v),
+        (None, Some(default)) => Ok(default.to_string()),
+        (None, None) if !scope.strict => Ok(String::new()),
+        (None, None) => bail!(
+            "unresolved reference `${{{}}}` ({})",
+            r.raw,
+            describe_available(&r.reference, scope)
+        ),
+    }
+}
+
+/// What the failed reference could have named instead, for the error.
+fn describe_available(reference: &Reference, scope: &TemplateScope) -> String {
+    let mut available: Vec<String> = match reference {
+        Reference::Secret(_) => scope
+            .secrets
+            .keys()
+            .map(|name| format!("secrets.{}", name))
+            .collect(),
+        Reference::Param(_) => scope
+            .params
+            .keys()
+            .map(|name| format!("params.{}", name))
+            .collect(),
+        Reference::Failure(_) => scope
+            .failure
+            .map(|_| {
+                flow::FAILURE_FIELDS
+                    .iter()
+                    .map(|field| format!("failure.{}", field))
+                    .collect()
+            })
+            .unwrap_or_default(),
+        Reference::Output { .. } => scope
+            .outputs
+            .iter()
+            .flat_map(|(step, outputs)| {
+                outputs.keys().map(move |name| format!("{}.{}", step, name))
+            })
+            .collect(),
+        Reference::Other(_) => Vec::new(),
+    };
+    if available.is_empty() {
+        return match reference {
+            Reference::Secret(_) => "no secrets are declared on this flow".to_string(),
+            Reference::Param(_) => "no params are declared on this flow".to_string(),
+            Reference::Failure(_) => "only cleanup steps can read the failure".to_string(),
+            Reference::Output { .. } => "no step has produced outputs yet".to_string(),
+            Reference::Other(_) => "not a reference fleet knows".to_string(),
         };
-        result = format!("{}{}{}", &result[..start], replacement, &rest[end + 1..]);
     }
-    result
+    available.sort();
+    format!("available: {}", available.join(", "))
 }
 
 fn resolve_step_targets(
@@ -357,119 +1243,226 @@ fn resolve_step_targets(
     targeting::resolve(registry, targets, all)
 }
 
-fn print_execution_plan(flow_def: &crate::config::FlowDef, levels: &[Vec<usize>]) {
+fn print_execution_plan(
+    ctx: &RunContext,
+    flow_def: &FlowDef,
+    levels: &[Vec<usize>],
+    cli_targets: &[String],
+    params: &HashMap<String, String>,
+) {
     println!("{}", "Execution plan (dry-run):".bold());
+    if flow_def.failure_mode != FailureMode::FailFast {
+        println!("  failure_mode: {}", flow_def.failure_mode.name());
+    }
     println!();
 
+    let (outputs, secrets) = (HashMap::new(), HashMap::new());
+    let scope = TemplateScope {
+        outputs: &outputs,
+        secrets: &secrets,
+        params,
+        strict: flow_def.strict_templates,
+        failure: None,
+    };
+
     for (level_idx, level) in levels.iter().enumerate() {
         println!("  {} {}:", "Level".blue(), level_idx + 1);
         for &step_idx in level {
-            let step = &flow_def.steps[step_idx];
-            let action_type = match &step.action {
-                ActionDef::Deploy { .. } => "deploy",
-                ActionDef::Build { .. } => "build",
-                ActionDef::Diff => "diff",
-                ActionDef::Status => "status",
-                ActionDef::Ping => "ping",
-                ActionDef::Rollback => "rollback",
-                ActionDef::Reboot => "reboot",
-                ActionDef::Exec { .. } => "exec",
-                ActionDef::Shell { .. } => "shell",
-                ActionDef::DarwinRebuild { .. } => "darwin-rebuild",
-                ActionDef::HomeManagerRebuild { .. } => "home-manager-rebuild",
-                ActionDef::FlakeUpdate { .. } => "flake-update",
-                ActionDef::PitrForge { .. } => "pitr-forge",
-                ActionDef::Pangea { .. } => "pangea",
-            };
-            let targets_str = if step.targets.is_empty() {
-                "(inherit CLI targets)".to_string()
-            } else {
-                step.targets.join(", ")
-            };
-            println!(
-                "    {} [{}] targets: {}",
-                step.id.bold(),
-                action_type.cyan(),
-                targets_str
-            );
-            if !step.depends_on.is_empty() {
-                println!("      depends_on: {}", step.depends_on.join(", "));
-            }
-            if step.condition.is_some() {
-                println!("      has condition");
-            }
-            // Show PitrForge-specific details
-            if let ActionDef::PitrForge {
-                command,
-                tenant,
-                environment,
-                output_json,
-                ..
-            } = &step.action
-            {
-                let cmd_str = match command {
-                    crate::config::PitrForgeCommand::Verify => "verify",
-                    crate::config::PitrForgeCommand::Drill => "drill",
-                    crate::config::PitrForgeCommand::Restore => "restore",
-                    crate::config::PitrForgeCommand::Status => "status",
-                    crate::config::PitrForgeCommand::Teardown => "teardown",
-                    crate::config::PitrForgeCommand::Test => "test",
-                    crate::config::PitrForgeCommand::Combine => "combine",
-                };
-                let tenant_str = tenant.as_deref().unwrap_or("-");
-                let env_str = environment.as_deref().unwrap_or("-");
-                println!(
-                    "      pitr-forge: {} --tenant {} --env {}",
-                    cmd_str, tenant_str, env_str
-                );
-                if let Some(out) = output_json {
-                    println!("      output_json: {}", out);
-                }
-            }
-            // Show Pangea-specific details
-            if let ActionDef::Pangea {
-                file,
-                namespace,
-                operation,
-                env,
-                ..
-            } = &step.action
-            {
-                let op_str = match operation {
-                    crate::config::PangeaOperation::Plan => "plan",
-                    crate::config::PangeaOperation::Apply => "apply",
-                    crate::config::PangeaOperation::Destroy => "destroy",
-                    crate::config::PangeaOperation::Output => "output",
-                    crate::config::PangeaOperation::Synth => "synth",
-                };
-                println!(
-                    "      pangea: {} {} --namespace {}",
-                    op_str, file, namespace
-                );
-                if !env.is_empty() {
-                    for (k, v) in env {
-                        println!("      env: {}={}", k, v);
-                    }
-                }
-            }
+            print_plan_step(ctx, &flow_def.steps[step_idx], &scope, cli_targets);
+        }
+    }
+    for (title, steps) in [
+        ("On failure", &flow_def.on_failure),
+        ("Finally", &flow_def.finally),
+    ] {
+        if steps.is_empty() {
+            continue;
+        }
+        println!("  {}:", title.blue());
+        for step in steps {
+            print_plan_step(ctx, step, &scope, cli_targets);
         }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::config::*;
-
-    #[test]
-    fn test_pangea_operation_serde() {
-        let yaml = r#"
-type: pangea
-file: k3s_permissions.rb
-namespace: development
-operation: apply
-"#;
-        let action: ActionDef = serde_yaml_ng::from_str(yaml).unwrap();
+fn print_plan_step(
+    ctx: &RunContext,
+    step: &StepDef,
+    scope: &TemplateScope,
+    cli_targets: &[String],
+) {
+    let action_type = step.action.type_name();
+    let targets_str = if step.targets.is_empty() {
+        "(inherit CLI targets)".to_string()
+    } else {
+        step.targets.join(", ")
+    };
+    println!(
+        "    {} [{}] targets: {}",
+        step.id.bold(),
+        action_type.cyan(),
+        targets_str
+    );
+    if let Some(base) = &step.instance_of {
+        println!(
+            "      foreach: instance of {} ({})",
+            base,
+            if step.serial { "serial" } else { "concurrent" }
+        );
+    }
+    if !step.depends_on.is_empty() {
+        println!("      depends_on: {}", step.depends_on.join(", "));
+    }
+    match &step.condition {
+        None => {}
+        Some(ConditionDef::Command { command }) => {
+            println!(
+                "      condition: sh -c {:?} (checked when the step starts)",
+                command
+            )
+        }
+        Some(ConditionDef::Expr(src)) => {
+            let facts = StepFacts {
+                ctx,
+                scope,
+                statuses: None,
+                targets: if step.targets.is_empty() {
+                    cli_targets
+                } else {
+                    &step.targets
+                },
+            };
+            let outcome = match Expr::parse(src).and_then(|e| e.eval(&facts)) {
+                Ok(Some(true)) => "true".green().to_string(),
+                Ok(Some(false)) => "false — step will be skipped".yellow().to_string(),
+                Ok(None) => "decided at run time".to_string(),
+                Err(e) => format!("error: {}", e).red().to_string(),
+            };
+            println!("      condition: {} ({})", src, outcome);
+        }
+    }
+    // Show PitrForge-specific details
+    if let ActionDef::PitrForge {
+        command,
+        tenant,
+        environment,
+        output_json,
+        ..
+    } = &step.action
+    {
+        let cmd_str = match command {
+            crate::config::PitrForgeCommand::Verify => "verify",
+            crate::config::PitrForgeCommand::Drill => "drill",
+            crate::config::PitrForgeCommand::Restore => "restore",
+            crate::config::PitrForgeCommand::Status => "status",
+            crate::config::PitrForgeCommand::Teardown => "teardown",
+            crate::config::PitrForgeCommand::Test => "test",
+            crate::config::PitrForgeCommand::Combine => "combine",
+        };
+        let tenant_str = tenant.as_deref().unwrap_or("-");
+        let env_str = environment.as_deref().unwrap_or("-");
+        println!(
+            "      pitr-forge: {} --tenant {} --env {}",
+            cmd_str, tenant_str, env_str
+        );
+        if let Some(out) = output_json {
+            println!("      output_json: {}", out);
+        }
+    }
+    // Show approval details
+    if let ActionDef::Approval {
+        message,
+        approvers,
+        timeout,
+    } = &step.action
+    {
+        println!("      approval: {}", message);
+        if !approvers.is_empty() {
+            println!("      approvers: {}", approvers.join(", "));
+        }
+        if *timeout > 0 {
+            println!("      timeout: {}s", timeout);
+        }
+    }
+    // Show sub-flow details
+    if let ActionDef::Flow { name, params } = &step.action {
+        println!("      flow: {}", name);
+        let mut params: Vec<_> = params.iter().collect();
+        params.sort();
+        for (k, v) in params {
+            println!("      param: {}={}", k, v);
+        }
+    }
+    // Show Pangea-specific details
+    if let ActionDef::Pangea {
+        file,
+        namespace,
+        operation,
+        env,
+        ..
+    } = &step.action
+    {
+        let op_str = match operation {
+            crate::config::PangeaOperation::Plan => "plan",
+            crate::config::PangeaOperation::Apply => "apply",
+            crate::config::PangeaOperation::Destroy => "destroy",
+            crate::config::PangeaOperation::Output => "output",
+            crate::config::PangeaOperation::Synth => "synth",
+        };
+        println!(
+            "      pangea: {} {} --namespace {}",
+            op_str, file, namespace
+        );
+        if !env.is_empty() {
+            for (k, v) in env {
+                println!("      env: {}={}", k, v);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::*;
+    use crate::transport::OpenSsh;
+
+    /// Locks in a per-process directory, so a run under test never waits on
+    /// a real one.
+    fn test_locks() -> Locks {
+        let dir = std::env::temp_dir().join(format!("fleet-flow-locks-{}", std::process::id()));
+        Locks::new(
+            dir,
+            crate::lock::LockWait::Bounded(std::time::Duration::from_secs(60)),
+        )
+    }
+
+    static NO_PARAMS: std::sync::LazyLock<HashMap<String, String>> =
+        std::sync::LazyLock::new(HashMap::new);
+
+    fn scope<'a>(
+        outputs: &'a FlowOutputs,
+        secrets: &'a HashMap<String, String>,
+        strict: bool,
+    ) -> TemplateScope<'a> {
+        TemplateScope {
+            outputs,
+            secrets,
+            params: &NO_PARAMS,
+            strict,
+            failure: None,
+        }
+    }
+
+    #[test]
+    fn test_pangea_operation_serde() {
+        let yaml = r#"
+type: pangea
+file: k3s_permissions.rb
+namespace: development
+operation: apply
+"#;
+        let action: ActionDef = serde_yaml_ng::from_str(yaml).unwrap();
         match action {
             ActionDef::Pangea {
                 file,
@@ -557,9 +1550,9 @@ env:
 
         let result = resolve_template(
             "${permissions.node_role_arn}",
-            &all_outputs,
-            &HashMap::new(),
-        );
+            &scope(&all_outputs, &HashMap::new(), true),
+        )
+        .unwrap();
         assert_eq!(result, "arn:aws:iam::123:role/test");
     }
 
@@ -579,23 +1572,95 @@ env:
 
         let result = resolve_template(
             "role=${step1.arn},profile=${step1.name}",
-            &all_outputs,
-            &HashMap::new(),
-        );
+            &scope(&all_outputs, &HashMap::new(), true),
+        )
+        .unwrap();
         assert_eq!(result, "role=arn:123,profile=my-profile");
     }
 
     #[test]
     fn test_resolve_template_missing_output() {
         let all_outputs = HashMap::new();
-        let result = resolve_template("${missing.output}", &all_outputs, &HashMap::new());
+        let secrets = HashMap::new();
+        let result =
+            resolve_template("${missing.output}", &scope(&all_outputs, &secrets, false)).unwrap();
         assert_eq!(result, "");
     }
 
+    #[test]
+    fn test_resolve_template_missing_output_strict() {
+        let mut all_outputs = HashMap::new();
+        let mut step_outputs = HashMap::new();
+        step_outputs.insert("node_role_arn".to_string(), serde_json::json!("arn:1"));
+        all_outputs.insert("permissions".to_string(), step_outputs);
+        let secrets = HashMap::new();
+
+        let err = resolve_template(
+            "${permissions.node_role_ARN}",
+            &scope(&all_outputs, &secrets, true),
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("${permissions.node_role_ARN}"), "{err}");
+        assert!(
+            err.contains("available: permissions.node_role_arn"),
+            "{err}"
+        );
+    }
+
+    #[test]
+    fn test_resolve_template_missing_output_strict_no_outputs() {
+        let (all_outputs, secrets) = (HashMap::new(), HashMap::new());
+        let err = resolve_template("${missing.output}", &scope(&all_outputs, &secrets, true))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("no step has produced outputs yet"), "{err}");
+    }
+
+    #[test]
+    fn test_resolve_template_default() {
+        let mut all_outputs = HashMap::new();
+        let mut step_outputs = HashMap::new();
+        step_outputs.insert("arn".to_string(), serde_json::json!("arn:123"));
+        all_outputs.insert("step1".to_string(), step_outputs);
+        let secrets = HashMap::new();
+        let scope = scope(&all_outputs, &secrets, true);
+
+        assert_eq!(
+            resolve_template("${missing.arn:-fallback}", &scope).unwrap(),
+            "fallback"
+        );
+        assert_eq!(resolve_template("${missing.arn:-}", &scope).unwrap(), "");
+        // A default never overrides a value that resolved.
+        assert_eq!(
+            resolve_template("${step1.arn:-fallback}", &scope).unwrap(),
+            "arn:123"
+        );
+    }
+
+    #[test]
+    fn test_resolve_template_escape() {
+        let (all_outputs, secrets) = (HashMap::new(), HashMap::new());
+        let scope = scope(&all_outputs, &secrets, true);
+        assert_eq!(
+            resolve_template("echo $${HOME} costs $5", &scope).unwrap(),
+            "echo ${HOME} costs $5"
+        );
+    }
+
+    #[test]
+    fn test_resolve_template_malformed_reference() {
+        let (all_outputs, secrets) = (HashMap::new(), HashMap::new());
+        let scope = scope(&all_outputs, &secrets, true);
+        assert!(resolve_template("${HOME}", &scope).is_err());
+        assert!(resolve_template("${step.output", &scope).is_err());
+    }
+
     #[test]
     fn test_resolve_template_no_refs() {
         let all_outputs = HashMap::new();
-        let result = resolve_template("plain-value", &all_outputs, &HashMap::new());
+        let result =
+            resolve_template("plain-value", &scope(&all_outputs, &HashMap::new(), true)).unwrap();
         assert_eq!(result, "plain-value");
     }
 
@@ -604,18 +1669,28 @@ env:
         let all_outputs = HashMap::new();
         let mut secrets = HashMap::new();
         secrets.insert("oauth_id".to_string(), "tskey-client-abc".to_string());
-        let result = resolve_template("${secrets.oauth_id}", &all_outputs, &secrets);
+        let result =
+            resolve_template("${secrets.oauth_id}", &scope(&all_outputs, &secrets, true)).unwrap();
         assert_eq!(result, "tskey-client-abc");
     }
 
     #[test]
     fn test_resolve_template_secret_missing() {
+        let (all_outputs, secrets) = (HashMap::new(), HashMap::new());
         let result = resolve_template(
             "${secrets.does_not_exist}",
-            &HashMap::new(),
-            &HashMap::new(),
-        );
+            &scope(&all_outputs, &secrets, false),
+        )
+        .unwrap();
         assert_eq!(result, "");
+
+        let err = resolve_template(
+            "${secrets.does_not_exist}",
+            &scope(&all_outputs, &secrets, true),
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("no secrets are declared"), "{err}");
     }
 
     #[test]
@@ -625,7 +1700,11 @@ env:
         step_outputs.insert("count".to_string(), serde_json::json!(42));
         all_outputs.insert("infra".to_string(), step_outputs);
 
-        let result = resolve_template("${infra.count}", &all_outputs, &HashMap::new());
+        let result = resolve_template(
+            "${infra.count}",
+            &scope(&all_outputs, &HashMap::new(), true),
+        )
+        .unwrap();
         assert_eq!(result, "42");
     }
 
@@ -660,7 +1739,7 @@ flows:
         assert_eq!(flow.steps[1].depends_on, vec!["permissions"]);
 
         // Verify DAG validation passes
-        let validated = crate::flow::validate(flow).unwrap();
+        let validated = crate::flow::validate(&config.flows, "deploy").unwrap();
         let levels = crate::dag::topo_levels(flow.steps.len(), &validated.deps);
         assert_eq!(levels.len(), 2);
         assert_eq!(levels[0], vec![0]); // permissions first
@@ -690,8 +1769,7 @@ flows:
         depends_on: [a]
 "#;
         let config: FleetConfig = serde_yaml_ng::from_str(yaml).unwrap();
-        let flow = config.flows.get("bad").unwrap();
-        let result = crate::flow::validate(flow);
+        let result = crate::flow::validate(&config.flows, "bad");
         assert!(result.is_err());
         let err = result.unwrap_err().to_string();
         assert!(err.contains("Cycle"));
@@ -711,8 +1789,786 @@ flows:
         );
         all_outputs.insert("step1".to_string(), step1_outputs);
 
-        let resolved = resolve_step_env(&env, &all_outputs, &HashMap::new());
+        let resolved = resolve_step_env(&env, &scope(&all_outputs, &HashMap::new(), true)).unwrap();
         assert_eq!(resolved.get("ARN").unwrap(), "arn:aws:iam::123:role/node");
         assert_eq!(resolved.get("STATIC").unwrap(), "hello");
     }
+
+    #[test]
+    fn test_validate_template_references() {
+        let yaml = r#"
+flows:
+  deploy:
+    secrets:
+      role: { source: sops, file: secrets.yaml, key: aws/role }
+    steps:
+      - id: permissions
+        action: { type: pangea, file: p.rb, namespace: dev, operation: apply }
+      - id: network
+        action:
+          type: pangea
+          file: n.rb
+          namespace: dev
+          operation: apply
+          env:
+            ROLE_ARN: "${permissions.node_role_arn}"
+            ASSUME: "${secrets.role}"
+            LITERAL: "$${not_a_ref}"
+        depends_on: [permissions]
+"#;
+        let config: FleetConfig = serde_yaml_ng::from_str(yaml).unwrap();
+        assert!(crate::flow::validate(&config.flows, "deploy").is_ok());
+    }
+
+    fn validate_err(env_value: &str, depends_on: &str, strict: bool) -> String {
+        let yaml = format!(
+            r#"
+flows:
+  f:
+    strict_templates: {strict}
+    secrets:
+      role: {{ source: sops, file: secrets.yaml, key: aws/role }}
+    steps:
+      - id: permissions
+        action: {{ type: pangea, file: p.rb, namespace: dev, operation: apply }}
+      - id: network
+        action:
+          type: shell
+          command: "true"
+          env:
+            VALUE: "{env_value}"
+        depends_on: [{depends_on}]
+"#
+        );
+        let config: FleetConfig = serde_yaml_ng::from_str(&yaml).unwrap();
+        match crate::flow::validate(&config.flows, "f") {
+            Ok(_) => String::new(),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_validate_template_unknown_step() {
+        let err = validate_err("${permisions.arn}", "permissions", true);
+        assert!(err.contains("unknown step 'permisions'"), "{err}");
+    }
+
+    #[test]
+    fn test_validate_template_undeclared_secret() {
+        let err = validate_err("${secrets.rol}", "permissions", true);
+        assert!(err.contains("undeclared secret 'rol'"), "{err}");
+        assert!(err.contains("declared: role"), "{err}");
+    }
+
+    #[test]
+    fn test_validate_template_requires_dependency_when_strict() {
+        let err = validate_err("${permissions.arn}", "", true);
+        assert!(err.contains("does not depend on 'permissions'"), "{err}");
+        // A fallback, or opting out of strict mode, makes ordering optional.
+        assert_eq!(validate_err("${permissions.arn:-none}", "", true), "");
+        assert_eq!(validate_err("${permissions.arn}", "", false), "");
+    }
+
+    #[test]
+    fn test_validate_template_malformed() {
+        let err = validate_err("${HOME}", "permissions", true);
+        assert!(err.contains("Step 'network' env VALUE"), "{err}");
+    }
+
+    #[test]
+    fn test_non_strict_flows_substitute_what_strict_ones_reject() {
+        for value in [
+            "${HOME}",
+            "${permisions.arn}",
+            "${secrets.rol}",
+            "${unterminated",
+        ] {
+            assert_ne!(validate_err(value, "permissions", true), "", "{value}");
+            assert_eq!(validate_err(value, "permissions", false), "", "{value}");
+        }
+
+        let (all_outputs, secrets) = (HashMap::new(), HashMap::new());
+        let lax = scope(&all_outputs, &secrets, false);
+        assert_eq!(
+            resolve_template(
+                "home=${HOME} user=${USER:-root} ${secrets.rol}|${open",
+                &lax
+            )
+            .unwrap(),
+            "home= user=root |${open"
+        );
+        assert!(resolve_template("${HOME}", &scope(&all_outputs, &secrets, true)).is_err());
+    }
+
+    const SUBFLOW_YAML: &str = r#"
+flows:
+  release:
+    params:
+      namespace: {}
+      region: { default: us-east-1 }
+    steps:
+      - id: apply
+        action:
+          type: pangea
+          file: k3s.rb
+          namespace: development
+          operation: apply
+          env:
+            NS: "${params.namespace}"
+  cluster:
+    steps:
+      - id: infra
+        action:
+          type: flow
+          name: release
+          params:
+            namespace: development
+      - id: report
+        action:
+          type: shell
+          command: "echo $ROLE"
+          env:
+            ROLE: "${infra.apply.role_arn}"
+        depends_on: [infra]
+"#;
+
+    #[test]
+    fn test_subflow_validates() {
+        let config = FleetConfig::from_yaml(SUBFLOW_YAML).unwrap();
+        assert!(crate::flow::validate(&config.flows, "cluster").is_ok());
+        assert!(crate::flow::validate(&config.flows, "release").is_ok());
+    }
+
+    #[test]
+    fn test_subflow_unknown_flow() {
+        let yaml = SUBFLOW_YAML.replace("name: release", "name: relase");
+        let config = FleetConfig::from_yaml(&yaml).unwrap();
+        let err = crate::flow::validate(&config.flows, "cluster")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("runs unknown flow 'relase'"), "{err}");
+    }
+
+    #[test]
+    fn test_subflow_params_checked() {
+        let yaml = SUBFLOW_YAML.replace(
+            "namespace: development\n      - id: report",
+            "nmespace: development\n      - id: report",
+        );
+        let config = FleetConfig::from_yaml(&yaml).unwrap();
+        let err = crate::flow::validate(&config.flows, "cluster")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("unknown param 'nmespace'"), "{err}");
+
+        let yaml = SUBFLOW_YAML.replace(
+            "          params:\n            namespace: development\n",
+            "",
+        );
+        let config = FleetConfig::from_yaml(&yaml).unwrap();
+        let err = crate::flow::validate(&config.flows, "cluster")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("required param 'namespace'"), "{err}");
+    }
+
+    #[test]
+    fn test_subflow_cycle_detection() {
+        let yaml = r#"
+flows:
+  a:
+    steps:
+      - id: run-b
+        action: { type: flow, name: b }
+  b:
+    steps:
+      - id: noop
+        action: { type: shell, command: "true" }
+      - id: run-c
+        action: { type: flow, name: c }
+  c:
+    steps:
+      - id: run-a
+        action: { type: flow, name: a }
+"#;
+        let config = FleetConfig::from_yaml(yaml).unwrap();
+        let err = crate::flow::validate(&config.flows, "a")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("Flow cycle detected: a → b → c → a"), "{err}");
+    }
+
+    #[test]
+    fn test_subflow_self_reference() {
+        let yaml = r#"
+flows:
+  a:
+    steps:
+      - id: again
+        action: { type: flow, name: a }
+"#;
+        let config = FleetConfig::from_yaml(yaml).unwrap();
+        assert!(crate::flow::validate(&config.flows, "a").is_err());
+    }
+
+    #[test]
+    fn test_undeclared_param_reference() {
+        let yaml = SUBFLOW_YAML.replace("${params.namespace}", "${params.namespcae}");
+        let config = FleetConfig::from_yaml(&yaml).unwrap();
+        let err = crate::flow::validate(&config.flows, "release")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("undeclared param 'namespcae'"), "{err}");
+        assert!(err.contains("declared: namespace, region"), "{err}");
+    }
+
+    #[test]
+    fn test_bind_params() {
+        let config = FleetConfig::from_yaml(SUBFLOW_YAML).unwrap();
+        let release = &config.flows["release"];
+
+        let given = parse_params(&["namespace=prod".to_string()]).unwrap();
+        let bound = bind_params("release", release, given).unwrap();
+        assert_eq!(bound["namespace"], "prod");
+        assert_eq!(bound["region"], "us-east-1");
+
+        assert!(bind_params("release", release, HashMap::new()).is_err());
+        let unknown = parse_params(&["namespace=a".to_string(), "zone=b".to_string()]).unwrap();
+        assert!(bind_params("release", release, unknown).is_err());
+        assert!(parse_params(&["novalue".to_string()]).is_err());
+        // Only the first `=` splits, so values may contain one.
+        let eq = parse_params(&["filter=a=b".to_string()]).unwrap();
+        assert_eq!(eq["filter"], "a=b");
+    }
+
+    const TEMPLATE_YAML: &str = r#"
+step_templates:
+  rolling-deploy:
+    action: { type: deploy, show_trace: true }
+    targets: [web1]
+    condition:
+      command: "test -f .deploy-enabled"
+flows:
+  rollout:
+    steps:
+      - id: build
+        action: { type: build }
+      - id: deploy-web
+        template: rolling-deploy
+        depends_on: [build]
+      - id: deploy-db
+        template: rolling-deploy
+        targets: [db1]
+        action: { dry_run: true }
+        depends_on: [deploy-web]
+"#;
+
+    #[test]
+    fn test_step_template_expansion() {
+        let config = FleetConfig::from_yaml(TEMPLATE_YAML).unwrap();
+        let steps = &config.flows["rollout"].steps;
+        assert_eq!(steps.len(), 3);
+
+        assert_eq!(steps[1].id, "deploy-web");
+        assert_eq!(steps[1].targets, vec!["web1"]);
+        assert_eq!(steps[1].depends_on, vec!["build"]);
+        assert!(steps[1].condition.is_some());
+        assert!(matches!(
+            steps[1].action,
+            ActionDef::Deploy {
+                show_trace: true,
+                dry_run: false
+            }
+        ));
+
+        // Lists replace, maps merge field by field.
+        assert_eq!(steps[2].targets, vec!["db1"]);
+        assert!(matches!(
+            steps[2].action,
+            ActionDef::Deploy {
+                show_trace: true,
+                dry_run: true
+            }
+        ));
+        assert!(crate::flow::validate(&config.flows, "rollout").is_ok());
+    }
+
+    #[test]
+    fn test_step_template_unknown() {
+        let yaml = TEMPLATE_YAML.replace(
+            "template: rolling-deploy\n        depends_on: [build]",
+            "template: rolling-deplyo\n        depends_on: [build]",
+        );
+        let err = FleetConfig::from_yaml(&yaml).unwrap_err().to_string();
+        assert!(
+            err.contains("step 'deploy-web' uses unknown template 'rolling-deplyo'"),
+            "{err}"
+        );
+        assert!(err.contains("defined: rolling-deploy"), "{err}");
+    }
+
+    fn k3s_registry() -> NodeRegistry {
+        serde_json::from_str(
+            r#"{
+  "web1": { "system": "x86_64-linux", "hostname": "10.0.0.1", "sshUser": "root", "tags": ["k3s"] },
+  "web2": { "system": "x86_64-linux", "hostname": "10.0.0.2", "sshUser": "root", "tags": ["k3s"] },
+  "db1":  { "system": "x86_64-linux", "hostname": "10.0.0.3", "sshUser": "root", "tags": ["db"] }
+}"#,
+        )
+        .unwrap()
+    }
+
+    const FOREACH_YAML: &str = r#"
+flows:
+  rolling:
+    steps:
+      - id: build
+        action: { type: build }
+      - id: deploy
+        action: { type: deploy }
+        foreach: "@k3s"
+        serial: true
+        depends_on: [build]
+      - id: check
+        action:
+          type: shell
+          command: "curl -f http://$FLEET_NODE/healthz"
+        foreach: "@k3s"
+        depends_on: [deploy]
+      - id: report
+        action:
+          type: shell
+          command: "echo done"
+          env:
+            FIRST: "${check[web1].status:-unknown}"
+        depends_on: [check]
+"#;
+
+    #[test]
+    fn test_foreach_expansion() {
+        let config = FleetConfig::from_yaml(FOREACH_YAML).unwrap();
+        let registry = k3s_registry();
+        let (flow_def, levels) = plan(&config, &registry, "rolling").unwrap();
+
+        let ids: Vec<&str> = flow_def.steps.iter().map(|s| s.id.as_str()).collect();
+        assert_eq!(
+            ids,
+            vec![
+                "build",
+                "deploy[web1]",
+                "deploy[web2]",
+                "check[web1]",
+                "check[web2]",
+                "report"
+            ]
+        );
+
+        let step = |id: &str| flow_def.steps.iter().find(|s| s.id == id).unwrap();
+        assert_eq!(step("deploy[web2]").targets, vec!["web2"]);
+        assert_eq!(step("deploy[web1]").depends_on, vec!["build"]);
+        // Serial: each instance waits for the previous node.
+        assert_eq!(
+            step("deploy[web2]").depends_on,
+            vec!["build", "deploy[web1]"]
+        );
+        // Dependents of a fan-out wait for every instance.
+        assert_eq!(
+            step("check[web1]").depends_on,
+            vec!["deploy[web1]", "deploy[web2]"]
+        );
+        assert_eq!(
+            step("report").depends_on,
+            vec!["check[web1]", "check[web2]"]
+        );
+        assert_eq!(step("check[web2]").instance_of.as_deref(), Some("check"));
+        match &step("check[web2]").action {
+            ActionDef::Shell { env, .. } => assert_eq!(env["FLEET_NODE"], "web2"),
+            _ => panic!("Expected Shell variant"),
+        }
+
+        // build | deploy[web1] | deploy[web2] | both checks | report
+        assert_eq!(levels.len(), 5);
+        assert_eq!(levels[3].len(), 2);
+        assert_eq!(batches(&flow_def, &levels[3]).len(), 1);
+    }
+
+    #[test]
+    fn test_foreach_serial_instances_run_alone() {
+        let yaml = FOREACH_YAML.replace("        serial: true\n", "");
+        let config = FleetConfig::from_yaml(&yaml).unwrap();
+        let registry = k3s_registry();
+        let (flow_def, levels) = plan(&config, &registry, "rolling").unwrap();
+        // Without serial both deploys share a level and a batch.
+        assert_eq!(levels[1].len(), 2);
+        assert_eq!(batches(&flow_def, &levels[1]), vec![levels[1].clone()]);
+    }
+
+    #[test]
+    fn test_foreach_unknown_instance_reference() {
+        let yaml = FOREACH_YAML.replace("check[web1].status:-unknown", "check[db1].status");
+        let config = FleetConfig::from_yaml(&yaml).unwrap();
+        // Statically fine: `check` is a fan-out step...
+        assert!(crate::flow::validate(&config.flows, "rolling").is_ok());
+        // ...but db1 is not in @k3s, so there is no such instance.
+        let err = plan(&config, &k3s_registry(), "rolling")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("after foreach expansion"), "{err}");
+    }
+
+    #[test]
+    fn test_foreach_rejects_targets_and_bare_serial() {
+        let yaml = FOREACH_YAML.replace(
+            "        foreach: \"@k3s\"\n        serial: true\n",
+            "        foreach: \"@k3s\"\n        targets: [web1]\n",
+        );
+        let config = FleetConfig::from_yaml(&yaml).unwrap();
+        let err = crate::flow::validate(&config.flows, "rolling")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("both `foreach` and `targets`"), "{err}");
+
+        let yaml = FOREACH_YAML.replace(
+            "        foreach: \"@k3s\"\n        serial",
+            "        serial",
+        );
+        let config = FleetConfig::from_yaml(&yaml).unwrap();
+        let err = crate::flow::validate(&config.flows, "rolling")
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("`serial` without `foreach`"), "{err}");
+    }
+
+    #[test]
+    fn test_foreach_selector_must_match() {
+        let yaml = FOREACH_YAML.replace(
+            "foreach: \"@k3s\"\n        serial",
+            "foreach: \"@gpu\"\n        serial",
+        );
+        let config = FleetConfig::from_yaml(&yaml).unwrap();
+        let err = format!(
+            "{:#}",
+            plan(&config, &k3s_registry(), "rolling").unwrap_err()
+        );
+        assert!(err.contains("foreach '@gpu'"), "{err}");
+    }
+
+    const APPROVAL_YAML: &str = r#"
+flows:
+  guarded:
+    steps:
+      - id: confirm
+        action:
+          type: approval
+          message: "Deploy to production?"
+          approvers: [alice]
+      - id: ship
+        action: { type: flow, name: inner }
+        depends_on: [confirm]
+  inner:
+    steps:
+      - id: inner-gate
+        action: { type: approval, message: "Really?", timeout: 0 }
+"#;
+
+    #[test]
+    fn test_approval_parses_with_defaults() {
+        let config = FleetConfig::from_yaml(APPROVAL_YAML).unwrap();
+        match &config.flows["guarded"].steps[0].action {
+            ActionDef::Approval {
+                approvers, timeout, ..
+            } => {
+                assert_eq!(approvers, &["alice"]);
+                assert_eq!(*timeout, 600);
+            }
+            other => panic!("expected approval, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_approve_flag_must_name_an_approval_step() {
+        let config = FleetConfig::from_yaml(APPROVAL_YAML).unwrap();
+        let approve = |ids: &[&str]| {
+            let ids: Vec<String> = ids.iter().map(|s| s.to_string()).collect();
+            check_approvals(&config, "guarded", &ids)
+        };
+        approve(&["confirm", "inner-gate"]).unwrap();
+        let err = approve(&["ship"]).unwrap_err().to_string();
+        assert!(err.contains("approval steps: confirm, inner-gate"), "{err}");
+    }
+
+    const CONDITION_YAML: &str = r#"
+flows:
+  gated:
+    params:
+      force: { default: "false" }
+    steps:
+      - id: build
+        action: { type: shell, command: "true" }
+      - id: canary
+        action: { type: shell, command: "true" }
+        foreach: "@k3s"
+      - id: report
+        action: { type: shell, command: "true" }
+        depends_on: [build, canary]
+        condition: "failed(canary) || ${params.force} == true || target_count(@k3s) > 1"
+      - id: legacy
+        action: { type: shell, command: "true" }
+        condition: { command: "test -f .enabled" }
+"#;
+
+    #[test]
+    fn test_condition_forms_parse_and_validate() {
+        let config = FleetConfig::from_yaml(CONDITION_YAML).unwrap();
+        flow::validate(&config.flows, "gated").unwrap();
+        let steps = &config.flows["gated"].steps;
+        assert!(matches!(steps[2].condition, Some(ConditionDef::Expr(_))));
+        assert!(matches!(
+            steps[3].condition,
+            Some(ConditionDef::Command { .. })
+        ));
+    }
+
+    #[test]
+    fn test_condition_validation_errors() {
+        let err = |condition: &str| {
+            let yaml = CONDITION_YAML.replace(
+                "failed(canary) || ${params.force} == true || target_count(@k3s) > 1",
+                condition,
+            );
+            let config = FleetConfig::from_yaml(&yaml).unwrap();
+            flow::validate(&config.flows, "gated")
+                .unwrap_err()
+                .to_string()
+        };
+        assert!(err("failed(deploy)").contains("status of unknown step 'deploy'"));
+        assert!(err("succeeded(legacy)").contains("does not depend on it"));
+        assert!(err("${params.nope} == 1").contains("undeclared param 'nope'"));
+        assert!(err("${legacy.x} == 1").contains("does not depend on 'legacy'"));
+        assert!(err("failed(build").contains("Step 'report' condition"));
+    }
+
+    #[test]
+    fn test_condition_facts() {
+        let config = FleetConfig::from_yaml(CONDITION_YAML).unwrap();
+        let registry = k3s_registry();
+        let ctx = RunContext {
+            flow: "conditions",
+            config: &config,
+            registry: &registry,
+            cli_all: false,
+            approved: &[],
+            recorder: None,
+            record_prefix: String::new(),
+            locks: &test_locks(),
+            sub_flow_locks: &Mutex::default(),
+            transport: &OpenSsh::default(),
+            flow_dir: &config.config_dir,
+        };
+        let (outputs, secrets) = (FlowOutputs::new(), HashMap::new());
+        let params = HashMap::from([("force".to_string(), "true".to_string())]);
+        let scope = TemplateScope {
+            outputs: &outputs,
+            secrets: &secrets,
+            params: &params,
+            strict: true,
+            failure: None,
+        };
+        let statuses = HashMap::from([
+            ("canary[web1]".to_string(), StepStatus::Succeeded),
+            ("canary[web2]".to_string(), StepStatus::Failed),
+        ]);
+        let eval = |src: &str, statuses| {
+            let facts = StepFacts {
+                ctx: &ctx,
+                scope: &scope,
+                statuses,
+                targets: &[],
+            };
+            Expr::parse(src).unwrap().eval(&facts).unwrap()
+        };
+
+        // A fan-out fails if any instance did
+        assert_eq!(eval("failed(canary)", Some(&statuses)), Some(true));
+        assert_eq!(eval("target_count(@k3s) == 2", None), Some(true));
+        assert_eq!(eval("target_count(@gpu) == 0", None), Some(true));
+        // Unfiltered target_count() is the whole registry
+        assert_eq!(eval("target_count() == 3", None), Some(true));
+        // At plan time params are known, outputs and statuses are not
+        assert_eq!(eval("${params.force}", None), Some(true));
+        assert_eq!(eval("${build.x} == 1", None), None);
+        assert_eq!(eval("failed(canary)", None), None);
+    }
+
+    const CLEANUP_YAML: &str = r#"
+flows:
+  drill:
+    params:
+      log: {}
+    steps:
+      - id: provision
+        action: { type: shell, command: "echo up >> $LOG", env: { LOG: "${params.log}" } }
+      - id: verify
+        action: { type: shell, command: "exit 3" }
+        depends_on: [provision]
+    on_failure:
+      - id: report
+        action:
+          type: shell
+          command: "echo \"failed $STEP $ACTION\" >> $LOG"
+          env: { LOG: "${params.log}", STEP: "${failure.step}", ACTION: "${failure.action}" }
+    finally:
+      - id: teardown
+        action: { type: shell, command: "echo down >> $LOG", env: { LOG: "${params.log}" } }
+        condition: "succeeded(provision)"
+"#;
+
+    #[test]
+    fn test_cleanup_validation() {
+        let config = FleetConfig::from_yaml(CLEANUP_YAML).unwrap();
+        flow::validate(&config.flows, "drill").unwrap();
+
+        let err = |from: &str, to: &str| {
+            let config = FleetConfig::from_yaml(&CLEANUP_YAML.replace(from, to)).unwrap();
+            flow::validate(&config.flows, "drill")
+                .unwrap_err()
+                .to_string()
+        };
+        assert!(err(
+            "\"exit 3\" }",
+            "\"exit 3\", env: { E: \"${failure.error}\" } }"
+        )
+        .contains("only set in on_failure and finally steps"));
+        assert!(err("${failure.action}", "${failure.exit_code}")
+            .contains("unknown failure field 'exit_code'"));
+        assert!(err("id: teardown", "id: verify").contains("Duplicate step ID 'verify'"));
+        assert!(err(
+            "condition: \"succeeded(provision)\"",
+            "depends_on: [report]"
+        )
+        .contains("cleanup steps run in the order listed"));
+    }
+
+    #[test]
+    fn test_cleanup_runs_after_failure_and_success() {
+        let dir = std::env::temp_dir().join(format!("fleet-cleanup-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let registry = k3s_registry();
+        let locks = test_locks();
+        let run = |yaml: &str, log: &str| {
+            let config = FleetConfig::from_yaml(yaml).unwrap();
+            let ctx = RunContext {
+                flow: "drill",
+                config: &config,
+                registry: &registry,
+                cli_all: false,
+                approved: &[],
+                recorder: None,
+                record_prefix: String::new(),
+                locks: &locks,
+                sub_flow_locks: &Mutex::default(),
+                transport: &OpenSsh::default(),
+                flow_dir: &config.config_dir,
+            };
+            let (flow_def, levels) = plan(&config, &registry, "drill").unwrap();
+            let log = dir.join(log);
+            let params = HashMap::from([("log".to_string(), log.display().to_string())]);
+            let result = execute_flow(&ctx, "drill", &flow_def, &levels, &[], &params);
+            (result, std::fs::read_to_string(&log).unwrap_or_default())
+        };
+
+        // The failure is reported, then teardown runs; the run still fails
+        let (result, log) = run(CLEANUP_YAML, "failed.log");
+        assert!(result.is_err());
+        assert_eq!(log, "up\nfailed verify shell\ndown\n");
+
+        // on_failure is skipped when the main steps succeed
+        let (result, log) = run(&CLEANUP_YAML.replace("exit 3", "true"), "passed.log");
+        assert!(result.is_ok());
+        assert_eq!(log, "up\ndown\n");
+
+        // A failed cleanup step fails an otherwise good run
+        let (result, _) = run(
+            &CLEANUP_YAML
+                .replace("exit 3", "true")
+                .replace("echo down >> $LOG", "false"),
+            "teardown.log",
+        );
+        assert!(result
+            .unwrap_err()
+            .to_string()
+            .contains("cleanup failed in: teardown"));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    const FAILURE_MODE_YAML: &str = r#"
+flows:
+  fan:
+    failure_mode: fail-fast
+    params:
+      log: {}
+    steps:
+      - id: a
+        action: { type: shell, command: "exit 1" }
+      - id: b
+        action: { type: shell, command: "echo b >> $LOG", env: { LOG: "${params.log}" } }
+      - id: c
+        action: { type: shell, command: "echo c >> $LOG", env: { LOG: "${params.log}" } }
+        depends_on: [a]
+      - id: d
+        action: { type: shell, command: "echo d >> $LOG", env: { LOG: "${params.log}" } }
+        depends_on: [b]
+      - id: e
+        action: { type: shell, command: "echo e >> $LOG", env: { LOG: "${params.log}" } }
+        depends_on: [c]
+      - id: f
+        action: { type: shell, command: "echo f >> $LOG", env: { LOG: "${params.log}" } }
+        depends_on: [a]
+        condition: "failed(a)"
+"#;
+
+    #[test]
+    fn test_failure_modes() {
+        let dir = std::env::temp_dir().join(format!("fleet-failure-mode-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let registry = k3s_registry();
+        let locks = test_locks();
+        let run = |mode: &str| {
+            let yaml = FAILURE_MODE_YAML.replace("fail-fast", mode);
+            let config = FleetConfig::from_yaml(&yaml).unwrap();
+            let ctx = RunContext {
+                flow: "fan",
+                config: &config,
+                registry: &registry,
+                cli_all: false,
+                approved: &[],
+                recorder: None,
+                record_prefix: String::new(),
+                locks: &locks,
+                sub_flow_locks: &Mutex::default(),
+                transport: &OpenSsh::default(),
+                flow_dir: &config.config_dir,
+            };
+            let (flow_def, levels) = plan(&config, &registry, "fan").unwrap();
+            let log = dir.join(mode);
+            let params = HashMap::from([("log".to_string(), log.display().to_string())]);
+            let err = execute_flow(&ctx, "fan", &flow_def, &levels, &[], &params).unwrap_err();
+            (
+                err.to_string(),
+                std::fs::read_to_string(&log).unwrap_or_default(),
+            )
+        };
+
+        assert_eq!(run("fail-fast").1, "");
+        assert_eq!(run("finish-level").1, "b\n");
+        // c and e sit below the failure; f reacts to it, so it still runs
+        let (err, log) = run("best-effort");
+        let mut ran: Vec<_> = log.lines().collect();
+        ran.sort();
+        assert_eq!(ran, ["b", "d", "f"]);
+        assert!(err.contains("exit code: Some(1)"));
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
 }
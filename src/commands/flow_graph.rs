@@ -0,0 +1,488 @@
+use anyhow::{bail, Result};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::path::Path;
+
+use crate::condition::StepStatus;
+use crate::config::{ConditionDef, FleetConfig, FlowDef};
+use crate::flow;
+use crate::registry::NodeRegistry;
+use crate::runs::{self, format_duration_ms, RunRecord};
+
+#[derive(Debug, Clone, Copy, PartialEq, clap::ValueEnum)]
+pub enum GraphFormat {
+    Dot,
+    Mermaid,
+    Json,
+}
+
+/// One step as drawn: everything the renderers show, in one place.
+#[derive(Debug, Serialize)]
+struct GraphStep {
+    id: String,
+    action: &'static str,
+    /// Empty when the step inherits the CLI targets.
+    targets: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    condition: Option<String>,
+    /// The `foreach` step this is an instance of.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instance_of: Option<String>,
+    /// `on_failure` or `finally` for a cleanup step.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cleanup: Option<&'static str>,
+    /// How the step ended in the annotating run, if it got that far.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<StepStatus>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    duration_ms: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct Edge {
+    from: String,
+    to: String,
+}
+
+#[derive(Debug, Serialize)]
+struct Graph {
+    flow: String,
+    description: String,
+    /// The run whose step statuses annotate the graph.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    run: Option<String>,
+    steps: Vec<GraphStep>,
+    edges: Vec<Edge>,
+}
+
+/// `fleet flow graph <name>`: print the flow's DAG for reviews and docs.
+/// With a node registry, `foreach` steps are drawn as their per-node
+/// instances, as they would run. With `run_id`, each step is annotated with
+/// its status and duration in that recorded run.
+pub fn run(
+    config: &FleetConfig,
+    registry: &NodeRegistry,
+    name: &str,
+    format: GraphFormat,
+    run_id: Option<&str>,
+    runs_dir: &Path,
+) -> Result<()> {
+    flow::validate(&config.flows, name)?;
+    let flow_def = &config.flows[name];
+    let has_foreach = flow_def.steps.iter().any(|s| s.foreach.is_some());
+    let flow_def = if has_foreach && !registry.is_empty() {
+        flow::expand_foreach(flow_def, registry)?
+    } else {
+        Cow::Borrowed(flow_def)
+    };
+    let mut graph = build(name, &flow_def);
+    if let Some(id) = run_id {
+        let record = runs::find(runs_dir, id)?;
+        if record.flow != name {
+            bail!(
+                "Run '{}' is of flow '{}', not '{}'",
+                record.id,
+                record.flow,
+                name
+            );
+        }
+        annotate(&mut graph, &record);
+    }
+
+    match format {
+        GraphFormat::Dot => print!("{}", render_dot(&graph)),
+        GraphFormat::Mermaid => print!("{}", render_mermaid(&graph)),
+        GraphFormat::Json => println!("{}", serde_json::to_string_pretty(&graph)?),
+    }
+    Ok(())
+}
+
+/// Flatten a validated flow into the drawable graph. Cleanup steps follow
+/// the main steps, chained in the order they run.
+fn build(name: &str, flow_def: &FlowDef) -> Graph {
+    let mut edges: Vec<Edge> = flow_def
+        .steps
+        .iter()
+        .flat_map(|step| {
+            step.depends_on.iter().map(|dep| Edge {
+                from: dep.clone(),
+                to: step.id.clone(),
+            })
+        })
+        .collect();
+    let cleanup: Vec<_> = flow_def.cleanup_steps().collect();
+    edges.extend(cleanup.windows(2).map(|pair| Edge {
+        from: pair[0].id.clone(),
+        to: pair[1].id.clone(),
+    }));
+
+    let lists = flow_def
+        .steps
+        .iter()
+        .map(|step| (step, None))
+        .chain(
+            flow_def
+                .on_failure
+                .iter()
+                .map(|step| (step, Some("on_failure"))),
+        )
+        .chain(flow_def.finally.iter().map(|step| (step, Some("finally"))));
+    let steps = lists
+        .map(|(step, cleanup)| GraphStep {
+            id: step.id.clone(),
+            action: step.action.type_name(),
+            targets: step.targets.clone(),
+            condition: step.condition.as_ref().map(|c| match c {
+                ConditionDef::Expr(src) => src.clone(),
+                ConditionDef::Command { command } => format!("sh: {}", command),
+            }),
+            instance_of: step.instance_of.clone(),
+            cleanup,
+            status: None,
+            duration_ms: None,
+        })
+        .collect();
+    Graph {
+        flow: name.to_string(),
+        description: flow_def.description.clone(),
+        run: None,
+        steps,
+        edges,
+    }
+}
+
+/// Mark each step with how it ended in `record`. Steps the run never
+/// reached stay unmarked.
+fn annotate(graph: &mut Graph, record: &RunRecord) {
+    graph.run = Some(record.id.clone());
+    for step in &mut graph.steps {
+        if let Some(ran) = record.steps.iter().find(|s| s.id == step.id) {
+            step.status = Some(ran.status);
+            step.duration_ms = Some(ran.duration_ms);
+        }
+    }
+}
+
+fn status_name(status: StepStatus) -> &'static str {
+    match status {
+        StepStatus::Succeeded => "succeeded",
+        StepStatus::Failed => "failed",
+        StepStatus::Skipped => "skipped",
+    }
+}
+
+/// Fill and stroke colours for an annotated step.
+fn status_colors(status: StepStatus) -> (&'static str, &'static str) {
+    match status {
+        StepStatus::Succeeded => ("#d4edda", "#28a745"),
+        StepStatus::Failed => ("#f8d7da", "#dc3545"),
+        StepStatus::Skipped => ("#e2e3e5", "#6c757d"),
+    }
+}
+
+/// The lines of a step's box: id, action, targets, condition.
+fn label_lines(step: &GraphStep) -> Vec<String> {
+    let mut lines = vec![step.id.clone(), format!("[{}]", step.action)];
+    if let Some(list) = step.cleanup {
+        lines[1].push_str(&format!(" ({})", list));
+    }
+    if !step.targets.is_empty() {
+        lines.push(format!("targets: {}", step.targets.join(", ")));
+    }
+    if let Some(condition) = &step.condition {
+        lines.push(format!("if: {}", condition));
+    }
+    if let (Some(status), Some(ms)) = (step.status, step.duration_ms) {
+        lines.push(format!(
+            "{} · {}",
+            status_name(status),
+            format_duration_ms(ms)
+        ));
+    }
+    lines
+}
+
+fn render_dot(graph: &Graph) -> String {
+    fn quote(s: &str) -> String {
+        format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+
+    let mut out = format!("digraph {} {{\n", quote(&graph.flow));
+    out.push_str("  rankdir=TB;\n  node [shape=box, fontname=\"monospace\"];\n");
+    let mut in_cleanup = false;
+    for step in &graph.steps {
+        if step.cleanup.is_some() && !in_cleanup {
+            in_cleanup = true;
+            out.push_str(
+                "  subgraph cluster_cleanup {\n    label=\"cleanup\";\n    style=dotted;\n",
+            );
+        }
+        let indent = if in_cleanup { "    " } else { "  " };
+        let label = label_lines(step)
+            .iter()
+            .map(|l| l.replace('\\', "\\\\").replace('"', "\\\""))
+            .collect::<Vec<_>>()
+            .join("\\n");
+        let mut attrs = vec![format!("label=\"{}\"", label)];
+        if step.action == "approval" {
+            attrs.push("shape=hexagon".to_string());
+        }
+        let mut style = Vec::new();
+        if step.condition.is_some() {
+            style.push("dashed");
+        }
+        if let Some(status) = step.status {
+            let (fill, stroke) = status_colors(status);
+            style.push("filled");
+            attrs.push(format!("fillcolor=\"{}\", color=\"{}\"", fill, stroke));
+        }
+        if !style.is_empty() {
+            attrs.push(format!("style=\"{}\"", style.join(",")));
+        }
+        out.push_str(&format!(
+            "{}{} [{}];\n",
+            indent,
+            quote(&step.id),
+            attrs.join(", ")
+        ));
+    }
+    if in_cleanup {
+        out.push_str("  }\n");
+    }
+    for edge in &graph.edges {
+        out.push_str(&format!(
+            "  {} -> {};\n",
+            quote(&edge.from),
+            quote(&edge.to)
+        ));
+    }
+    out.push_str("}\n");
+    out
+}
+
+fn render_mermaid(graph: &Graph) -> String {
+    // Step IDs such as `drain[web1]` are not valid Mermaid node IDs, so
+    // nodes are numbered and the ID goes in the label.
+    let index = |id: &str| graph.steps.iter().position(|s| s.id == id).unwrap_or(0);
+    let escape = |s: &str| {
+        s.replace('&', "#amp;")
+            .replace('"', "#quot;")
+            .replace('<', "#lt;")
+            .replace('>', "#gt;")
+    };
+
+    let mut out = String::from("flowchart TD\n");
+    let mut in_cleanup = false;
+    for (i, step) in graph.steps.iter().enumerate() {
+        if step.cleanup.is_some() && !in_cleanup {
+            in_cleanup = true;
+            out.push_str("  subgraph cleanup\n");
+        }
+        let indent = if in_cleanup { "    " } else { "  " };
+        let label = label_lines(step)
+            .iter()
+            .map(|l| escape(l))
+            .collect::<Vec<_>>()
+            .join("<br/>");
+        let (open, close) = if step.action == "approval" {
+            ("{{", "}}")
+        } else {
+            ("[", "]")
+        };
+        out.push_str(&format!("{}s{}{}\"{}\"{}\n", indent, i, open, label, close));
+    }
+    if in_cleanup {
+        out.push_str("  end\n");
+    }
+    for edge in &graph.edges {
+        let arrow = if graph.steps[index(&edge.to)].condition.is_some() {
+            "-.->"
+        } else {
+            "-->"
+        };
+        out.push_str(&format!(
+            "  s{} {} s{}\n",
+            index(&edge.from),
+            arrow,
+            index(&edge.to)
+        ));
+    }
+    for status in [
+        StepStatus::Succeeded,
+        StepStatus::Failed,
+        StepStatus::Skipped,
+    ] {
+        let marked: Vec<String> = graph
+            .steps
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| s.status == Some(status))
+            .map(|(i, _)| format!("s{}", i))
+            .collect();
+        if marked.is_empty() {
+            continue;
+        }
+        let (fill, stroke) = status_colors(status);
+        let name = status_name(status);
+        out.push_str(&format!(
+            "  classDef {} fill:{},stroke:{}\n  class {} {}\n",
+            name,
+            fill,
+            stroke,
+            marked.join(","),
+            name
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GRAPH_YAML: &str = r#"
+flows:
+  release:
+    description: "Build then ship"
+    steps:
+      - id: build
+        action: { type: build }
+        targets: [web1, web2]
+      - id: confirm
+        action: { type: approval, message: "Ship it?" }
+        depends_on: [build]
+      - id: deploy
+        action: { type: deploy }
+        targets: ["@web"]
+        depends_on: [confirm]
+        condition: 'succeeded(build) && ${params.env} == "prod"'
+    params:
+      env: { default: prod }
+"#;
+
+    fn graph() -> Graph {
+        let config = FleetConfig::from_yaml(GRAPH_YAML).unwrap();
+        build("release", &config.flows["release"])
+    }
+
+    #[test]
+    fn dot_output() {
+        let dot = render_dot(&graph());
+        assert!(dot.starts_with("digraph \"release\" {\n"));
+        assert!(dot.contains("  \"build\" [label=\"build\\n[build]\\ntargets: web1, web2\"];\n"));
+        assert!(dot.contains("\"confirm\" [label=\"confirm\\n[approval]\", shape=hexagon];"));
+        assert!(dot.contains(
+            "if: succeeded(build) && ${params.env} == \\\"prod\\\"\", style=\"dashed\"];"
+        ));
+        assert!(dot.contains("  \"build\" -> \"confirm\";\n"));
+        assert!(dot.ends_with("}\n"));
+    }
+
+    #[test]
+    fn mermaid_output() {
+        let mermaid = render_mermaid(&graph());
+        assert!(mermaid.starts_with("flowchart TD\n"));
+        assert!(mermaid.contains("  s1{{\"confirm<br/>[approval]\"}}\n"));
+        assert!(mermaid.contains("#amp;#amp; ${params.env} == #quot;prod#quot;"));
+        assert!(mermaid.contains("  s0 --> s1\n"));
+        // Conditional steps are reached by a dotted edge
+        assert!(mermaid.contains("  s1 -.-> s2\n"));
+    }
+
+    #[test]
+    fn json_output() {
+        let json = serde_json::to_value(graph()).unwrap();
+        assert_eq!(json["flow"], "release");
+        assert_eq!(json["steps"][1]["action"], "approval");
+        assert_eq!(json["steps"][1].get("condition"), None);
+        assert_eq!(json["steps"][2]["targets"][0], "@web");
+        assert_eq!(json["edges"][1]["from"], "confirm");
+        assert_eq!(json["edges"][1]["to"], "deploy");
+    }
+
+    #[test]
+    fn cleanup_steps_are_clustered_in_run_order() {
+        let yaml = format!(
+            "{}{}",
+            GRAPH_YAML,
+            r#"    on_failure:
+      - id: notify
+        action: { type: shell, command: "true" }
+    finally:
+      - id: cleanup
+        action: { type: shell, command: "true" }
+"#
+        );
+        let config = FleetConfig::from_yaml(&yaml).unwrap();
+        let graph = build("release", &config.flows["release"]);
+
+        let dot = render_dot(&graph);
+        assert!(dot.contains("  subgraph cluster_cleanup {\n"));
+        assert!(dot.contains("    \"notify\" [label=\"notify\\n[shell] (on_failure)\"];\n"));
+        assert!(dot.contains("  \"notify\" -> \"cleanup\";\n"));
+        let mermaid = render_mermaid(&graph);
+        assert!(
+            mermaid.contains("  subgraph cleanup\n    s3[\"notify<br/>[shell] (on_failure)\"]\n")
+        );
+        assert!(mermaid.contains("  s3 --> s4\n"));
+    }
+
+    fn annotated() -> Graph {
+        let mut graph = graph();
+        let step = |id: &str, status, duration_ms| runs::StepRecord {
+            id: id.to_string(),
+            action: String::new(),
+            status,
+            started_at: 0,
+            duration_ms,
+            error: None,
+            outputs: Vec::new(),
+            approval: None,
+            blocked_by: Vec::new(),
+        };
+        let record = RunRecord {
+            id: "100-1".to_string(),
+            flow: "release".to_string(),
+            params: Default::default(),
+            targets: Vec::new(),
+            all: false,
+            user: "ops".to_string(),
+            git_rev: None,
+            git_dirty: false,
+            started_at: 100,
+            finished_at: Some(110),
+            duration_ms: Some(10_000),
+            status: runs::RunStatus::Failed,
+            error: None,
+            steps: vec![
+                step("build", StepStatus::Succeeded, 3_200),
+                step("confirm", StepStatus::Failed, 50),
+            ],
+        };
+        annotate(&mut graph, &record);
+        graph
+    }
+
+    #[test]
+    fn run_annotations() {
+        let graph = annotated();
+        let dot = render_dot(&graph);
+        assert!(dot.contains(
+            "\"build\" [label=\"build\\n[build]\\ntargets: web1, web2\\nsucceeded · 3.2s\", \
+             fillcolor=\"#d4edda\", color=\"#28a745\", style=\"filled\"];"
+        ));
+        // The step the run never reached is left plain
+        assert!(dot.contains("== \\\"prod\\\"\", style=\"dashed\"];"));
+
+        let mermaid = render_mermaid(&graph);
+        assert!(mermaid.contains("  class s0 succeeded\n"));
+        assert!(mermaid.contains("  class s1 failed\n"));
+        assert!(!mermaid.contains("skipped"));
+
+        let json = serde_json::to_value(&graph).unwrap();
+        assert_eq!(json["run"], "100-1");
+        assert_eq!(json["steps"][1]["status"], "failed");
+        assert_eq!(json["steps"][0]["duration_ms"], 3200);
+        assert_eq!(json["steps"][2].get("status"), None);
+    }
+}
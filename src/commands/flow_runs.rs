@@ -0,0 +1,162 @@
+use anyhow::Result;
+use colored::{ColoredString, Colorize};
+use std::path::Path;
+
+use crate::condition::StepStatus;
+use crate::runs::{self, format_duration_ms, format_utc, RunRecord, RunStatus};
+
+/// `fleet flow runs [name]`: past runs, newest first.
+pub fn list(runs_dir: &Path, flow: Option<&str>, limit: usize, json: bool) -> Result<()> {
+    let mut records = runs::load_all(runs_dir)?;
+    records.retain(|r| flow.is_none_or(|f| r.flow == f));
+    records.reverse();
+    records.truncate(limit);
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&records)?);
+        return Ok(());
+    }
+    if records.is_empty() {
+        println!("No recorded runs in {}", runs_dir.display());
+        return Ok(());
+    }
+
+    println!(
+        "{:<20} {:<20} {:<10} {:<20} {:>9}  {:<12} {}",
+        "RUN".bold(),
+        "FLOW".bold(),
+        "STATUS".bold(),
+        "STARTED (UTC)".bold(),
+        "DURATION".bold(),
+        "STEPS".bold(),
+        "GIT".bold(),
+    );
+    println!("{}", "-".repeat(106));
+    for r in &records {
+        println!(
+            "{:<20} {:<20} {:<10} {:<20} {:>9}  {:<12} {}",
+            r.id,
+            r.flow,
+            run_status(r.status),
+            format_utc(r.started_at),
+            r.duration_ms
+                .map(format_duration_ms)
+                .unwrap_or_else(|| "-".to_string()),
+            step_counts(r),
+            git_label(r),
+        );
+    }
+    Ok(())
+}
+
+/// `fleet flow show <run-id>`: one run, step by step.
+pub fn show(runs_dir: &Path, id: &str, json: bool) -> Result<()> {
+    let r = runs::find(runs_dir, id)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&r)?);
+        return Ok(());
+    }
+
+    println!("run      : {}", r.id);
+    println!("flow     : {}", r.flow);
+    println!("status   : {}", run_status(r.status));
+    println!("user     : {}", r.user);
+    println!("started  : {} UTC", format_utc(r.started_at));
+    if let (Some(end), Some(ms)) = (r.finished_at, r.duration_ms) {
+        println!(
+            "finished : {} UTC ({})",
+            format_utc(end),
+            format_duration_ms(ms)
+        );
+    }
+    println!("git      : {}", git_label(&r));
+    if !r.targets.is_empty() || r.all {
+        let all = if r.all { " (--all)" } else { "" };
+        println!("targets  : {}{}", r.targets.join(", "), all);
+    }
+    for (k, v) in &r.params {
+        println!("param    : {}={}", k, v);
+    }
+    if let Some(error) = &r.error {
+        println!("error    : {}", error.red());
+    }
+
+    println!();
+    println!(
+        "{:<32} {:<14} {:<10} {:>9}",
+        "STEP".bold(),
+        "ACTION".bold(),
+        "STATUS".bold(),
+        "DURATION".bold(),
+    );
+    println!("{}", "-".repeat(68));
+    for step in &r.steps {
+        println!(
+            "{:<32} {:<14} {:<10} {:>9}",
+            step.id,
+            step.action,
+            step_status(step.status),
+            format_duration_ms(step.duration_ms),
+        );
+        if let Some(approval) = &step.approval {
+            let field = |k: &str| approval.get(k).map_or("?".to_string(), |v| v.to_string());
+            println!(
+                "    approved by {} via {} at {} UTC",
+                field("approved_by").trim_matches('"'),
+                field("approved_via").trim_matches('"'),
+                approval
+                    .get("approved_at")
+                    .and_then(|v| v.as_u64())
+                    .map_or("?".to_string(), format_utc),
+            );
+        }
+        if let Some(error) = &step.error {
+            println!("    {}", error.red());
+        }
+        if !step.blocked_by.is_empty() {
+            println!("    upstream failure: {}", step.blocked_by.join(", "));
+        }
+    }
+    Ok(())
+}
+
+fn run_status(status: RunStatus) -> ColoredString {
+    match status {
+        RunStatus::Running => "running".yellow(),
+        RunStatus::Succeeded => "succeeded".green(),
+        RunStatus::Failed => "failed".red(),
+    }
+}
+
+fn step_status(status: StepStatus) -> ColoredString {
+    match status {
+        StepStatus::Succeeded => "succeeded".green(),
+        StepStatus::Failed => "failed".red(),
+        StepStatus::Skipped => "skipped".dimmed(),
+    }
+}
+
+/// `ok/failed/skipped` step counts.
+fn step_counts(r: &RunRecord) -> String {
+    let count = |s| r.steps.iter().filter(|step| step.status == s).count();
+    format!(
+        "{}/{}/{}",
+        count(StepStatus::Succeeded),
+        count(StepStatus::Failed),
+        count(StepStatus::Skipped)
+    )
+}
+
+fn git_label(r: &RunRecord) -> String {
+    match &r.git_rev {
+        Some(rev) => {
+            let short = &rev[..rev.len().min(12)];
+            if r.git_dirty {
+                format!("{}+dirty", short)
+            } else {
+                short.to_string()
+            }
+        }
+        None => "-".to_string(),
+    }
+}
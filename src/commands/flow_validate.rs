@@ -0,0 +1,582 @@
+use anyhow::{bail, Result};
+use colored::Colorize;
+use serde::Serialize;
+use std::path::Path;
+
+use crate::condition::Expr;
+use crate::config::{
+    ActionDef, ConditionDef, FleetConfig, FlowDef, FlowSecret, PangeaOperation, PitrForgeCommand,
+    StepDef,
+};
+use crate::flow::{self, Reference, Segment};
+use crate::registry::NodeRegistry;
+use crate::targeting;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, Serialize)]
+struct Finding {
+    flow: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    step: Option<String>,
+    severity: Severity,
+    message: String,
+}
+
+#[derive(Serialize)]
+struct Report {
+    flows: Vec<String>,
+    errors: usize,
+    warnings: usize,
+    findings: Vec<Finding>,
+}
+
+/// `fleet flow validate [name]`: everything `fleet flow run` would reject,
+/// plus what it would only discover partway through a run — selectors that
+/// match no node, missing Pangea/pitr-forge/SOPS files, references to
+/// outputs a step can never produce — and redundant dependencies. Exits
+/// non-zero if any error was found; warnings alone pass.
+pub fn run(
+    config: &FleetConfig,
+    registry: &NodeRegistry,
+    name: Option<&str>,
+    json: bool,
+) -> Result<()> {
+    let mut names: Vec<String> = match name {
+        Some(name) if !config.flows.contains_key(name) => bail!("Unknown flow: '{}'", name),
+        Some(name) => vec![name.to_string()],
+        None => config.flows.keys().cloned().collect(),
+    };
+    names.sort();
+
+    let mut findings = Vec::new();
+    for name in &names {
+        findings.extend(lint_flow(config, registry, name));
+    }
+    let errors = findings
+        .iter()
+        .filter(|f| f.severity == Severity::Error)
+        .count();
+    let report = Report {
+        flows: names,
+        errors,
+        warnings: findings.len() - errors,
+        findings,
+    };
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print_report(&report, registry.is_empty());
+    }
+
+    if report.errors > 0 {
+        bail!(
+            "{} error(s) in {} flow(s)",
+            report.errors,
+            report.flows.len()
+        );
+    }
+    Ok(())
+}
+
+fn print_report(report: &Report, no_registry: bool) {
+    if no_registry {
+        println!(
+            "{} node registry is empty (is FLEET_NODES set?) — targets were not checked",
+            "note:".yellow()
+        );
+    }
+    for name in &report.flows {
+        let findings: Vec<_> = report.findings.iter().filter(|f| &f.flow == name).collect();
+        if findings.is_empty() {
+            println!("{} {}", "ok".green().bold(), name);
+            continue;
+        }
+        let failed = findings.iter().any(|f| f.severity == Severity::Error);
+        let mark = if failed {
+            "FAIL".red().bold()
+        } else {
+            "warn".yellow().bold()
+        };
+        println!("{} {}", mark, name);
+        for f in findings {
+            let severity = match f.severity {
+                Severity::Error => "error".red(),
+                Severity::Warning => "warning".yellow(),
+            };
+            match &f.step {
+                Some(step) => println!("    {:<8} step '{}': {}", severity, step, f.message),
+                None => println!("    {:<8} {}", severity, f.message),
+            }
+        }
+    }
+    println!();
+    println!(
+        "{} flow(s), {} error(s), {} warning(s)",
+        report.flows.len(),
+        report.errors,
+        report.warnings
+    );
+}
+
+fn lint_flow(config: &FleetConfig, registry: &NodeRegistry, name: &str) -> Vec<Finding> {
+    let flow_def = &config.flows[name];
+    let mut findings = Vec::new();
+    let mut add = |step: Option<&str>, severity, message: String| {
+        findings.push(Finding {
+            flow: name.to_string(),
+            step: step.map(str::to_string),
+            severity,
+            message,
+        })
+    };
+
+    // Files and selectors can be checked even when the graph is broken
+    let mut unmatched_foreach = false;
+    for (secret, def) in sorted(&flow_def.secrets) {
+        let FlowSecret::Sops { file, .. } = def;
+        if let Some(missing) = missing_file(config.flow_dir(flow_def), file) {
+            add(
+                None,
+                Severity::Error,
+                format!("secret '{}': SOPS file {} not found", secret, missing),
+            );
+        }
+    }
+    for step in flow_def.all_steps() {
+        let file = match &step.action {
+            ActionDef::Pangea { file, .. } => Some(("pangea file", file)),
+            ActionDef::PitrForge {
+                config: Some(file), ..
+            } => Some(("pitr-forge config", file)),
+            _ => None,
+        };
+        if let Some((what, file)) = file {
+            if let Some(missing) = missing_file(config.flow_dir(flow_def), file) {
+                add(
+                    Some(&step.id),
+                    Severity::Error,
+                    format!("{} {} not found", what, missing),
+                );
+            }
+        }
+        if registry.is_empty() {
+            continue;
+        }
+        if !step.targets.is_empty() {
+            if let Err(e) = targeting::resolve(registry, &step.targets, false) {
+                add(
+                    Some(&step.id),
+                    Severity::Error,
+                    format!("targets [{}]: {}", step.targets.join(", "), e),
+                );
+            }
+        }
+        if let Some(selector) = &step.foreach {
+            if let Err(e) = targeting::resolve(registry, std::slice::from_ref(selector), false) {
+                unmatched_foreach = true;
+                add(
+                    Some(&step.id),
+                    Severity::Error,
+                    format!("foreach '{}': {}", selector, e),
+                );
+            }
+        }
+    }
+
+    // The graph itself: ids, deps, cycles, sub-flows, template references
+    let validated = match flow::validate(&config.flows, name) {
+        Ok(validated) => validated,
+        Err(e) => {
+            add(None, Severity::Error, format!("{:#}", e));
+            return findings;
+        }
+    };
+    // Instance references (`${id[node].x}`) can only be checked expanded
+    if !registry.is_empty() && !unmatched_foreach {
+        let expanded = flow::expand_foreach(flow_def, registry)
+            .and_then(|expanded| flow::validate_steps(&expanded).map(|_| ()));
+        if let Err(e) = expanded {
+            add(
+                None,
+                Severity::Error,
+                format!("after foreach expansion: {:#}", e),
+            );
+        }
+    }
+
+    for step in flow_def.all_steps() {
+        for r in output_refs(step, flow_def.strict_templates) {
+            if let Some(problem) = output_problem(config, flow_def, &r.step, &r.name) {
+                // A fallback — or a non-strict flow's empty string — keeps
+                // the run going, but the reference is still dead
+                let severity = if r.fallback || !flow_def.strict_templates {
+                    Severity::Warning
+                } else {
+                    Severity::Error
+                };
+                add(
+                    Some(&step.id),
+                    severity,
+                    format!("reads `${{{}}}`, but {}", r.raw, problem),
+                );
+            }
+        }
+    }
+
+    for (i, step) in flow_def.steps.iter().enumerate() {
+        // A dependency already implied by another is noise, and hides which
+        // edges actually order the flow
+        let deps = &validated.deps[i];
+        for (n, &dep) in deps.iter().enumerate() {
+            if deps[..n].contains(&dep) {
+                add(
+                    Some(&step.id),
+                    Severity::Warning,
+                    format!("lists '{}' in depends_on twice", flow_def.steps[dep].id),
+                );
+                continue;
+            }
+            if let Some(&via) = deps
+                .iter()
+                .find(|&&other| other != dep && flow::is_upstream(dep, other, &validated.deps))
+            {
+                add(
+                    Some(&step.id),
+                    Severity::Warning,
+                    format!(
+                        "depends on '{}', which is already upstream via '{}'",
+                        flow_def.steps[dep].id, flow_def.steps[via].id
+                    ),
+                );
+            }
+        }
+    }
+
+    findings
+}
+
+fn sorted<V>(map: &std::collections::HashMap<String, V>) -> Vec<(&String, &V)> {
+    let mut entries: Vec<_> = map.iter().collect();
+    entries.sort_by_key(|(k, _)| *k);
+    entries
+}
+
+/// The resolved path, for the message, if `file` does not exist. Relative
+/// paths resolve against the directory of the file defining the flow.
+fn missing_file(flow_dir: &Path, file: &str) -> Option<String> {
+    let path = if Path::new(file).is_absolute() {
+        Path::new(file).to_path_buf()
+    } else {
+        flow_dir.join(file)
+    };
+    (!path.exists()).then(|| format!("'{}' ({})", file, path.display()))
+}
+
+/// One `${step.output}` read by a step.
+struct OutputRef {
+    raw: String,
+    step: String,
+    name: String,
+    fallback: bool,
+}
+
+/// Every step output a step reads, from its templated values and its
+/// condition. Parse errors are left to [`flow::validate`].
+fn output_refs(step: &StepDef, strict: bool) -> Vec<OutputRef> {
+    let mut out = Vec::new();
+    let mut push = |r: &flow::TemplateRef| {
+        if let Reference::Output { step, name } = r.reference {
+            out.push(OutputRef {
+                raw: r.raw.to_string(),
+                step: step.to_string(),
+                name: name.to_string(),
+                fallback: r.default.is_some(),
+            });
+        }
+    };
+    if let Some((_, values)) = step.action.templated() {
+        let mut values: Vec<_> = values.iter().collect();
+        values.sort();
+        for (_, value) in values {
+            for segment in flow::parse_template(value, strict).unwrap_or_default() {
+                if let Segment::Ref(r) = segment {
+                    push(&r);
+                }
+            }
+        }
+    }
+    if let Some(ConditionDef::Expr(src)) = &step.condition {
+        if let Ok(expr) = Expr::parse(src) {
+            for r in expr.references() {
+                push(r);
+            }
+        }
+    }
+    out
+}
+
+/// Why output `name` of step `step_id` can never exist, if it can't. Steps
+/// that capture outputs only do so for some operations, and some capture a
+/// fixed set of names.
+fn output_problem(
+    config: &FleetConfig,
+    flow_def: &FlowDef,
+    step_id: &str,
+    name: &str,
+) -> Option<String> {
+    // `<id>[<node>]` is an instance of fan-out step `<id>`
+    let base = step_id
+        .strip_suffix(']')
+        .and_then(|id| id.split_once('['))
+        .map_or(step_id, |(base, _)| base);
+    let step = flow_def.all_steps().find(|s| s.id == base)?;
+    let known = |names: &[&str]| {
+        (!names.contains(&name)).then(|| {
+            format!(
+                "step '{}' ({}) has no output '{}' (outputs: {})",
+                base,
+                step.action.type_name(),
+                name,
+                names.join(", ")
+            )
+        })
+    };
+
+    match &step.action {
+        ActionDef::Pangea {
+            operation: PangeaOperation::Apply,
+            ..
+        } => None,
+        ActionDef::Pangea { .. } => Some(format!(
+            "step '{}' (pangea) captures outputs only with `operation: apply`",
+            base
+        )),
+        ActionDef::PitrForge {
+            command: PitrForgeCommand::Drill | PitrForgeCommand::Restore,
+            output_json: Some(_),
+            ..
+        } => known(super::pitr_forge::OUTPUTS),
+        ActionDef::PitrForge { .. } => Some(format!(
+            "step '{}' (pitr-forge) captures outputs only from drill/restore with `output_json`",
+            base
+        )),
+        ActionDef::Approval { .. } => known(super::approval::OUTPUTS),
+        ActionDef::Flow { name: sub, .. } => {
+            let Some((sub_step, sub_name)) = name.split_once('.') else {
+                return Some(format!(
+                    "sub-flow outputs are read as `${{{}.<step>.<output>}}`",
+                    step_id
+                ));
+            };
+            let sub_def = config.flows.get(sub)?;
+            let sub_base = sub_step
+                .strip_suffix(']')
+                .and_then(|id| id.split_once('['))
+                .map_or(sub_step, |(base, _)| base);
+            if !sub_def.all_steps().any(|s| s.id == sub_base) {
+                return Some(format!("flow '{}' has no step '{}'", sub, sub_step));
+            }
+            output_problem(config, sub_def, sub_step, sub_name)
+                .map(|problem| format!("in flow '{}', {}", sub, problem))
+        }
+        other => Some(format!(
+            "step '{}' ({}) produces no outputs",
+            base,
+            other.type_name()
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::Node;
+
+    fn registry() -> NodeRegistry {
+        let node = |tags: &[&str]| Node {
+            system: "x86_64-linux".to_string(),
+            hostname: "10.0.0.1".to_string(),
+            ssh_user: "root".to_string(),
+            tags: tags.iter().map(|t| t.to_string()).collect(),
+            host_key: None,
+        };
+        NodeRegistry::from([
+            ("web1".to_string(), node(&["k3s"])),
+            ("web2".to_string(), node(&["k3s"])),
+        ])
+    }
+
+    const LINT_YAML: &str = r#"
+flows:
+  infra:
+    secrets:
+      token: { source: sops, file: secrets.yaml, key: a/b }
+    steps:
+      - id: network
+        action: { type: pangea, file: network.rb, namespace: dev, operation: apply }
+      - id: plan
+        action: { type: pangea, file: network.rb, namespace: dev, operation: plan }
+  main:
+    steps:
+      - id: infra
+        action: { type: flow, name: infra }
+      - id: gate
+        action: { type: approval, message: "go?" }
+        depends_on: [infra]
+      - id: build
+        action: { type: build }
+        targets: ["@k3s"]
+        depends_on: [gate, infra]
+      - id: use
+        action:
+          type: shell
+          command: "true"
+          env:
+            VPC: "${infra.network.vpc_id}"
+            WHO: "${gate.approved_by}"
+        depends_on: [build]
+"#;
+
+    /// A config directory containing `files`, removed on drop.
+    struct Dir(std::path::PathBuf);
+
+    impl Dir {
+        fn new(name: &str, files: &[&str]) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "fleet-flow-validate-{}-{}",
+                name,
+                std::process::id()
+            ));
+            std::fs::create_dir_all(&dir).unwrap();
+            for file in files {
+                std::fs::write(dir.join(file), "").unwrap();
+            }
+            Dir(dir)
+        }
+    }
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn lint(yaml: &str, dir: &Dir, name: &str) -> Vec<Finding> {
+        let mut config = FleetConfig::from_yaml(yaml).unwrap();
+        config.config_dir = dir.0.clone();
+        lint_flow(&config, &registry(), name)
+    }
+
+    fn messages(findings: &[Finding]) -> Vec<String> {
+        findings
+            .iter()
+            .map(|f| {
+                format!(
+                    "{:?} {}: {}",
+                    f.severity,
+                    f.step.as_deref().unwrap_or("-"),
+                    f.message
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn clean_flow_has_only_the_redundant_dependency() {
+        let dir = Dir::new("clean", &["secrets.yaml", "network.rb"]);
+        assert!(lint(LINT_YAML, &dir, "infra").is_empty());
+        assert_eq!(
+            messages(&lint(LINT_YAML, &dir, "main")),
+            ["Warning build: depends on 'infra', which is already upstream via 'gate'"]
+        );
+    }
+
+    #[test]
+    fn missing_files_are_errors() {
+        let dir = Dir::new("missing", &[]);
+        let found = messages(&lint(LINT_YAML, &dir, "infra"));
+        assert_eq!(found.len(), 3, "{found:?}");
+        assert!(found[0].starts_with("Error -: secret 'token': SOPS file 'secrets.yaml'"));
+        assert!(found[1].starts_with("Error network: pangea file 'network.rb'"));
+    }
+
+    #[test]
+    fn included_flows_resolve_files_against_their_own_file() {
+        let dir = Dir::new("included", &["network.rb"]);
+        let infra = LINT_YAML.split("  main:").next().unwrap();
+        std::fs::write(dir.0.join("fleet.yaml"), "include: [infra/*.yaml]\n").unwrap();
+        std::fs::create_dir_all(dir.0.join("infra")).unwrap();
+        std::fs::write(dir.0.join("infra/flows.yaml"), infra).unwrap();
+        std::fs::write(dir.0.join("infra/secrets.yaml"), "").unwrap();
+
+        let config = FleetConfig::load(&dir.0, None).unwrap();
+        let found = messages(&lint_flow(&config, &registry(), "infra"));
+        assert_eq!(found.len(), 2, "{found:?}");
+        assert!(
+            found[0].contains(&format!("({})", dir.0.join("infra/network.rb").display())),
+            "{found:?}"
+        );
+
+        std::fs::write(dir.0.join("infra/network.rb"), "").unwrap();
+        let config = FleetConfig::load(&dir.0, None).unwrap();
+        assert!(lint_flow(&config, &registry(), "infra").is_empty());
+    }
+
+    #[test]
+    fn selectors_must_match() {
+        let dir = Dir::new("selectors", &["secrets.yaml", "network.rb"]);
+        let yaml = LINT_YAML.replace("targets: [\"@k3s\"]", "targets: [\"@gpu\"]");
+        let found = messages(&lint(&yaml, &dir, "main"));
+        assert!(
+            found[0].starts_with("Error build: targets [@gpu]"),
+            "{found:?}"
+        );
+    }
+
+    #[test]
+    fn references_must_name_produced_outputs() {
+        let dir = Dir::new("outputs", &["secrets.yaml", "network.rb"]);
+        let bad = LINT_YAML
+            .replace("${infra.network.vpc_id}", "${infra.plan.vpc_id}")
+            .replace("${gate.approved_by}", "${build.hash:-none}");
+        let found = messages(&lint(&bad, &dir, "main"));
+        assert!(
+            found.contains(
+                &"Error use: reads `${infra.plan.vpc_id}`, but in flow 'infra', step 'plan' \
+                  (pangea) captures outputs only with `operation: apply`"
+                    .to_string()
+            ),
+            "{found:?}"
+        );
+        assert!(
+            found.contains(
+                &"Warning use: reads `${build.hash:-none}`, but step 'build' (build) \
+                  produces no outputs"
+                    .to_string()
+            ),
+            "{found:?}"
+        );
+
+        let bad = LINT_YAML.replace("${gate.approved_by}", "${gate.approver}");
+        let found = messages(&lint(&bad, &dir, "main"));
+        assert!(found.iter().any(|f| f.contains("has no output 'approver'")));
+    }
+
+    #[test]
+    fn structural_errors_stop_the_graph_checks() {
+        let dir = Dir::new("structural", &["secrets.yaml", "network.rb"]);
+        let yaml = LINT_YAML.replace("depends_on: [build]", "depends_on: [nope]");
+        let found = messages(&lint(&yaml, &dir, "main"));
+        assert_eq!(
+            found,
+            ["Error -: Step 'use' depends on unknown step 'nope'"]
+        );
+    }
+}
@@ -0,0 +1,182 @@
+use anyhow::{bail, Result};
+use colored::Colorize;
+use std::path::Path;
+
+use super::utils::*;
+use crate::config::FleetConfig;
+use crate::hostkeys::{self, HostKey, KnownHosts, Verdict};
+use crate::targeting::ResolvedTargets;
+use crate::transport::{Target, Transport};
+
+/// What `fleet hostkeys` does with what it finds.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    /// Record hosts seen for the first time; report anything else.
+    Scan,
+    /// Report only.
+    Verify,
+    /// Also replace recorded keys a node no longer presents.
+    Update,
+}
+
+pub fn run(
+    targets: &ResolvedTargets,
+    mode: Mode,
+    known_hosts: &Path,
+    config: &FleetConfig,
+    transport: &dyn Transport,
+) -> Result<()> {
+    let mut known = KnownHosts::load(known_hosts)?;
+    log_info(&format!(
+        "Checking host keys against {}\n",
+        known.path().display()
+    ));
+
+    let mut problems = 0;
+    let mut changed = false;
+    for (name, node) in &targets.nodes {
+        let label = node_label(name);
+        let target = Target::node(config, name, node);
+        let host = known_as(&target, &node.hostname);
+
+        let declared = match node.host_key.as_deref().map(HostKey::parse).transpose() {
+            Ok(declared) => declared,
+            Err(e) => {
+                println!(
+                    "{} {} registry hostKey is invalid: {:#}",
+                    label,
+                    "✗".red(),
+                    e
+                );
+                problems += 1;
+                continue;
+            }
+        };
+        // The declared key's type, which ssh might not otherwise pick
+        let kind = declared.as_ref().map(|k| k.kind.as_str());
+        let live = match presented(transport, &target, kind) {
+            Ok(live) => live,
+            Err(e) => {
+                println!("{} {} {:#}", label, "✗".red(), e);
+                problems += 1;
+                continue;
+            }
+        };
+
+        match hostkeys::check(&known.keys_for(&host), declared.as_ref(), &live) {
+            Verdict::Unreachable => {
+                println!("{} {} no host keys (unreachable?)", label, "✗".red());
+                problems += 1;
+            }
+            Verdict::Matches => {
+                println!("{} {} {}", label, "✓".green(), live[0].fingerprint());
+            }
+            Verdict::New if mode == Mode::Verify => {
+                println!(
+                    "{} {} not in known_hosts ({})",
+                    label,
+                    "?".yellow(),
+                    live[0].fingerprint()
+                );
+                problems += 1;
+            }
+            Verdict::New => {
+                known.set(&host, &live);
+                changed = true;
+                println!(
+                    "{} {} recorded {}",
+                    label,
+                    "+".green(),
+                    live[0].fingerprint()
+                );
+            }
+            Verdict::Changed { old, new } if mode == Mode::Update => {
+                known.set(&host, &live);
+                changed = true;
+                println!(
+                    "{} {} updated {} → {}",
+                    label,
+                    "~".yellow(),
+                    old.fingerprint(),
+                    new.fingerprint()
+                );
+            }
+            Verdict::Changed { old, new } => {
+                println!(
+                    "{} {} CHANGED: known {}, node presents {} (`fleet hostkeys update` to accept)",
+                    label,
+                    "✗".red(),
+                    old.fingerprint(),
+                    new.fingerprint()
+                );
+                problems += 1;
+            }
+            Verdict::RegistryMismatch { declared, live } => {
+                println!(
+                    "{} {} MISMATCH: registry declares {}, node presents {}",
+                    label,
+                    "✗".red(),
+                    declared.fingerprint(),
+                    live.fingerprint()
+                );
+                problems += 1;
+            }
+            Verdict::Unverifiable { declared } => {
+                println!(
+                    "{} {} cannot verify: registry declares a {} key, node presents none",
+                    label,
+                    "✗".red(),
+                    declared.kind
+                );
+                problems += 1;
+            }
+        }
+    }
+
+    if changed {
+        known.save()?;
+    }
+    if problems > 0 {
+        bail!("{} node(s) failed host key checks", problems);
+    }
+    Ok(())
+}
+
+/// A setting from the node's ssh options, whose names ssh reads in any case.
+fn option<'a>(target: &'a Target, name: &str) -> Option<&'a str> {
+    target
+        .ssh
+        .options
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+/// The name ssh looks the node's key up by: a `HostKeyAlias` as it is,
+/// else the host it connects to — a `HostName` in the node's ssh options
+/// over the registry's — with any `Port` other than 22.
+fn known_as(target: &Target, hostname: &str) -> String {
+    if let Some(alias) = option(target, "HostKeyAlias") {
+        return alias.to_string();
+    }
+    let host = option(target, "HostName").unwrap_or(hostname);
+    let port = option(target, "Port")
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(22);
+    hostkeys::host_name(host, port)
+}
+
+/// The keys the node presents to ssh, asked for without trusting any.
+fn presented(
+    transport: &dyn Transport,
+    target: &Target,
+    kind: Option<&str>,
+) -> Result<Vec<HostKey>> {
+    let output = transport.host_keys(target, kind)?;
+    // A node that never got as far as showing a key leaves nothing, and
+    // ssh's own reason is the one worth reporting
+    if !output.success() && output.stdout.trim().is_empty() && !output.stderr.trim().is_empty() {
+        bail!("ssh failed: {}", output.stderr.trim());
+    }
+    Ok(hostkeys::parse_key_lines(&output.stdout))
+}
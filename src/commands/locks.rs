@@ -0,0 +1,68 @@
+use anyhow::Result;
+use colored::Colorize;
+use std::path::Path;
+
+use super::rebuild::REBUILD_LOCK_PATH;
+use super::utils::log_success;
+use crate::lock::{self, LockName, Locks};
+use crate::runs::{self, format_duration_ms};
+
+/// `fleet locks`: held locks, or every lock file with `--all`.
+pub fn list(locks: &Locks, all: bool) -> Result<()> {
+    let mut found = lock::list(locks.dir(), Path::new(REBUILD_LOCK_PATH))?;
+    found.retain(|l| all || l.held);
+    if found.is_empty() {
+        println!(
+            "No {}locks in {}",
+            if all { "" } else { "held " },
+            locks.dir().display()
+        );
+        return Ok(());
+    }
+
+    println!(
+        "{:<28} {:<6} {:>9}  {}",
+        "LOCK".bold(),
+        "STATE".bold(),
+        "HELD FOR".bold(),
+        "HOLDER".bold(),
+    );
+    println!("{}", "-".repeat(80));
+    let now = runs::now_epoch();
+    for l in &found {
+        let state = if l.held {
+            "held".red()
+        } else {
+            "free".dimmed()
+        };
+        let held_for = match (l.held, l.since) {
+            (true, Some(since)) => format_duration_ms(now.saturating_sub(since) * 1000),
+            _ => "-".to_string(),
+        };
+        // A free lock's stamp names its LAST holder, which is still worth
+        // seeing — but not in a way that reads as current.
+        let holder = match (&l.holder, l.held) {
+            (Some(h), true) => h.clone(),
+            (Some(h), false) => format!("(last: {})", h),
+            (None, _) => "-".to_string(),
+        };
+        println!(
+            "{:<28} {:<6} {:>9}  {}",
+            l.name.to_string(),
+            state,
+            held_for,
+            holder
+        );
+    }
+    Ok(())
+}
+
+/// `fleet locks break <lock>`: remove a lock file, refusing a held one
+/// without `--force`.
+pub fn break_lock(locks: &Locks, name: &str, force: bool) -> Result<()> {
+    let name = LockName::parse(name)?;
+    let path = lock::path_of(&name, locks.dir(), Path::new(REBUILD_LOCK_PATH));
+    lock::break_lock(&path, &name.what(), force)?;
+    log_success(&format!("Broke the {} ({})", name.what(), path.display()));
+    Ok(())
+}
@@ -0,0 +1,251 @@
+use anyhow::{bail, Context, Result};
+use colored::Colorize;
+use regex::{Regex, RegexBuilder};
+use std::sync::Mutex;
+
+use super::utils::*;
+use crate::config::FleetConfig;
+use crate::registry::Node;
+use crate::runs::format_utc;
+use crate::targeting::ResolvedTargets;
+use crate::transport::{shell_join, Target, Transport};
+
+/// How many lines each node contributes when neither `--lines` nor
+/// `--since` says.
+const DEFAULT_LINES: u32 = 100;
+
+/// What `fleet logs` asks journald for, and how it shows the answer.
+#[derive(Debug, Default)]
+pub struct LogOptions {
+    pub units: Vec<String>,
+    pub since: Option<String>,
+    pub lines: Option<u32>,
+    pub follow: bool,
+    /// Interleave every node's entries by their journald timestamps.
+    pub merge: bool,
+    pub grep: Option<String>,
+    pub ignore_case: bool,
+}
+
+/// One journal entry, from `journalctl -o json`.
+#[derive(Debug, PartialEq)]
+struct Entry {
+    /// Microseconds since the epoch.
+    time: u64,
+    ident: String,
+    message: String,
+}
+
+/// Read every node's journal at once. Lines are printed as they arrive,
+/// each behind its node's label; with `merge` they come from journald's
+/// JSON and — unless following, where arrival order is all there is —
+/// are printed in time order once every node has answered.
+pub fn run(
+    targets: &ResolvedTargets,
+    opts: &LogOptions,
+    config: &FleetConfig,
+    transport: &dyn Transport,
+) -> Result<()> {
+    let grep = opts
+        .grep
+        .as_deref()
+        .map(|pattern| {
+            RegexBuilder::new(pattern)
+                .case_insensitive(opts.ignore_case)
+                .build()
+                .with_context(|| format!("invalid --grep pattern `{}`", pattern))
+        })
+        .transpose()?;
+    let command = journalctl_command(opts);
+    log_info(&format!("Reading: {}\n", command));
+
+    let collected: Mutex<Vec<(Entry, usize)>> = Mutex::new(Vec::new());
+    let results: Vec<Result<()>> = std::thread::scope(|s| {
+        let handles: Vec<_> = targets
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(i, (name, node))| {
+                let (command, grep, collected) = (&command, grep.as_ref(), &collected);
+                s.spawn(move || {
+                    read_node(
+                        name, node, i, command, opts, grep, collected, config, transport,
+                    )
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| {
+                h.join()
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("log thread panicked")))
+            })
+            .collect()
+    });
+
+    let mut entries = collected.into_inner().unwrap();
+    // Stable, so one node's entries within the same microsecond keep order
+    entries.sort_by_key(|(entry, _)| entry.time);
+    for (entry, i) in &entries {
+        print_entry(&targets.nodes[*i].0, entry);
+    }
+
+    let mut failed = 0;
+    for ((name, _), result) in targets.nodes.iter().zip(results) {
+        if let Err(e) = result {
+            log_error(&format!("{} {:#}", node_label(name), e));
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        bail!("{} node(s) failed", failed);
+    }
+    Ok(())
+}
+
+/// Stream one node's journal: printing each line that passes `grep`, or
+/// — merging without following — keeping its entries for the sort.
+#[allow(clippy::too_many_arguments)]
+fn read_node(
+    name: &str,
+    node: &Node,
+    index: usize,
+    command: &str,
+    opts: &LogOptions,
+    grep: Option<&Regex>,
+    collected: &Mutex<Vec<(Entry, usize)>>,
+    config: &FleetConfig,
+    transport: &dyn Transport,
+) -> Result<()> {
+    let label = node_label(name);
+    let keep = |text: &str| grep.is_none_or(|re| re.is_match(text));
+    let mut line = |text: &str| {
+        if !opts.merge {
+            if keep(text) {
+                println!("{} {}", label, text);
+            }
+            return;
+        }
+        let Some(entry) = parse_entry(text) else {
+            return;
+        };
+        if !keep(&entry.message) {
+            return;
+        }
+        if opts.follow {
+            print_entry(name, &entry);
+        } else {
+            collected.lock().unwrap().push((entry, index));
+        }
+    };
+    let output = transport.stream(&Target::node(config, name, node), command, &mut line)?;
+    if !output.success() {
+        bail!("journalctl failed: {}", output.stderr.trim());
+    }
+    Ok(())
+}
+
+fn print_entry(name: &str, entry: &Entry) {
+    println!(
+        "{} {} {}: {}",
+        format_utc(entry.time / 1_000_000).dimmed(),
+        node_label(name),
+        entry.ident,
+        entry.message
+    );
+}
+
+/// The `journalctl` command line for `opts`.
+fn journalctl_command(opts: &LogOptions) -> String {
+    let mut words = vec!["journalctl", "--no-pager", "-o"];
+    words.push(if opts.merge { "json" } else { "short-iso" });
+    for unit in &opts.units {
+        words.extend(["-u", unit]);
+    }
+    if let Some(since) = &opts.since {
+        words.extend(["--since", since]);
+    }
+    let lines = match (opts.lines, &opts.since) {
+        (Some(n), _) => Some(n),
+        (None, None) => Some(DEFAULT_LINES),
+        (None, Some(_)) => None,
+    }
+    .map(|n| n.to_string());
+    if let Some(n) = &lines {
+        words.extend(["-n", n]);
+    }
+    if opts.follow {
+        words.push("-f");
+    }
+    shell_join(&words.into_iter().map(String::from).collect::<Vec<_>>())
+}
+
+/// One line of `journalctl -o json`. MESSAGE is a string, or an array of
+/// bytes when it is not valid UTF-8; an entry without one is skipped.
+fn parse_entry(line: &str) -> Option<Entry> {
+    let value: serde_json::Value = serde_json::from_str(line).ok()?;
+    let field = |key: &str| value.get(key).and_then(|v| v.as_str());
+    let message = match value.get("MESSAGE")? {
+        serde_json::Value::String(s) => s.clone(),
+        serde_json::Value::Array(bytes) => {
+            let bytes: Vec<u8> = bytes
+                .iter()
+                .filter_map(|b| b.as_u64())
+                .map(|b| b as u8)
+                .collect();
+            String::from_utf8_lossy(&bytes).into_owned()
+        }
+        _ => return None,
+    };
+    let ident = field("SYSLOG_IDENTIFIER").or(field("_COMM")).unwrap_or("?");
+    let ident = match field("_PID") {
+        Some(pid) => format!("{}[{}]", ident, pid),
+        None => ident.to_string(),
+    };
+    Some(Entry {
+        time: field("__REALTIME_TIMESTAMP")?.parse().ok()?,
+        ident,
+        message,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn journalctl_reads_the_last_lines_unless_given_a_start() {
+        let mut opts = LogOptions {
+            units: vec!["nginx.service".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(
+            journalctl_command(&opts),
+            "journalctl --no-pager -o short-iso -u nginx.service -n 100"
+        );
+        opts.since = Some("1 hour ago".to_string());
+        opts.merge = true;
+        opts.follow = true;
+        assert_eq!(
+            journalctl_command(&opts),
+            "journalctl --no-pager -o json -u nginx.service --since '1 hour ago' -f"
+        );
+    }
+
+    #[test]
+    fn json_entries_carry_time_ident_and_message() {
+        let line = r#"{"__REALTIME_TIMESTAMP":"1760832000123456","SYSLOG_IDENTIFIER":"nginx","_PID":"812","MESSAGE":"started"}"#;
+        assert_eq!(
+            parse_entry(line),
+            Some(Entry {
+                time: 1_760_832_000_123_456,
+                ident: "nginx[812]".to_string(),
+                message: "started".to_string(),
+            })
+        );
+        let bytes = r#"{"__REALTIME_TIMESTAMP":"1","_COMM":"app","MESSAGE":[104,105,255]}"#;
+        assert_eq!(parse_entry(bytes).unwrap().message, "hi\u{fffd}");
+        assert_eq!(parse_entry(r#"{"__REALTIME_TIMESTAMP":"1"}"#), None);
+        assert_eq!(parse_entry("-- No entries --"), None);
+    }
+}
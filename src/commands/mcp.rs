@@ -36,6 +36,9 @@ pub struct ConvergenceInput {}
 
 #[derive(Clone)]
 pub struct FleetMcp {
+    // Read through the `#[tool_handler]` expansion, which rustc's dead-code
+    // pass cannot see into.
+    #[allow(dead_code)]
     tool_router: ToolRouter<Self>,
 }
 
@@ -1,10 +1,19 @@
+pub mod approval;
 pub mod build;
+pub mod config;
 pub mod convergence;
+pub mod cp;
 pub mod deploy;
 pub mod diff;
 pub mod exec;
 pub mod flow;
+pub mod flow_graph;
+pub mod flow_runs;
+pub mod flow_validate;
+pub mod hostkeys;
 pub mod info;
+pub mod locks;
+pub mod logs;
 pub mod mcp;
 pub mod nix_credential;
 pub mod pangea;
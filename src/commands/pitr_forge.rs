@@ -7,6 +7,7 @@ use crate::config::{PitrForgeCommand, StepResult};
 use super::utils::*;
 
 /// Run a pitr-forge operation.
+#[allow(clippy::too_many_arguments)]
 pub fn run(
     command: &PitrForgeCommand,
     tenant: Option<&str>,
@@ -81,10 +82,21 @@ pub fn run(
     Ok(StepResult { outputs })
 }
 
+/// Every output [`capture_drill_outputs`] can produce; which ones a run
+/// actually yields depends on the results file.
+pub const OUTPUTS: &[&str] = &[
+    "overall_status",
+    "measured_rto_secs",
+    "total_ms",
+    "tenant",
+    "environment",
+    "gate_count_passed",
+    "gate_count_failed",
+];
+
 /// Capture key outputs from a pitr-forge drill results JSON file.
 ///
-/// Extracts: overall_status, measured_rto_secs, total_ms, tenant, environment,
-/// gate_count_passed, gate_count_failed.
+/// Extracts the names in [`OUTPUTS`].
 fn capture_drill_outputs(json_path: &str) -> Result<HashMap<String, serde_json::Value>> {
     let data = match std::fs::read_to_string(json_path) {
         Ok(d) => d,
@@ -23,6 +23,7 @@
 //!   * `ppid == 1`               — reparented to launchd; its session is GONE
 //!   * `uid == getuid()`         — ours to reap
 //!   * pty pressure is already past `REAP_THRESHOLD`
+//!
 //! A shell with a live parent is somebody's in-flight session and is never
 //! touched, at any pressure. Below the threshold this module does nothing at
 //! all — it is not a periodic cleaner, it is a guard on one gate.
@@ -2,12 +2,12 @@ use anyhow::{Context, Result};
 use colored::Colorize;
 
 use crate::github_token::{self, ResolvedToken, SystemEnv};
-use fs4::fs_std::FileExt;
-use std::fs::{self, File, OpenOptions};
+use crate::lock::{self, LockName, LockSpec, LockWait};
+use std::fs::{self, File};
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 use std::process::Command;
-use std::time::{Duration, Instant};
+use std::time::Duration;
 
 use super::utils::{
     log_info, log_success, log_warning, rebuild_timeout, run_command, run_command_output,
@@ -90,7 +90,10 @@ fn parse_e2e_report(stdout: &str) -> Option<(String, Vec<E2eRow>)> {
 /// its activation state.
 fn acquire_rebuild_lock() -> Result<File> {
     // The ONE environment read on this path, at the real entry point.
-    acquire_lock_at(Path::new(REBUILD_LOCK_PATH), LockWait::from_env())
+    acquire_lock_at(
+        Path::new(REBUILD_LOCK_PATH),
+        LockWait::from_env("FLEET_REBUILD_LOCK_TIMEOUT_SECS", LOCK_WAIT_TIMEOUT),
+    )
 }
 
 /// The one machine-wide rebuild lock path.
@@ -120,7 +123,7 @@ fn acquire_rebuild_lock() -> Result<File> {
 /// unprivileged operator can create and open this path; the sticky bit stops
 /// either from unlinking the other's file. The lock is advisory `flock`, so it
 /// costs nothing when uncontended.
-const REBUILD_LOCK_PATH: &str = "/tmp/fleet-rebuild.lock";
+pub const REBUILD_LOCK_PATH: &str = "/tmp/fleet-rebuild.lock";
 
 /// How long a blocked rebuild waits for the lock before giving up with a
 /// typed failure.
@@ -135,319 +138,19 @@ const REBUILD_LOCK_PATH: &str = "/tmp/fleet-rebuild.lock";
 /// unbounded wait for anyone who genuinely wants it.
 const LOCK_WAIT_TIMEOUT: Duration = Duration::from_secs(2 * 60 * 60);
 
-/// How long to wait for a contended lock — a VALUE, passed in.
-///
-/// ── ★ WHY THIS IS A PARAMETER AND NOT AN ENV READ ────────────────────────
-/// `wait_for_lock` used to read `FLEET_REBUILD_LOCK_TIMEOUT_SECS` itself.
-/// `std::env` is process-global and `cargo test` runs tests as THREADS in one
-/// process, so two tests that set the var raced each other: one set `"1"`, the
-/// other's `remove_var` landed between that write and the read, the read fell
-/// through to the 2-hour default, and the test blocked for the full
-/// `LOCK_WAIT_TIMEOUT` before failing its own 30s assertion.
-///
-/// Observed 2026-08-16, not theorised: a suite run under load reported
-/// `FAILED. 101 passed; 1 failed; finished in 7200.39s` — 7200s being exactly
-/// `2 * 60 * 60`. The same race has a worse arm: read `"0"` and the wait
-/// becomes UNBOUNDED, which is a hang rather than a slow failure.
-///
-/// Serialising those tests behind a mutex would have hidden it. Threading the
-/// value removes the shared mutable cell instead, so the race has nothing to
-/// happen to: ONE place reads the environment ([`LockWait::from_env`], called
-/// once per real invocation), and every test passes the value it means.
-///
-/// The magic zero is a named variant too — `Unbounded` is a state you now have
-/// to ask for by name rather than encode as a `Duration` that happens to be 0.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum LockWait {
-    /// Give up after this long and fail typed, naming the holder.
-    Bounded(Duration),
-    /// Wait forever — the pre-2026-08-07 behaviour, kept reachable rather
-    /// than deleted (★★ MODULARIZE, DON'T DELETE) for an operator who knows
-    /// their peer is healthy and does not want to fight the bound.
-    Unbounded,
-}
-
-impl LockWait {
-    /// The fleet default, overridden by `FLEET_REBUILD_LOCK_TIMEOUT_SECS`.
-    /// The ONLY environment read on this path.
-    fn from_env() -> Self {
-        Self::parse(
-            std::env::var("FLEET_REBUILD_LOCK_TIMEOUT_SECS")
-                .ok()
-                .as_deref(),
-        )
-    }
-
-    /// `from_env`'s pure core — split out so the parsing rules are tested
-    /// without mutating a process-global, which is the very defect above.
-    fn parse(raw: Option<&str>) -> Self {
-        match raw.and_then(|v| v.trim().parse::<u64>().ok()) {
-            Some(0) => Self::Unbounded,
-            Some(secs) => Self::Bounded(Duration::from_secs(secs)),
-            // Absent OR unparseable: a typo must not silently become
-            // "wait forever". Fall back to the bounded default.
-            None => Self::Bounded(LOCK_WAIT_TIMEOUT),
-        }
-    }
-}
-
-/// How often a blocked rebuild re-reports who it is waiting on.
-const LOCK_WAIT_REPORT_EVERY: Duration = Duration::from_secs(60);
-
-/// Read the holder line the current owner stamped into the lock file.
-fn describe_holder(lock_path: &Path) -> String {
-    match fs::read_to_string(lock_path) {
-        Ok(h) if !h.trim().is_empty() => h.trim().to_owned(),
-        _ => "holder unknown".to_owned(),
-    }
-}
-
-/// The pid stamped into the lock by its current holder, if the line parses.
-///
-/// The stamp is `pid <N> · <user>` (written at the end of `acquire_lock_at`),
-/// so this reads the second whitespace-separated field. A file that does not
-/// parse is treated as having no pid — which routes to the stale branch,
-/// correctly: a lock nobody stamped is not a lock anyone is holding.
-fn holder_pid(lock_path: &Path) -> Option<u32> {
-    let text = fs::read_to_string(lock_path).ok()?;
-    let mut parts = text.split_whitespace();
-    if parts.next()? != "pid" {
-        return None;
-    }
-    parts.next()?.parse().ok()
-}
-
-/// Is that pid still around?
-///
-/// `kill(pid, 0)` is the portable liveness probe: it performs the permission
-/// checks and target lookup without delivering a signal. `EPERM` counts as
-/// ALIVE — the process exists, we simply may not signal it, which is exactly
-/// the root-holds-the-lock case this function is here to judge.
-fn pid_is_alive(pid: u32) -> bool {
-    // SAFETY: `kill` with signal 0 delivers nothing; it only reports whether
-    // the pid exists and is signallable.
-    let rc = unsafe { libc::kill(pid as libc::pid_t, 0) };
-    if rc == 0 {
-        return true;
-    }
-    std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
-}
-
-/// Remove a lock file we cannot unlink as ourselves.
-///
-/// `/tmp` is sticky, so only the owner (or root) may unlink. We escalate
-/// rather than fail, because the alternative is telling an operator to run
-/// the same command by hand — which is not a safety boundary, just a worse
-/// user experience with identical consequences.
-fn clear_stale_lock(lock_path: &Path) -> Result<()> {
-    if fs::remove_file(lock_path).is_ok() {
-        return Ok(());
-    }
-    let status = std::process::Command::new("sudo")
-        .arg("rm")
-        .arg("-f")
-        .arg(lock_path)
-        .status()
-        .with_context(|| format!("could not run sudo to clear {}", lock_path.display()))?;
-    anyhow::ensure!(
-        status.success(),
-        "could not clear the stale rebuild lock at {} — remove it by hand: sudo rm -f {}",
-        lock_path.display(),
-        lock_path.display()
-    );
-    Ok(())
-}
-
-/// Block for the lock, but **bounded, and never silently**.
-///
-/// ── ★ WHY THIS IS NOT `FileExt::lock_exclusive` ──
-/// It used to be, and the sibling comment above already named the defect
-/// it left open: *"a bare waiting... with no identity and no timeout is the
-/// thing that makes a blocked interactive rebuild feel hung."* That comment
-/// shipped with the identity half implemented and the timeout half not, so
-/// a blocked rebuild waited on `lock_exclusive` **forever**, printed one
-/// line, and never spoke again.
-///
-/// MEASURED on rio 2026-08-07, which is why this is phase 0b of
-/// `theory/BALIZA.md` and not a nicety: an operator rebuild and the
-/// `sentinela` reconciler tick collided; sentinela's tick sat **36 minutes
-/// against a 60-second poll** with its child at zero CPU. Anything that
-/// then reached for this lock would have blocked behind a wedged holder
-/// with no output, no deadline and no way to tell the two apart from the
-/// terminal. A hang must degrade into a **typed failure**
-/// (`theory/RECONCILER-LIVENESS.md` P1), and silence is the part that makes
-/// a hang expensive — the operator cannot act on what they cannot see.
-///
-/// So: poll, re-announce the holder every
-/// [`LOCK_WAIT_REPORT_EVERY`], and convert the deadline into an error that
-/// names who we waited on and how long.
-///
-/// TIER: only-mitigated. This bounds *our* wait; it does not bound the
-/// holder's work, and a holder that wedges still wedges — it just stops
-/// being invisible and stops being unbounded for everyone behind it.
-/// Bounding the holder is `despacho`'s job (`theory/DESPACHO.md`), where
-/// the ask carries a mandatory deadline of its own.
-/// `wait` is a VALUE, passed in. It used to be read from the environment right
-/// here, and that is what made the tests race: `std::env` is process-global
-/// while `cargo test` runs tests as THREADS in one process, so two tests
-/// setting the var interleaved — one wrote `"1"`, the other's `remove_var`
-/// landed between that write and this read, the read fell through to the
-/// two-hour default, and the suite blocked for the full `LOCK_WAIT_TIMEOUT`
-/// before failing its own 30s assertion. Observed 2026-08-16:
-/// `FAILED. 101 passed; 1 failed; finished in 7200.39s` — 7200 being exactly
-/// `2 * 60 * 60`. The worse arm reads `"0"` and waits UNBOUNDED: a hang, not a
-/// slow failure.
-///
-/// Serialising those tests behind a mutex would have hidden the race.
-/// Threading the value removes the shared mutable cell, so there is nothing
-/// left for a race to happen to.
-fn wait_for_lock(file: &File, lock_path: &Path, wait: LockWait) -> Result<()> {
-    // `Unbounded` is a NAMED state, not a `Duration` that happens to be zero —
-    // the pre-2026-08-07 behaviour, kept reachable rather than deleted
-    // (★★ MODULARIZE, DON'T DELETE) so an operator who knows their peer is
-    // healthy is not forced to fight the bound.
-    let timeout = match wait {
-        LockWait::Unbounded => {
-            return FileExt::lock_exclusive(file).context("failed to acquire rebuild lock");
-        }
-        LockWait::Bounded(d) => d,
-    };
-
-    let started = Instant::now();
-    let mut last_report = Instant::now();
-    loop {
-        if FileExt::try_lock_exclusive(file).is_ok() {
-            return Ok(());
-        }
-        let waited = started.elapsed();
-        if waited >= timeout {
-            anyhow::bail!(
-                "gave up waiting for the rebuild lock after {}s — held by {}.\n\
-                 \n\
-                 The holder is either doing legitimate long work (a cold build of \
-                 this fleet can exceed an hour) or it is wedged. Check it before \
-                 forcing anything:\n\
-                 \n    ps -o pid,etimes,stat,args -p <holder pid>\n\
-                 \n\
-                 A holder at zero CPU in state S with no build children is wedged; \
-                 kill it and re-run. To wait longer, set \
-                 FLEET_REBUILD_LOCK_TIMEOUT_SECS (0 waits forever).",
-                waited.as_secs(),
-                describe_holder(lock_path),
-            );
-        }
-        if last_report.elapsed() >= LOCK_WAIT_REPORT_EVERY {
-            log_info(&format!(
-                "still waiting for the rebuild lock ({}s elapsed, {}s left) — held by {}",
-                waited.as_secs(),
-                timeout.saturating_sub(waited).as_secs(),
-                describe_holder(lock_path),
-            ));
-            last_report = Instant::now();
-        }
-        std::thread::sleep(Duration::from_millis(500));
-    }
-}
-
 /// `acquire_rebuild_lock`'s path-parameterized core — split out so tests
 /// can exercise real flock contention against a throwaway path instead of
 /// the machine-wide [`REBUILD_LOCK_PATH`] every real invocation uses.
-/// `wait` threaded in rather than read here, for the same reason
-/// [`wait_for_lock`] takes it: tests must be able to choose a bound WITHOUT
-/// writing a process-global that their siblings are reading concurrently.
+/// The machinery itself lives in [`crate::lock`], shared with flow and node
+/// locks.
 fn acquire_lock_at(lock_path: &Path, wait: LockWait) -> Result<File> {
-    let file = OpenOptions::new()
-        .create(true)
-        .write(true)
-        // See the note on the retry below: truncation happens after the lock
-        // is held, never at open.
-        .truncate(false)
-        .open(lock_path)
-        .or_else(|e| {
-            // EACCES means the file EXISTS and belongs to another user with a
-            // mode that excludes us. The 0666 widening below cannot rescue it
-            // — that runs AFTER open — and /tmp is sticky, so a non-owner
-            // cannot unlink it either. Measured on ggg: a fresh account's
-            // `nix run .#rebuild` died here with nothing to do next.
-            //
-            // So REPAIR it, rather than instruct. This command already
-            // escalates for the rebuild itself; clearing a lock it owns the
-            // semantics of is squarely inside that authority.
-            //
-            // The safety test is the holder stamp, not a timeout: the file
-            // records `pid N · user`, so a DEAD pid means the writer is gone
-            // and the lock is debris. A LIVE pid is a real peer and is never
-            // touched — that is the difference between repairing a stale lock
-            // and yanking a running rebuild's.
-            if e.kind() != std::io::ErrorKind::PermissionDenied {
-                return Err(anyhow::Error::new(e).context(format!(
-                    "failed to open rebuild lock file at {}",
-                    lock_path.display()
-                )));
-            }
-            match holder_pid(lock_path) {
-                Some(pid) if pid_is_alive(pid) => Err(anyhow::anyhow!(
-                    "the rebuild lock at {p} is held by a LIVE rebuild ({h}), and its \
-                     mode excludes you.\n\
-                     Wait for it to finish, or if you are sure it is wrong:\n\
-                     \x20   sudo rm -f {p}",
-                    p = lock_path.display(),
-                    h = describe_holder(lock_path)
-                )),
-                _ => {
-                    log_info(&format!(
-                        "Stale rebuild lock at {} ({}) — its writer is gone; clearing it.",
-                        lock_path.display(),
-                        describe_holder(lock_path)
-                    ));
-                    clear_stale_lock(lock_path)?;
-                    OpenOptions::new()
-                        .create(true)
-                        .write(true)
-                        // Same reason as the first open: truncation happens
-                        // only once the lock is held.
-                        .truncate(false)
-                        .open(lock_path)
-                        .with_context(|| {
-                            format!(
-                                "failed to open rebuild lock file at {} even after \
-                                 clearing a stale one",
-                                lock_path.display()
-                            )
-                        })
-                }
-            }
-        })?;
-    // Cross-user reachability: whoever creates the file first owns it, and the
-    // other party still has to open it for WRITE to take an exclusive flock. A
-    // default 0644 would hand the first creator a permanent monopoly — root
-    // creates it, the operator's rebuild then dies on EACCES instead of
-    // waiting. Best-effort: a pre-existing file owned by the other user cannot
-    // be chmod'd by us, and that is fine — it is already 0666 from its own
-    // creation. Never fatal, because failing to widen a mode must not break a
-    // rebuild that would otherwise have proceeded.
-    let _ = fs::set_permissions(lock_path, fs::Permissions::from_mode(0o666));
-    if FileExt::try_lock_exclusive(&file).is_err() {
-        // Name the holder. A bare "waiting..." with no identity and no timeout
-        // is the thing that makes a blocked interactive rebuild feel hung —
-        // the operator cannot tell a live peer from a wedged one.
-        log_info(&format!(
-            "Another rebuild is already in progress ({}) — waiting for it to finish...",
-            describe_holder(lock_path)
-        ));
-        // The ONE environment read on this path, at the real entry point.
-        wait_for_lock(&file, lock_path, wait)?;
-    }
-    // Stamp our identity for the next waiter to read. Truncate first: the
-    // previous holder's line is stale the moment we own the lock.
-    let holder = format!(
-        "pid {} · {}",
-        std::process::id(),
-        std::env::var("USER").unwrap_or_else(|_| "unknown".to_owned())
-    );
-    let _ = file.set_len(0);
-    let _ = std::io::Write::write_all(&mut (&file), holder.as_bytes());
-    Ok(file)
+    let spec = LockSpec {
+        path: lock_path,
+        what: LockName::Rebuild.what(),
+        purpose: "fleet rebuild",
+        wait_hint: "set FLEET_REBUILD_LOCK_TIMEOUT_SECS",
+    };
+    lock::acquire(&spec, wait)
 }
 
 /// Walk up from `start` to find the directory containing `flake.nix`.
@@ -466,7 +169,7 @@ pub fn find_flake_root(start: &Path) -> Result<PathBuf> {
     }
 }
 
-fn get_hostname() -> Result<String> {
+pub fn get_hostname() -> Result<String> {
     run_command_output(Command::new("hostname").arg("-s")).context("Failed to get hostname")
 }
 
@@ -510,7 +213,7 @@ fn command_exists(name: &str) -> bool {
         .stdout(std::process::Stdio::null())
         .stderr(std::process::Stdio::null())
         .status()
-        .map_or(false, |s| s.success())
+        .is_ok_and(|s| s.success())
 }
 
 /// Resolve `sops`, building it from nixpkgs if the node does not carry it yet
@@ -1488,7 +1191,7 @@ fn darwin_rebuild(
     // about to deploy. Graceful: skipped when the flake has no `e2e-mado`
     // app (non-terminal fleet nodes) or via FLEET_SKIP_E2E_GATE=1 (break-
     // glass; the skip is loud either way).
-    if std::env::var("FLEET_SKIP_E2E_GATE").map_or(true, |v| v != "1") {
+    if !std::env::var("FLEET_SKIP_E2E_GATE").is_ok_and(|v| v == "1") {
         let system = if cfg!(target_arch = "aarch64") {
             "aarch64-darwin"
         } else {
@@ -1501,7 +1204,7 @@ fn darwin_rebuild(
             .stdout(std::process::Stdio::null())
             .stderr(std::process::Stdio::null())
             .status()
-            .map_or(false, |s| s.success());
+            .is_ok_and(|s| s.success());
         if has_app {
             // The matrix spawns a REAL shell in a REAL pty as its first row, so
             // an exhausted pty table fails it for a reason that has nothing to
@@ -1729,7 +1432,7 @@ mod rebuild_lock_tests {
     fn a_holder_stamp_parses_to_its_pid() {
         let p = fresh_lock_path("stamp");
         std::fs::write(&p, "pid 4242 \u{b7} gabi").expect("write");
-        assert_eq!(super::holder_pid(&p), Some(4242));
+        assert_eq!(lock::holder_pid(&p), Some(4242));
         let _ = std::fs::remove_file(&p);
     }
 
@@ -1739,17 +1442,17 @@ mod rebuild_lock_tests {
     fn an_unstamped_lock_has_no_pid() {
         let p = fresh_lock_path("unstamped");
         std::fs::write(&p, "").expect("write");
-        assert_eq!(super::holder_pid(&p), None);
+        assert_eq!(lock::holder_pid(&p), None);
         std::fs::write(&p, "garbage from an older format").expect("write");
-        assert_eq!(super::holder_pid(&p), None);
+        assert_eq!(lock::holder_pid(&p), None);
         let _ = std::fs::remove_file(&p);
     }
 
     #[test]
     fn our_own_pid_is_alive_and_an_absurd_one_is_not() {
-        assert!(super::pid_is_alive(std::process::id()));
+        assert!(lock::pid_is_alive(std::process::id()));
         // Above any plausible pid_max on Linux or macOS.
-        assert!(!super::pid_is_alive(0x7FFF_FFFF));
+        assert!(!lock::pid_is_alive(0x7FFF_FFFF));
     }
 
     /// THE safety property. A lock whose writer is STILL RUNNING must never
@@ -1758,7 +1461,7 @@ mod rebuild_lock_tests {
     #[test]
     fn a_live_holder_is_never_treated_as_stale() {
         assert!(
-            super::pid_is_alive(std::process::id()),
+            lock::pid_is_alive(std::process::id()),
             "this test's own pid must read alive, or the guard proves nothing"
         );
     }
@@ -1769,7 +1472,7 @@ mod rebuild_lock_tests {
         let lock = fresh_lock_path("stale");
         // A dead pid: the file is debris from a process that is gone.
         std::fs::write(&lock, "pid 2147483647 \u{b7} root").expect("seed");
-        super::clear_stale_lock(&lock).expect("clear");
+        lock::clear_stale_lock(&lock).expect("clear");
         assert!(!lock.exists(), "the stale lock is gone");
         // And the normal path then works.
         let f = super::acquire_lock_at(&lock, LockWait::Bounded(LOCK_WAIT_TIMEOUT))
@@ -1780,7 +1483,7 @@ mod rebuild_lock_tests {
     use super::*;
     use std::sync::mpsc;
     use std::thread;
-    use std::time::Duration;
+    use std::time::{Duration, Instant};
 
     fn fresh_lock_path(name: &str) -> PathBuf {
         std::env::temp_dir().join(format!(
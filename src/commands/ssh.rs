@@ -1,21 +1,202 @@
 use anyhow::{bail, Result};
-use std::os::unix::process::CommandExt;
+use std::io::{self, IsTerminal, Write};
 
 use super::utils::*;
 use crate::config::FleetConfig;
 use crate::targeting::ResolvedTargets;
+use crate::transport::{shell_join, Target, Transport};
 
-pub fn run(targets: &ResolvedTargets, config: &FleetConfig) -> Result<()> {
-    if !targets.is_single() {
-        bail!("ssh requires exactly one target node");
+/// `fleet ssh`: one node gets a session of its own; several get a tmux
+/// window with a pane each, or — without tmux — a menu to pick one from.
+/// `command`, if any, runs in place of a login shell, with a terminal.
+pub fn run(
+    targets: &ResolvedTargets,
+    command: Option<&str>,
+    sync: bool,
+    config: &FleetConfig,
+    transport: &dyn Transport,
+) -> Result<()> {
+    if targets.is_single() {
+        let (name, node) = &targets.nodes[0];
+        log_info(&format!("Connecting to {} ({})", name, node.hostname));
+        // Only returns on error
+        return Err(transport.interactive(&Target::node(config, name, node), command));
     }
 
-    let (name, node) = &targets.nodes[0];
+    if has_tmux(transport) {
+        return tmux(targets, command, sync, config, transport);
+    }
+    if sync {
+        log_warning("tmux not found; --sync needs it");
+    }
+    let (name, node) = pick(targets)?;
     log_info(&format!("Connecting to {} ({})", name, node.hostname));
+    Err(transport.interactive(&Target::node(config, name, node), command))
+}
+
+fn has_tmux(transport: &dyn Transport) -> bool {
+    transport.local("tmux", &["-V"]).is_ok_and(|o| o.success())
+}
+
+/// A tmux session with one pane per node, each titled with its node, then
+/// attach to it — or switch to it, from inside tmux already.
+fn tmux(
+    targets: &ResolvedTargets,
+    command: Option<&str>,
+    sync: bool,
+    config: &FleetConfig,
+    transport: &dyn Transport,
+) -> Result<()> {
+    let session = format!("fleet-{}", std::process::id());
+    // By name and pane id: indexes depend on the user's base-index settings
+    let window = format!("{}:fleet", session);
+    let tmux = |args: &[&str]| -> Result<String> {
+        let output = transport.local("tmux", args)?;
+        if !output.success() {
+            bail!("tmux {} failed: {}", args[0], output.stderr.trim());
+        }
+        Ok(output.stdout.trim().to_string())
+    };
 
-    let ssh = config.resolve_ssh(name);
-    let err = ssh_cmd_with_config(&node.ssh_user, &node.hostname, &ssh).exec();
+    let login = |name: &str, node| {
+        shell_join(&transport.login_command(&Target::node(config, name, node), command))
+    };
+    let mut nodes = targets.nodes.iter();
+    let Some((first, node)) = nodes.next() else {
+        bail!("no nodes to open");
+    };
+    let first_login = login(first, node);
+    let mut args = vec![
+        "new-session",
+        "-d",
+        "-P",
+        "-F",
+        "#{pane_id}",
+        "-s",
+        &session,
+        "-n",
+        "fleet",
+        &first_login,
+    ];
+    // Options go in the same tmux invocation, before a fast-failing
+    // first pane can take the session down with it
+    args.extend([
+        ";",
+        "set-option",
+        "-t",
+        &session,
+        "pane-border-status",
+        "top",
+    ]);
+    if command.is_some() {
+        // Keep each node's output on screen after its command exits
+        args.extend([";", "set-option", "-t", &session, "remain-on-exit", "on"]);
+    }
+    let first_pane = tmux(&args)?;
+
+    let fill = || -> Result<()> {
+        tmux(&["select-pane", "-t", &first_pane, "-T", first])?;
+        for (name, node) in nodes {
+            let pane = tmux(&[
+                "split-window",
+                "-P",
+                "-F",
+                "#{pane_id}",
+                "-t",
+                &window,
+                &login(name, node),
+            ])?;
+            // Re-tile as we go, so no split runs out of room
+            tmux(&["select-layout", "-t", &window, "tiled"])?;
+            tmux(&["select-pane", "-t", &pane, "-T", name])?;
+        }
+        if sync {
+            tmux(&[
+                "set-window-option",
+                "-t",
+                &window,
+                "synchronize-panes",
+                "on",
+            ])?;
+        }
+        Ok(())
+    };
+    if let Err(e) = fill() {
+        // Not left running detached, its ssh sessions open, for nobody
+        let _ = transport.local("tmux", &["kill-session", "-t", &session]);
+        return Err(e);
+    }
+
+    log_info(&format!(
+        "Opened {} panes in tmux session {}{}",
+        targets.nodes.len(),
+        session,
+        if sync { " (input synchronized)" } else { "" }
+    ));
+    let inside_tmux = std::env::var_os("TMUX").is_some_and(|v| !v.is_empty());
+    let err = if inside_tmux {
+        transport.hand_over("tmux", &["switch-client", "-t", &session])
+    } else {
+        transport.hand_over("tmux", &["attach-session", "-t", &session])
+    };
+    Err(err)
+}
 
-    // exec() only returns on error
-    bail!("Failed to exec ssh: {}", err)
+/// Ask which of several nodes to connect to.
+fn pick(targets: &ResolvedTargets) -> Result<&(String, crate::registry::Node)> {
+    if !io::stdin().is_terminal() {
+        bail!(
+            "{} nodes match and tmux is not available; name one of: {}",
+            targets.nodes.len(),
+            targets.names().join(", ")
+        );
+    }
+    for (i, (name, node)) in targets.nodes.iter().enumerate() {
+        println!("  {:>2}) {} {}", i + 1, node_label(name), node.hostname);
+    }
+    print!("Connect to [1-{}]: ", targets.nodes.len());
+    io::stdout().flush()?;
+    let mut input = String::new();
+    io::stdin().read_line(&mut input)?;
+    match choice(&input, targets) {
+        Some(i) => Ok(&targets.nodes[i]),
+        None => bail!("No node chosen"),
+    }
+}
+
+/// The node an answer to the menu means: its number or its name.
+fn choice(input: &str, targets: &ResolvedTargets) -> Option<usize> {
+    let input = input.trim();
+    match input.parse::<usize>() {
+        Ok(n) => (1..=targets.nodes.len()).contains(&n).then(|| n - 1),
+        Err(_) => targets.nodes.iter().position(|(name, _)| name == input),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::registry::Node;
+
+    #[test]
+    fn menu_answers_are_a_number_or_a_name() {
+        let node = Node {
+            system: "x86_64-linux".to_string(),
+            hostname: "10.0.0.1".to_string(),
+            ssh_user: "root".to_string(),
+            tags: vec![],
+            host_key: None,
+        };
+        let targets = ResolvedTargets {
+            nodes: vec![
+                ("web1".to_string(), node.clone()),
+                ("web2".to_string(), node),
+            ],
+        };
+        assert_eq!(choice("2\n", &targets), Some(1));
+        assert_eq!(choice(" web1 ", &targets), Some(0));
+        assert_eq!(choice("3", &targets), None);
+        assert_eq!(choice("0", &targets), None);
+        assert_eq!(choice("", &targets), None);
+    }
 }
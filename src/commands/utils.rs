@@ -182,30 +182,6 @@ pub fn run_command_output(cmd: &mut Command) -> Result<String> {
     Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
 }
 
-pub fn ssh_cmd_with_config(user: &str, host: &str, ssh: &crate::config::ResolvedSsh) -> Command {
-    let mut cmd = Command::new("ssh");
-    cmd.arg("-o")
-        .arg(format!("ConnectTimeout={}", ssh.connect_timeout));
-    cmd.arg("-o")
-        .arg(format!("StrictHostKeyChecking={}", ssh.strict_host_key));
-    for (k, v) in &ssh.options {
-        cmd.arg("-o").arg(format!("{}={}", k, v));
-    }
-    cmd.arg(format!("{}@{}", user, host));
-    cmd
-}
-
-pub fn ssh_run_with_config(
-    user: &str,
-    host: &str,
-    ssh: &crate::config::ResolvedSsh,
-    remote_cmd: &str,
-) -> Result<String> {
-    let mut cmd = ssh_cmd_with_config(user, host, ssh);
-    cmd.arg(remote_cmd);
-    run_command_output(&mut cmd)
-}
-
 pub fn node_label(name: &str) -> String {
     format!("[{}]", name).cyan().bold().to_string()
 }
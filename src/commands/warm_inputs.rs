@@ -31,10 +31,12 @@ use std::path::Path;
 use std::process::Command;
 
 use super::utils::{log_info, log_success, log_warning};
+use crate::config::FleetConfig;
 use crate::fetch_recovery::{
     classify_fetch_failure, parse_build_machines, parse_prefetch_json, pinned_nar_hash,
     FetchFailure, RecoveryPlan, WarmOutcome,
 };
+use crate::transport::{self, OpenSsh, Target, Transport};
 
 /// How many recover-then-retry rounds to run before giving up.
 ///
@@ -210,16 +212,12 @@ fn attempt(
 
 /// Run the prefetch on the builder over ssh, returning its parsed result.
 fn prefetch_on(plan: &RecoveryPlan) -> Option<crate::fetch_recovery::PrefetchResult> {
-    let argv = plan.prefetch_argv();
-    let out = Command::new("ssh")
-        .arg("-o")
-        .arg("BatchMode=yes")
-        .arg(&plan.builder)
-        .arg(argv.join(" "))
-        .output()
-        .ok()?;
-    if !out.status.success() {
+    // Quoted: a flake ref's `?ref=…&rev=…` means something to a shell
+    let command = transport::shell_join(&plan.prefetch_argv());
+    let builder = Target::host(&FleetConfig::default(), &plan.builder);
+    let out = OpenSsh::default().run(&builder, &command).ok()?;
+    if !out.success() {
         return None;
     }
-    parse_prefetch_json(&String::from_utf8_lossy(&out.stdout))
+    parse_prefetch_json(&out.stdout)
 }
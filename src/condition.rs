@@ -0,0 +1,522 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::flow::{self, Segment, TemplateRef};
+
+/// How a step ended, as seen by the conditions of later steps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StepStatus {
+    Succeeded,
+    Failed,
+    /// Its condition was not met.
+    Skipped,
+}
+
+/// A parsed declarative condition, e.g.
+/// `failed(build) || (${diff.kernel_changed} == true && target_count(@k3s) >= 2)`.
+///
+/// Grammar, loosest binding first:
+///
+/// ```text
+/// expr    := and ( "||" and )*
+/// and     := unary ( "&&" unary )*
+/// unary   := "!" unary | "(" expr ")" | status | operand ( cmp operand )?
+/// status  := ( "succeeded" | "failed" | "skipped" ) "(" step_id ")"
+/// operand := "${...}" | "target_count(" selector? ")" | 'text' | "text" | word
+/// cmp     := "==" | "!=" | "<" | "<=" | ">" | ">="
+/// ```
+///
+/// An operand on its own must be `true` or `false`. `<`, `<=`, `>` and `>=`
+/// compare numbers; `==` and `!=` compare numbers numerically and anything
+/// else as text.
+#[derive(Debug, PartialEq)]
+pub enum Expr<'a> {
+    Not(Box<Expr<'a>>),
+    And(Box<Expr<'a>>, Box<Expr<'a>>),
+    Or(Box<Expr<'a>>, Box<Expr<'a>>),
+    Status(StepStatus, &'a str),
+    Compare(Operand<'a>, CmpOp, Operand<'a>),
+    Truthy(Operand<'a>),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Operand<'a> {
+    /// A `${...}` template reference.
+    Ref(TemplateRef<'a>),
+    /// `target_count()` for the step's own targets, or
+    /// `target_count(<selector>)` for a node name or `@tag`.
+    TargetCount(Option<&'a str>),
+    Literal(&'a str),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+/// What a condition can observe. Each answer may be `None` — not known
+/// yet — which is how `--dry-run` evaluates a condition before anything has
+/// run: params and target counts are settled at plan time, step outputs and
+/// statuses are not.
+pub trait Facts {
+    fn value(&self, r: &TemplateRef) -> Result<Option<String>>;
+    fn status(&self, step: &str) -> Option<StepStatus>;
+    fn target_count(&self, selector: Option<&str>) -> Result<Option<usize>>;
+}
+
+impl<'a> Expr<'a> {
+    pub fn parse(src: &'a str) -> Result<Self> {
+        let tokens = tokenize(src)?;
+        let mut parser = Parser {
+            tokens,
+            pos: 0,
+            len: src.len(),
+        };
+        let expr = parser.or()?;
+        if let Some(&(_, at)) = parser.tokens.get(parser.pos) {
+            bail!("unexpected `{}` at column {}", &src[at..], at + 1);
+        }
+        Ok(expr)
+    }
+
+    /// Evaluate with three-valued logic: `None` when the answer depends on
+    /// something not known yet. `&&` and `||` still decide when one side
+    /// settles the result on its own.
+    pub fn eval(&self, facts: &dyn Facts) -> Result<Option<bool>> {
+        Ok(match self {
+            Expr::Not(inner) => inner.eval(facts)?.map(|b| !b),
+            Expr::And(l, r) => match (l.eval(facts)?, r.eval(facts)?) {
+                (Some(false), _) | (_, Some(false)) => Some(false),
+                (Some(true), Some(true)) => Some(true),
+                _ => None,
+            },
+            Expr::Or(l, r) => match (l.eval(facts)?, r.eval(facts)?) {
+                (Some(true), _) | (_, Some(true)) => Some(true),
+                (Some(false), Some(false)) => Some(false),
+                _ => None,
+            },
+            Expr::Status(want, step) => facts.status(step).map(|s| s == *want),
+            Expr::Compare(l, op, r) => match (l.eval(facts)?, r.eval(facts)?) {
+                (Some(l), Some(r)) => Some(compare(&l, *op, &r)?),
+                _ => None,
+            },
+            Expr::Truthy(operand) => match operand.eval(facts)? {
+                Some(v) if v == "true" => Some(true),
+                Some(v) if v == "false" => Some(false),
+                Some(v) => bail!(
+                    "'{}' is neither true nor false — compare it explicitly (e.g. `== yes`)",
+                    v
+                ),
+                None => None,
+            },
+        })
+    }
+
+    /// Every `${...}` reference in the condition, for validation.
+    pub fn references(&self) -> Vec<&TemplateRef<'a>> {
+        let mut out = Vec::new();
+        self.walk(&mut |e| match e {
+            Expr::Compare(l, _, r) => {
+                for operand in [l, r] {
+                    if let Operand::Ref(r) = operand {
+                        out.push(r);
+                    }
+                }
+            }
+            Expr::Truthy(Operand::Ref(r)) => out.push(r),
+            _ => {}
+        });
+        out
+    }
+
+    /// Every step whose status the condition reads, for validation.
+    pub fn status_steps(&self) -> Vec<&'a str> {
+        let mut out = Vec::new();
+        self.walk(&mut |e| {
+            if let Expr::Status(_, step) = e {
+                out.push(*step);
+            }
+        });
+        out
+    }
+
+    fn walk<'s>(&'s self, f: &mut impl FnMut(&'s Expr<'a>)) {
+        f(self);
+        match self {
+            Expr::Not(inner) => inner.walk(f),
+            Expr::And(l, r) | Expr::Or(l, r) => {
+                l.walk(f);
+                r.walk(f);
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Operand<'_> {
+    fn eval(&self, facts: &dyn Facts) -> Result<Option<String>> {
+        match self {
+            Operand::Ref(r) => facts.value(r),
+            Operand::TargetCount(selector) => {
+                Ok(facts.target_count(*selector)?.map(|n| n.to_string()))
+            }
+            Operand::Literal(text) => Ok(Some(text.to_string())),
+        }
+    }
+}
+
+fn compare(l: &str, op: CmpOp, r: &str) -> Result<bool> {
+    if let (Ok(l), Ok(r)) = (l.parse::<f64>(), r.parse::<f64>()) {
+        return Ok(match op {
+            CmpOp::Eq => l == r,
+            CmpOp::Ne => l != r,
+            CmpOp::Lt => l < r,
+            CmpOp::Le => l <= r,
+            CmpOp::Gt => l > r,
+            CmpOp::Ge => l >= r,
+        });
+    }
+    match op {
+        CmpOp::Eq => Ok(l == r),
+        CmpOp::Ne => Ok(l != r),
+        _ => bail!(
+            "cannot order '{}' and '{}' — `<`, `>` compare numbers",
+            l,
+            r
+        ),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Token<'a> {
+    LParen,
+    RParen,
+    And,
+    Or,
+    Not,
+    Cmp(CmpOp),
+    /// A whole `${...}`, braces included.
+    Ref(&'a str),
+    Quoted(&'a str),
+    Word(&'a str),
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || "_-.@/:[]".contains(c)
+}
+
+fn tokenize(src: &str) -> Result<Vec<(Token<'_>, usize)>> {
+    let mut tokens = Vec::new();
+    let mut chars = src.char_indices().peekable();
+
+    while let Some(&(at, c)) = chars.peek() {
+        let rest = &src[at..];
+        let (token, len) = if c.is_whitespace() {
+            chars.next();
+            continue;
+        } else if rest.starts_with("${") {
+            let Some(end) = rest.find('}') else {
+                bail!("unterminated `${{` at column {}", at + 1);
+            };
+            (Token::Ref(&rest[..=end]), end + 1)
+        } else if c == '\'' || c == '"' {
+            let Some(end) = rest[1..].find(c) else {
+                bail!("unterminated string at column {}", at + 1);
+            };
+            (Token::Quoted(&rest[1..=end]), end + 2)
+        } else {
+            let word_len = rest.find(|c| !is_word_char(c)).unwrap_or(rest.len());
+            if word_len > 0 {
+                (Token::Word(&rest[..word_len]), word_len)
+            } else {
+                let two = rest.get(..2).unwrap_or(rest);
+                match two {
+                    "&&" => (Token::And, 2),
+                    "||" => (Token::Or, 2),
+                    "==" => (Token::Cmp(CmpOp::Eq), 2),
+                    "!=" => (Token::Cmp(CmpOp::Ne), 2),
+                    "<=" => (Token::Cmp(CmpOp::Le), 2),
+                    ">=" => (Token::Cmp(CmpOp::Ge), 2),
+                    _ => match c {
+                        '(' => (Token::LParen, 1),
+                        ')' => (Token::RParen, 1),
+                        '!' => (Token::Not, 1),
+                        '<' => (Token::Cmp(CmpOp::Lt), 1),
+                        '>' => (Token::Cmp(CmpOp::Gt), 1),
+                        _ => bail!("unexpected `{}` at column {}", c, at + 1),
+                    },
+                }
+            }
+        };
+        tokens.push((token, at));
+        while chars.peek().is_some_and(|&(i, _)| i < at + len) {
+            chars.next();
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<(Token<'a>, usize)>,
+    pos: usize,
+    /// Source length, for "at end" positions.
+    len: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<Token<'a>> {
+        self.tokens.get(self.pos).map(|&(t, _)| t)
+    }
+
+    fn column(&self) -> usize {
+        self.tokens.get(self.pos).map_or(self.len, |&(_, at)| at) + 1
+    }
+
+    fn expect(&mut self, want: Token<'a>, what: &str) -> Result<()> {
+        if self.peek() != Some(want) {
+            bail!("expected {} at column {}", what, self.column());
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn or(&mut self) -> Result<Expr<'a>> {
+        let mut lhs = self.and()?;
+        while self.peek() == Some(Token::Or) {
+            self.pos += 1;
+            lhs = Expr::Or(Box::new(lhs), Box::new(self.and()?));
+        }
+        Ok(lhs)
+    }
+
+    fn and(&mut self) -> Result<Expr<'a>> {
+        let mut lhs = self.unary()?;
+        while self.peek() == Some(Token::And) {
+            self.pos += 1;
+            lhs = Expr::And(Box::new(lhs), Box::new(self.unary()?));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr<'a>> {
+        match self.peek() {
+            Some(Token::Not) => {
+                self.pos += 1;
+                Ok(Expr::Not(Box::new(self.unary()?)))
+            }
+            Some(Token::LParen) => {
+                self.pos += 1;
+                let inner = self.or()?;
+                self.expect(Token::RParen, "`)`")?;
+                Ok(inner)
+            }
+            Some(Token::Word(name))
+                if self.tokens.get(self.pos + 1).map(|t| t.0) == Some(Token::LParen) =>
+            {
+                let status = match name {
+                    "succeeded" => StepStatus::Succeeded,
+                    "failed" => StepStatus::Failed,
+                    "skipped" => StepStatus::Skipped,
+                    _ => return self.comparison(),
+                };
+                self.pos += 2;
+                let Some(Token::Word(step)) = self.peek() else {
+                    bail!(
+                        "expected a step ID in `{}(...)` at column {}",
+                        name,
+                        self.column()
+                    );
+                };
+                self.pos += 1;
+                self.expect(Token::RParen, "`)`")?;
+                Ok(Expr::Status(status, step))
+            }
+            _ => self.comparison(),
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Expr<'a>> {
+        let lhs = self.operand()?;
+        let Some(Token::Cmp(op)) = self.peek() else {
+            return Ok(Expr::Truthy(lhs));
+        };
+        self.pos += 1;
+        Ok(Expr::Compare(lhs, op, self.operand()?))
+    }
+
+    fn operand(&mut self) -> Result<Operand<'a>> {
+        let column = self.column();
+        let operand = match self.peek() {
+            Some(Token::Ref(text)) => {
+                let mut segments = flow::parse_template(text, true)?;
+                match segments.pop() {
+                    Some(Segment::Ref(r)) if segments.is_empty() => Operand::Ref(r),
+                    _ => bail!("malformed reference `{}` at column {}", text, column),
+                }
+            }
+            Some(Token::Word("target_count"))
+                if self.tokens.get(self.pos + 1).map(|t| t.0) == Some(Token::LParen) =>
+            {
+                self.pos += 2;
+                let selector = match self.peek() {
+                    Some(Token::Word(selector)) => {
+                        self.pos += 1;
+                        Some(selector)
+                    }
+                    _ => None,
+                };
+                self.expect(Token::RParen, "`)`")?;
+                return Ok(Operand::TargetCount(selector));
+            }
+            Some(Token::Word(name))
+                if self.tokens.get(self.pos + 1).map(|t| t.0) == Some(Token::LParen) =>
+            {
+                bail!(
+                    "unknown function `{}` at column {} (known: succeeded, failed, skipped, target_count)",
+                    name,
+                    column
+                )
+            }
+            Some(Token::Quoted(text)) | Some(Token::Word(text)) => Operand::Literal(text),
+            _ => bail!("expected a value at column {}", column),
+        };
+        self.pos += 1;
+        Ok(operand)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flow::Reference;
+    use std::collections::HashMap;
+
+    /// Outputs and statuses by name; anything absent is "not known yet".
+    #[derive(Default)]
+    struct Known {
+        values: HashMap<&'static str, &'static str>,
+        statuses: HashMap<&'static str, StepStatus>,
+        targets: Option<usize>,
+    }
+
+    impl Facts for Known {
+        fn value(&self, r: &TemplateRef) -> Result<Option<String>> {
+            let key = match r.reference {
+                Reference::Param(name) | Reference::Secret(name) => name,
+                Reference::Output { .. } | Reference::Failure(_) | Reference::Other(_) => r.raw,
+            };
+            Ok(self.values.get(key).map(|v| v.to_string()))
+        }
+        fn status(&self, step: &str) -> Option<StepStatus> {
+            self.statuses.get(step).copied()
+        }
+        fn target_count(&self, _selector: Option<&str>) -> Result<Option<usize>> {
+            Ok(self.targets)
+        }
+    }
+
+    fn eval(src: &str, facts: &Known) -> Option<bool> {
+        Expr::parse(src).unwrap().eval(facts).unwrap()
+    }
+
+    #[test]
+    fn precedence_and_grouping() {
+        let expr = Expr::parse("!failed(a) && b == 1 || (c)").unwrap();
+        let Expr::Or(lhs, rhs) = expr else {
+            panic!("`||` should bind loosest");
+        };
+        assert!(matches!(*lhs, Expr::And(ref not, _) if matches!(**not, Expr::Not(_))));
+        assert_eq!(*rhs, Expr::Truthy(Operand::Literal("c")));
+    }
+
+    #[test]
+    fn comparisons_on_outputs_and_params() {
+        let facts = Known {
+            values: HashMap::from([
+                ("diff.kernel_changed", "true"),
+                ("env", "production"),
+                ("build.size", "10"),
+            ]),
+            ..Known::default()
+        };
+        assert_eq!(eval("${diff.kernel_changed} == true", &facts), Some(true));
+        assert_eq!(eval("${params.env} != 'production'", &facts), Some(false));
+        assert_eq!(eval("${build.size} > 9.5", &facts), Some(true));
+        assert_eq!(eval("${build.size} == 10.0", &facts), Some(true));
+        assert_eq!(eval("${diff.kernel_changed}", &facts), Some(true));
+    }
+
+    #[test]
+    fn status_functions() {
+        let facts = Known {
+            statuses: HashMap::from([("build", StepStatus::Failed), ("lint", StepStatus::Skipped)]),
+            ..Known::default()
+        };
+        assert_eq!(eval("failed(build)", &facts), Some(true));
+        assert_eq!(
+            eval("succeeded(build) || skipped(lint)", &facts),
+            Some(true)
+        );
+        assert_eq!(eval("failed(lint)", &facts), Some(false));
+    }
+
+    #[test]
+    fn unknowns_propagate_unless_decided() {
+        let facts = Known {
+            values: HashMap::from([("force", "true")]),
+            targets: Some(3),
+            ..Known::default()
+        };
+        assert_eq!(eval("${diff.changed} == true", &facts), None);
+        assert_eq!(eval("${params.force} || failed(build)", &facts), Some(true));
+        assert_eq!(
+            eval("!${params.force} && failed(build)", &facts),
+            Some(false)
+        );
+        assert_eq!(eval("${params.force} && failed(build)", &facts), None);
+        assert_eq!(eval("target_count(@k3s) >= 3", &facts), Some(true));
+    }
+
+    #[test]
+    fn evaluation_errors() {
+        let facts = Known {
+            values: HashMap::from([("env", "production")]),
+            ..Known::default()
+        };
+        let err = |src| {
+            Expr::parse(src)
+                .unwrap()
+                .eval(&facts)
+                .unwrap_err()
+                .to_string()
+        };
+        assert!(err("${params.env}").contains("neither true nor false"));
+        assert!(err("${params.env} < 3").contains("compare numbers"));
+    }
+
+    #[test]
+    fn parse_errors_name_the_column() {
+        let err = |src| Expr::parse(src).unwrap_err().to_string();
+        assert!(err("failed(build").contains("expected `)` at column 13"));
+        assert!(err("a == ").contains("expected a value at column 6"));
+        assert!(err("a b").contains("unexpected `b` at column 3"));
+        assert!(err("count(x) > 1").contains("unknown function `count`"));
+        assert!(err("${oops").contains("unterminated"));
+        assert!(err("a & b").contains("unexpected `&`"));
+    }
+
+    #[test]
+    fn collects_references_and_status_steps() {
+        let expr =
+            Expr::parse("failed(a) || (${b.x} == 1 && !skipped(c[web1]) && ${params.p})").unwrap();
+        assert_eq!(expr.status_steps(), ["a", "c[web1]"]);
+        let raws: Vec<_> = expr.references().iter().map(|r| r.raw).collect();
+        assert_eq!(raws, ["b.x", "params.p"]);
+    }
+}
@@ -1,7 +1,10 @@
-use anyhow::Result;
+use anyhow::{bail, Result};
 use serde::Deserialize;
-use std::collections::HashMap;
-use std::path::Path;
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use crate::config_errors::{self, Step};
+use crate::include::{self, Origin};
 
 /// pitr-forge subcommand type.
 #[derive(Debug, Deserialize, Clone)]
@@ -34,23 +37,56 @@ pub struct StepResult {
 }
 
 #[derive(Debug, Default, Deserialize)]
-#[serde(default)]
+#[serde(default, deny_unknown_fields)]
 pub struct FleetConfig {
     pub ssh: SshConfig,
     pub deploy: DeployConfig,
     pub nodes: HashMap<String, NodeOverride>,
+    /// Overrides for every node carrying a tag, below `nodes`.
+    pub tags: HashMap<String, NodeOverride>,
     pub hooks: HashMap<String, HookPair>,
     pub flows: HashMap<String, FlowDef>,
     pub secrets: HashMap<String, SecretDef>,
+    pub runs: RunsConfig,
+    /// Named overlays, one of which `--profile`/`FLEET_PROFILE` selects.
+    pub profiles: HashMap<String, ProfileDef>,
+    /// The active profile. Set by `FleetConfig::load`.
+    #[serde(skip)]
+    pub profile: Option<String>,
     /// Directory containing fleet.yaml. Populated by `FleetConfig::load`,
     /// used as the base for resolving relative SOPS file paths declared
     /// on flows. Skipped at deserialization.
     #[serde(skip)]
     pub config_dir: std::path::PathBuf,
+    /// Where each top-level entry was defined, keyed `<section>.<key>`.
+    /// Populated by `FleetConfig::load`, which follows `include:`.
+    #[serde(skip)]
+    pub origins: BTreeMap<String, Origin>,
+}
+
+/// Retention for flow run records (`fleet flow runs`), applied after
+/// every run.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RunsConfig {
+    /// Records kept per flow, newest first
+    pub keep: usize,
+    /// Records older than this are deleted regardless of `keep`
+    pub max_age_days: Option<u64>,
+}
+
+impl Default for RunsConfig {
+    fn default() -> Self {
+        Self {
+            keep: 50,
+            max_age_days: None,
+        }
+    }
 }
 
 /// A secret that can be provisioned from an external provider before commands run.
 #[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct SecretDef {
     /// Provider type (currently only "onepassword" is supported)
     pub provider: String,
@@ -71,7 +107,7 @@ fn default_mode() -> String {
 }
 
 #[derive(Debug, Deserialize)]
-#[serde(default)]
+#[serde(default, deny_unknown_fields)]
 pub struct SshConfig {
     pub connect_timeout: u32,
     pub strict_host_key: String,
@@ -89,7 +125,7 @@ impl Default for SshConfig {
 }
 
 #[derive(Debug, Deserialize)]
-#[serde(default)]
+#[serde(default, deny_unknown_fields)]
 pub struct DeployConfig {
     pub show_trace: bool,
     pub magic_rollback: bool,
@@ -105,14 +141,14 @@ impl Default for DeployConfig {
 }
 
 #[derive(Debug, Default, Deserialize)]
-#[serde(default)]
+#[serde(default, deny_unknown_fields)]
 pub struct NodeOverride {
     pub ssh: SshOverride,
     pub deploy: DeployOverride,
 }
 
 #[derive(Debug, Default, Deserialize)]
-#[serde(default)]
+#[serde(default, deny_unknown_fields)]
 pub struct SshOverride {
     pub connect_timeout: Option<u32>,
     pub strict_host_key: Option<String>,
@@ -120,20 +156,59 @@ pub struct SshOverride {
 }
 
 #[derive(Debug, Default, Deserialize)]
-#[serde(default)]
+#[serde(default, deny_unknown_fields)]
 pub struct DeployOverride {
     pub show_trace: Option<bool>,
     pub magic_rollback: Option<bool>,
 }
 
+/// A profile: settings laid over the global ones while it is active. Each
+/// takes precedence over the setting it names and leaves the rest alone.
 #[derive(Debug, Default, Deserialize)]
-#[serde(default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProfileDef {
+    pub ssh: SshOverride,
+    pub deploy: DeployOverride,
+    pub tags: HashMap<String, NodeOverride>,
+    pub nodes: HashMap<String, NodeOverride>,
+    pub hooks: HashMap<String, HookPair>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct HookPair {
     pub pre: Option<String>,
     pub post: Option<String>,
+    /// Runs once before the whole operation, rather than once per node.
+    pub pre_all: Option<String>,
+    /// Runs once after the whole operation, whether it succeeded or not.
+    pub post_all: Option<String>,
+    /// Runs on each node itself, over SSH, after `pre`.
+    pub remote_pre: Option<String>,
+    /// Runs on each node itself, over SSH, whatever the outcome, before
+    /// `post` or `on_failure`.
+    pub remote_post: Option<String>,
+    /// Runs instead of `post` when a pre-hook or the operation failed.
+    pub on_failure: Option<String>,
+    /// Seconds each of these scripts may run before it is killed.
+    pub timeout: Option<u64>,
 }
 
-#[derive(Debug, Deserialize)]
+impl HookPair {
+    /// Whether there is no script to run.
+    pub fn is_empty(&self) -> bool {
+        self.pre.is_none()
+            && self.post.is_none()
+            && self.pre_all.is_none()
+            && self.post_all.is_none()
+            && self.remote_pre.is_none()
+            && self.remote_post.is_none()
+            && self.on_failure.is_none()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct FlowDef {
     #[serde(default)]
     pub description: String,
@@ -142,14 +217,85 @@ pub struct FlowDef {
     /// Currently only `source: sops` is supported.
     #[serde(default)]
     pub secrets: HashMap<String, FlowSecret>,
+    /// Fail a step when a `${...}` reference in its `env:` block cannot be
+    /// resolved, instead of substituting an empty string. On by default;
+    /// `${ref:-default}` is the per-reference way to make a value optional.
+    #[serde(default = "default_strict_templates")]
+    pub strict_templates: bool,
+    /// What a failed step does to the steps that have not run yet.
+    #[serde(default)]
+    pub failure_mode: FailureMode,
+    /// Parameters the flow accepts, referenced as `${params.<name>}`.
+    /// Supplied with `fleet flow run --param name=value`, or by the
+    /// `params:` of a `type: flow` step in a parent flow.
+    #[serde(default)]
+    pub params: HashMap<String, FlowParam>,
     pub steps: Vec<StepDef>,
+    /// Cleanup steps run, in order, only when a main step failed. They run
+    /// before `finally` and can read the failure as `${failure.<field>}`.
+    #[serde(default)]
+    pub on_failure: Vec<StepDef>,
+    /// Cleanup steps always run, in order, once the main steps are done —
+    /// whether they succeeded or not.
+    #[serde(default)]
+    pub finally: Vec<StepDef>,
+    /// The file the flow was defined in, when loaded from disk — relative
+    /// `file:` paths resolve against its directory.
+    #[serde(skip)]
+    pub source: Option<PathBuf>,
+}
+
+impl FlowDef {
+    /// The `on_failure` then `finally` steps, in the order they run.
+    pub fn cleanup_steps(&self) -> impl Iterator<Item = &StepDef> {
+        self.on_failure.iter().chain(&self.finally)
+    }
+
+    /// Main steps followed by cleanup steps.
+    pub fn all_steps(&self) -> impl Iterator<Item = &StepDef> {
+        self.steps.iter().chain(self.cleanup_steps())
+    }
+}
+
+/// How a flow carries on after one of its steps fails.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FailureMode {
+    /// Stop at the first failure; nothing else starts.
+    #[default]
+    FailFast,
+    /// Let the rest of the failed step's level run, then stop.
+    FinishLevel,
+    /// Keep going: only steps downstream of a failure are skipped.
+    BestEffort,
+}
+
+impl FailureMode {
+    pub fn name(self) -> &'static str {
+        match self {
+            FailureMode::FailFast => "fail-fast",
+            FailureMode::FinishLevel => "finish-level",
+            FailureMode::BestEffort => "best-effort",
+        }
+    }
+}
+
+/// A parameter declared on a flow. One without a `default` is required.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct FlowParam {
+    pub default: Option<String>,
+}
+
+fn default_strict_templates() -> bool {
+    true
 }
 
 /// A secret declared on a flow. Resolved once at flow start and cached for
 /// the entire run; the plaintext never lands on disk and never appears in
 /// fleet's stdout/stderr logs.
 #[derive(Debug, Deserialize, Clone)]
-#[serde(tag = "source", rename_all = "kebab-case")]
+#[serde(tag = "source", rename_all = "kebab-case", deny_unknown_fields)]
 pub enum FlowSecret {
     /// Decrypt a value out of a SOPS-encrypted YAML file via `sops decrypt`.
     /// `file` is resolved relative to the fleet.yaml; `key` is the dotted
@@ -163,7 +309,8 @@ pub enum FlowSecret {
     },
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct StepDef {
     pub id: String,
     pub action: ActionDef,
@@ -172,10 +319,23 @@ pub struct StepDef {
     #[serde(default)]
     pub depends_on: Vec<String>,
     pub condition: Option<ConditionDef>,
+    /// Node selector (name or `@tag`) to fan this step out over: at plan
+    /// time it becomes one `<id>[<node>]` instance per matched node, each
+    /// targeting that node alone. Steps depending on it wait for every
+    /// instance.
+    pub foreach: Option<String>,
+    /// Run `foreach` instances one node at a time, in registry order,
+    /// instead of concurrently.
+    #[serde(default)]
+    pub serial: bool,
+    /// Set on the instances `foreach` expands into: the ID of the step they
+    /// were expanded from. Never read from YAML.
+    #[serde(skip)]
+    pub instance_of: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
-#[serde(tag = "type", rename_all = "kebab-case")]
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
 pub enum ActionDef {
     Deploy {
         #[serde(default)]
@@ -249,6 +409,30 @@ pub enum ActionDef {
         #[serde(default)]
         env: HashMap<String, String>,
     },
+    /// Pause until an operator approves on a terminal, or the step was
+    /// pre-approved with `fleet flow run --approve <step_id>`. Who approved,
+    /// when and how become the step's outputs.
+    Approval {
+        /// Prompt shown to the operator
+        message: String,
+        /// Users (`$USER`) allowed to approve; anyone when empty
+        #[serde(default)]
+        approvers: Vec<String>,
+        /// Seconds to wait for an answer; 0 waits indefinitely
+        #[serde(default = "default_approval_timeout")]
+        timeout: u64,
+    },
+    /// Run another flow from `fleet.yaml` as a single step. The step's
+    /// targets become the sub-flow's default targets, and its outputs are
+    /// exposed to this flow as `${step_id.<sub_step>.<output>}`.
+    Flow {
+        /// Name of the flow to run
+        name: String,
+        /// Values for the sub-flow's declared params, with the same
+        /// `${...}` resolution as `env:` blocks
+        #[serde(default)]
+        params: HashMap<String, String>,
+    },
     /// Run a Pangea infrastructure operation (plan, apply, destroy, output)
     Pangea {
         /// Path to the .rb template file
@@ -265,12 +449,68 @@ pub enum ActionDef {
     },
 }
 
-#[derive(Debug, Deserialize)]
-pub struct ConditionDef {
-    pub command: String,
+fn default_approval_timeout() -> u64 {
+    600
+}
+
+impl ActionDef {
+    /// The action's `type:` tag as written in `fleet.yaml`.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            ActionDef::Deploy { .. } => "deploy",
+            ActionDef::Build { .. } => "build",
+            ActionDef::Diff => "diff",
+            ActionDef::Status => "status",
+            ActionDef::Ping => "ping",
+            ActionDef::Rollback => "rollback",
+            ActionDef::Reboot => "reboot",
+            ActionDef::Exec { .. } => "exec",
+            ActionDef::Shell { .. } => "shell",
+            ActionDef::DarwinRebuild { .. } => "darwin-rebuild",
+            ActionDef::HomeManagerRebuild { .. } => "home-manager-rebuild",
+            ActionDef::FlakeUpdate { .. } => "flake-update",
+            ActionDef::PitrForge { .. } => "pitr-forge",
+            ActionDef::Pangea { .. } => "pangea",
+            ActionDef::Flow { .. } => "flow",
+            ActionDef::Approval { .. } => "approval",
+        }
+    }
+
+    /// The block of `${...}` templated values this action carries, if any,
+    /// with the YAML key it lives under.
+    pub fn templated(&self) -> Option<(&'static str, &HashMap<String, String>)> {
+        match self {
+            ActionDef::Shell { env, .. }
+            | ActionDef::PitrForge { env, .. }
+            | ActionDef::Pangea { env, .. } => Some(("env", env)),
+            ActionDef::Flow { params, .. } => Some(("params", params)),
+            _ => None,
+        }
+    }
+
+    /// Mutable access to the `env:` block, for the actions that carry one.
+    pub fn env_mut(&mut self) -> Option<&mut HashMap<String, String>> {
+        match self {
+            ActionDef::Shell { env, .. }
+            | ActionDef::PitrForge { env, .. }
+            | ActionDef::Pangea { env, .. } => Some(env),
+            _ => None,
+        }
+    }
+}
+
+/// When a step runs. Either a declarative expression evaluated by fleet
+/// (see [`crate::condition::Expr`]), or a shell command whose success
+/// gates the step.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum ConditionDef {
+    Expr(String),
+    Command { command: String },
 }
 
 /// Resolved SSH config for a specific node (all merging done).
+#[derive(Debug, Clone)]
 pub struct ResolvedSsh {
     pub connect_timeout: u32,
     pub strict_host_key: String,
@@ -285,32 +525,143 @@ pub struct ResolvedDeploy {
 }
 
 impl FleetConfig {
-    pub fn load(dir: &Path) -> Result<Self> {
+    /// Load `fleet.yaml` from `dir`, with every file it includes, and
+    /// `profile` active.
+    pub fn load(dir: &Path, profile: Option<&str>) -> Result<Self> {
         let path = dir.join("fleet.yaml");
         if !path.exists() {
-            return Ok(Self::default());
+            if let Some(name) = profile {
+                bail!(
+                    "profile '{}' selected, but there is no fleet.yaml in {}",
+                    name,
+                    dir.display()
+                );
+            }
+            return Ok(Self {
+                config_dir: dir.to_path_buf(),
+                ..Self::default()
+            });
+        }
+        let mut loaded = include::load(&path)?;
+        interpolate_env(&mut loaded.value, profile, &loaded.origins)?;
+        let mut config = Self::from_value(loaded.value, &loaded.origins)?;
+        if let Some(name) = profile {
+            if !config.profiles.contains_key(name) {
+                let mut known: Vec<&str> = config.profiles.keys().map(String::as_str).collect();
+                known.sort();
+                bail!(
+                    "unknown profile '{}' (defined: {})",
+                    name,
+                    if known.is_empty() {
+                        "none".to_string()
+                    } else {
+                        known.join(", ")
+                    }
+                );
+            }
+            config.profile = Some(name.to_string());
         }
-        let contents = std::fs::read_to_string(&path)?;
-        let mut config: FleetConfig = serde_yaml_ng::from_str(&contents)?;
         config.config_dir = dir.to_path_buf();
+        for (name, flow) in &mut config.flows {
+            flow.source = loaded
+                .origins
+                .get(&format!("flows.{}", name))
+                .map(|o| o.path.clone());
+        }
+        config.origins = loaded.origins;
         Ok(config)
     }
 
-    pub fn resolve_ssh(&self, node_name: &str) -> ResolvedSsh {
+    /// Parse fleet.yaml contents, expanding `step_templates` into the steps
+    /// that instantiate them. No `include:` — that needs files.
+    #[cfg(test)]
+    pub fn from_yaml(contents: &str) -> Result<Self> {
+        Self::from_value(serde_yaml_ng::from_str(contents)?, &BTreeMap::new())
+    }
+
+    /// Deserialize the merged config strictly: an unknown key is an error,
+    /// located through `origins` to the file and line it came from.
+    fn from_value(
+        mut raw: serde_yaml_ng::Value,
+        origins: &BTreeMap<String, Origin>,
+    ) -> Result<Self> {
+        expand_step_templates(&mut raw)?;
+        serde_path_to_error::deserialize(raw).map_err(|e| config_errors::describe(e, origins))
+    }
+
+    /// The directory a flow's relative paths resolve against: that of the
+    /// file defining it, else the one holding fleet.yaml.
+    pub fn flow_dir<'a>(&'a self, flow: &'a FlowDef) -> &'a Path {
+        flow.source
+            .as_deref()
+            .and_then(Path::parent)
+            .unwrap_or(&self.config_dir)
+    }
+
+    /// The profile `load` was asked for, with its overlay.
+    pub fn active_profile(&self) -> Option<(&str, &ProfileDef)> {
+        let name = self.profile.as_deref()?;
+        self.profiles.get(name).map(|p| (name, p))
+    }
+
+    /// The overrides that apply to a node's settings on top of the global
+    /// ones, lowest precedence first: each tag's in the order the node
+    /// lists its tags, then the node's own — and after the global section
+    /// and each of these, the active profile's counterpart.
+    fn overrides(
+        &self,
+        node_name: &str,
+        tags: &[String],
+    ) -> Vec<(Layer, &SshOverride, &DeployOverride)> {
+        let profile = self.active_profile();
+        let mut layers = Vec::new();
+        if let Some((name, p)) = profile {
+            layers.push((Layer::Profile(name.to_string(), None), &p.ssh, &p.deploy));
+        }
+        let tag_layers = tags.iter().map(|tag| {
+            (
+                Layer::Tag(tag.clone()),
+                format!("tags.{}", tag),
+                self.tags.get(tag),
+                profile.and_then(|(_, p)| p.tags.get(tag)),
+            )
+        });
+        let node_layer = (
+            Layer::Node(node_name.to_string()),
+            format!("nodes.{}", node_name),
+            self.nodes.get(node_name),
+            profile.and_then(|(_, p)| p.nodes.get(node_name)),
+        );
+        for (layer, key, ovr, in_profile) in tag_layers.chain([node_layer]) {
+            if let Some(ovr) = ovr {
+                layers.push((layer, &ovr.ssh, &ovr.deploy));
+            }
+            if let (Some((name, _)), Some(ovr)) = (profile, in_profile) {
+                layers.push((
+                    Layer::Profile(name.to_string(), Some(key)),
+                    &ovr.ssh,
+                    &ovr.deploy,
+                ));
+            }
+        }
+        layers
+    }
+
+    pub fn resolve_ssh(&self, node_name: &str, tags: &[String]) -> ResolvedSsh {
         let mut resolved = ResolvedSsh {
             connect_timeout: self.ssh.connect_timeout,
             strict_host_key: self.ssh.strict_host_key.clone(),
             options: self.ssh.options.clone(),
         };
 
-        if let Some(ovr) = self.nodes.get(node_name) {
-            if let Some(t) = ovr.ssh.connect_timeout {
+        for (_, ssh, _) in self.overrides(node_name, tags) {
+            if let Some(t) = ssh.connect_timeout {
                 resolved.connect_timeout = t;
             }
-            if let Some(ref s) = ovr.ssh.strict_host_key {
+            if let Some(ref s) = ssh.strict_host_key {
                 resolved.strict_host_key = s.clone();
             }
-            for (k, v) in &ovr.ssh.options {
+            for (k, v) in &ssh.options {
                 resolved.options.insert(k.clone(), v.clone());
             }
         }
@@ -319,21 +670,496 @@ impl FleetConfig {
     }
 
     #[allow(dead_code)]
-    pub fn resolve_deploy(&self, node_name: &str) -> ResolvedDeploy {
+    pub fn resolve_deploy(&self, node_name: &str, tags: &[String]) -> ResolvedDeploy {
         let mut resolved = ResolvedDeploy {
             show_trace: self.deploy.show_trace,
             magic_rollback: self.deploy.magic_rollback,
         };
 
-        if let Some(ovr) = self.nodes.get(node_name) {
-            if let Some(v) = ovr.deploy.show_trace {
+        for (_, _, deploy) in self.overrides(node_name, tags) {
+            if let Some(v) = deploy.show_trace {
                 resolved.show_trace = v;
             }
-            if let Some(v) = ovr.deploy.magic_rollback {
+            if let Some(v) = deploy.magic_rollback {
                 resolved.magic_rollback = v;
             }
         }
 
         resolved
     }
+
+    /// The hook a command runs: `hooks.<command>`, with the active
+    /// profile's settings for it taking precedence one by one.
+    pub fn hook(&self, command_name: &str) -> HookPair {
+        self.explain_hook(command_name)
+            .map(|(hook, _)| hook)
+            .unwrap_or_default()
+    }
+
+    /// `hook`, each setting with the layer it came from.
+    pub fn explain_hook(&self, command_name: &str) -> Option<(HookPair, HookLayers)> {
+        let base = self.hooks.get(command_name);
+        let profile = self
+            .active_profile()
+            .and_then(|(name, p)| p.hooks.get(command_name).map(|h| (name, h)));
+        if base.is_none() && profile.is_none() {
+            return None;
+        }
+        let key = format!("hooks.{}", command_name);
+        fn pick<T: Clone>(
+            base: Option<&HookPair>,
+            profile: Option<(&str, &HookPair)>,
+            key: &str,
+            get: fn(&HookPair) -> &Option<T>,
+        ) -> (Option<T>, Option<Layer>) {
+            let from_profile = profile.and_then(|(name, h)| {
+                let layer = Layer::Profile(name.to_string(), Some(key.to_string()));
+                get(h).clone().map(|value| (value, layer))
+            });
+            from_profile
+                .or_else(|| {
+                    base.and_then(|h| get(h).clone())
+                        .map(|value| (value, Layer::Global(key.to_string())))
+                })
+                .unzip()
+        }
+        let (pre, pre_layer) = pick(base, profile, &key, |h| &h.pre);
+        let (post, post_layer) = pick(base, profile, &key, |h| &h.post);
+        let (pre_all, pre_all_layer) = pick(base, profile, &key, |h| &h.pre_all);
+        let (post_all, post_all_layer) = pick(base, profile, &key, |h| &h.post_all);
+        let (remote_pre, remote_pre_layer) = pick(base, profile, &key, |h| &h.remote_pre);
+        let (remote_post, remote_post_layer) = pick(base, profile, &key, |h| &h.remote_post);
+        let (on_failure, on_failure_layer) = pick(base, profile, &key, |h| &h.on_failure);
+        let (timeout, timeout_layer) = pick(base, profile, &key, |h| &h.timeout);
+        Some((
+            HookPair {
+                pre,
+                post,
+                pre_all,
+                post_all,
+                remote_pre,
+                remote_post,
+                on_failure,
+                timeout,
+            },
+            HookLayers {
+                pre: pre_layer,
+                post: post_layer,
+                pre_all: pre_all_layer,
+                post_all: post_all_layer,
+                remote_pre: remote_pre_layer,
+                remote_post: remote_post_layer,
+                on_failure: on_failure_layer,
+                timeout: timeout_layer,
+            },
+        ))
+    }
+
+    /// `resolve_ssh`, setting by setting, with the layer each value came
+    /// from. Options are listed as `options.<name>`, sorted.
+    pub fn explain_ssh(&self, node_name: &str, tags: &[String]) -> Vec<Setting> {
+        let defaults = SshConfig::default();
+        let overrides = self.overrides(node_name, tags);
+        let mut settings = vec![
+            self.setting(
+                ("ssh", "connect_timeout"),
+                self.ssh.connect_timeout != defaults.connect_timeout,
+                Some(self.ssh.connect_timeout.to_string()),
+                &overrides,
+                |ssh, _| ssh.connect_timeout.map(|t| t.to_string()),
+            ),
+            self.setting(
+                ("ssh", "strict_host_key"),
+                self.ssh.strict_host_key != defaults.strict_host_key,
+                Some(self.ssh.strict_host_key.clone()),
+                &overrides,
+                |ssh, _| ssh.strict_host_key.clone(),
+            ),
+        ];
+        let mut options: Vec<&String> = self
+            .ssh
+            .options
+            .keys()
+            .chain(overrides.iter().flat_map(|(_, ssh, _)| ssh.options.keys()))
+            .collect();
+        options.sort();
+        options.dedup();
+        for name in options {
+            let global = self.ssh.options.get(name);
+            let mut setting = self.setting(
+                ("ssh", "options"),
+                global.is_some(),
+                global.cloned(),
+                &overrides,
+                |ssh, _| ssh.options.get(name).cloned(),
+            );
+            setting.name = format!("options.{}", name);
+            settings.push(setting);
+        }
+        settings
+    }
+
+    /// `resolve_deploy`, setting by setting, with the layer each value
+    /// came from.
+    pub fn explain_deploy(&self, node_name: &str, tags: &[String]) -> Vec<Setting> {
+        let defaults = DeployConfig::default();
+        let overrides = self.overrides(node_name, tags);
+        vec![
+            self.setting(
+                ("deploy", "show_trace"),
+                self.deploy.show_trace != defaults.show_trace,
+                Some(self.deploy.show_trace.to_string()),
+                &overrides,
+                |_, deploy| deploy.show_trace.map(|v| v.to_string()),
+            ),
+            self.setting(
+                ("deploy", "magic_rollback"),
+                self.deploy.magic_rollback != defaults.magic_rollback,
+                Some(self.deploy.magic_rollback.to_string()),
+                &overrides,
+                |_, deploy| deploy.magic_rollback.map(|v| v.to_string()),
+            ),
+        ]
+    }
+
+    /// One setting: the last override that sets it, else the global value
+    /// — which counts as set when it differs from the default or a file
+    /// names it.
+    fn setting(
+        &self,
+        (section, field): (&str, &str),
+        differs: bool,
+        global: Option<String>,
+        overrides: &[(Layer, &SshOverride, &DeployOverride)],
+        get: impl Fn(&SshOverride, &DeployOverride) -> Option<String>,
+    ) -> Setting {
+        let key = format!("{}.{}", section, field);
+        let overridden = overrides
+            .iter()
+            .filter_map(|(layer, ssh, deploy)| get(ssh, deploy).map(|v| (v, layer)))
+            .last();
+        let (value, layer) = match overridden {
+            // The profile's own `ssh:`/`deploy:`: name the setting within it
+            Some((v, Layer::Profile(name, None))) => (v, Layer::Profile(name.clone(), Some(key))),
+            Some((v, layer)) => (v, layer.clone()),
+            None if differs || self.origins.contains_key(&key) => {
+                (global.unwrap_or_default(), Layer::Global(key))
+            }
+            None => (global.unwrap_or_default(), Layer::Default),
+        };
+        Setting {
+            name: field.to_string(),
+            value,
+            layer,
+        }
+    }
+
+    /// The file and line a layer was defined at, when loaded from disk.
+    pub fn layer_origin(&self, layer: &Layer) -> Option<&Origin> {
+        match layer {
+            Layer::Default => None,
+            Layer::Global(key) => self.origins.get(key),
+            Layer::Profile(name, _) => self.origins.get(&format!("profiles.{}", name)),
+            Layer::Tag(tag) => self.origins.get(&format!("tags.{}", tag)),
+            Layer::Node(name) => self.origins.get(&format!("nodes.{}", name)),
+        }
+    }
+}
+
+/// One effective setting for a node, as `fleet config show` reports it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Setting {
+    pub name: String,
+    pub value: String,
+    pub layer: Layer,
+}
+
+/// Where each of a hook's settings came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HookLayers {
+    pub pre: Option<Layer>,
+    pub post: Option<Layer>,
+    pub pre_all: Option<Layer>,
+    pub post_all: Option<Layer>,
+    pub remote_pre: Option<Layer>,
+    pub remote_post: Option<Layer>,
+    pub on_failure: Option<Layer>,
+    pub timeout: Option<Layer>,
+}
+
+/// Where an effective setting came from, lowest precedence first.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Layer {
+    /// Built into fleet
+    Default,
+    /// The global `ssh:`, `deploy:` or `hooks:` section; holds the
+    /// setting's key there, e.g. `ssh.connect_timeout`.
+    Global(String),
+    /// `profiles.<name>`, and the key within it, e.g. `nodes.web1` or
+    /// `ssh.connect_timeout`.
+    Profile(String, Option<String>),
+    /// `tags.<tag>`
+    Tag(String),
+    /// `nodes.<name>`
+    Node(String),
+}
+
+impl std::fmt::Display for Layer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Layer::Default => write!(f, "default"),
+            Layer::Global(key) => write!(f, "{}", key),
+            Layer::Profile(name, None) => write!(f, "profiles.{}", name),
+            Layer::Profile(name, Some(key)) => write!(f, "profiles.{}.{}", name, key),
+            Layer::Tag(tag) => write!(f, "tags.{}", tag),
+            Layer::Node(name) => write!(f, "nodes.{}", name),
+        }
+    }
+}
+
+/// Replace `${env:VAR}` — or `${env:VAR:-fallback}` — in every string value
+/// with the variable's value. An unset variable without a fallback is an
+/// error at its key. Profiles other than `profile` are left alone, so a CI
+/// profile may name variables a laptop never sets.
+fn interpolate_env(
+    value: &mut serde_yaml_ng::Value,
+    profile: Option<&str>,
+    origins: &BTreeMap<String, Origin>,
+) -> Result<()> {
+    fn walk(
+        value: &mut serde_yaml_ng::Value,
+        steps: &mut Vec<Step>,
+        profile: Option<&str>,
+        origins: &BTreeMap<String, Origin>,
+    ) -> Result<()> {
+        use serde_yaml_ng::Value;
+        match value {
+            Value::String(s) if s.contains("${env:") => {
+                *s = expand_env(s, |name| std::env::var(name).ok())
+                    .map_err(|e| config_errors::at(steps, origins, &e))?;
+            }
+            Value::Sequence(items) => {
+                for (i, item) in items.iter_mut().enumerate() {
+                    steps.push(Step::Index(i));
+                    walk(item, steps, profile, origins)?;
+                    steps.pop();
+                }
+            }
+            Value::Mapping(map) => {
+                for (key, item) in map.iter_mut() {
+                    let Some(key) = key.as_str() else { continue };
+                    if let [Step::Key(section)] = &steps[..] {
+                        if section == "profiles" && Some(key) != profile {
+                            continue;
+                        }
+                    }
+                    steps.push(Step::Key(key.to_string()));
+                    walk(item, steps, profile, origins)?;
+                    steps.pop();
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+    walk(value, &mut Vec::new(), profile, origins)
+}
+
+/// `text` with its `${env:...}` references expanded through `lookup`.
+fn expand_env(text: &str, lookup: impl Fn(&str) -> Option<String>) -> Result<String, String> {
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find("${env:") {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + "${env:".len()..];
+        let end = after
+            .find('}')
+            .ok_or_else(|| format!("unterminated `${{env:` in '{}'", text))?;
+        let (name, fallback) = match after[..end].split_once(":-") {
+            Some((name, fallback)) => (name, Some(fallback)),
+            None => (&after[..end], None),
+        };
+        match (lookup(name), fallback) {
+            (Some(value), _) => out.push_str(&value),
+            (None, Some(fallback)) => out.push_str(fallback),
+            (None, None) => {
+                return Err(format!(
+                    "environment variable {} is not set (in '{}')",
+                    name, text
+                ))
+            }
+        }
+        rest = &after[end + 1..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// Replace every flow step carrying `template: <name>` with a copy of the
+/// named entry under the top-level `step_templates:` map, overlaid with the
+/// step's own keys. Maps merge key by key (so `action: { show_trace: true }`
+/// overrides one field of the template's action); anything else — strings,
+/// lists — is replaced outright. The `step_templates` key is consumed here
+/// and never reaches the typed config.
+fn expand_step_templates(raw: &mut serde_yaml_ng::Value) -> Result<()> {
+    use serde_yaml_ng::Value;
+
+    let Some(root) = raw.as_mapping_mut() else {
+        return Ok(());
+    };
+    let templates = match root.remove("step_templates") {
+        Some(Value::Mapping(m)) => m,
+        Some(Value::Null) | None => serde_yaml_ng::Mapping::new(),
+        Some(_) => anyhow::bail!("step_templates must be a map of template name to step"),
+    };
+
+    let Some(flows) = root.get_mut("flows").and_then(Value::as_mapping_mut) else {
+        return Ok(());
+    };
+    for (flow_name, flow) in flows.iter_mut() {
+        let Some(flow) = flow.as_mapping_mut() else {
+            continue;
+        };
+        let steps = flow
+            .iter_mut()
+            .filter(|(k, _)| matches!(k.as_str(), Some("steps" | "on_failure" | "finally")))
+            .filter_map(|(_, v)| v.as_sequence_mut())
+            .flat_map(|steps| steps.iter_mut().enumerate());
+        for (i, step) in steps {
+            let Some(mapping) = step.as_mapping_mut() else {
+                continue;
+            };
+            let Some(template) = mapping.remove("template") else {
+                continue;
+            };
+            let describe = || {
+                format!(
+                    "flow '{}' step {}",
+                    flow_name.as_str().unwrap_or("?"),
+                    mapping_id(mapping).unwrap_or_else(|| i.to_string())
+                )
+            };
+            let Some(name) = template.as_str() else {
+                anyhow::bail!("{}: `template` must be a template name", describe());
+            };
+            let Some(base) = templates.get(name) else {
+                let mut known: Vec<_> = templates.keys().filter_map(Value::as_str).collect();
+                known.sort();
+                anyhow::bail!(
+                    "{} uses unknown template '{}' (defined: {})",
+                    describe(),
+                    name,
+                    if known.is_empty() {
+                        "none".to_string()
+                    } else {
+                        known.join(", ")
+                    }
+                );
+            };
+            if base.get("template").is_some() {
+                anyhow::bail!("step template '{}' cannot itself use `template`", name);
+            }
+            let mut expanded = base.clone();
+            merge_yaml(&mut expanded, Value::Mapping(std::mem::take(mapping)));
+            *step = expanded;
+        }
+    }
+    Ok(())
+}
+
+/// The `id:` of a raw step, for error messages.
+fn mapping_id(mapping: &serde_yaml_ng::Mapping) -> Option<String> {
+    mapping.get("id")?.as_str().map(|id| format!("'{}'", id))
+}
+
+/// Overlay `over` onto `base`: maps merge recursively, everything else replaces.
+fn merge_yaml(base: &mut serde_yaml_ng::Value, over: serde_yaml_ng::Value) {
+    use serde_yaml_ng::Value;
+
+    match (base, over) {
+        (Value::Mapping(base), Value::Mapping(over)) => {
+            for (k, v) in over {
+                match base.get_mut(&k) {
+                    Some(slot) => merge_yaml(slot, v),
+                    None => {
+                        base.insert(k, v);
+                    }
+                }
+            }
+        }
+        (slot, over) => *slot = over,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn env_references_expand_with_fallbacks() {
+        let lookup = |name: &str| (name == "JUMP").then(|| "jump.ci".to_string());
+        assert_eq!(expand_env("${env:JUMP}:22", lookup).unwrap(), "jump.ci:22");
+        assert_eq!(
+            expand_env("${env:NOPE:-none}/${env:JUMP}", lookup).unwrap(),
+            "none/jump.ci"
+        );
+        assert_eq!(expand_env("${secrets.x}", lookup).unwrap(), "${secrets.x}");
+        assert!(expand_env("${env:NOPE}", lookup)
+            .unwrap_err()
+            .contains("NOPE is not set"));
+        assert!(expand_env("${env:JUMP", lookup).is_err());
+    }
+
+    #[test]
+    fn profiles_overlay_and_only_the_active_one_interpolates() {
+        let dir =
+            std::env::temp_dir().join(format!("fleet-config-profiles-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            dir.join("fleet.yaml"),
+            "ssh:\n  connect_timeout: 5\n  options:\n    ProxyJump: ${env:FLEET_TEST_UNSET_JUMP:-jump.lan}\n\
+             hooks:\n  deploy:\n    pre: laptop-pre\n    post: laptop-post\n\
+             profiles:\n  ci:\n    ssh:\n      connect_timeout: 30\n    hooks:\n      deploy:\n        pre: ci-pre\n\
+             \x20   nodes:\n      web1:\n        ssh:\n          options:\n            ProxyJump: bastion.ci\n\
+             \x20 broken:\n    ssh:\n      options:\n        ProxyJump: ${env:FLEET_TEST_UNSET_JUMP}\n",
+        )
+        .unwrap();
+
+        let laptop = FleetConfig::load(&dir, None).unwrap();
+        assert_eq!(laptop.resolve_ssh("web1", &[]).connect_timeout, 5);
+        assert_eq!(
+            laptop.resolve_ssh("web1", &[]).options["ProxyJump"],
+            "jump.lan"
+        );
+        assert_eq!(laptop.hook("deploy").pre.as_deref(), Some("laptop-pre"));
+
+        let ci = FleetConfig::load(&dir, Some("ci")).unwrap();
+        assert_eq!(ci.resolve_ssh("web1", &[]).connect_timeout, 30);
+        assert_eq!(
+            ci.resolve_ssh("web1", &[]).options["ProxyJump"],
+            "bastion.ci"
+        );
+        assert_eq!(ci.resolve_ssh("db1", &[]).options["ProxyJump"], "jump.lan");
+        let hook = ci.hook("deploy");
+        assert_eq!(
+            (hook.pre.as_deref(), hook.post.as_deref()),
+            (Some("ci-pre"), Some("laptop-post"))
+        );
+
+        let err = FleetConfig::load(&dir, Some("broken"))
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.starts_with(
+                "fleet.yaml:24:9: profiles.broken.ssh.options.ProxyJump: environment variable"
+            ),
+            "{}",
+            err
+        );
+        let err = FleetConfig::load(&dir, Some("staging"))
+            .unwrap_err()
+            .to_string();
+        assert_eq!(err, "unknown profile 'staging' (defined: broken, ci)");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
 }
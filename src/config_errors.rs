@@ -0,0 +1,348 @@
+use std::collections::BTreeMap;
+use std::fs;
+
+use serde_path_to_error::Segment;
+
+use crate::include::Origin;
+
+/// One step into the YAML document: a map key or a list index.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    Key(String),
+    Index(usize),
+}
+
+/// Turn a failed deserialization of the merged config into an error an
+/// operator can act on: the file, line and column of the offending key,
+/// its path, and — for a misspelled key or action type — the closest
+/// valid name.
+pub fn describe(
+    err: serde_path_to_error::Error<serde_yaml_ng::Error>,
+    origins: &BTreeMap<String, Origin>,
+) -> anyhow::Error {
+    let mut steps: Vec<Step> = err
+        .path()
+        .iter()
+        .filter_map(|segment| match segment {
+            Segment::Map { key } => Some(Step::Key(key.clone())),
+            Segment::Seq { index } => Some(Step::Index(*index)),
+            _ => None,
+        })
+        .collect();
+    let raw = err.into_inner().to_string();
+    let message = match unknown_name(&raw) {
+        Some((kind, name, expected)) => {
+            // Usually the path already ends at the unknown key; when it does
+            // not, the key is what to point at.
+            let key = Step::Key(name.to_string());
+            if kind == "field" && steps.last() != Some(&key) {
+                steps.push(key);
+            }
+            match closest(name, &expected) {
+                Some(close) => format!("unknown {} `{}` — did you mean `{}`?", kind, name, close),
+                None => raw.clone(),
+            }
+        }
+        None => raw.clone(),
+    };
+
+    at(&steps, origins, &message)
+}
+
+/// `message` about the node at `steps`, prefixed with its file, line,
+/// column and path.
+pub fn at(steps: &[Step], origins: &BTreeMap<String, Origin>, message: &str) -> anyhow::Error {
+    let location = locate_origin(steps, origins).map(|origin| {
+        let at = fs::read_to_string(&origin.path)
+            .ok()
+            .and_then(|text| locate(&text, steps));
+        match at {
+            Some((line, col)) => format!("{}:{}:{}", origin.label, line, col),
+            None => origin.to_string(),
+        }
+    });
+    let mut path = String::new();
+    for step in steps {
+        match step {
+            Step::Key(key) if path.is_empty() => path.push_str(key),
+            Step::Key(key) => path.push_str(&format!(".{}", key)),
+            Step::Index(index) => path.push_str(&format!("[{}]", index)),
+        }
+    }
+    match (location, path.is_empty()) {
+        (Some(at), true) => anyhow::anyhow!("{}: {}", at, message),
+        (Some(at), false) => anyhow::anyhow!("{}: {}: {}", at, path, message),
+        (None, true) => anyhow::anyhow!("{}", message),
+        (None, false) => anyhow::anyhow!("{}: {}", path, message),
+    }
+}
+
+/// The file holding the entry at the top of `steps`.
+fn locate_origin<'a>(steps: &[Step], origins: &'a BTreeMap<String, Origin>) -> Option<&'a Origin> {
+    let section = match steps.first()? {
+        Step::Key(s) => s,
+        Step::Index(_) => return None,
+    };
+    if let Some(Step::Key(key)) = steps.get(1) {
+        if let Some(origin) = origins.get(&format!("{}.{}", section, key)) {
+            return Some(origin);
+        }
+    }
+    let prefix = format!("{}.", section);
+    origins.get(section).or_else(|| {
+        origins
+            .iter()
+            .find(|(k, _)| k.starts_with(&prefix))
+            .map(|(_, o)| o)
+    })
+}
+
+/// serde's "unknown field `x`, expected one of `a`, `b`" (or variant) as
+/// its kind, the name, and the names it expected.
+fn unknown_name(message: &str) -> Option<(&str, &str, Vec<&str>)> {
+    let rest = message.strip_prefix("unknown ")?;
+    let (kind, rest) = rest.split_once(' ')?;
+    if kind != "field" && kind != "variant" {
+        return None;
+    }
+    let mut quoted = rest.split('`').skip(1).step_by(2);
+    let name = quoted.next()?;
+    Some((kind, name, quoted.collect()))
+}
+
+/// The candidate closest to `name`, if it is close enough to be a typo.
+fn closest<'a>(name: &str, candidates: &[&'a str]) -> Option<&'a str> {
+    let limit = (name.chars().count() / 3).max(2);
+    candidates
+        .iter()
+        .map(|c| (edit_distance(name, c), *c))
+        .filter(|(d, _)| *d <= limit)
+        .min_by_key(|(d, _)| *d)
+        .map(|(_, c)| c)
+}
+
+/// Levenshtein distance.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = (above + 1)
+                .min(row[j] + 1)
+                .min(diagonal + usize::from(ca != *cb));
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
+
+/// The 1-based line and column of the node at `steps` in block-style YAML.
+///
+/// A text scan by indentation, not a parser: it follows keys and `- ` list
+/// items down from the top, and stops at the deepest node it could find when
+/// the layout turns unusual (flow style, anchors) — a line near the problem
+/// beats no line at all.
+fn locate(text: &str, steps: &[Step]) -> Option<(usize, usize)> {
+    let lines: Vec<Line> = text.lines().map(Line::parse).collect();
+    // The lines making up the current node.
+    let (mut lo, mut hi) = (0, lines.len());
+    let mut found = None;
+    for step in steps {
+        let children = (lo..hi).filter(|&i| lines[i].is_content());
+        let hit = match step {
+            Step::Key(key) => children.clone().next().and_then(|first| {
+                let column = lines[first].key_col;
+                children
+                    .clone()
+                    .find(|&i| lines[i].key_col == column && lines[i].key() == Some(key.as_str()))
+                    .map(|i| (i, column))
+            }),
+            Step::Index(index) => children.clone().find(|&i| lines[i].item).and_then(|first| {
+                let column = lines[first].indent;
+                children
+                    .clone()
+                    .filter(|&i| lines[i].item && lines[i].indent == column)
+                    .nth(*index)
+                    .map(|i| (i, column))
+            }),
+        };
+        let Some((line, column)) = hit else {
+            break;
+        };
+        found = Some((line + 1, column + 1));
+        // The node runs until the next content line at or left of it; an
+        // item also ends at its next sibling `- `.
+        lo = line + 1;
+        hi = (lo..hi)
+            .find(|&i| {
+                let l = &lines[i];
+                l.is_content()
+                    && match step {
+                        Step::Key(_) => l.indent < column || (l.indent == column && !l.item),
+                        Step::Index(_) => l.indent <= column,
+                    }
+            })
+            .unwrap_or(hi);
+        if let Step::Index(_) = step {
+            // An item's first key shares its `- ` line.
+            lo = line;
+        }
+    }
+    found
+}
+
+/// One line of YAML, as `locate` sees it.
+struct Line<'a> {
+    /// Column of the first non-space character.
+    indent: usize,
+    /// Whether the line starts a list item (`- ` at `indent`).
+    item: bool,
+    /// Column where this line's key (if any) starts.
+    key_col: usize,
+    /// The line from `key_col` on.
+    rest: &'a str,
+}
+
+impl<'a> Line<'a> {
+    fn parse(line: &'a str) -> Self {
+        let trimmed = line.trim_start_matches(' ');
+        let indent = line.len() - trimmed.len();
+        match trimmed.strip_prefix("- ") {
+            Some(item) => {
+                let inner = item.trim_start_matches(' ');
+                Line {
+                    indent,
+                    item: true,
+                    key_col: line.len() - inner.len(),
+                    rest: inner,
+                }
+            }
+            None => Line {
+                indent,
+                item: false,
+                key_col: indent,
+                rest: trimmed,
+            },
+        }
+    }
+
+    fn is_content(&self) -> bool {
+        (self.item || !self.rest.is_empty()) && !self.rest.starts_with('#')
+    }
+
+    /// The mapping key this line starts with, unquoted.
+    fn key(&self) -> Option<&'a str> {
+        let (key, _) = self.rest.split_once(':')?;
+        let key = key.trim_end();
+        Some(
+            key.strip_prefix('"')
+                .and_then(|k| k.strip_suffix('"'))
+                .or_else(|| key.strip_prefix('\'').and_then(|k| k.strip_suffix('\'')))
+                .unwrap_or(key),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const YAML: &str = "\
+# comment
+flows:
+  deploy:
+    steps:
+      - id: build
+        action: { type: build }
+      - id: ship
+        action:
+          type: deploy
+        taget: [web1]
+  other:
+    steps: []
+";
+
+    fn at(steps: &[Step]) -> Option<(usize, usize)> {
+        locate(YAML, steps)
+    }
+
+    fn key(k: &str) -> Step {
+        Step::Key(k.to_string())
+    }
+
+    #[test]
+    fn keys_and_items_are_found_by_indentation() {
+        assert_eq!(at(&[key("flows")]), Some((2, 1)));
+        assert_eq!(at(&[key("flows"), key("other")]), Some((11, 3)));
+        let ship = [key("flows"), key("deploy"), key("steps"), Step::Index(1)];
+        assert_eq!(at(&ship), Some((7, 7)));
+        assert_eq!(at(&[&ship[..], &[key("id")]].concat()), Some((7, 9)));
+        assert_eq!(at(&[&ship[..], &[key("taget")]].concat()), Some((10, 9)));
+        assert_eq!(
+            at(&[&ship[..], &[key("action"), key("type")]].concat()),
+            Some((9, 11))
+        );
+    }
+
+    #[test]
+    fn an_unfollowable_path_stops_at_the_deepest_node_found() {
+        let build = [key("flows"), key("deploy"), key("steps"), Step::Index(0)];
+        // `action` is flow style; its `type` is not on a line of its own
+        let path = [&build[..], &[key("action"), key("type")]].concat();
+        assert_eq!(at(&path), Some((6, 9)));
+        assert_eq!(at(&[key("nodes")]), None);
+    }
+
+    #[test]
+    fn load_errors_point_into_the_file_that_has_the_typo() {
+        let dir = std::env::temp_dir().join(format!("fleet-config-errors-{}", std::process::id()));
+        fs::create_dir_all(dir.join("flows")).unwrap();
+        fs::write(
+            dir.join("fleet.yaml"),
+            "include: flows/*.yaml\nssh:\n  connect_timeout: 5\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("flows/deploy.yaml"),
+            YAML.replace("# comment\n", ""),
+        )
+        .unwrap();
+
+        let err = crate::config::FleetConfig::load(&dir, None)
+            .unwrap_err()
+            .to_string();
+        assert_eq!(
+            err,
+            "flows/deploy.yaml:9:9: flows.deploy.steps[1].taget: \
+             unknown field `taget` — did you mean `targets`?"
+        );
+
+        fs::write(dir.join("fleet.yaml"), "ssh:\n  connect_timeout: soon\n").unwrap();
+        fs::remove_dir_all(dir.join("flows")).unwrap();
+        let err = crate::config::FleetConfig::load(&dir, None)
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.starts_with("fleet.yaml:2:3: ssh.connect_timeout: invalid type"),
+            "{}",
+            err
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn typos_get_the_closest_name() {
+        let expected = ["id", "action", "targets", "depends_on", "condition"];
+        assert_eq!(closest("taget", &expected), Some("targets"));
+        assert_eq!(closest("depend_on", &expected), Some("depends_on"));
+        assert_eq!(closest("colour", &expected), None);
+        assert_eq!(
+            unknown_name("unknown field `taget`, expected one of `id`, `targets`"),
+            Some(("field", "taget", vec!["id", "targets"]))
+        );
+        assert_eq!(unknown_name("invalid type: string"), None);
+    }
+}
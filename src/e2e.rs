@@ -0,0 +1,927 @@
+//! The CLI end to end — argument parsing, config loading, targeting, hooks,
+//! locks and flows — against a fake registry and a scripted transport, so
+//! orchestration regressions show up without any machines.
+
+use anyhow::Result;
+use clap::Parser;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::config::FleetConfig;
+use crate::fake_transport::{FakeTransport, Reply, ANY, LOCAL};
+use crate::lock::{LockWait, Locks};
+use crate::registry::{Node, NodeRegistry};
+use crate::{dispatch, Cli};
+
+/// A fleet of three nodes — web1 and web2 tagged `web`, db1 tagged `db` —
+/// with its own fleet.yaml, lock and state directories and scripted network.
+struct Fleet {
+    dir: PathBuf,
+    registry: NodeRegistry,
+    net: FakeTransport,
+    locks: Locks,
+    /// Where flow run records and known_hosts go.
+    state: PathBuf,
+}
+
+impl Fleet {
+    fn new(name: &str, yaml: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("fleet-e2e-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("fleet.yaml"),
+            yaml.replace("$DIR", &dir.display().to_string()),
+        )
+        .unwrap();
+        let node = |host: &str, tag: &str| Node {
+            system: "x86_64-linux".to_string(),
+            hostname: host.to_string(),
+            ssh_user: "root".to_string(),
+            tags: vec![tag.to_string()],
+            host_key: None,
+        };
+        let registry = NodeRegistry::from([
+            ("web1".to_string(), node("10.0.0.1", "web")),
+            ("web2".to_string(), node("10.0.0.2", "web")),
+            ("db1".to_string(), node("10.0.0.3", "db")),
+        ]);
+        let locks = Locks::new(
+            dir.join("locks"),
+            LockWait::Bounded(Duration::from_secs(60)),
+        );
+        Self {
+            state: dir.join("state"),
+            dir,
+            registry,
+            net: FakeTransport::new(),
+            locks,
+        }
+    }
+
+    /// `fleet <args>`, as main runs it.
+    fn run(&self, args: &[&str]) -> Result<()> {
+        let cli = Cli::try_parse_from(std::iter::once("fleet").chain(args.iter().copied()))?;
+        let config = FleetConfig::load(&self.dir, cli.profile.as_deref())?;
+        dispatch(
+            cli.command,
+            &config,
+            &self.locks,
+            &self.net,
+            &self.state,
+            &|| Ok(self.registry.clone()),
+        )
+    }
+
+    /// Lines hooks appended to `$DIR/hooks.log`.
+    fn hook_log(&self) -> Vec<String> {
+        fs::read_to_string(self.dir.join("hooks.log"))
+            .unwrap_or_default()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+}
+
+impl Drop for Fleet {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+const HOOKS_YAML: &str = r#"
+hooks:
+  exec:
+    pre: echo "pre-exec $FLEET_NODE $FLEET_HOST" >> $DIR/hooks.log
+    post: echo "post-exec $FLEET_NODE" >> $DIR/hooks.log
+  reboot:
+    pre: echo "pre-reboot $FLEET_NODE" >> $DIR/hooks.log
+    post: echo "post-reboot $FLEET_NODE" >> $DIR/hooks.log
+"#;
+
+#[test]
+fn status_asks_every_node_and_tolerates_one_being_down() {
+    let fleet = Fleet::new("status", "");
+    fleet
+        .net
+        .on(ANY, "uname -r", Reply::ok("6.6.30\n"))
+        .on(ANY, "uptime", Reply::ok("up 3 days"))
+        .on(ANY, "readlink", Reply::ok("42"))
+        .on(ANY, "cat /run/current-system", Reply::ok("24.05"))
+        .unreachable("db1");
+
+    fleet.run(&["status"]).unwrap();
+    for node in ["web1", "web2", "db1"] {
+        assert_eq!(fleet.net.commands_on(node).len(), 4, "{}", node);
+    }
+}
+
+#[test]
+fn exec_quotes_words_runs_hooks_and_reports_failed_nodes() {
+    let fleet = Fleet::new("exec", HOOKS_YAML);
+    fleet.net.on(ANY, "touch", Reply::ok("")).on(
+        "web2",
+        "touch",
+        Reply::fail(1, "read-only file system"),
+    );
+
+    let err = fleet
+        .run(&["exec", "@web", "--", "touch", "a b"])
+        .unwrap_err();
+    assert_eq!(err.to_string(), "Some nodes failed");
+    assert_eq!(fleet.net.commands_on("web1"), ["touch 'a b'"]);
+    assert_eq!(fleet.net.commands_on("web2"), ["touch 'a b'"]);
+    assert!(fleet.net.commands_on("db1").is_empty());
+    assert!(fleet.dir.join("locks/node.web1.lock").exists());
+    // Every pre-hook runs before any node is touched; post-hooks only when
+    // the command as a whole succeeded
+    assert_eq!(
+        fleet.hook_log(),
+        ["pre-exec web1 10.0.0.1", "pre-exec web2 10.0.0.2"]
+    );
+}
+
+#[test]
+fn a_failing_pre_hook_stops_the_command_before_any_node() {
+    let fleet = Fleet::new("prehook", "hooks:\n  exec:\n    pre: exit 3\n");
+    let err = fleet.run(&["exec", "web1", "--", "uptime"]).unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("Pre-exec hook failed for web1 (exit 3)"),
+        "{}",
+        err
+    );
+    assert!(fleet.net.calls().is_empty());
+}
+
+#[test]
+fn a_failed_command_runs_its_failure_hooks_with_the_error() {
+    let fleet = Fleet::new(
+        "onfailure",
+        r#"
+hooks:
+  exec:
+    post: echo "post $FLEET_NODE" >> $DIR/hooks.log
+    on_failure: echo "$FLEET_OUTCOME $FLEET_COMMAND [$FLEET_TARGETS] $FLEET_NODE $FLEET_TAGS -- $FLEET_ERROR" >> $DIR/hooks.log
+"#,
+    );
+    fleet
+        .net
+        .on(ANY, "uptime", Reply::ok("up"))
+        .on("web2", "uptime", Reply::fail(1, "boom"));
+
+    assert!(fleet.run(&["exec", "@web", "--", "uptime"]).is_err());
+    assert_eq!(
+        fleet.hook_log(),
+        [
+            "failure exec [web1,web2] web1 web -- Some nodes failed",
+            "failure exec [web1,web2] web2 web -- Some nodes failed",
+        ]
+    );
+}
+
+#[test]
+fn failure_hooks_run_only_for_the_nodes_that_started() {
+    let yaml = r#"
+hooks:
+  exec:
+    pre_all: PRE_ALL
+    pre: test "$FLEET_NODE" != web1
+    on_failure: echo "on_failure $FLEET_NODE" >> $DIR/hooks.log
+    post_all: echo "post_all $FLEET_OUTCOME" >> $DIR/hooks.log
+"#;
+
+    // pre_all failing starts no node at all
+    let fleet = Fleet::new("onfailure-pre-all", &yaml.replace("PRE_ALL", "exit 3"));
+    let err = fleet.run(&["exec", "--all", "--", "uptime"]).unwrap_err();
+    assert_eq!(err.to_string(), "Pre_all-exec hook failed (exit 3)");
+    assert_eq!(fleet.hook_log(), ["post_all failure"]);
+    assert!(fleet.net.calls().is_empty());
+
+    // A pre-hook failing stops the nodes after it from starting
+    let fleet = Fleet::new("onfailure-pre", &yaml.replace("PRE_ALL", "'true'"));
+    let err = fleet.run(&["exec", "--all", "--", "uptime"]).unwrap_err();
+    assert_eq!(err.to_string(), "Pre-exec hook failed for web1 (exit 1)");
+    assert_eq!(
+        fleet.hook_log(),
+        ["on_failure db1", "on_failure web1", "post_all failure"]
+    );
+}
+
+#[test]
+fn all_hooks_run_once_around_every_node() {
+    let fleet = Fleet::new(
+        "allhooks",
+        r#"
+hooks:
+  exec:
+    pre_all: echo "pre_all $FLEET_TARGETS" >> $DIR/hooks.log
+    pre: echo "pre $FLEET_NODE" >> $DIR/hooks.log
+    post: echo "post $FLEET_NODE" >> $DIR/hooks.log
+    post_all: echo "post_all $FLEET_OUTCOME $FLEET_NODE" >> $DIR/hooks.log
+"#,
+    );
+    fleet.net.on(ANY, "uptime", Reply::ok("up"));
+
+    fleet.run(&["exec", "@web", "--", "uptime"]).unwrap();
+    assert_eq!(
+        fleet.hook_log(),
+        [
+            "pre_all web1,web2",
+            "pre web1",
+            "pre web2",
+            "post web1",
+            "post web2",
+            // No node of its own
+            "post_all success ",
+        ]
+    );
+}
+
+const REMOTE_HOOKS_YAML: &str = r#"
+hooks:
+  exec:
+    remote_pre: systemctl stop ingest
+    remote_post: systemctl start ingest
+"#;
+
+#[test]
+fn remote_hooks_run_on_each_node_around_the_command() {
+    let fleet = Fleet::new("remotehooks", REMOTE_HOOKS_YAML);
+    fleet
+        .net
+        .on(ANY, "env FLEET_COMMAND=exec", Reply::ok("stopped\n"))
+        .on(ANY, "uptime", Reply::ok("up"));
+
+    fleet.run(&["exec", "@web", "--", "uptime"]).unwrap();
+    for node in ["web1", "web2"] {
+        let commands = fleet.net.commands_on(node);
+        assert_eq!(commands.len(), 3, "{:?}", commands);
+        assert!(
+            commands[0].ends_with(" fleet-hook 'systemctl stop ingest' 2>&1"),
+            "{}",
+            commands[0]
+        );
+        assert!(
+            commands[0].contains(&format!(" FLEET_NODE={} ", node)),
+            "{}",
+            commands[0]
+        );
+        assert_eq!(commands[1], "uptime");
+        assert!(
+            commands[2].ends_with(" fleet-hook 'systemctl start ingest' 2>&1"),
+            "{}",
+            commands[2]
+        );
+    }
+}
+
+#[test]
+fn a_failing_remote_pre_hook_stops_the_command_like_a_local_one() {
+    let fleet = Fleet::new("remotepre", REMOTE_HOOKS_YAML);
+    fleet
+        .net
+        .on(ANY, "env FLEET_COMMAND=exec", Reply::ok(""))
+        .on("web2", "env FLEET_COMMAND=exec", Reply::fail(5, ""))
+        .on(ANY, "uptime", Reply::ok("up"));
+
+    let err = fleet.run(&["exec", "@web", "--", "uptime"]).unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("Remote pre-exec hook failed on web2 (exit 5)"),
+        "{}",
+        err
+    );
+    assert!(!fleet.net.calls().iter().any(|c| c.command == "uptime"));
+}
+
+#[test]
+fn a_remote_hook_exiting_255_is_not_mistaken_for_a_failed_connection() {
+    let fleet = Fleet::new("remote255", REMOTE_HOOKS_YAML);
+    let exit_255 = Reply {
+        code: 255,
+        ..Reply::ok("fleet-hook-started\ndraining\n")
+    };
+    fleet
+        .net
+        .on(
+            ANY,
+            "env FLEET_COMMAND=exec",
+            Reply::ok("fleet-hook-started\n"),
+        )
+        .on("web2", "env FLEET_COMMAND=exec", exit_255);
+
+    let err = fleet.run(&["exec", "@web", "--", "uptime"]).unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("Remote pre-exec hook failed on web2 (exit 255)"),
+        "{}",
+        err
+    );
+
+    fleet.net.unreachable("web2");
+    let err = fleet.run(&["exec", "@web", "--", "uptime"]).unwrap_err();
+    assert!(
+        err.to_string().contains(
+            "Remote pre-exec hook failed on web2 (ssh: connect to host web2 port 22: Connection timed out)"
+        ),
+        "{}",
+        err
+    );
+}
+
+#[test]
+fn remote_post_hooks_run_after_a_failed_command_too_and_know_it_failed() {
+    let fleet = Fleet::new("remotepost-failure", REMOTE_HOOKS_YAML);
+    fleet
+        .net
+        .on(ANY, "env FLEET_COMMAND=exec", Reply::ok(""))
+        .on(ANY, "uptime", Reply::ok("up"))
+        .on("web2", "uptime", Reply::fail(1, "boom"));
+
+    assert!(fleet.run(&["exec", "@web", "--", "uptime"]).is_err());
+    for node in ["web1", "web2"] {
+        let commands = fleet.net.commands_on(node);
+        assert_eq!(commands.len(), 3, "{:?}", commands);
+        assert!(
+            commands[2].ends_with(" fleet-hook 'systemctl start ingest' 2>&1"),
+            "{}",
+            commands[2]
+        );
+        assert!(
+            commands[2].contains(" FLEET_OUTCOME=failure FLEET_ERROR='Some nodes failed' "),
+            "{}",
+            commands[2]
+        );
+    }
+}
+
+#[test]
+fn a_hook_past_its_timeout_fails_like_any_other() {
+    let fleet = Fleet::new(
+        "hooktimeout",
+        "hooks:\n  status:\n    pre: sleep 30\n    timeout: 1\n",
+    );
+    let started = Instant::now();
+    let err = fleet.run(&["status", "web1"]).unwrap_err();
+    assert!(
+        err.to_string()
+            .contains("Pre-status hook failed for web1 (timed out after 1s)"),
+        "{}",
+        err
+    );
+    assert!(
+        started.elapsed() < Duration::from_secs(10),
+        "{:?}",
+        started.elapsed()
+    );
+    assert!(fleet.net.calls().is_empty());
+}
+
+#[test]
+fn ping_fails_naming_how_many_nodes_are_down() {
+    let fleet = Fleet::new("ping", "");
+    fleet.net.on(ANY, "true", Reply::ok("")).unreachable("db1");
+
+    let err = fleet.run(&["ping"]).unwrap_err();
+    assert_eq!(err.to_string(), "1 node(s) unreachable");
+    fleet.run(&["ping", "@web"]).unwrap();
+}
+
+#[test]
+fn unprompted_reboots_and_rollbacks_take_node_locks_and_run_hooks() {
+    // A flow step confirms for the operator, as `--yes` does for reboot
+    let yaml = format!(
+        "{}{}",
+        HOOKS_YAML,
+        r#"
+flows:
+  undo:
+    steps:
+      - id: rollback
+        action: { type: rollback }
+        targets: [db1]
+"#
+    );
+    let fleet = Fleet::new("reboot", &yaml);
+    // A reboot drops the connection under the command; that still counts
+    fleet
+        .net
+        .on(
+            ANY,
+            "systemctl reboot",
+            Reply::fail(255, "Connection closed"),
+        )
+        .on(ANY, "nixos-rebuild switch --rollback", Reply::ok(""));
+
+    fleet.run(&["reboot", "--yes", "web1"]).unwrap();
+    fleet.run(&["flow", "run", "undo"]).unwrap();
+    assert_eq!(fleet.net.commands_on("web1"), ["systemctl reboot"]);
+    assert_eq!(
+        fleet.net.commands_on("db1"),
+        ["nixos-rebuild switch --rollback"]
+    );
+    assert_eq!(fleet.hook_log(), ["pre-reboot web1", "post-reboot web1"]);
+    assert!(fleet.dir.join("locks/node.web1.lock").exists());
+    assert!(fleet.dir.join("locks/node.db1.lock").exists());
+}
+
+#[test]
+fn diff_builds_locally_and_compares_with_what_the_node_runs() {
+    let fleet = Fleet::new("diff", "");
+    fleet
+        .net
+        .on_local("nix build", Reply::ok("/nix/store/new-system\n"))
+        .on_local("nix store diff-closures", Reply::ok("openssl: 3.0 → 3.1\n"))
+        .on(
+            "web1",
+            "readlink /run/current-system",
+            Reply::ok("/nix/store/old-system\n"),
+        );
+
+    fleet.run(&["diff", "web1"]).unwrap();
+    let local = fleet.net.commands_on(LOCAL);
+    assert_eq!(local.len(), 2);
+    assert!(local[0].contains("#nixosConfigurations.web1.config.system.build.toplevel"));
+    assert_eq!(
+        local[1],
+        "nix store diff-closures /nix/store/old-system /nix/store/new-system"
+    );
+}
+
+#[test]
+fn ssh_hands_over_to_an_interactive_session() {
+    let fleet = Fleet::new("ssh", "");
+    let err = fleet.run(&["ssh", "web2"]).unwrap_err();
+    assert_eq!(err.to_string(), "fake transport: no terminal for web2");
+    assert_eq!(fleet.net.commands_on("web2"), ["<login shell>"]);
+
+    assert!(fleet
+        .run(&["ssh", "web1", "--", "journalctl", "-f"])
+        .is_err());
+    assert_eq!(fleet.net.commands_on("web1"), ["journalctl -f"]);
+}
+
+#[test]
+fn ssh_to_several_nodes_opens_a_tmux_pane_for_each() {
+    let fleet = Fleet::new("ssh-tmux", "");
+    fleet.net.on_local("tmux", Reply::ok("%7\n"));
+
+    let err = fleet
+        .run(&["ssh", "@web", "--sync", "--", "htop"])
+        .unwrap_err();
+    assert_eq!(err.to_string(), "fake transport: no terminal for tmux");
+    let tmux = fleet.net.commands_on(LOCAL);
+    assert_eq!(tmux[0], "tmux -V");
+    assert!(tmux[1].starts_with("tmux new-session -d -P -F #{pane_id} -s fleet-"));
+    assert!(tmux[1].contains("-n fleet ssh web1 htop ; "), "{}", tmux[1]);
+    assert!(tmux[1].ends_with("remain-on-exit on"), "{}", tmux[1]);
+    assert!(tmux.contains(&"tmux select-pane -t %7 -T web2".to_string()));
+    assert!(tmux
+        .iter()
+        .any(|c| c.starts_with("tmux split-window") && c.ends_with("ssh web2 htop")));
+    assert!(tmux[tmux.len() - 2].ends_with("synchronize-panes on"));
+    // Attached (or switched to, from inside tmux) last
+    let attach = tmux.last().unwrap();
+    assert!(
+        attach.starts_with("tmux attach-session -t fleet-")
+            || attach.starts_with("tmux switch-client -t fleet-"),
+        "{}",
+        attach
+    );
+    // The nodes themselves are only reached from the panes
+    assert!(fleet.net.commands_on("web1").is_empty());
+}
+
+#[test]
+fn a_tmux_session_that_could_not_be_set_up_is_not_left_running() {
+    let fleet = Fleet::new("ssh-tmux-cleanup", "");
+    fleet
+        .net
+        .on_local("tmux", Reply::ok("%7\n"))
+        .on_local("tmux split-window", Reply::fail(1, "no space for new pane"));
+
+    let err = fleet.run(&["ssh", "@web"]).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "tmux split-window failed: no space for new pane"
+    );
+    let tmux = fleet.net.commands_on(LOCAL);
+    assert!(tmux[1].starts_with("tmux new-session "), "{}", tmux[1]);
+    let session = tmux[1]
+        .split(" -s ")
+        .nth(1)
+        .unwrap()
+        .split(' ')
+        .next()
+        .unwrap();
+    assert_eq!(
+        tmux.last().unwrap(),
+        &format!("tmux kill-session -t {}", session)
+    );
+}
+
+const FLOW_YAML: &str = r#"
+flows:
+  roll:
+    steps:
+      - id: check
+        action: { type: exec, command: [systemctl, is-active, nginx] }
+        foreach: "@web"
+      - id: restart
+        action: { type: exec, command: [systemctl, restart, nginx] }
+        targets: ["@web"]
+        depends_on: [check]
+"#;
+
+#[test]
+fn fanned_out_steps_run_at_once_and_dependents_wait() {
+    let fleet = Fleet::new("flow", FLOW_YAML);
+    let slow = Duration::from_millis(300);
+    fleet
+        .net
+        .on(ANY, "systemctl is-active", Reply::ok("active").after(slow))
+        .on(ANY, "systemctl restart", Reply::ok(""));
+
+    let started = Instant::now();
+    fleet.run(&["flow", "run", "roll"]).unwrap();
+    // Two 300ms checks side by side, not one after the other
+    assert!(started.elapsed() < slow * 2, "{:?}", started.elapsed());
+    assert_eq!(
+        fleet.net.commands_on("web1"),
+        ["systemctl is-active nginx", "systemctl restart nginx"]
+    );
+    assert_eq!(
+        fleet.net.commands_on("web2"),
+        ["systemctl is-active nginx", "systemctl restart nginx"]
+    );
+}
+
+#[test]
+fn a_failed_flow_step_skips_what_depends_on_it() {
+    let fleet = Fleet::new("flowfail", FLOW_YAML);
+    fleet
+        .net
+        .on(ANY, "systemctl is-active", Reply::ok("active"))
+        .on("web2", "systemctl is-active", Reply::fail(3, "inactive"));
+
+    assert!(fleet.run(&["flow", "run", "roll"]).is_err());
+    assert!(!fleet
+        .net
+        .calls()
+        .iter()
+        .any(|c| c.command.starts_with("systemctl restart")));
+}
+
+#[test]
+fn concurrent_steps_share_one_lock_on_a_sub_flow() {
+    let fleet = Fleet::new(
+        "subflowlock",
+        r#"
+flows:
+  restart-one:
+    steps:
+      - id: restart
+        action: { type: exec, command: [systemctl, restart, nginx] }
+  roll:
+    steps:
+      - id: each
+        action: { type: flow, name: restart-one }
+        foreach: "@web"
+"#,
+    );
+    let slow = Duration::from_millis(300);
+    fleet
+        .net
+        .on(ANY, "systemctl restart", Reply::ok("").after(slow));
+
+    let started = Instant::now();
+    fleet.run(&["flow", "run", "roll"]).unwrap();
+    // Side by side, not queued behind each other's flock on the sub-flow
+    assert!(started.elapsed() < slow * 2, "{:?}", started.elapsed());
+    assert_eq!(fleet.net.commands_on("web1"), ["systemctl restart nginx"]);
+    assert_eq!(fleet.net.commands_on("web2"), ["systemctl restart nginx"]);
+    assert!(fleet.dir.join("locks/flow.restart-one.lock").exists());
+}
+
+#[test]
+fn flow_steps_run_the_hooks_of_their_action() {
+    let yaml = format!(
+        "{}hooks:\n  exec:\n    post: echo \"$FLEET_FLOW/$FLEET_STEP $FLEET_NODE\" >> $DIR/hooks.log\n",
+        FLOW_YAML
+    );
+    let fleet = Fleet::new("flowhooks", &yaml);
+    fleet.net.on(ANY, "systemctl", Reply::ok("active"));
+
+    fleet.run(&["flow", "run", "roll"]).unwrap();
+    let mut log = fleet.hook_log();
+    // The fanned-out check finishes on its nodes in either order
+    log[..2].sort();
+    assert_eq!(
+        log,
+        [
+            "roll/check[web1] web1",
+            "roll/check[web2] web2",
+            "roll/restart web1",
+            "roll/restart web2",
+        ]
+    );
+}
+
+#[test]
+fn flow_steps_run_remote_hooks_on_their_nodes() {
+    let yaml = format!("{}{}", FLOW_YAML, REMOTE_HOOKS_YAML);
+    let fleet = Fleet::new("flowremote", &yaml);
+    fleet
+        .net
+        .on(ANY, "env FLEET_COMMAND=exec", Reply::ok(""))
+        .on(ANY, "systemctl", Reply::ok("active"));
+
+    fleet.run(&["flow", "run", "roll"]).unwrap();
+    let commands = fleet.net.commands_on("web1");
+    assert_eq!(commands.len(), 6, "{:?}", commands);
+    assert!(
+        commands[0].contains(" FLEET_FLOW=roll FLEET_STEP='check[web1]' "),
+        "{}",
+        commands[0]
+    );
+    assert_eq!(commands[1], "systemctl is-active nginx");
+    assert!(
+        commands[3].contains(" FLEET_STEP=restart "),
+        "{}",
+        commands[3]
+    );
+    assert_eq!(commands[4], "systemctl restart nginx");
+}
+
+const KEY_A: &str =
+    "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIMeIHrYrRjZY/5PxPCXWEwcYR8Au6G/quoUHBCIEWBMj";
+const KEY_B: &str =
+    "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIC9YX+OasUEHXkDrw8V1lTDuBUS9jgn6zeomQ4NQF92j";
+
+/// The known_hosts lines recorded for `host`.
+fn known_hosts_for(fleet: &Fleet, host: &str) -> Vec<String> {
+    let path = crate::hostkeys::known_hosts_path(&fleet.state);
+    fs::read_to_string(path)
+        .unwrap_or_default()
+        .lines()
+        .filter(|l| l.starts_with(&format!("{} ", host)))
+        .map(String::from)
+        .collect()
+}
+
+#[test]
+fn hostkeys_records_new_hosts_and_only_update_accepts_a_changed_one() {
+    let mut fleet = Fleet::new("hostkeys", "");
+    fleet.registry.retain(|name, _| name == "web1");
+    fleet.registry.get_mut("web1").unwrap().hostname = "keys-web1.test".to_string();
+    fleet.net.on(
+        "web1",
+        "host-keys",
+        Reply::ok(&format!("keys-web1.test {}\n", KEY_A)),
+    );
+
+    assert!(fleet.run(&["hostkeys", "verify"]).is_err());
+    fleet.run(&["hostkeys", "scan"]).unwrap();
+    assert_eq!(
+        known_hosts_for(&fleet, "keys-web1.test"),
+        [format!("keys-web1.test {}", KEY_A)]
+    );
+    fleet.run(&["hostkeys", "verify", "web1"]).unwrap();
+
+    // Reinstalled: scan and verify refuse, update accepts
+    fleet.net.on(
+        "web1",
+        "host-keys",
+        Reply::ok(&format!("keys-web1.test {}\n", KEY_B)),
+    );
+    let err = fleet.run(&["hostkeys", "scan"]).unwrap_err();
+    assert_eq!(err.to_string(), "1 node(s) failed host key checks");
+    assert!(fleet.run(&["hostkeys", "verify"]).is_err());
+    assert_eq!(
+        known_hosts_for(&fleet, "keys-web1.test"),
+        [format!("keys-web1.test {}", KEY_A)]
+    );
+    assert!(
+        fleet.run(&["hostkeys", "update"]).is_err(),
+        "update needs targets"
+    );
+    fleet.run(&["hostkeys", "update", "web1"]).unwrap();
+    assert_eq!(
+        known_hosts_for(&fleet, "keys-web1.test"),
+        [format!("keys-web1.test {}", KEY_B)]
+    );
+}
+
+#[test]
+fn hostkeys_never_records_a_key_the_registry_contradicts() {
+    let mut fleet = Fleet::new("hostkeys-registry", "");
+    fleet.registry.retain(|name, _| name == "db1");
+    let db1 = fleet.registry.get_mut("db1").unwrap();
+    db1.hostname = "keys-db1.test".to_string();
+    db1.host_key = Some(KEY_A.to_string());
+    fleet.net.on(
+        "db1",
+        "host-keys",
+        Reply::ok(&format!("keys-db1.test {}\n", KEY_B)),
+    );
+
+    assert!(fleet.run(&["hostkeys", "scan"]).is_err());
+    assert!(fleet.run(&["hostkeys", "update", "--all"]).is_err());
+    assert!(known_hosts_for(&fleet, "keys-db1.test").is_empty());
+    // Asked for a key of the declared type, not whichever ssh prefers
+    assert_eq!(fleet.net.commands_on("db1")[0], "host-keys ssh-ed25519");
+}
+
+#[test]
+fn hostkeys_records_a_node_behind_a_jump_host_under_the_name_ssh_looks_up() {
+    let mut fleet = Fleet::new(
+        "hostkeys-jump",
+        r#"
+nodes:
+  web1:
+    ssh:
+      options:
+        ProxyJump: "jump.example.com"
+        HostName: "10.1.0.9"
+        Port: "2222"
+"#,
+    );
+    fleet.registry.retain(|name, _| name == "web1");
+    fleet.net.on(
+        "web1",
+        "host-keys",
+        Reply::ok(&format!("[10.1.0.9]:2222 {}\n", KEY_A)),
+    );
+
+    fleet.run(&["hostkeys", "scan"]).unwrap();
+    assert_eq!(
+        known_hosts_for(&fleet, "[10.1.0.9]:2222"),
+        [format!("[10.1.0.9]:2222 {}", KEY_A)]
+    );
+    assert!(known_hosts_for(&fleet, "10.0.0.1").is_empty());
+    // Asked of the node itself, not scanned from here
+    assert_eq!(fleet.net.commands_on("web1"), ["host-keys"]);
+    assert!(fleet.net.commands_on(LOCAL).is_empty());
+
+    fleet.net.unreachable("web1");
+    let err = fleet.run(&["hostkeys", "verify"]).unwrap_err();
+    assert_eq!(err.to_string(), "1 node(s) failed host key checks");
+}
+
+/// A file `fleet cp` copies, and the SHA-256 fleet works out for it.
+const APP_CONF: &str = "listen 80;\n";
+const APP_CONF_SUM: &str = "ae7ea37f433fb97005c5025e69e5fcefe0ea3c808e5d3a1dd0c3499958dfe539";
+
+/// What `sha256sum` prints on a node holding a copy of `APP_CONF`.
+fn app_conf_sum(path: &str) -> Reply {
+    Reply::ok(&format!("{}  {}\n", APP_CONF_SUM, path))
+}
+
+/// A pulled copy, where the fake's `pull` would have put it.
+fn pulled(out: &std::path::Path, node: &str, name: &str) {
+    fs::create_dir_all(out.join(node)).unwrap();
+    fs::write(out.join(node).join(name), APP_CONF).unwrap();
+}
+
+#[test]
+fn cp_pushes_to_every_node_at_once_and_checks_each_copy() {
+    let fleet = Fleet::new("cp-push", "");
+    let file = fleet.dir.join("app.conf");
+    fs::write(&file, APP_CONF).unwrap();
+    let slow = Duration::from_millis(300);
+    fleet
+        .net
+        .on(ANY, "push", Reply::ok("").after(slow))
+        .on(ANY, "f=/etc/app/;", app_conf_sum("/etc/app/app.conf"))
+        .on(
+            "web2",
+            "f=/etc/app/;",
+            Reply::ok("badbad  /etc/app/app.conf\n"),
+        );
+
+    let started = Instant::now();
+    let err = fleet
+        .run(&["cp", &file.display().to_string(), "@web:/etc/app/"])
+        .unwrap_err();
+    assert!(started.elapsed() < slow * 2, "{:?}", started.elapsed());
+    assert_eq!(err.to_string(), "1 node(s) failed");
+    assert_eq!(
+        fleet.net.commands_on("web1")[0],
+        format!("push {} /etc/app/", file.display())
+    );
+    assert!(fleet.net.commands_on("db1").is_empty());
+    assert!(fleet.net.commands_on(LOCAL).is_empty());
+}
+
+#[test]
+fn cp_pulls_into_a_directory_per_node() {
+    let fleet = Fleet::new("cp-pull", "");
+    let out = fleet.dir.join("out");
+    pulled(&out, "web1", "app.log");
+    fleet
+        .net
+        .on(ANY, "pull", Reply::ok(""))
+        .on(
+            ANY,
+            "sha256sum -- /var/log/app.log",
+            app_conf_sum("/var/log/app.log"),
+        )
+        .unreachable("web2");
+
+    let err = fleet
+        .run(&["cp", "@web:/var/log/app.log", &out.display().to_string()])
+        .unwrap_err();
+    assert_eq!(err.to_string(), "1 node(s) failed");
+    assert_eq!(
+        fleet.net.commands_on("web1")[0],
+        format!(
+            "pull /var/log/app.log {}",
+            out.join("web1/app.log").display()
+        )
+    );
+    // The received copy is hashed here, whatever this machine's tools
+    assert!(fleet.net.commands_on(LOCAL).is_empty());
+    assert!(out.join("web2").is_dir());
+}
+
+#[test]
+fn cp_checksums_home_relative_paths_in_the_home_directory() {
+    let fleet = Fleet::new("cp-home", "");
+    let file = fleet.dir.join("app.conf");
+    fs::write(&file, APP_CONF).unwrap();
+    let out = fleet.dir.join("out");
+    pulled(&out, "web1", "app.log");
+    pulled(&out, "web2", "app.log");
+    let push_sum = "f=\"$HOME\"/app.conf; [ -d \"$f\" ]";
+    let pull_sum = "sha256sum -- \"$HOME\"/app.log";
+    fleet
+        .net
+        .on(ANY, "push", Reply::ok(""))
+        .on(ANY, "pull", Reply::ok(""))
+        .on(ANY, push_sum, app_conf_sum("/root/app.conf"))
+        .on(ANY, pull_sum, app_conf_sum("/root/app.log"))
+        .on("web2", pull_sum, Reply::ok("badbad  /root/app.log\n"));
+
+    fleet
+        .run(&["cp", &file.display().to_string(), "@web:~/app.conf"])
+        .unwrap();
+    assert_eq!(
+        fleet.net.commands_on("web1"),
+        [
+            format!("push {} ~/app.conf", file.display()),
+            format!("{} && f=\"$f\"/app.conf; sha256sum -- \"$f\"", push_sum),
+        ]
+    );
+
+    let err = fleet
+        .run(&["cp", "@web:~/app.log", &out.display().to_string()])
+        .unwrap_err();
+    assert_eq!(err.to_string(), "1 node(s) failed");
+    assert_eq!(
+        fleet.net.commands_on("web2")[2..],
+        [
+            format!("pull ~/app.log {}", out.join("web2/app.log").display()),
+            pull_sum.to_string(),
+        ]
+    );
+}
+
+#[test]
+fn logs_reads_every_journal_at_once_and_names_the_nodes_that_failed() {
+    let fleet = Fleet::new("logs", "");
+    let slow = Duration::from_millis(300);
+    let entry = |time: u64, message: &str| {
+        format!(
+            "{{\"__REALTIME_TIMESTAMP\":\"{}\",\"SYSLOG_IDENTIFIER\":\"nginx\",\"MESSAGE\":\"{}\"}}\n",
+            time, message
+        )
+    };
+    fleet
+        .net
+        .on(
+            ANY,
+            "journalctl",
+            Reply::ok(&entry(2, "reload")).after(slow),
+        )
+        .on(
+            "web2",
+            "journalctl",
+            Reply::fail(1, "No journal files were found.").after(slow),
+        );
+
+    let started = Instant::now();
+    let err = fleet
+        .run(&[
+            "logs", "@web", "-u", "nginx", "--merge", "--since", "today", "-g", "RELOAD", "-i",
+        ])
+        .unwrap_err();
+    assert!(started.elapsed() < slow * 2, "{:?}", started.elapsed());
+    assert_eq!(err.to_string(), "1 node(s) failed");
+    assert_eq!(
+        fleet.net.commands_on("web1"),
+        ["journalctl --no-pager -o json -u nginx --since today"]
+    );
+    assert!(fleet.run(&["logs", "web1", "--grep", "("]).is_err());
+}
@@ -0,0 +1,221 @@
+//! An in-process stand-in for the network, for tests: each node answers
+//! from a script of canned replies instead of over ssh.
+
+use anyhow::{anyhow, Result};
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::transport::{Output, Target, Transport};
+
+/// What a scripted command answers.
+#[derive(Debug, Clone)]
+pub struct Reply {
+    pub code: i32,
+    pub stdout: String,
+    pub stderr: String,
+    /// How long the command "runs" before answering.
+    pub delay: Duration,
+}
+
+impl Reply {
+    pub fn ok(stdout: &str) -> Self {
+        Self {
+            code: 0,
+            stdout: stdout.to_string(),
+            stderr: String::new(),
+            delay: Duration::ZERO,
+        }
+    }
+
+    pub fn fail(code: i32, stderr: &str) -> Self {
+        Self {
+            code,
+            stdout: String::new(),
+            stderr: stderr.to_string(),
+            delay: Duration::ZERO,
+        }
+    }
+
+    pub fn after(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+}
+
+/// One command a test's code ran, in the order it ran.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Call {
+    /// The node's name, or [`LOCAL`] for a command run on this machine.
+    pub target: String,
+    pub command: String,
+}
+
+/// `Call::target` of a command run on this machine.
+pub const LOCAL: &str = "local";
+
+/// `Call::target` pattern matching any node.
+pub const ANY: &str = "*";
+
+struct Rule {
+    target: String,
+    prefix: String,
+    reply: Reply,
+}
+
+/// A transport answering from a script. A command gets the reply of the
+/// LAST rule registered for its target (or [`ANY`]) whose prefix it starts
+/// with — so a harness can script defaults and a test override them. A
+/// command nothing matches fails with exit 127, as an unknown one would.
+/// Copies are commands on the node they involve too — `push <local>
+/// <remote>` and `pull <remote> <local>` — and move no files.
+#[derive(Default)]
+pub struct FakeTransport {
+    rules: Mutex<Vec<Rule>>,
+    unreachable: Mutex<HashSet<String>>,
+    calls: Mutex<Vec<Call>>,
+}
+
+impl FakeTransport {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Answer commands on `target` starting with `prefix`.
+    pub fn on(&self, target: &str, prefix: &str, reply: Reply) -> &Self {
+        self.rules.lock().unwrap().push(Rule {
+            target: target.to_string(),
+            prefix: prefix.to_string(),
+            reply,
+        });
+        self
+    }
+
+    /// Answer local commands (`program args…`) starting with `prefix`.
+    pub fn on_local(&self, prefix: &str, reply: Reply) -> &Self {
+        self.on(LOCAL, prefix, reply)
+    }
+
+    /// Make `target` refuse connections, as ssh reports a host that is down.
+    pub fn unreachable(&self, target: &str) -> &Self {
+        self.unreachable.lock().unwrap().insert(target.to_string());
+        self
+    }
+
+    /// Every command run so far.
+    pub fn calls(&self) -> Vec<Call> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// The commands run on `target`.
+    pub fn commands_on(&self, target: &str) -> Vec<String> {
+        self.calls()
+            .into_iter()
+            .filter(|c| c.target == target)
+            .map(|c| c.command)
+            .collect()
+    }
+
+    fn answer(&self, target: &str, command: &str) -> Output {
+        self.calls.lock().unwrap().push(Call {
+            target: target.to_string(),
+            command: command.to_string(),
+        });
+        if self.unreachable.lock().unwrap().contains(target) {
+            return Output {
+                code: Some(255),
+                stdout: String::new(),
+                stderr: format!(
+                    "ssh: connect to host {} port 22: Connection timed out",
+                    target
+                ),
+            };
+        }
+        let reply = self
+            .rules
+            .lock()
+            .unwrap()
+            .iter()
+            .rev()
+            .find(|r| {
+                (r.target == target || (r.target == ANY && target != LOCAL))
+                    && command.starts_with(&r.prefix)
+            })
+            .map(|r| r.reply.clone())
+            .unwrap_or_else(|| {
+                Reply::fail(
+                    127,
+                    &format!("fake: nothing scripted for `{}` on {}", command, target),
+                )
+            });
+        // Outside the locks, so concurrent commands really overlap
+        std::thread::sleep(reply.delay);
+        Output {
+            code: Some(reply.code),
+            stdout: reply.stdout,
+            stderr: reply.stderr,
+        }
+    }
+}
+
+impl Transport for FakeTransport {
+    fn run(&self, target: &Target, command: &str) -> Result<Output> {
+        Ok(self.answer(&target.name, command))
+    }
+
+    fn interactive(&self, target: &Target, command: Option<&str>) -> anyhow::Error {
+        let command = command.unwrap_or("<login shell>");
+        self.answer(&target.name, command);
+        anyhow!("fake transport: no terminal for {}", target.name)
+    }
+
+    fn stream(&self, target: &Target, command: &str, line: &mut dyn FnMut(&str)) -> Result<Output> {
+        let mut output = self.answer(&target.name, command);
+        for text in std::mem::take(&mut output.stdout).lines() {
+            line(text);
+        }
+        Ok(output)
+    }
+
+    fn push(&self, target: &Target, from: &Path, to: &str) -> Result<Output> {
+        Ok(self.answer(&target.name, &format!("push {} {}", from.display(), to)))
+    }
+
+    fn pull(&self, target: &Target, from: &str, to: &Path) -> Result<Output> {
+        Ok(self.answer(&target.name, &format!("pull {} {}", from, to.display())))
+    }
+
+    fn host_keys(&self, target: &Target, kind: Option<&str>) -> Result<Output> {
+        let command = match kind {
+            Some(kind) => format!("host-keys {}", kind),
+            None => "host-keys".to_string(),
+        };
+        Ok(self.answer(&target.name, &command))
+    }
+
+    fn login_command(&self, target: &Target, command: Option<&str>) -> Vec<String> {
+        ["ssh", &target.name]
+            .into_iter()
+            .chain(command)
+            .map(String::from)
+            .collect()
+    }
+
+    fn hand_over(&self, program: &str, args: &[&str]) -> anyhow::Error {
+        let command = std::iter::once(program)
+            .chain(args.iter().copied())
+            .collect::<Vec<_>>()
+            .join(" ");
+        self.answer(LOCAL, &command);
+        anyhow!("fake transport: no terminal for {}", program)
+    }
+
+    fn local(&self, program: &str, args: &[&str]) -> Result<Output> {
+        let command = std::iter::once(program)
+            .chain(args.iter().copied())
+            .collect::<Vec<_>>()
+            .join(" ");
+        Ok(self.answer(LOCAL, &command))
+    }
+}
@@ -195,7 +195,7 @@ fn extract_url(line: &str) -> Option<String> {
     let start = line.find("https://")?;
     let tail = &line[start..];
     let end = tail
-        .find(|c: char| c == '\'' || c == '"' || c == ' ' || c == ')')
+        .find(['\'', '"', ' ', ')'])
         .unwrap_or(tail.len());
     let url = &tail[..end];
     if url.len() > "https://".len() {
@@ -276,6 +276,7 @@ impl RecoveryPlan {
     /// Accept the builder's result only if its hash equals the lock's pin.
     ///
     /// This is the check that separates a hermetic recovery from a hole.
+    #[allow(clippy::result_large_err)]
     pub fn verify(&self, reported_nar_hash: &str) -> Result<(), HashMismatch> {
         if reported_nar_hash == self.expected_nar_hash {
             Ok(())
@@ -1,7 +1,11 @@
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
+use std::borrow::Cow;
 use std::collections::HashMap;
 
-use crate::config::FlowDef;
+use crate::condition::Expr;
+use crate::config::{ActionDef, ConditionDef, FlowDef, StepDef};
+use crate::registry::NodeRegistry;
+use crate::targeting;
 
 /// A validated flow with step indices resolved from string IDs.
 #[derive(Debug)]
@@ -10,8 +14,21 @@ pub struct ValidatedFlow {
     pub deps: Vec<Vec<usize>>,
 }
 
-/// Validate a flow definition: check for duplicate IDs, unknown deps, and cycles.
-pub fn validate(flow: &FlowDef) -> Result<ValidatedFlow> {
+/// Validate the named flow: its own steps (duplicate IDs, unknown deps,
+/// cycles, template references) and every flow it runs as a sub-flow,
+/// including cycles across flow references.
+pub fn validate(flows: &HashMap<String, FlowDef>, name: &str) -> Result<ValidatedFlow> {
+    let flow = flows
+        .get(name)
+        .ok_or_else(|| anyhow::anyhow!("Unknown flow: '{}'", name))?;
+    let validated = validate_steps(flow)?;
+    validate_subflows(flows, name, &mut vec![name], &mut Vec::new())?;
+    Ok(validated)
+}
+
+/// Validate one flow's steps in isolation. Also run on the output of
+/// [`expand_foreach`] to order the per-node instances.
+pub fn validate_steps(flow: &FlowDef) -> Result<ValidatedFlow> {
     let mut id_to_idx: HashMap<&str, usize> = HashMap::new();
 
     // Check for duplicate step IDs
@@ -21,6 +38,19 @@ pub fn validate(flow: &FlowDef) -> Result<ValidatedFlow> {
         }
     }
 
+    // Fan-out settings are only meaningful together
+    for step in &flow.steps {
+        if step.foreach.is_some() && !step.targets.is_empty() {
+            bail!(
+                "Step '{}' has both `foreach` and `targets` — foreach sets each instance's target",
+                step.id
+            );
+        }
+        if step.serial && step.foreach.is_none() && step.instance_of.is_none() {
+            bail!("Step '{}' sets `serial` without `foreach`", step.id);
+        }
+    }
+
     // Resolve depends_on to indices, check for unknown deps
     let mut deps: Vec<Vec<usize>> = Vec::with_capacity(flow.steps.len());
     for step in &flow.steps {
@@ -37,9 +67,505 @@ pub fn validate(flow: &FlowDef) -> Result<ValidatedFlow> {
     // Cycle detection via DFS coloring
     detect_cycle(flow, &deps)?;
 
+    // Template references in env blocks, checked before anything runs
+    validate_references(flow, &id_to_idx, &deps, flow.steps.len())?;
+
+    validate_cleanup(flow, &deps)?;
+
     Ok(ValidatedFlow { deps })
 }
 
+/// Check the `on_failure` and `finally` steps. They run one at a time, in
+/// the order listed, after every main step has finished, so they take no
+/// `depends_on` or `foreach`. Their references are checked as if each
+/// depended on every main step and every cleanup step listed before it.
+fn validate_cleanup(flow: &FlowDef, deps: &[Vec<usize>]) -> Result<()> {
+    for (list, steps) in [("on_failure", &flow.on_failure), ("finally", &flow.finally)] {
+        for step in steps {
+            if !step.depends_on.is_empty() {
+                bail!(
+                    "Step '{}' in `{}` sets depends_on — cleanup steps run in the order listed, \
+                     after the main steps",
+                    step.id,
+                    list
+                );
+            }
+            if step.foreach.is_some() || step.serial {
+                bail!(
+                    "Step '{}' in `{}` uses foreach, which cleanup steps do not support",
+                    step.id,
+                    list
+                );
+            }
+        }
+    }
+    if flow.on_failure.is_empty() && flow.finally.is_empty() {
+        return Ok(());
+    }
+
+    let combined = FlowDef {
+        steps: flow.all_steps().cloned().collect(),
+        on_failure: Vec::new(),
+        finally: Vec::new(),
+        ..flow.clone()
+    };
+    let mut id_to_idx: HashMap<&str, usize> = HashMap::new();
+    for (i, step) in combined.steps.iter().enumerate() {
+        if id_to_idx.insert(&step.id, i).is_some() {
+            bail!(
+                "Duplicate step ID '{}' (IDs must be unique across steps, on_failure and finally)",
+                step.id
+            );
+        }
+    }
+    let main = flow.steps.len();
+    let deps: Vec<Vec<usize>> = deps
+        .iter()
+        .cloned()
+        .chain((main..combined.steps.len()).map(|i| (0..i).collect()))
+        .collect();
+    validate_references(&combined, &id_to_idx, &deps, main)
+}
+
+/// Walk `type: flow` steps depth-first. `stack` is the chain of flows being
+/// expanded (a repeat is a cycle); `done` holds flows already checked, so a
+/// sub-flow shared by several steps is validated once.
+fn validate_subflows<'a>(
+    flows: &'a HashMap<String, FlowDef>,
+    name: &'a str,
+    stack: &mut Vec<&'a str>,
+    done: &mut Vec<&'a str>,
+) -> Result<()> {
+    for step in flows[name].all_steps() {
+        let ActionDef::Flow { name: sub, params } = &step.action else {
+            continue;
+        };
+        let Some((sub_name, sub_flow)) = flows.get_key_value(sub) else {
+            bail!(
+                "Step '{}' in flow '{}' runs unknown flow '{}'",
+                step.id,
+                name,
+                sub
+            );
+        };
+        if let Some(pos) = stack.iter().position(|f| f == sub) {
+            bail!(
+                "Flow cycle detected: {} → {}",
+                stack[pos..].join(" → "),
+                sub
+            );
+        }
+
+        for param in params.keys() {
+            if !sub_flow.params.contains_key(param) {
+                bail!(
+                    "Step '{}' in flow '{}' passes unknown param '{}' to flow '{}'",
+                    step.id,
+                    name,
+                    param,
+                    sub
+                );
+            }
+        }
+        for (param, def) in &sub_flow.params {
+            if def.default.is_none() && !params.contains_key(param) {
+                bail!(
+                    "Step '{}' in flow '{}' does not pass required param '{}' to flow '{}'",
+                    step.id,
+                    name,
+                    param,
+                    sub
+                );
+            }
+        }
+
+        if done.contains(&sub_name.as_str()) {
+            continue;
+        }
+        validate_steps(sub_flow).map_err(|e| anyhow::anyhow!("In flow '{}': {}", sub, e))?;
+        stack.push(sub_name);
+        validate_subflows(flows, sub_name, stack, done)?;
+        stack.pop();
+        done.push(sub_name);
+    }
+    Ok(())
+}
+
+/// What `${failure.<field>}` can read in a cleanup step: the failed step's
+/// ID, its action type, and the error. All empty when nothing failed.
+pub const FAILURE_FIELDS: &[&str] = &["step", "action", "error"];
+
+/// A piece of a templated `env:` value.
+#[derive(Debug, PartialEq)]
+pub enum Segment<'a> {
+    /// Text copied through unchanged (`$${` has already become `${`).
+    Literal(&'a str),
+    /// A `${...}` reference.
+    Ref(TemplateRef<'a>),
+}
+
+/// One `${reference}` or `${reference:-default}` occurrence.
+#[derive(Debug, PartialEq)]
+pub struct TemplateRef<'a> {
+    /// Everything between `${` and `}`, for error messages.
+    pub raw: &'a str,
+    pub reference: Reference<'a>,
+    pub default: Option<&'a str>,
+}
+
+/// What a `${...}` reference points at.
+#[derive(Debug, PartialEq)]
+pub enum Reference<'a> {
+    /// `${secrets.<name>}` — a flow-level secret.
+    Secret(&'a str),
+    /// `${params.<name>}` — a parameter of the running flow.
+    Param(&'a str),
+    /// `${failure.<field>}` — what made the main steps fail, in cleanup steps.
+    Failure(&'a str),
+    /// `${<step_id>.<output>}` — an output captured from an earlier step.
+    Output { step: &'a str, name: &'a str },
+    /// Anything else, like `${HOME}`. Only a flow with `strict_templates:
+    /// false` has these; they resolve to their fallback or to nothing.
+    Other(&'a str),
+}
+
+impl<'a> Reference<'a> {
+    fn parse(reference: &'a str) -> Result<Self> {
+        if let Some(name) = reference.strip_prefix("secrets.") {
+            if !name.is_empty() {
+                return Ok(Reference::Secret(name));
+            }
+        } else if let Some(name) = reference.strip_prefix("params.") {
+            if !name.is_empty() {
+                return Ok(Reference::Param(name));
+            }
+        } else if let Some(field) = reference.strip_prefix("failure.") {
+            if !field.is_empty() {
+                return Ok(Reference::Failure(field));
+            }
+        } else if let Some((step, name)) = reference.split_once('.') {
+            if !step.is_empty() && !name.is_empty() {
+                return Ok(Reference::Output { step, name });
+            }
+        }
+        bail!(
+            "`${{{}}}` is not a `${{step_id.output}}`, `${{secrets.name}}`, `${{params.name}}` \
+             or `${{failure.field}}` reference (write `$${{` for a literal `${{`)",
+            reference
+        )
+    }
+}
+
+/// Split a templated value into literals and references.
+///
+/// `$${` is the escape for a literal `${`. A reference may carry a fallback
+/// after `:-`, used when the reference does not resolve. When `strict`, an
+/// unterminated `${` or a reference that is none of the known forms is an
+/// error rather than text, so a typo cannot quietly become part of a value;
+/// otherwise the first is kept as text and the second is `Reference::Other`.
+pub fn parse_template(template: &str, strict: bool) -> Result<Vec<Segment<'_>>> {
+    let mut segments = Vec::new();
+    let mut rest = template;
+
+    while let Some(pos) = rest.find('$') {
+        let tail = &rest[pos..];
+        if let Some(after) = tail.strip_prefix("$${") {
+            segments.push(Segment::Literal(&rest[..pos]));
+            segments.push(Segment::Literal("${"));
+            rest = after;
+        } else if let Some(body) = tail.strip_prefix("${") {
+            let Some(end) = body.find('}') else {
+                if !strict {
+                    break;
+                }
+                bail!("Unterminated `${{` in '{}'", template);
+            };
+            segments.push(Segment::Literal(&rest[..pos]));
+            let raw = &body[..end];
+            let (reference, default) = match raw.split_once(":-") {
+                Some((r, d)) => (r, Some(d)),
+                None => (raw, None),
+            };
+            segments.push(Segment::Ref(TemplateRef {
+                raw,
+                reference: match Reference::parse(reference) {
+                    Err(_) if !strict => Reference::Other(reference),
+                    parsed => parsed?,
+                },
+                default,
+            }));
+            rest = &body[end + 1..];
+        } else {
+            segments.push(Segment::Literal(&rest[..=pos]));
+            rest = &rest[pos + 1..];
+        }
+    }
+    segments.push(Segment::Literal(rest));
+    segments.retain(|s| *s != Segment::Literal(""));
+
+    Ok(segments)
+}
+
+/// Check every `${...}` in every step's templated block (`env:`, or the
+/// `params:` passed to a sub-flow) and declarative condition against the
+/// flow: secrets and params must be declared, steps must exist, and a step
+/// whose output is required must be upstream of the step reading it,
+/// otherwise whether the output exists depends on scheduling luck.
+///
+/// A flow with `strict_templates: false` opts out of all of this for its
+/// templated blocks: what does not resolve there becomes empty at run time,
+/// as before strict templates existed. Its conditions must still name steps,
+/// secrets and params that exist, but need not depend on the steps they
+/// read.
+///
+/// A reference with a `:-` fallback is allowed to name a step that is not
+/// upstream (or one skipped by its condition); the fallback covers it. A
+/// condition's `failed(step)`-style status checks have no fallback, so
+/// their step must always be upstream.
+///
+/// Steps from index `cleanup_from` on are cleanup steps, the only ones that
+/// may read `${failure.<field>}`.
+fn validate_references(
+    flow: &FlowDef,
+    id_to_idx: &HashMap<&str, usize>,
+    deps: &[Vec<usize>],
+    cleanup_from: usize,
+) -> Result<()> {
+    for (i, step) in flow.steps.iter().enumerate() {
+        let cleanup = i >= cleanup_from;
+        if let Some((block, values)) = step.action.templated() {
+            let mut keys: Vec<_> = values.keys().collect();
+            keys.sort();
+
+            for key in keys {
+                let at = format!("Step '{}' {} {}", step.id, block, key);
+                let segments = parse_template(&values[key], flow.strict_templates)
+                    .map_err(|e| anyhow::anyhow!("{}: {}", at, e))?;
+                if !flow.strict_templates {
+                    continue;
+                }
+                for segment in segments {
+                    if let Segment::Ref(r) = segment {
+                        check_reference(flow, id_to_idx, deps, i, cleanup, &at, &r)?;
+                    }
+                }
+            }
+        }
+
+        if let Some(ConditionDef::Expr(src)) = &step.condition {
+            let at = format!("Step '{}' condition", step.id);
+            let expr = Expr::parse(src).map_err(|e| anyhow::anyhow!("{} `{}`: {}", at, src, e))?;
+            for r in expr.references() {
+                check_reference(flow, id_to_idx, deps, i, cleanup, &at, r)?;
+            }
+            for target in expr.status_steps() {
+                let Some(target_idx) = resolve_step_ref(flow, id_to_idx, target) else {
+                    bail!("{} checks the status of unknown step '{}'", at, target);
+                };
+                if !is_upstream(target_idx, i, deps) {
+                    bail!(
+                        "{} checks the status of '{}' but does not depend on it",
+                        at,
+                        target
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check one reference made by step `i`; `at` says where, for errors.
+fn check_reference(
+    flow: &FlowDef,
+    id_to_idx: &HashMap<&str, usize>,
+    deps: &[Vec<usize>],
+    i: usize,
+    cleanup: bool,
+    at: &str,
+    r: &TemplateRef,
+) -> Result<()> {
+    match r.reference {
+        Reference::Failure(field) => {
+            if !cleanup {
+                bail!(
+                    "{} reads `${{{}}}`, which is only set in on_failure and finally steps",
+                    at,
+                    r.raw
+                );
+            }
+            if !FAILURE_FIELDS.contains(&field) {
+                bail!(
+                    "{} reads unknown failure field '{}' (fields: {})",
+                    at,
+                    field,
+                    FAILURE_FIELDS.join(", ")
+                );
+            }
+        }
+        Reference::Secret(name) => {
+            if !flow.secrets.contains_key(name) {
+                bail!(
+                    "{} references undeclared secret '{}' (declared: {})",
+                    at,
+                    name,
+                    declared(flow.secrets.keys())
+                );
+            }
+        }
+        Reference::Param(name) => {
+            if !flow.params.contains_key(name) {
+                bail!(
+                    "{} references undeclared param '{}' (declared: {})",
+                    at,
+                    name,
+                    declared(flow.params.keys())
+                );
+            }
+        }
+        Reference::Output { step: target, .. } => {
+            let Some(target_idx) = resolve_step_ref(flow, id_to_idx, target) else {
+                bail!(
+                    "{} references unknown step '{}' in `${{{}}}`",
+                    at,
+                    target,
+                    r.raw
+                );
+            };
+            if flow.strict_templates && r.default.is_none() && !is_upstream(target_idx, i, deps) {
+                bail!(
+                    "{} reads `${{{}}}` but does not depend on '{}' \
+                     (add it to depends_on, or give a fallback with `:-`)",
+                    at,
+                    r.raw,
+                    target
+                );
+            }
+        }
+        // Only in non-strict templates, which are not checked
+        Reference::Other(_) => {}
+    }
+    Ok(())
+}
+
+/// Index of the step a reference names. Before expansion, the per-node
+/// instance `<id>[<node>]` of a `foreach` step resolves to the step itself;
+/// whether that node is actually matched is checked once the flow is
+/// expanded, when the instance exists under its own ID.
+fn resolve_step_ref(flow: &FlowDef, id_to_idx: &HashMap<&str, usize>, id: &str) -> Option<usize> {
+    if let Some(&idx) = id_to_idx.get(id) {
+        return Some(idx);
+    }
+    let (base, _node) = id.strip_suffix(']')?.split_once('[')?;
+    let &idx = id_to_idx.get(base)?;
+    flow.steps[idx].foreach.is_some().then_some(idx)
+}
+
+/// ID of the instance of fan-out step `id` that targets `node`.
+pub fn instance_id(id: &str, node: &str) -> String {
+    format!("{}[{}]", id, node)
+}
+
+/// Expand `foreach` steps into one instance per node their selector
+/// matches, in registry order. Each instance targets its node alone and
+/// sees it as `FLEET_NODE` in its `env:` block; with `serial: true` each
+/// instance also depends on the one before it. A dependency on the
+/// fan-out step becomes a dependency on all of its instances.
+///
+/// A flow without `foreach` is returned as-is.
+pub fn expand_foreach<'a>(flow: &'a FlowDef, registry: &NodeRegistry) -> Result<Cow<'a, FlowDef>> {
+    if flow.steps.iter().all(|s| s.foreach.is_none()) {
+        return Ok(Cow::Borrowed(flow));
+    }
+
+    let mut nodes: HashMap<&str, Vec<String>> = HashMap::new();
+    for step in &flow.steps {
+        if let Some(selector) = &step.foreach {
+            let resolved = targeting::resolve(registry, std::slice::from_ref(selector), false)
+                .with_context(|| format!("Step '{}' foreach '{}'", step.id, selector))?;
+            let names = resolved.names().into_iter().map(String::from).collect();
+            nodes.insert(&step.id, names);
+        }
+    }
+
+    let mut steps = Vec::with_capacity(flow.steps.len());
+    for step in &flow.steps {
+        let depends_on: Vec<String> = step
+            .depends_on
+            .iter()
+            .flat_map(|dep| match nodes.get(dep.as_str()) {
+                Some(dep_nodes) => dep_nodes.iter().map(|n| instance_id(dep, n)).collect(),
+                None => vec![dep.clone()],
+            })
+            .collect();
+
+        let Some(step_nodes) = nodes.get(step.id.as_str()) else {
+            steps.push(StepDef {
+                depends_on,
+                ..step.clone()
+            });
+            continue;
+        };
+        for (i, node) in step_nodes.iter().enumerate() {
+            let mut instance = StepDef {
+                id: instance_id(&step.id, node),
+                targets: vec![node.clone()],
+                depends_on: depends_on.clone(),
+                foreach: None,
+                instance_of: Some(step.id.clone()),
+                ..step.clone()
+            };
+            if step.serial && i > 0 {
+                instance
+                    .depends_on
+                    .push(instance_id(&step.id, &step_nodes[i - 1]));
+            }
+            if let Some(env) = instance.action.env_mut() {
+                env.entry("FLEET_NODE".to_string())
+                    .or_insert_with(|| node.clone());
+            }
+            steps.push(instance);
+        }
+    }
+
+    Ok(Cow::Owned(FlowDef {
+        description: flow.description.clone(),
+        secrets: flow.secrets.clone(),
+        strict_templates: flow.strict_templates,
+        failure_mode: flow.failure_mode,
+        params: flow.params.clone(),
+        steps,
+        on_failure: flow.on_failure.clone(),
+        finally: flow.finally.clone(),
+        source: flow.source.clone(),
+    }))
+}
+
+/// Sorted, comma-separated names for an error message.
+fn declared<'a>(names: impl Iterator<Item = &'a String>) -> String {
+    let mut names: Vec<_> = names.map(String::as_str).collect();
+    if names.is_empty() {
+        return "none".to_string();
+    }
+    names.sort();
+    names.join(", ")
+}
+
+/// Whether `ancestor` is reachable from `step` by following `depends_on`.
+pub fn is_upstream(ancestor: usize, step: usize, deps: &[Vec<usize>]) -> bool {
+    let mut stack = deps[step].clone();
+    let mut seen = vec![false; deps.len()];
+    while let Some(next) = stack.pop() {
+        if next == ancestor {
+            return true;
+        }
+        if !std::mem::replace(&mut seen[next], true) {
+            stack.extend(&deps[next]);
+        }
+    }
+    false
+}
+
 /// DFS coloring: White=0, Gray=1, Black=2. Gray→Gray edge = cycle.
 fn detect_cycle(flow: &FlowDef, deps: &[Vec<usize>]) -> Result<()> {
     let n = flow.steps.len();
@@ -1,75 +1,520 @@
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
+use std::collections::HashSet;
+use std::os::unix::process::CommandExt;
 use std::process::Command;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use crate::commands::utils::{log_info, log_warning};
-use crate::config::FleetConfig;
+use crate::commands::utils::{flake_dir, log_info, log_warning, node_label};
+use crate::config::{FleetConfig, HookPair};
 use crate::registry::Node;
+use crate::targeting::ResolvedTargets;
+use crate::transport::{shell_quote, Target, Transport};
+
+/// The commands that run `hooks.<command>`, in the order `fleet config show`
+/// lists them. A flow step runs the hooks of its action's type.
+pub const HOOKED_COMMANDS: &[&str] = &[
+    "deploy", "build", "diff", "exec", "rollback", "reboot", "status", "ping", "ssh", "rebuild",
+];
+
+/// The commands that act on all their nodes at once — colmena does, for
+/// more than one — so their per-node hooks run at once too.
+const PARALLEL_COMMANDS: &[&str] = &["deploy", "build"];
+
+/// The line a remote hook's shell prints before the hook itself runs, so an
+/// exit code of 255 can be told apart: ssh's, for a connection that never
+/// got that far, or the hook's own.
+const REMOTE_STARTED: &str = "fleet-hook-started";
+
+/// How long a hook that was timed out gets to exit after SIGTERM.
+const KILL_GRACE: Duration = Duration::from_secs(2);
+
+/// The operation hooks run around, exported to them as `FLEET_*`.
+pub struct HookContext<'a> {
+    pub command: &'a str,
+    pub targets: &'a ResolvedTargets,
+    /// The flow and step running the operation, if a flow is.
+    pub flow: Option<(&'a str, &'a str)>,
+    /// How to reach the nodes, for `remote_pre` and `remote_post`; they
+    /// do not run without one.
+    pub transport: Option<&'a dyn Transport>,
+}
+
+impl<'a> HookContext<'a> {
+    pub fn new(command: &'a str, targets: &'a ResolvedTargets) -> Self {
+        Self {
+            command,
+            targets,
+            flow: None,
+            transport: None,
+        }
+    }
+
+    pub fn over(mut self, transport: &'a dyn Transport) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+
+    pub fn in_flow(mut self, flow: &'a str, step: &'a str) -> Self {
+        self.flow = Some((flow, step));
+        self
+    }
+}
+
+/// How an operation ended, for the hooks that run after it.
+#[derive(Clone, Copy)]
+enum Outcome<'a> {
+    Success,
+    Failure(&'a str),
+}
+
+/// Run `op` inside its command's hooks: `pre_all` once, every node's
+/// pre-hook and then `remote_pre` (the first failure aborts), then `op`;
+/// after it, every node's `remote_post` either way, then its post-hook if
+/// it succeeded or `on_failure` hook if it — or a pre-hook — failed, then
+/// `post_all` once either way. Per-node hooks after the operation run only for the nodes
+/// whose pre-hooks were reached: none when `pre_all` fails. Hooks that run
+/// after the operation only warn when they fail themselves.
+pub fn around<T>(
+    config: &FleetConfig,
+    ctx: &HookContext,
+    op: impl FnOnce() -> Result<T>,
+) -> Result<T> {
+    let hook = config.hook(ctx.command);
+    let started = Mutex::new(HashSet::new());
+    let result = run_pre_all(&hook, ctx)
+        .and_then(|()| {
+            each_node(ctx, |name, node| {
+                started.lock().unwrap().insert(name.to_string());
+                run_pre(&hook, ctx, name, node)?;
+                run_remote_pre(config, &hook, ctx, name, node)
+            })
+        })
+        .and_then(|()| op());
+    let error = result.as_ref().err().map(|e| format!("{:#}", e));
+    let outcome = match &error {
+        None => Outcome::Success,
+        Some(error) => Outcome::Failure(error),
+    };
+    let started = started.into_inner().unwrap();
+    let _ = each_node(ctx, |name, node| {
+        if !started.contains(name) {
+            return Ok(());
+        }
+        run_remote_post(config, &hook, ctx, name, node, outcome);
+        run_after(&hook, ctx, name, node, outcome);
+        Ok(())
+    });
+    run_post_all(&hook, ctx, outcome);
+    result
+}
+
+/// Run `f` for every target node: one after another, stopping at the first
+/// error — or, for a command that acts on its nodes in parallel, all at
+/// once, returning the first error in target order.
+fn each_node(ctx: &HookContext, f: impl Fn(&str, &Node) -> Result<()> + Sync) -> Result<()> {
+    let nodes = &ctx.targets.nodes;
+    if nodes.len() < 2 || !PARALLEL_COMMANDS.contains(&ctx.command) {
+        return nodes.iter().try_for_each(|(name, node)| f(name, node));
+    }
+    let results: Vec<Result<()>> = std::thread::scope(|s| {
+        let handles: Vec<_> = nodes
+            .iter()
+            .map(|(name, node)| {
+                let f = &f;
+                s.spawn(move || f(name, node))
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|h| {
+                h.join()
+                    .unwrap_or_else(|_| Err(anyhow::anyhow!("hook thread panicked")))
+            })
+            .collect()
+    });
+    results.into_iter().collect()
+}
+
+/// Run the once-per-invocation pre-hook, if configured. Aborts on failure.
+fn run_pre_all(hook: &HookPair, ctx: &HookContext) -> Result<()> {
+    if let Some(ref script) = hook.pre_all {
+        log_info(&format!("Running pre_all-{} hook", ctx.command));
+        if let Err(e) = run_timed(&mut command(script, ctx), hook.timeout) {
+            bail!("Pre_all-{} hook failed ({})", ctx.command, e);
+        }
+    }
+    Ok(())
+}
 
 /// Run the pre-hook for a command, if configured. Aborts on failure.
-pub fn run_pre(
+fn run_pre(hook: &HookPair, ctx: &HookContext, node_name: &str, node: &Node) -> Result<()> {
+    if let Some(ref script) = hook.pre {
+        log_info(&format!(
+            "Running pre-{} hook for {}",
+            ctx.command, node_name
+        ));
+        let mut cmd = node_command(script, ctx, node_name, node);
+        if let Err(e) = run_timed(&mut cmd, hook.timeout) {
+            bail!("Pre-{} hook failed for {} ({})", ctx.command, node_name, e);
+        }
+    }
+    Ok(())
+}
+
+/// Run the remote pre-hook on the node itself, if configured. Aborts on
+/// failure.
+fn run_remote_pre(
     config: &FleetConfig,
-    command_name: &str,
+    hook: &HookPair,
+    ctx: &HookContext,
     node_name: &str,
     node: &Node,
 ) -> Result<()> {
-    if let Some(hook) = config.hooks.get(command_name) {
-        if let Some(ref script) = hook.pre {
-            log_info(&format!(
-                "Running pre-{} hook for {}",
-                command_name, node_name
-            ));
-            let status = Command::new("sh")
-                .arg("-c")
-                .arg(script)
-                .env("FLEET_NODE", node_name)
-                .env("FLEET_HOST", &node.hostname)
-                .env("FLEET_USER", &node.ssh_user)
-                .status()?;
-            if !status.success() {
-                bail!(
-                    "Pre-{} hook failed for {} (exit {})",
-                    command_name,
-                    node_name,
-                    status.code().unwrap_or(-1)
-                );
-            }
-        }
+    let (Some(script), Some(transport)) = (&hook.remote_pre, ctx.transport) else {
+        return Ok(());
+    };
+    log_info(&format!(
+        "Running remote_pre-{} hook on {}",
+        ctx.command, node_name
+    ));
+    let command = remote_command(script, hook.timeout, ctx, node_name, node, None);
+    if let Err(e) = run_remote(config, transport, &command, hook.timeout, node_name, node) {
+        bail!(
+            "Remote pre-{} hook failed on {} ({})",
+            ctx.command,
+            node_name,
+            e
+        );
     }
     Ok(())
 }
 
-/// Run the post-hook for a command, if configured. Warns on failure but does not abort.
-pub fn run_post(config: &FleetConfig, command_name: &str, node_name: &str, node: &Node) {
-    if let Some(hook) = config.hooks.get(command_name) {
-        if let Some(ref script) = hook.post {
-            log_info(&format!(
-                "Running post-{} hook for {}",
-                command_name, node_name
-            ));
-            match Command::new("sh")
-                .arg("-c")
-                .arg(script)
-                .env("FLEET_NODE", node_name)
-                .env("FLEET_HOST", &node.hostname)
-                .env("FLEET_USER", &node.ssh_user)
-                .status()
-            {
-                Ok(status) if !status.success() => {
-                    log_warning(&format!(
-                        "Post-{} hook failed for {} (exit {})",
-                        command_name,
-                        node_name,
-                        status.code().unwrap_or(-1)
-                    ));
-                }
-                Err(e) => {
-                    log_warning(&format!(
-                        "Post-{} hook error for {}: {}",
-                        command_name, node_name, e
-                    ));
-                }
-                _ => {}
+/// Run the remote post-hook on the node itself, if configured, whatever the
+/// outcome — undoing what `remote_pre` did matters most when the operation
+/// failed. Warns on failure but does not abort.
+fn run_remote_post(
+    config: &FleetConfig,
+    hook: &HookPair,
+    ctx: &HookContext,
+    node_name: &str,
+    node: &Node,
+    outcome: Outcome,
+) {
+    let (Some(script), Some(transport)) = (&hook.remote_post, ctx.transport) else {
+        return;
+    };
+    log_info(&format!(
+        "Running remote_post-{} hook on {}",
+        ctx.command, node_name
+    ));
+    let command = remote_command(script, hook.timeout, ctx, node_name, node, Some(outcome));
+    if let Err(e) = run_remote(config, transport, &command, hook.timeout, node_name, node) {
+        log_warning(&format!(
+            "remote_post-{} hook failed on {} ({})",
+            ctx.command, node_name, e
+        ));
+    }
+}
+
+/// Run the post-hook, or the failure hook, for a command, if configured.
+/// Warns on failure but does not abort.
+fn run_after(hook: &HookPair, ctx: &HookContext, node_name: &str, node: &Node, outcome: Outcome) {
+    let (when, script) = match outcome {
+        Outcome::Success => ("post", &hook.post),
+        Outcome::Failure(_) => ("on_failure", &hook.on_failure),
+    };
+    let Some(script) = script else {
+        return;
+    };
+    log_info(&format!(
+        "Running {}-{} hook for {}",
+        when, ctx.command, node_name
+    ));
+    let mut cmd = node_command(script, ctx, node_name, node);
+    set_outcome(&mut cmd, outcome);
+    if let Err(e) = run_timed(&mut cmd, hook.timeout) {
+        log_warning(&format!(
+            "{}-{} hook failed for {} ({})",
+            when, ctx.command, node_name, e
+        ));
+    }
+}
+
+/// Run the once-per-invocation post-hook, if configured, whatever the
+/// outcome. Warns on failure but does not abort.
+fn run_post_all(hook: &HookPair, ctx: &HookContext, outcome: Outcome) {
+    let Some(ref script) = hook.post_all else {
+        return;
+    };
+    log_info(&format!("Running post_all-{} hook", ctx.command));
+    let mut cmd = command(script, ctx);
+    set_outcome(&mut cmd, outcome);
+    if let Err(e) = run_timed(&mut cmd, hook.timeout) {
+        log_warning(&format!("post_all-{} hook failed ({})", ctx.command, e));
+    }
+}
+
+fn set_outcome(cmd: &mut Command, outcome: Outcome) {
+    cmd.envs(outcome_environment(outcome));
+}
+
+/// How the operation ended, as `FLEET_OUTCOME` and, on failure, `FLEET_ERROR`.
+fn outcome_environment(outcome: Outcome) -> Vec<(&'static str, String)> {
+    match outcome {
+        Outcome::Success => vec![("FLEET_OUTCOME", "success".to_string())],
+        Outcome::Failure(error) => vec![
+            ("FLEET_OUTCOME", "failure".to_string()),
+            ("FLEET_ERROR", error.to_string()),
+        ],
+    }
+}
+
+/// The operation's context, as `FLEET_*` variables — with one node's
+/// details when a hook runs for one.
+fn environment(ctx: &HookContext, node: Option<(&str, &Node)>) -> Vec<(&'static str, String)> {
+    let mut env = vec![
+        ("FLEET_COMMAND", ctx.command.to_string()),
+        ("FLEET_TARGETS", ctx.targets.names().join(",")),
+    ];
+    if let Some((flow, step)) = ctx.flow {
+        env.extend([
+            ("FLEET_FLOW", flow.to_string()),
+            ("FLEET_STEP", step.to_string()),
+        ]);
+    }
+    if let Some((name, node)) = node {
+        env.extend([
+            ("FLEET_NODE", name.to_string()),
+            ("FLEET_HOST", node.hostname.clone()),
+            ("FLEET_USER", node.ssh_user.clone()),
+            ("FLEET_SYSTEM", node.system.clone()),
+            ("FLEET_TAGS", node.tags.join(",")),
+        ]);
+    }
+    env
+}
+
+/// `sh -c <script>` with the operation's context in its environment.
+fn command(script: &str, ctx: &HookContext) -> Command {
+    let mut cmd = Command::new("sh");
+    cmd.arg("-c")
+        .arg(script)
+        .envs(environment(ctx, None))
+        .env("FLEET_FLAKE_DIR", flake_dir());
+    cmd
+}
+
+/// `command`, with one node's details added.
+fn node_command(script: &str, ctx: &HookContext, node_name: &str, node: &Node) -> Command {
+    let mut cmd = command(script, ctx);
+    cmd.envs(environment(ctx, Some((node_name, node))));
+    cmd
+}
+
+/// The command line that runs `script` on a node: under `sh -c`, with the
+/// operation's context — and its outcome, for a hook that runs after it —
+/// in its environment but not the local flake's path, stderr folded into
+/// stdout, and `timeout(1)` keeping the limit. The script is handed to an
+/// outer shell as it is, after that shell prints `REMOTE_STARTED`.
+fn remote_command(
+    script: &str,
+    timeout: Option<u64>,
+    ctx: &HookContext,
+    node_name: &str,
+    node: &Node,
+    outcome: Option<Outcome>,
+) -> String {
+    let mut env = environment(ctx, Some((node_name, node)));
+    env.extend(outcome.map(outcome_environment).unwrap_or_default());
+    let mut words = vec!["env".to_string()];
+    for (key, value) in env {
+        words.push(format!("{}={}", key, shell_quote(&value)));
+    }
+    if let Some(timeout) = timeout {
+        words.extend(["timeout".to_string(), timeout.to_string()]);
+    }
+    let start = format!("echo {} && exec sh -c \"$1\"", REMOTE_STARTED);
+    words.extend(["sh", "-c"].map(String::from));
+    words.extend([
+        shell_quote(&start),
+        "fleet-hook".to_string(),
+        shell_quote(script),
+    ]);
+    words.push("2>&1".to_string());
+    words.join(" ")
+}
+
+/// Run a hook's `remote_command` on its node, printing each line it writes
+/// behind the node's label.
+fn run_remote(
+    config: &FleetConfig,
+    transport: &dyn Transport,
+    command: &str,
+    timeout: Option<u64>,
+    node_name: &str,
+    node: &Node,
+) -> Result<()> {
+    let label = node_label(node_name);
+    let mut started = false;
+    let output = transport.stream(
+        &Target::node(config, node_name, node),
+        command,
+        &mut |line| {
+            if !started && line == REMOTE_STARTED {
+                started = true;
+            } else {
+                println!("{} {}", label, line)
             }
+        },
+    )?;
+    match output.code {
+        Some(0) => Ok(()),
+        // timeout(1)'s own exit code for a command it had to stop
+        Some(124) if timeout.is_some() => bail!("timed out after {}s", timeout.unwrap_or_default()),
+        // ssh's, for a connection that failed before the hook could start
+        Some(255) if !started => bail!("{}", output.stderr.trim()),
+        Some(code) => bail!("exit {}", code),
+        None => bail!("killed by a signal"),
+    }
+}
+
+/// Run a hook to completion, or for at most `timeout` seconds: then its
+/// whole process group — a hook's own children included — is terminated.
+fn run_timed(cmd: &mut Command, timeout: Option<u64>) -> Result<()> {
+    cmd.process_group(0);
+    let mut child = cmd.spawn().context("could not start sh")?;
+    let limit = timeout.map(Duration::from_secs);
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
         }
+        if limit.is_some_and(|limit| started.elapsed() >= limit) {
+            let group = -(child.id() as i32);
+            // SAFETY: kill(2) on the process group we just created; no memory
+            // is involved.
+            unsafe { libc::kill(group, libc::SIGTERM) };
+            let deadline = Instant::now() + KILL_GRACE;
+            while child.try_wait()?.is_none() && Instant::now() < deadline {
+                std::thread::sleep(Duration::from_millis(20));
+            }
+            // SAFETY: as above.
+            unsafe { libc::kill(group, libc::SIGKILL) };
+            let _ = child.wait();
+            bail!("timed out after {}s", timeout.unwrap_or_default());
+        }
+        std::thread::sleep(Duration::from_millis(20));
+    };
+    if !status.success() {
+        bail!("exit {}", status.code().unwrap_or(-1));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn per_node_hooks_of_a_parallel_command_run_at_once() {
+        let node = Node {
+            system: "x86_64-linux".to_string(),
+            hostname: "10.0.0.1".to_string(),
+            ssh_user: "root".to_string(),
+            tags: vec![],
+            host_key: None,
+        };
+        let targets = ResolvedTargets {
+            nodes: vec![
+                ("web1".to_string(), node.clone()),
+                ("web2".to_string(), node),
+            ],
+        };
+        let marks =
+            std::env::temp_dir().join(format!("fleet-hooks-overlap-{}", std::process::id()));
+        // Each node's hook marks itself started, then waits — for at most
+        // `tries` × 50ms — until both have
+        let hook = |tries: u32| {
+            format!(
+                "touch {dir}/$FLEET_NODE.$FLEET_COMMAND; i=0; \
+                 until [ -e {dir}/web1.$FLEET_COMMAND ] && [ -e {dir}/web2.$FLEET_COMMAND ]; do \
+                 i=$((i+1)); [ $i -gt {tries} ] && exit 1; sleep 0.05; done",
+                dir = marks.display(),
+                tries = tries
+            )
+        };
+        let yaml = format!(
+            "hooks:\n  deploy:\n    pre: '{}'\n  exec:\n    pre: '{}'\n",
+            hook(1200),
+            hook(4)
+        );
+        let config = FleetConfig::from_yaml(&yaml).unwrap();
+        fs::create_dir_all(&marks).unwrap();
+
+        around(&config, &HookContext::new("deploy", &targets), || Ok(())).unwrap();
+        // One node at a time, web1 waits for a web2 that cannot have started
+        let err = around(&config, &HookContext::new("exec", &targets), || Ok(())).unwrap_err();
+        assert_eq!(err.to_string(), "Pre-exec hook failed for web1 (exit 1)");
+        assert!(!marks.join("web2.exec").exists());
+        let _ = fs::remove_dir_all(&marks);
+    }
+
+    #[test]
+    fn remote_hooks_carry_their_context_and_timeout_to_the_node() {
+        let node = Node {
+            system: "x86_64-linux".to_string(),
+            hostname: "10.0.0.1".to_string(),
+            ssh_user: "root".to_string(),
+            tags: vec!["k3s".to_string(), "agent".to_string()],
+            host_key: None,
+        };
+        let targets = ResolvedTargets {
+            nodes: vec![("agent1".to_string(), node.clone())],
+        };
+        let ctx = HookContext::new("deploy", &targets).in_flow("roll out", "drain");
+        assert_eq!(
+            remote_command("kubectl drain \"$FLEET_NODE\"", Some(60), &ctx, "agent1", &node, None),
+            "env FLEET_COMMAND=deploy FLEET_TARGETS=agent1 FLEET_FLOW='roll out' FLEET_STEP=drain \
+             FLEET_NODE=agent1 FLEET_HOST=10.0.0.1 FLEET_USER=root FLEET_SYSTEM=x86_64-linux \
+             FLEET_TAGS=k3s,agent timeout 60 sh -c 'echo fleet-hook-started && exec sh -c \"$1\"' fleet-hook \
+             'kubectl drain \"$FLEET_NODE\"' 2>&1"
+        );
+        let outcome = Some(Outcome::Failure("it's down"));
+        assert_eq!(
+            remote_command(
+                "kubectl uncordon \"$FLEET_NODE\"",
+                None,
+                &ctx,
+                "agent1",
+                &node,
+                outcome
+            ),
+            "env FLEET_COMMAND=deploy FLEET_TARGETS=agent1 FLEET_FLOW='roll out' FLEET_STEP=drain \
+             FLEET_NODE=agent1 FLEET_HOST=10.0.0.1 FLEET_USER=root FLEET_SYSTEM=x86_64-linux \
+             FLEET_TAGS=k3s,agent FLEET_OUTCOME=failure FLEET_ERROR='it'\\''s down' \
+             sh -c 'echo fleet-hook-started && exec sh -c \"$1\"' fleet-hook \
+             'kubectl uncordon \"$FLEET_NODE\"' 2>&1"
+        );
+    }
+
+    #[test]
+    fn a_hook_past_its_timeout_is_killed_with_its_children() {
+        let started = Instant::now();
+        let err = run_timed(
+            Command::new("sh").arg("-c").arg("sleep 30 & sleep 30"),
+            Some(1),
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "timed out after 1s");
+        assert!(
+            started.elapsed() < Duration::from_secs(5),
+            "{:?}",
+            started.elapsed()
+        );
+
+        let err = run_timed(Command::new("sh").arg("-c").arg("exit 4"), None).unwrap_err();
+        assert_eq!(err.to_string(), "exit 4");
     }
 }
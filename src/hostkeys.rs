@@ -0,0 +1,321 @@
+//! The fleet's own known_hosts file, and how a node's live host keys
+//! compare with what it and the registry say they should be.
+
+use anyhow::{bail, Context, Result};
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use base64::Engine;
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// The known_hosts file every fleet ssh call trusts: `FLEET_KNOWN_HOSTS`,
+/// else `known_hosts` in the state directory.
+pub fn known_hosts_path(state_dir: &Path) -> PathBuf {
+    std::env::var_os("FLEET_KNOWN_HOSTS")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| state_dir.join("known_hosts"))
+}
+
+/// How known_hosts names a host: bare on port 22, `[host]:port` elsewhere.
+pub fn host_name(hostname: &str, port: u16) -> String {
+    if port == 22 {
+        hostname.to_string()
+    } else {
+        format!("[{}]:{}", hostname, port)
+    }
+}
+
+/// One public host key, `<type> <base64>`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HostKey {
+    pub kind: String,
+    pub blob: String,
+}
+
+impl HostKey {
+    /// `ssh-ed25519 AAAA… [comment]`, as in a `.pub` file.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut fields = text.split_whitespace();
+        let (Some(kind), Some(blob)) = (fields.next(), fields.next()) else {
+            bail!("expected `<type> <base64>`, got `{}`", text.trim());
+        };
+        let decoded = STANDARD
+            .decode(blob)
+            .with_context(|| format!("{} key is not valid base64", kind))?;
+        // The blob starts with its own type, length-prefixed
+        if decoded.get(4..4 + kind.len()) != Some(kind.as_bytes()) {
+            bail!("key data does not hold a {} key", kind);
+        }
+        Ok(Self {
+            kind: kind.to_string(),
+            blob: blob.to_string(),
+        })
+    }
+
+    /// `SHA256:…`, as `ssh` and `ssh-keygen -l` print it.
+    pub fn fingerprint(&self) -> String {
+        let blob = STANDARD.decode(&self.blob).unwrap_or_default();
+        format!("SHA256:{}", STANDARD_NO_PAD.encode(Sha256::digest(&blob)))
+    }
+}
+
+impl fmt::Display for HostKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.kind, self.blob)
+    }
+}
+
+/// The keys in known_hosts-style lines, as ssh records them and
+/// `ssh-keyscan` prints them, best type first.
+pub fn parse_key_lines(output: &str) -> Vec<HostKey> {
+    let mut keys: Vec<HostKey> = output
+        .lines()
+        .filter(|l| !l.trim_start().starts_with('#'))
+        .filter_map(|l| l.split_once(char::is_whitespace))
+        .filter_map(|(_, key)| HostKey::parse(key).ok())
+        .collect();
+    keys.sort_by_key(|k| preference(&k.kind));
+    keys.dedup();
+    keys
+}
+
+fn preference(kind: &str) -> usize {
+    ["ssh-ed25519", "ecdsa-sha2-nistp256", "ssh-rsa"]
+        .iter()
+        .position(|k| *k == kind)
+        .unwrap_or(usize::MAX)
+}
+
+/// A known_hosts file. Lines fleet does not manage — comments, hashed
+/// names, `@cert-authority` markers — are kept as they were.
+pub struct KnownHosts {
+    path: PathBuf,
+    lines: Vec<String>,
+}
+
+impl KnownHosts {
+    /// The file at `path`; empty if there is none yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e).with_context(|| format!("failed to read {}", path.display())),
+        };
+        Ok(Self {
+            path: path.to_path_buf(),
+            lines: text.lines().map(String::from).collect(),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The keys recorded for `name`.
+    pub fn keys_for(&self, name: &str) -> Vec<HostKey> {
+        self.lines
+            .iter()
+            .filter_map(|line| Self::entry(line))
+            .filter(|(names, _)| names.split(',').any(|n| n == name))
+            .filter_map(|(_, key)| HostKey::parse(key).ok())
+            .collect()
+    }
+
+    /// Make `keys` the only ones recorded for `name`.
+    pub fn set(&mut self, name: &str, keys: &[HostKey]) {
+        let mut lines = Vec::new();
+        for line in self.lines.drain(..) {
+            let Some((names, key)) = Self::entry(&line) else {
+                lines.push(line);
+                continue;
+            };
+            let others: Vec<&str> = names.split(',').filter(|n| *n != name).collect();
+            if others.len() == names.split(',').count() {
+                lines.push(line);
+            } else if !others.is_empty() {
+                lines.push(format!("{} {}", others.join(","), key));
+            }
+        }
+        lines.extend(keys.iter().map(|k| format!("{} {}", name, k)));
+        self.lines = lines;
+    }
+
+    /// Write the file back, whole or not at all.
+    pub fn save(&self) -> Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+        }
+        let tmp = self.path.with_extension("tmp");
+        let mut text = self.lines.join("\n");
+        text.push('\n');
+        fs::write(&tmp, text).with_context(|| format!("failed to write {}", tmp.display()))?;
+        fs::rename(&tmp, &self.path)
+            .with_context(|| format!("failed to replace {}", self.path.display()))
+    }
+
+    /// A plain `names key…` entry as its names and key; `None` for
+    /// comments, blank lines, markers and hashed names.
+    fn entry(line: &str) -> Option<(&str, &str)> {
+        let line = line.trim();
+        if line.is_empty()
+            || line.starts_with('#')
+            || line.starts_with('@')
+            || line.starts_with('|')
+        {
+            return None;
+        }
+        let (names, key) = line.split_once(char::is_whitespace)?;
+        Some((names, key.trim_start()))
+    }
+}
+
+/// How a node's live keys compare with the recorded and declared ones.
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    /// The node answered with no keys.
+    Unreachable,
+    /// Recorded, and the node still presents the same keys.
+    Matches,
+    /// Nothing recorded yet.
+    New,
+    /// The node presents keys other than the recorded ones.
+    Changed { old: HostKey, new: HostKey },
+    /// The node does not present the key the registry declares for it.
+    RegistryMismatch { declared: HostKey, live: HostKey },
+    /// The node presents no key of the registry's type at all, so the
+    /// declared key can be neither confirmed nor ruled out.
+    Unverifiable { declared: HostKey },
+}
+
+/// Judge `live`, the keys a node presents (best first), against `known`,
+/// those recorded for it, and `declared`, the registry's. The registry is
+/// the authority: a node contradicting it is a mismatch, whatever is
+/// recorded — but only a key of the declared type can contradict it.
+pub fn check(known: &[HostKey], declared: Option<&HostKey>, live: &[HostKey]) -> Verdict {
+    let Some(best) = live.first() else {
+        return Verdict::Unreachable;
+    };
+    if let Some(declared) = declared {
+        if !live.contains(declared) {
+            return match live.iter().find(|k| k.kind == declared.kind) {
+                Some(live) => Verdict::RegistryMismatch {
+                    declared: declared.clone(),
+                    live: live.clone(),
+                },
+                None => Verdict::Unverifiable {
+                    declared: declared.clone(),
+                },
+            };
+        }
+    }
+    if known.is_empty() {
+        return Verdict::New;
+    }
+    // A recorded type the node now presents differently, or no overlap at
+    // all, is a different machine (or a reinstalled one)
+    let replaced = known.iter().find_map(|old| {
+        live.iter()
+            .find(|new| new.kind == old.kind && *new != old)
+            .map(|new| (old, new))
+    });
+    match replaced {
+        Some((old, new)) => Verdict::Changed {
+            old: old.clone(),
+            new: new.clone(),
+        },
+        None if !known.iter().any(|k| live.contains(k)) => Verdict::Changed {
+            old: known[0].clone(),
+            new: best.clone(),
+        },
+        None => Verdict::Matches,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ED25519: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIMeIHrYrRjZY/5PxPCXWEwcYR8Au6G/quoUHBCIEWBMj";
+    const OTHER: &str =
+        "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIC9YX+OasUEHXkDrw8V1lTDuBUS9jgn6zeomQ4NQF92j";
+    const ECDSA: &str = "ecdsa-sha2-nistp256 AAAAE2VjZHNhLXNoYTItbmlzdHAyNTYAAAAIbmlzdHAyNTYAAABBBF+c6WzRt6iUH8qNBP4rJTmkCt9mgWCgi/CmutfIPLXHpj/uDcJiIypJSwQfRoFBKqRQOw6dlOSf3djwSn+iSzk=";
+
+    fn key(text: &str) -> HostKey {
+        HostKey::parse(text).unwrap()
+    }
+
+    #[test]
+    fn fingerprints_match_ssh_keygen() {
+        assert_eq!(
+            key(ED25519).fingerprint(),
+            "SHA256:FOHpYCsu2tsGQlJ8CeIuryIFTmF2CSL/7iGAa2O5+dw"
+        );
+        assert!(HostKey::parse("ssh-rsa AAAAC3NzaC1lZDI1NTE5AAAAIMeI").is_err());
+        assert!(HostKey::parse("ssh-ed25519").is_err());
+    }
+
+    #[test]
+    fn verdicts() {
+        let (a, b) = (key(ED25519), key(OTHER));
+        let live = std::slice::from_ref(&a);
+        assert_eq!(check(&[], None, &[]), Verdict::Unreachable);
+        assert_eq!(check(&[], None, live), Verdict::New);
+        assert_eq!(check(live, Some(&a), live), Verdict::Matches);
+        assert_eq!(
+            check(std::slice::from_ref(&b), None, live),
+            Verdict::Changed {
+                old: b.clone(),
+                new: a.clone()
+            }
+        );
+        // The registry wins even over a recorded key the node still has
+        assert_eq!(
+            check(live, Some(&b), live),
+            Verdict::RegistryMismatch {
+                declared: b,
+                live: a.clone()
+            }
+        );
+        // An ed25519 key says nothing about the ecdsa key the registry holds
+        let ecdsa = key(ECDSA);
+        assert_eq!(
+            check(&[], Some(&ecdsa), live),
+            Verdict::Unverifiable {
+                declared: ecdsa.clone()
+            }
+        );
+        assert_eq!(check(&[], Some(&ecdsa), &[a, ecdsa.clone()]), Verdict::New);
+    }
+
+    #[test]
+    fn setting_a_host_keeps_every_other_line() {
+        let path = std::env::temp_dir().join(format!("fleet-known-hosts-{}", std::process::id()));
+        fs::write(
+            &path,
+            format!(
+                "# managed by fleet\n|1|abc= {}\nweb1,10.0.0.1 {}\n[db1]:2222 {}\n",
+                OTHER, OTHER, OTHER
+            ),
+        )
+        .unwrap();
+
+        let mut known = KnownHosts::load(&path).unwrap();
+        assert_eq!(known.keys_for("10.0.0.1"), [key(OTHER)]);
+        assert_eq!(known.keys_for(&host_name("db1", 2222)), [key(OTHER)]);
+        known.set("10.0.0.1", &[key(ED25519)]);
+        known.save().unwrap();
+
+        let text = fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            text,
+            format!(
+                "# managed by fleet\n|1|abc= {}\nweb1 {}\n[db1]:2222 {}\n10.0.0.1 {}\n",
+                OTHER, OTHER, OTHER, ED25519
+            )
+        );
+        let _ = fs::remove_file(&path);
+    }
+}
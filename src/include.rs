@@ -0,0 +1,505 @@
+use anyhow::{bail, Context, Result};
+use serde_yaml_ng::{Mapping, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Where a top-level entry of the merged config was defined.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Origin {
+    /// The file, as read.
+    pub path: PathBuf,
+    /// The file relative to the root `fleet.yaml`'s directory, for messages.
+    pub label: String,
+    /// 1-based line of the entry's key, when it could be found.
+    pub line: Option<usize>,
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}", self.label, line),
+            None => write!(f, "{}", self.label),
+        }
+    }
+}
+
+/// `fleet.yaml` with its `include:`s merged in, and where each entry came
+/// from — keyed `<section>.<key>` (`flows.deploy`), or `<section>` for a
+/// section that is not a map.
+#[derive(Debug)]
+pub struct Loaded {
+    pub value: Value,
+    pub origins: BTreeMap<String, Origin>,
+}
+
+/// Read `root` and every file it includes, recursively.
+///
+/// Files merge one level deep: each top-level section is a map, and its keys
+/// are collected from every file. A key defined in two files is an error
+/// naming both, rather than a silent override whose winner depends on
+/// include order — so the order below only decides where an error points,
+/// never what a flow means. Order is the including file's own entries, then
+/// its `include:` patterns in the order listed, each glob's matches sorted by
+/// path, depth first.
+pub fn load(root: &Path) -> Result<Loaded> {
+    let base = root.parent().unwrap_or(Path::new("")).to_path_buf();
+    let mut loader = Loader {
+        base,
+        merged: Mapping::new(),
+        origins: BTreeMap::new(),
+        seen: HashMap::new(),
+    };
+    loader.file(root, None)?;
+    Ok(Loaded {
+        value: Value::Mapping(loader.merged),
+        origins: loader.origins,
+    })
+}
+
+struct Loader {
+    base: PathBuf,
+    merged: Mapping,
+    origins: BTreeMap<String, Origin>,
+    /// Canonical path of every file read, and the label of what included it.
+    seen: HashMap<PathBuf, Option<String>>,
+}
+
+impl Loader {
+    fn label(&self, path: &Path) -> String {
+        path.strip_prefix(&self.base)
+            .unwrap_or(path)
+            .display()
+            .to_string()
+    }
+
+    fn file(&mut self, path: &Path, includer: Option<&str>) -> Result<()> {
+        let label = self.label(path);
+        let canonical =
+            fs::canonicalize(path).with_context(|| format!("failed to read {}", label))?;
+        if let Some(first) = self.seen.get(&canonical) {
+            let by = |i: &Option<String>| match i {
+                Some(i) => format!("included by {}", i),
+                None => "the root config".to_string(),
+            };
+            bail!(
+                "{} is loaded twice ({}, and {}) — each file may be included once",
+                label,
+                by(first),
+                by(&includer.map(str::to_string))
+            );
+        }
+        self.seen.insert(canonical, includer.map(str::to_string));
+
+        let text = fs::read_to_string(path).with_context(|| format!("failed to read {}", label))?;
+        let value: Value = serde_yaml_ng::from_str(&text).map_err(|e| syntax_error(&label, &e))?;
+        let mut doc = match value {
+            Value::Mapping(m) => m,
+            Value::Null => Mapping::new(),
+            _ => bail!("{}: expected a map of config sections", label),
+        };
+        let includes = include_patterns(doc.remove("include"), &label)?;
+
+        for (section, value) in doc {
+            let Some(section) = section.as_str().map(str::to_string) else {
+                bail!("{}: config section names must be strings", label);
+            };
+            self.section(&section, value, path, &label, &text)?;
+        }
+
+        let dir = path.parent().unwrap_or(Path::new(""));
+        for pattern in includes {
+            let matches = expand(dir, &pattern)
+                .with_context(|| format!("{}: include '{}'", label, pattern))?;
+            for found in matches {
+                // A glob like `*.yaml` next to the including file would
+                // otherwise match that file itself.
+                if has_wildcard(&pattern) && found == path {
+                    continue;
+                }
+                self.file(&found, Some(&label))?;
+            }
+        }
+        Ok(())
+    }
+
+    fn section(
+        &mut self,
+        section: &str,
+        value: Value,
+        path: &Path,
+        label: &str,
+        text: &str,
+    ) -> Result<()> {
+        let origin = |line| Origin {
+            path: path.to_path_buf(),
+            label: label.to_string(),
+            line,
+        };
+        let entries = match value {
+            Value::Null => return Ok(()),
+            Value::Mapping(m) => m,
+            other => {
+                // Not a map, so there is nothing to merge: one file owns it.
+                if let Some(first) = self.section_origin(section) {
+                    return Err(conflict(
+                        section,
+                        &first,
+                        &origin(key_line(text, section, None)),
+                    ));
+                }
+                self.claim(section.to_string(), origin(key_line(text, section, None)))?;
+                self.merged.insert(section.into(), other);
+                return Ok(());
+            }
+        };
+        if let Some(first) = self.origins.get(section) {
+            return Err(conflict(
+                section,
+                first,
+                &origin(key_line(text, section, None)),
+            ));
+        }
+        let slot = self
+            .merged
+            .entry(section.into())
+            .or_insert_with(|| Value::Mapping(Mapping::new()));
+        let Value::Mapping(slot) = slot else {
+            unreachable!("claimed above")
+        };
+        let mut claims = Vec::new();
+        for (key, value) in entries {
+            let name = match &key {
+                Value::String(s) => s.clone(),
+                other => serde_yaml_ng::to_string(other)
+                    .unwrap_or_default()
+                    .trim()
+                    .to_string(),
+            };
+            claims.push((name.clone(), key_line(text, section, Some(&name))));
+            if !slot.contains_key(&key) {
+                slot.insert(key, value);
+            }
+        }
+        for (name, line) in claims {
+            self.claim(format!("{}.{}", section, name), origin(line))?;
+        }
+        Ok(())
+    }
+
+    /// Record where `key` is defined, failing if another file got there first.
+    fn claim(&mut self, key: String, origin: Origin) -> Result<()> {
+        if let Some(first) = self.origins.get(&key) {
+            return Err(conflict(&key, first, &origin));
+        }
+        self.origins.insert(key, origin);
+        Ok(())
+    }
+
+    /// Where any part of `section` was first defined.
+    fn section_origin(&self, section: &str) -> Option<Origin> {
+        let prefix = format!("{}.", section);
+        self.origins
+            .iter()
+            .find(|(k, _)| *k == section || k.starts_with(&prefix))
+            .map(|(_, o)| o.clone())
+    }
+}
+
+/// `fleet.yaml:3:7: did not find expected key`, from serde_yaml_ng's
+/// `... at line 3 column 7`.
+fn syntax_error(label: &str, err: &serde_yaml_ng::Error) -> anyhow::Error {
+    let message = err.to_string();
+    match err.location() {
+        Some(at) => {
+            let position = format!(" at line {} column {}", at.line(), at.column());
+            anyhow::anyhow!(
+                "{}:{}:{}: {}",
+                label,
+                at.line(),
+                at.column(),
+                message.replacen(&position, "", 1)
+            )
+        }
+        None => anyhow::anyhow!("{}: {}", label, message),
+    }
+}
+
+fn conflict(key: &str, first: &Origin, second: &Origin) -> anyhow::Error {
+    anyhow::anyhow!(
+        "{} is defined in both {} and {} — each entry may be defined in one file only",
+        key,
+        first,
+        second
+    )
+}
+
+/// `include:` as a list of patterns: one string or a list of them.
+fn include_patterns(value: Option<Value>, label: &str) -> Result<Vec<String>> {
+    let items = match value {
+        None | Some(Value::Null) => return Ok(Vec::new()),
+        Some(Value::String(s)) => return Ok(vec![s]),
+        Some(Value::Sequence(items)) => items,
+        Some(_) => bail!("{}: `include` must be a path or a list of paths", label),
+    };
+    items
+        .into_iter()
+        .map(|item| match item {
+            Value::String(s) => Ok(s),
+            _ => bail!("{}: `include` entries must be paths", label),
+        })
+        .collect()
+}
+
+fn has_wildcard(s: &str) -> bool {
+    s.contains(['*', '?'])
+}
+
+/// Files matching `pattern`, relative to `dir` unless absolute. `*` and `?`
+/// match within one path component; a pattern without them must name a file
+/// that exists, while a glob may match nothing.
+fn expand(dir: &Path, pattern: &str) -> Result<Vec<PathBuf>> {
+    let full = dir.join(pattern);
+    if !has_wildcard(pattern) {
+        if !full.is_file() {
+            bail!("{} not found", full.display());
+        }
+        return Ok(vec![full]);
+    }
+    let mut paths = vec![PathBuf::new()];
+    for component in full.components() {
+        let part = component.as_os_str().to_string_lossy();
+        if !has_wildcard(&part) {
+            for p in &mut paths {
+                p.push(component);
+            }
+            continue;
+        }
+        let mut next = Vec::new();
+        for p in &paths {
+            let listing = if p.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                p.as_path()
+            };
+            let Ok(entries) = fs::read_dir(listing) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                // Like a shell: wildcards do not match hidden files.
+                if !name.starts_with('.') && wildcard_match(&part, &name) {
+                    next.push(p.join(name));
+                }
+            }
+        }
+        paths = next;
+    }
+    paths.retain(|p| p.is_file());
+    paths.sort();
+    Ok(paths)
+}
+
+/// Shell-style match of one path component: `*` is any run, `?` one char.
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let (p, n): (Vec<char>, Vec<char>) = (pattern.chars().collect(), name.chars().collect());
+    let (mut pi, mut ni) = (0, 0);
+    let mut star: Option<(usize, usize)> = None;
+    while ni < n.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == n[ni]) {
+            pi += 1;
+            ni += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ni));
+            pi += 1;
+        } else if let Some((sp, sn)) = star {
+            pi = sp + 1;
+            ni = sn + 1;
+            star = Some((sp, sn + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|&c| c == '*')
+}
+
+/// The 1-based line of `section:` in `text`, or of `key:` directly under it.
+/// A best-effort text scan — good enough to point at an entry, and `None`
+/// rather than a wrong line when the layout is unusual (flow style, anchors).
+fn key_line(text: &str, section: &str, key: Option<&str>) -> Option<usize> {
+    let is_key = |line: &str, name: &str| {
+        [
+            name.to_string(),
+            format!("\"{}\"", name),
+            format!("'{}'", name),
+        ]
+        .iter()
+        .any(|k| {
+            line.strip_prefix(k.as_str())
+                .is_some_and(|rest| rest.starts_with(':'))
+        })
+    };
+    let mut lines = text.lines().enumerate();
+    let (start, _) = lines.find(|(_, l)| is_key(l, section))?;
+    let Some(key) = key else {
+        return Some(start + 1);
+    };
+    let mut indent = None;
+    for (i, line) in lines {
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let depth = line.len() - trimmed.len();
+        if depth == 0 {
+            return None;
+        }
+        if *indent.get_or_insert(depth) == depth && is_key(trimmed, key) {
+            return Some(i + 1);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Tree(PathBuf);
+
+    impl Tree {
+        fn new(name: &str, files: &[(&str, &str)]) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("fleet-include-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            for (file, contents) in files {
+                let path = dir.join(file);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, contents).unwrap();
+            }
+            Tree(dir)
+        }
+
+        fn load(&self) -> Result<Loaded> {
+            load(&self.0.join("fleet.yaml"))
+        }
+    }
+
+    impl Drop for Tree {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn includes_merge_by_key_and_remember_their_files() {
+        let tree = Tree::new(
+            "merge",
+            &[
+                (
+                    "fleet.yaml",
+                    "include:\n  - flows/*.yaml\n  - hooks.yaml\nssh:\n  connect_timeout: 9\nflows:\n  local:\n    steps: []\n",
+                ),
+                ("flows/b.yaml", "flows:\n  beta:\n    steps: []\n"),
+                (
+                    "flows/a.yaml",
+                    "# alpha and gamma\nflows:\n\n  alpha:\n    steps: []\n  gamma:\n    steps: []\n",
+                ),
+                ("flows/.hidden.yaml", "flows:\n  local:\n    steps: []\n"),
+                ("hooks.yaml", "hooks:\n  deploy:\n    pre: 'true'\nssh:\n  options: {}\n"),
+            ],
+        );
+        let loaded = tree.load().unwrap();
+        let flows = loaded.value["flows"].as_mapping().unwrap();
+        let names: Vec<_> = flows.keys().filter_map(Value::as_str).collect();
+        assert_eq!(names, ["local", "alpha", "gamma", "beta"]);
+        assert_eq!(loaded.value["ssh"]["connect_timeout"], Value::from(9));
+        assert!(loaded.value["ssh"]["options"].is_mapping());
+
+        let at = |key: &str| loaded.origins[key].to_string();
+        assert_eq!(at("flows.local"), "fleet.yaml:7");
+        assert_eq!(at("flows.gamma"), "flows/a.yaml:6");
+        assert_eq!(at("flows.beta"), "flows/b.yaml:2");
+        assert_eq!(at("hooks.deploy"), "hooks.yaml:2");
+        assert_eq!(
+            loaded.origins["flows.beta"].path,
+            tree.0.join("flows/b.yaml")
+        );
+    }
+
+    #[test]
+    fn a_key_defined_twice_names_both_files() {
+        let tree = Tree::new(
+            "dup",
+            &[
+                (
+                    "fleet.yaml",
+                    "include: extra.yaml\nsecrets:\n  token:\n    item: a\n    path: /a\n",
+                ),
+                (
+                    "extra.yaml",
+                    "secrets:\n  other: {}\n  token:\n    item: b\n",
+                ),
+            ],
+        );
+        let err = tree.load().unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "secrets.token is defined in both fleet.yaml:3 and extra.yaml:3 — \
+             each entry may be defined in one file only"
+        );
+    }
+
+    #[test]
+    fn include_problems_are_reported_against_the_including_file() {
+        let missing = Tree::new("missing", &[("fleet.yaml", "include: [nope.yaml]\n")]);
+        let err = format!("{:#}", missing.load().unwrap_err());
+        assert!(
+            err.starts_with("fleet.yaml: include 'nope.yaml': "),
+            "{}",
+            err
+        );
+        assert!(err.ends_with("nope.yaml not found"), "{}", err);
+
+        let empty_glob = Tree::new("glob", &[("fleet.yaml", "include: [flows/*.yaml]\n")]);
+        assert!(empty_glob.load().is_ok(), "a glob may match nothing");
+
+        let cycle = Tree::new(
+            "cycle",
+            &[
+                ("fleet.yaml", "include: a.yaml\n"),
+                ("a.yaml", "include: fleet.yaml\n"),
+            ],
+        );
+        let err = cycle.load().unwrap_err().to_string();
+        assert_eq!(
+            err,
+            "fleet.yaml is loaded twice (the root config, and included by a.yaml) — \
+             each file may be included once"
+        );
+    }
+
+    #[test]
+    fn syntax_errors_name_the_file_line_and_column() {
+        let tree = Tree::new(
+            "syntax",
+            &[
+                ("fleet.yaml", "include: bad.yaml\n"),
+                ("bad.yaml", "flows:\n  x:\n    steps: [\n"),
+            ],
+        );
+        let err = tree.load().unwrap_err().to_string();
+        assert!(err.starts_with("bad.yaml:4:1: "), "{}", err);
+        assert!(!err.contains(" at line "), "{}", err);
+    }
+
+    #[test]
+    fn wildcards_match_one_component() {
+        assert!(wildcard_match("*.yaml", "deploy.yaml"));
+        assert!(wildcard_match("d?ploy*", "deploy.yaml"));
+        assert!(wildcard_match("*a*l", "yaml"));
+        assert!(!wildcard_match("*.yaml", "deploy.yml"));
+        assert!(!wildcard_match("?", ""));
+    }
+}
@@ -0,0 +1,709 @@
+use anyhow::{bail, Context, Result};
+use fs4::fs_std::FileExt;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::commands::utils::log_info;
+use crate::runs::{format_duration_ms, now_epoch};
+
+/// How long a blocked flow or node command waits for its lock by default.
+///
+/// Shorter than the rebuild lock's two hours: a flow or a deploy that finds
+/// another operator mid-run should tell them so within the same sitting, not
+/// after lunch. `--lock-timeout` or `FLEET_LOCK_TIMEOUT_SECS` overrides it.
+pub const DEFAULT_WAIT: Duration = Duration::from_secs(30 * 60);
+
+/// Overrides [`DEFAULT_WAIT`] when `--lock-timeout` is not given.
+pub const LOCK_TIMEOUT_ENV: &str = "FLEET_LOCK_TIMEOUT_SECS";
+
+/// Where flow and node locks live unless `FLEET_LOCK_DIR` says otherwise.
+///
+/// Absolute, for the reason `REBUILD_LOCK_PATH` is: `temp_dir()` is per-user
+/// on macOS, and a lock that only serializes one user against themselves
+/// serializes nothing.
+pub const DEFAULT_LOCK_DIR: &str = "/tmp/fleet-locks";
+
+/// How long to wait for a contended lock — a VALUE, passed in.
+///
+/// ── ★ WHY THIS IS A PARAMETER AND NOT AN ENV READ ────────────────────────
+/// `wait_for_lock` used to read `FLEET_REBUILD_LOCK_TIMEOUT_SECS` itself.
+/// `std::env` is process-global and `cargo test` runs tests as THREADS in one
+/// process, so two tests that set the var raced each other: one set `"1"`, the
+/// other's `remove_var` landed between that write and the read, the read fell
+/// through to the 2-hour default, and the test blocked for the full
+/// `LOCK_WAIT_TIMEOUT` before failing its own 30s assertion.
+///
+/// Observed 2026-08-16, not theorised: a suite run under load reported
+/// `FAILED. 101 passed; 1 failed; finished in 7200.39s` — 7200s being exactly
+/// `2 * 60 * 60`. The same race has a worse arm: read `"0"` and the wait
+/// becomes UNBOUNDED, which is a hang rather than a slow failure.
+///
+/// Serialising those tests behind a mutex would have hidden it. Threading the
+/// value removes the shared mutable cell instead, so the race has nothing to
+/// happen to: ONE place reads the environment ([`LockWait::from_env`], called
+/// once per real invocation), and every test passes the value it means.
+///
+/// The magic zero is a named variant too — `Unbounded` is a state you now have
+/// to ask for by name rather than encode as a `Duration` that happens to be 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockWait {
+    /// Give up after this long and fail typed, naming the holder.
+    Bounded(Duration),
+    /// Wait forever — the pre-2026-08-07 behaviour, kept reachable rather
+    /// than deleted (★★ MODULARIZE, DON'T DELETE) for an operator who knows
+    /// their peer is healthy and does not want to fight the bound.
+    Unbounded,
+}
+
+impl LockWait {
+    /// `default`, overridden by the seconds in `var`.
+    /// The ONLY environment read on a lock's path.
+    pub fn from_env(var: &str, default: Duration) -> Self {
+        Self::parse(std::env::var(var).ok().as_deref(), default)
+    }
+
+    /// `from_env`'s pure core — split out so the parsing rules are tested
+    /// without mutating a process-global, which is the very defect above.
+    pub fn parse(raw: Option<&str>, default: Duration) -> Self {
+        match raw.and_then(|v| v.trim().parse::<u64>().ok()) {
+            Some(0) => Self::Unbounded,
+            Some(secs) => Self::Bounded(Duration::from_secs(secs)),
+            // Absent OR unparseable: a typo must not silently become
+            // "wait forever". Fall back to the bounded default.
+            None => Self::Bounded(default),
+        }
+    }
+}
+
+/// One lock, as its messages should describe it.
+pub struct LockSpec<'a> {
+    pub path: &'a Path,
+    /// `rebuild lock`, `lock on flow 'deploy-cluster'`, …
+    pub what: String,
+    /// What the holder is doing, stamped for the next waiter to read.
+    pub purpose: &'a str,
+    /// How to wait longer, finishing "To wait longer, …".
+    pub wait_hint: &'a str,
+}
+
+/// How often a blocked waiter re-reports who it is waiting on.
+const LOCK_WAIT_REPORT_EVERY: Duration = Duration::from_secs(60);
+
+/// Read the holder line the current owner stamped into the lock file.
+pub fn describe_holder(lock_path: &Path) -> String {
+    match fs::read_to_string(lock_path) {
+        Ok(h) if !h.trim().is_empty() => render_stamp(h.trim(), now_epoch()),
+        _ => "holder unknown".to_owned(),
+    }
+}
+
+/// `pid 42 · ana · flow run x · since <epoch>` → `pid 42 · ana · flow run x,
+/// held for 4m12s`. Stamps written before the `since` field render as-is.
+fn render_stamp(stamp: &str, now: u64) -> String {
+    match split_since(stamp) {
+        Some((head, since)) => format!(
+            "{}, held for {}",
+            head,
+            format_duration_ms(now.saturating_sub(since) * 1000)
+        ),
+        None => stamp.to_owned(),
+    }
+}
+
+/// The stamp without its ` · since <epoch>` tail, and that epoch.
+fn split_since(stamp: &str) -> Option<(&str, u64)> {
+    let (head, since) = stamp.rsplit_once(" · since ")?;
+    Some((head, since.trim().parse().ok()?))
+}
+
+/// The pid stamped into the lock by its current holder, if the line parses.
+///
+/// The stamp is `pid <N> · <user> · …` (written at the end of [`acquire`]),
+/// so this reads the second whitespace-separated field. A file that does not
+/// parse is treated as having no pid — which routes to the stale branch,
+/// correctly: a lock nobody stamped is not a lock anyone is holding.
+pub fn holder_pid(lock_path: &Path) -> Option<u32> {
+    let text = fs::read_to_string(lock_path).ok()?;
+    let mut parts = text.split_whitespace();
+    if parts.next()? != "pid" {
+        return None;
+    }
+    parts.next()?.parse().ok()
+}
+
+/// Is that pid still around?
+///
+/// `kill(pid, 0)` is the portable liveness probe: it performs the permission
+/// checks and target lookup without delivering a signal. `EPERM` counts as
+/// ALIVE — the process exists, we simply may not signal it, which is exactly
+/// the root-holds-the-lock case this function is here to judge.
+pub fn pid_is_alive(pid: u32) -> bool {
+    // SAFETY: `kill` with signal 0 delivers nothing; it only reports whether
+    // the pid exists and is signallable.
+    let rc = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    if rc == 0 {
+        return true;
+    }
+    std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+/// Remove a lock file we cannot unlink as ourselves.
+///
+/// `/tmp` is sticky, so only the owner (or root) may unlink. We escalate
+/// rather than fail, because the alternative is telling an operator to run
+/// the same command by hand — which is not a safety boundary, just a worse
+/// user experience with identical consequences.
+pub fn clear_stale_lock(lock_path: &Path) -> Result<()> {
+    if fs::remove_file(lock_path).is_ok() {
+        return Ok(());
+    }
+    let status = std::process::Command::new("sudo")
+        .arg("rm")
+        .arg("-f")
+        .arg(lock_path)
+        .status()
+        .with_context(|| format!("could not run sudo to clear {}", lock_path.display()))?;
+    anyhow::ensure!(
+        status.success(),
+        "could not clear the stale lock at {} — remove it by hand: sudo rm -f {}",
+        lock_path.display(),
+        lock_path.display()
+    );
+    Ok(())
+}
+
+/// Block for the lock, but **bounded, and never silently**.
+///
+/// ── ★ WHY THIS IS NOT `FileExt::lock_exclusive` ──
+/// It used to be, and the sibling comment above already named the defect
+/// it left open: *"a bare waiting... with no identity and no timeout is the
+/// thing that makes a blocked interactive rebuild feel hung."* That comment
+/// shipped with the identity half implemented and the timeout half not, so
+/// a blocked rebuild waited on `lock_exclusive` **forever**, printed one
+/// line, and never spoke again.
+///
+/// MEASURED on rio 2026-08-07, which is why this is phase 0b of
+/// `theory/BALIZA.md` and not a nicety: an operator rebuild and the
+/// `sentinela` reconciler tick collided; sentinela's tick sat **36 minutes
+/// against a 60-second poll** with its child at zero CPU. Anything that
+/// then reached for this lock would have blocked behind a wedged holder
+/// with no output, no deadline and no way to tell the two apart from the
+/// terminal. A hang must degrade into a **typed failure**
+/// (`theory/RECONCILER-LIVENESS.md` P1), and silence is the part that makes
+/// a hang expensive — the operator cannot act on what they cannot see.
+///
+/// So: poll, re-announce the holder every
+/// [`LOCK_WAIT_REPORT_EVERY`], and convert the deadline into an error that
+/// names who we waited on and how long.
+///
+/// TIER: only-mitigated. This bounds *our* wait; it does not bound the
+/// holder's work, and a holder that wedges still wedges — it just stops
+/// being invisible and stops being unbounded for everyone behind it.
+/// Bounding the holder is `despacho`'s job (`theory/DESPACHO.md`), where
+/// the ask carries a mandatory deadline of its own.
+///
+/// `wait` is a VALUE, passed in — see [`LockWait`] for the test race that
+/// reading the environment here caused.
+fn wait_for_lock(file: &File, spec: &LockSpec, wait: LockWait) -> Result<()> {
+    // `Unbounded` is a NAMED state, not a `Duration` that happens to be zero —
+    // the pre-2026-08-07 behaviour, kept reachable rather than deleted
+    // (★★ MODULARIZE, DON'T DELETE) so an operator who knows their peer is
+    // healthy is not forced to fight the bound.
+    let timeout = match wait {
+        LockWait::Unbounded => {
+            return FileExt::lock_exclusive(file)
+                .with_context(|| format!("failed to acquire the {}", spec.what));
+        }
+        LockWait::Bounded(d) => d,
+    };
+
+    let started = Instant::now();
+    let mut last_report = Instant::now();
+    loop {
+        if FileExt::try_lock_exclusive(file).is_ok() {
+            return Ok(());
+        }
+        let waited = started.elapsed();
+        if waited >= timeout {
+            anyhow::bail!(
+                "gave up waiting for the {} after {}s — held by {}.\n\
+                 \n\
+                 The holder is either doing legitimate long work (a cold build of \
+                 this fleet can exceed an hour) or it is wedged. Check it before \
+                 forcing anything:\n\
+                 \n    ps -o pid,etimes,stat,args -p <holder pid>\n\
+                 \n\
+                 A holder at zero CPU in state S with no build children is wedged; \
+                 kill it and re-run. To wait longer, {} (0 waits forever).",
+                spec.what,
+                waited.as_secs(),
+                describe_holder(spec.path),
+                spec.wait_hint,
+            );
+        }
+        if last_report.elapsed() >= LOCK_WAIT_REPORT_EVERY {
+            log_info(&format!(
+                "still waiting for the {} ({}s elapsed, {}s left) — held by {}",
+                spec.what,
+                waited.as_secs(),
+                timeout.saturating_sub(waited).as_secs(),
+                describe_holder(spec.path),
+            ));
+            last_report = Instant::now();
+        }
+        std::thread::sleep(Duration::from_millis(500));
+    }
+}
+
+/// Take an exclusive advisory lock on `spec.path`, waiting per `wait`.
+///
+/// The lock is released when the returned file is dropped. `wait` is threaded
+/// in rather than read here, for the same reason [`wait_for_lock`] takes it:
+/// tests must be able to choose a bound WITHOUT writing a process-global that
+/// their siblings are reading concurrently.
+pub fn acquire(spec: &LockSpec, wait: LockWait) -> Result<File> {
+    let lock_path = spec.path;
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        // See the note on the retry below: truncation happens after the lock
+        // is held, never at open.
+        .truncate(false)
+        .open(lock_path)
+        .or_else(|e| {
+            // EACCES means the file EXISTS and belongs to another user with a
+            // mode that excludes us. The 0666 widening below cannot rescue it
+            // — that runs AFTER open — and /tmp is sticky, so a non-owner
+            // cannot unlink it either. Measured on ggg: a fresh account's
+            // `nix run .#rebuild` died here with nothing to do next.
+            //
+            // So REPAIR it, rather than instruct. This command already
+            // escalates for the rebuild itself; clearing a lock it owns the
+            // semantics of is squarely inside that authority.
+            //
+            // The safety test is the holder stamp, not a timeout: the file
+            // records `pid N · user`, so a DEAD pid means the writer is gone
+            // and the lock is debris. A LIVE pid is a real peer and is never
+            // touched — that is the difference between repairing a stale lock
+            // and yanking a running rebuild's.
+            if e.kind() != std::io::ErrorKind::PermissionDenied {
+                return Err(anyhow::Error::new(e).context(format!(
+                    "failed to open {} file at {}",
+                    spec.what,
+                    lock_path.display()
+                )));
+            }
+            match holder_pid(lock_path) {
+                Some(pid) if pid_is_alive(pid) => Err(anyhow::anyhow!(
+                    "the {w} at {p} is held by a LIVE process ({h}), and its \
+                     mode excludes you.\n\
+                     Wait for it to finish, or if you are sure it is wrong:\n\
+                     \x20   sudo rm -f {p}",
+                    w = spec.what,
+                    p = lock_path.display(),
+                    h = describe_holder(lock_path)
+                )),
+                _ => {
+                    log_info(&format!(
+                        "Stale {} at {} ({}) — its writer is gone; clearing it.",
+                        spec.what,
+                        lock_path.display(),
+                        describe_holder(lock_path)
+                    ));
+                    clear_stale_lock(lock_path)?;
+                    OpenOptions::new()
+                        .create(true)
+                        .write(true)
+                        // Same reason as the first open: truncation happens
+                        // only once the lock is held.
+                        .truncate(false)
+                        .open(lock_path)
+                        .with_context(|| {
+                            format!(
+                                "failed to open {} file at {} even after \
+                                 clearing a stale one",
+                                spec.what,
+                                lock_path.display()
+                            )
+                        })
+                }
+            }
+        })?;
+    // Cross-user reachability: whoever creates the file first owns it, and the
+    // other party still has to open it for WRITE to take an exclusive flock. A
+    // default 0644 would hand the first creator a permanent monopoly — root
+    // creates it, the operator's rebuild then dies on EACCES instead of
+    // waiting. Best-effort: a pre-existing file owned by the other user cannot
+    // be chmod'd by us, and that is fine — it is already 0666 from its own
+    // creation. Never fatal, because failing to widen a mode must not break a
+    // command that would otherwise have proceeded.
+    let _ = fs::set_permissions(lock_path, fs::Permissions::from_mode(0o666));
+    if FileExt::try_lock_exclusive(&file).is_err() {
+        // Name the holder. A bare "waiting..." with no identity and no timeout
+        // is the thing that makes a blocked interactive command feel hung —
+        // the operator cannot tell a live peer from a wedged one.
+        log_info(&format!(
+            "The {} is held ({}) — waiting for it to be released...",
+            spec.what,
+            describe_holder(lock_path)
+        ));
+        wait_for_lock(&file, spec, wait)?;
+    }
+    // Stamp our identity for the next waiter to read. Truncate first: the
+    // previous holder's line is stale the moment we own the lock.
+    let holder = format!(
+        "pid {} · {} · {} · since {}",
+        std::process::id(),
+        std::env::var("USER").unwrap_or_else(|_| "unknown".to_owned()),
+        spec.purpose,
+        now_epoch()
+    );
+    let _ = file.set_len(0);
+    let _ = std::io::Write::write_all(&mut (&file), holder.as_bytes());
+    Ok(file)
+}
+
+/// A lock `fleet locks` can name: `flow:<name>`, `node:<name>` or `rebuild`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LockName {
+    Flow(String),
+    Node(String),
+    Rebuild,
+}
+
+impl LockName {
+    pub fn parse(s: &str) -> Result<Self> {
+        if s == "rebuild" {
+            return Ok(Self::Rebuild);
+        }
+        let name = match s.split_once(':') {
+            Some(("flow", n)) => Self::Flow(n.to_string()),
+            Some(("node", n)) => Self::Node(n.to_string()),
+            _ => bail!(
+                "unknown lock '{}' — expected flow:<name>, node:<name> or rebuild",
+                s
+            ),
+        };
+        match &name {
+            Self::Flow(n) | Self::Node(n) if n.is_empty() || n.contains('/') => {
+                bail!("invalid lock name '{}'", s)
+            }
+            _ => Ok(name),
+        }
+    }
+
+    /// The lock file's name inside the lock directory.
+    fn file_name(&self) -> String {
+        match self {
+            Self::Flow(n) => format!("flow.{}.lock", n),
+            Self::Node(n) => format!("node.{}.lock", n),
+            Self::Rebuild => "rebuild.lock".to_string(),
+        }
+    }
+
+    /// Inverse of [`file_name`](Self::file_name), for flow and node locks.
+    fn from_file_name(file: &str) -> Option<Self> {
+        let stem = file.strip_suffix(".lock")?;
+        match stem.split_once('.')? {
+            ("flow", n) if !n.is_empty() => Some(Self::Flow(n.to_string())),
+            ("node", n) if !n.is_empty() => Some(Self::Node(n.to_string())),
+            _ => None,
+        }
+    }
+
+    /// How messages name it: `lock on node 'web1'`.
+    pub fn what(&self) -> String {
+        match self {
+            Self::Flow(n) => format!("lock on flow '{}'", n),
+            Self::Node(n) => format!("lock on node '{}'", n),
+            Self::Rebuild => "rebuild lock".to_string(),
+        }
+    }
+}
+
+impl fmt::Display for LockName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Flow(n) => write!(f, "flow:{}", n),
+            Self::Node(n) => write!(f, "node:{}", n),
+            Self::Rebuild => write!(f, "rebuild"),
+        }
+    }
+}
+
+/// Flow and node locking, as configured once per invocation.
+pub struct Locks {
+    dir: PathBuf,
+    wait: LockWait,
+}
+
+impl Locks {
+    pub fn new(dir: PathBuf, wait: LockWait) -> Self {
+        Self { dir, wait }
+    }
+
+    /// The real entry point's settings: `--no-wait` or `--lock-timeout`,
+    /// else `FLEET_LOCK_TIMEOUT_SECS`, else [`DEFAULT_WAIT`]; the directory
+    /// is `FLEET_LOCK_DIR`, else [`DEFAULT_LOCK_DIR`].
+    pub fn from_cli(timeout_secs: Option<u64>, no_wait: bool) -> Self {
+        let wait = if no_wait {
+            LockWait::Bounded(Duration::ZERO)
+        } else {
+            match timeout_secs {
+                Some(secs) => LockWait::parse(Some(&secs.to_string()), DEFAULT_WAIT),
+                None => LockWait::from_env(LOCK_TIMEOUT_ENV, DEFAULT_WAIT),
+            }
+        };
+        Self::new(lock_dir(), wait)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Hold the lock on a flow for as long as the returned file lives.
+    pub fn flow(&self, name: &str, purpose: &str) -> Result<File> {
+        self.take(&LockName::Flow(name.to_string()), purpose)
+    }
+
+    /// Lock every node in `names`, in sorted order so two commands locking
+    /// overlapping sets cannot each hold what the other waits on.
+    pub fn nodes(&self, names: &[&str], purpose: &str) -> Result<Vec<File>> {
+        let mut names = names.to_vec();
+        names.sort();
+        names.dedup();
+        names
+            .into_iter()
+            .map(|n| self.take(&LockName::Node(n.to_string()), purpose))
+            .collect()
+    }
+
+    fn take(&self, name: &LockName, purpose: &str) -> Result<File> {
+        create_lock_dir(&self.dir)?;
+        let path = self.dir.join(name.file_name());
+        let spec = LockSpec {
+            path: &path,
+            what: name.what(),
+            purpose,
+            wait_hint: "pass --lock-timeout or set FLEET_LOCK_TIMEOUT_SECS",
+        };
+        acquire(&spec, self.wait)
+    }
+}
+
+/// `FLEET_LOCK_DIR`, else [`DEFAULT_LOCK_DIR`].
+pub fn lock_dir() -> PathBuf {
+    std::env::var_os("FLEET_LOCK_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_LOCK_DIR))
+}
+
+/// Create the lock directory world-writable and sticky, like `/tmp` itself,
+/// so root and operators can each create their own lock files in it and
+/// neither can unlink the other's. Widening the mode is best-effort, for the
+/// reason [`acquire`]'s is.
+fn create_lock_dir(dir: &Path) -> Result<()> {
+    if dir.is_dir() {
+        return Ok(());
+    }
+    fs::create_dir_all(dir)
+        .with_context(|| format!("failed to create lock directory {}", dir.display()))?;
+    let _ = fs::set_permissions(dir, fs::Permissions::from_mode(0o1777));
+    Ok(())
+}
+
+/// One lock file, as `fleet locks` reports it.
+#[derive(Debug)]
+pub struct LockInfo {
+    pub name: LockName,
+    /// Whether some process holds the flock right now.
+    pub held: bool,
+    /// The stamp's `pid · user · purpose`, if any.
+    pub holder: Option<String>,
+    /// Epoch seconds the holder took it, if stamped.
+    pub since: Option<u64>,
+}
+
+/// Whether anyone holds the flock on `path`. A shared probe needs only read
+/// access and is refused exactly when someone holds it exclusively.
+fn is_held(path: &Path) -> bool {
+    match File::open(path) {
+        Ok(f) => FileExt::try_lock_shared(&f).is_err(),
+        Err(_) => false,
+    }
+}
+
+fn inspect(name: LockName, path: &Path) -> LockInfo {
+    let stamp = fs::read_to_string(path).unwrap_or_default();
+    let stamp = stamp.trim();
+    let (holder, since) = match split_since(stamp) {
+        Some((head, since)) => (Some(head.to_string()), Some(since)),
+        None if stamp.is_empty() => (None, None),
+        None => (Some(stamp.to_string()), None),
+    };
+    LockInfo {
+        held: is_held(path),
+        name,
+        holder,
+        since,
+    }
+}
+
+/// Every lock file in `dir`, plus the rebuild lock at `rebuild_path` if it
+/// exists, sorted by name. Includes files nobody holds: a lock file outlives
+/// its holder, and a free one is harmless.
+pub fn list(dir: &Path, rebuild_path: &Path) -> Result<Vec<LockInfo>> {
+    let mut locks = Vec::new();
+    if rebuild_path.exists() {
+        locks.push(inspect(LockName::Rebuild, rebuild_path));
+    }
+    if dir.is_dir() {
+        for entry in
+            fs::read_dir(dir).with_context(|| format!("failed to read {}", dir.display()))?
+        {
+            let entry = entry?;
+            let file = entry.file_name();
+            if let Some(name) = file.to_str().and_then(LockName::from_file_name) {
+                locks.push(inspect(name, &entry.path()));
+            }
+        }
+    }
+    locks.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(locks)
+}
+
+/// The lock file `name` refers to.
+pub fn path_of(name: &LockName, dir: &Path, rebuild_path: &Path) -> PathBuf {
+    match name {
+        LockName::Rebuild => rebuild_path.to_path_buf(),
+        _ => dir.join(name.file_name()),
+    }
+}
+
+/// Remove a lock file so the next command can take it.
+///
+/// A lock nobody holds is debris and goes without ceremony. A HELD one is
+/// refused unless `force`: unlinking the file does not stop its holder — it
+/// keeps its flock on the old inode and carries on — it only lets the next
+/// command in beside it, which is the race the lock exists to prevent.
+/// `force` is for a holder you have already established is wedged.
+pub fn break_lock(path: &Path, what: &str, force: bool) -> Result<()> {
+    if !path.exists() {
+        bail!("no {} at {}", what, path.display());
+    }
+    if is_held(path) && !force {
+        bail!(
+            "the {} is held by {} — wait for it, or pass --force if it is wedged",
+            what,
+            describe_holder(path)
+        );
+    }
+    clear_stale_lock(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fresh_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("fleet-locks-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn lock_names_round_trip_through_their_files() {
+        for s in ["flow:deploy-cluster", "node:web1"] {
+            let name = LockName::parse(s).unwrap();
+            assert_eq!(name.to_string(), s);
+            assert_eq!(LockName::from_file_name(&name.file_name()), Some(name));
+        }
+        assert_eq!(LockName::parse("rebuild").unwrap(), LockName::Rebuild);
+        assert!(LockName::parse("web1").is_err());
+        assert!(LockName::parse("node:").is_err());
+        assert!(LockName::parse("flow:../etc").is_err());
+    }
+
+    #[test]
+    fn the_timeout_parses_like_the_rebuild_lock_one() {
+        let d = Duration::from_secs(7);
+        assert_eq!(LockWait::parse(None, d), LockWait::Bounded(d));
+        assert_eq!(LockWait::parse(Some("soon"), d), LockWait::Bounded(d));
+        assert_eq!(LockWait::parse(Some("0"), d), LockWait::Unbounded);
+        assert_eq!(
+            LockWait::parse(Some(" 90 "), d),
+            LockWait::Bounded(Duration::from_secs(90))
+        );
+    }
+
+    #[test]
+    fn the_stamp_renders_how_long_it_has_been_held() {
+        let stamp = "pid 42 · ana · flow run x · since 1000";
+        assert_eq!(
+            render_stamp(stamp, 1252),
+            "pid 42 · ana · flow run x, held for 4m12s"
+        );
+        assert_eq!(render_stamp("pid 42 · ana", 1252), "pid 42 · ana");
+    }
+
+    #[test]
+    fn a_held_node_lock_blocks_and_is_listed_with_its_holder() {
+        let dir = fresh_dir("held");
+        let locks = Locks::new(dir.clone(), LockWait::Bounded(Duration::ZERO));
+        let held = locks
+            .nodes(&["web2", "web1", "web1"], "fleet deploy")
+            .unwrap();
+        assert_eq!(held.len(), 2, "duplicates lock once");
+
+        let err = locks
+            .nodes(&["web1"], "fleet reboot")
+            .unwrap_err()
+            .to_string();
+        assert!(
+            err.contains("gave up waiting for the lock on node 'web1'"),
+            "{}",
+            err
+        );
+        assert!(err.contains("fleet deploy"), "names the holder: {}", err);
+
+        let listed = list(&dir, &dir.join("no-rebuild.lock")).unwrap();
+        let names: Vec<String> = listed.iter().map(|l| l.name.to_string()).collect();
+        assert_eq!(names, ["node:web1", "node:web2"]);
+        assert!(listed.iter().all(|l| l.held));
+        let holder = listed[0].holder.as_deref().unwrap();
+        assert!(holder.starts_with(&format!("pid {}", std::process::id())));
+        assert!(holder.ends_with("· fleet deploy"), "{}", holder);
+        assert!(listed[0].since.is_some());
+
+        drop(held);
+        assert!(list(&dir, &dir.join("no-rebuild.lock"))
+            .unwrap()
+            .iter()
+            .all(|l| !l.held));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn a_held_lock_is_broken_only_with_force() {
+        let dir = fresh_dir("break");
+        let locks = Locks::new(dir.clone(), LockWait::Bounded(Duration::ZERO));
+        let path = dir.join("flow.drill.lock");
+
+        let held = locks.flow("drill", "flow run drill").unwrap();
+        let err = break_lock(&path, "lock on flow 'drill'", false).unwrap_err();
+        assert!(err.to_string().contains("--force"), "{}", err);
+        assert!(path.exists());
+        break_lock(&path, "lock on flow 'drill'", true).unwrap();
+        assert!(!path.exists());
+        drop(held);
+
+        // A free lock file is debris and goes without --force.
+        drop(locks.flow("drill", "flow run drill").unwrap());
+        break_lock(&path, "lock on flow 'drill'", false).unwrap();
+        assert!(break_lock(&path, "lock on flow 'drill'", false).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -1,17 +1,28 @@
-use anyhow::Result;
+use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 mod commands;
+mod condition;
 mod config;
+mod config_errors;
 mod dag;
+#[cfg(test)]
+mod e2e;
+#[cfg(test)]
+mod fake_transport;
 mod fetch_recovery;
 mod flow;
 mod github_token;
 mod hooks;
+mod hostkeys;
+mod include;
+mod lock;
 mod registry;
+mod runs;
 mod secrets;
 mod targeting;
+mod transport;
 
 #[derive(Parser)]
 #[command(name = "fleet")]
@@ -20,6 +31,19 @@ mod targeting;
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Seconds to wait for a flow or node lock held by another command
+    /// (0 waits forever; default FLEET_LOCK_TIMEOUT_SECS, else 1800)
+    #[arg(long, global = true, value_name = "SECS")]
+    lock_timeout: Option<u64>,
+
+    /// Fail at once if a flow or node lock is held
+    #[arg(long, global = true, conflicts_with = "lock_timeout")]
+    no_wait: bool,
+
+    /// Lay this profile from fleet.yaml over the config (default FLEET_PROFILE)
+    #[arg(long, global = true, value_name = "NAME")]
+    profile: Option<String>,
 }
 
 #[derive(Subcommand)]
@@ -97,6 +121,15 @@ enum Commands {
         cmd: Vec<String>,
     },
 
+    /// Copy a file to or from nodes: `cp <file> <targets>:<path>` or `cp <targets>:<path> <dir>`
+    Cp {
+        /// Local file, or `<node or @tag>:<path>` to pull
+        from: String,
+
+        /// `<node or @tag>:<path>` to push to, or a local directory
+        to: String,
+    },
+
     /// Show status of remote nodes (generation, uptime, kernel)
     Status {
         /// Target nodes (names or @tag)
@@ -107,6 +140,44 @@ enum Commands {
         all: bool,
     },
 
+    /// Read nodes' journals at once (-u unit, --since, -f, --merge, --grep)
+    Logs {
+        /// Target nodes (names or @tag)
+        targets: Vec<String>,
+
+        /// Read all nodes
+        #[arg(long)]
+        all: bool,
+
+        /// Only this systemd unit (repeatable)
+        #[arg(short = 'u', long = "unit")]
+        units: Vec<String>,
+
+        /// Start at this time, as journalctl takes it ("1 hour ago", "2026-10-19 09:00")
+        #[arg(long)]
+        since: Option<String>,
+
+        /// Lines per node (default 100 unless --since is given)
+        #[arg(short = 'n', long)]
+        lines: Option<u32>,
+
+        /// Keep printing new entries as they are written
+        #[arg(short = 'f', long)]
+        follow: bool,
+
+        /// Interleave nodes' entries in time order, from journald's JSON output
+        #[arg(long)]
+        merge: bool,
+
+        /// Only lines (messages, with --merge) matching this regex
+        #[arg(short = 'g', long)]
+        grep: Option<String>,
+
+        /// Match --grep regardless of case
+        #[arg(short = 'i', long)]
+        ignore_case: bool,
+    },
+
     /// Rollback nodes to previous NixOS generation
     Rollback {
         /// Target nodes (names or @tag)
@@ -150,10 +221,19 @@ enum Commands {
         nix_options: Vec<String>,
     },
 
-    /// Open interactive SSH session to a node
+    /// Open an interactive SSH session — several nodes get a tmux pane each
     Ssh {
-        /// Node name
-        node: String,
+        /// Target nodes (names or @tag)
+        #[arg(required = true)]
+        targets: Vec<String>,
+
+        /// Type into every pane at once (several nodes, with tmux)
+        #[arg(long)]
+        sync: bool,
+
+        /// Command to run with a terminal, instead of a login shell (after --)
+        #[arg(last = true)]
+        cmd: Vec<String>,
     },
 
     /// Show node registry information
@@ -173,12 +253,34 @@ enum Commands {
         all: bool,
     },
 
+    /// Record and check node host keys in fleet's known_hosts file
+    Hostkeys {
+        #[command(subcommand)]
+        action: HostkeysAction,
+    },
+
     /// Run or list named DAG workflows
     Flow {
         #[command(subcommand)]
         action: FlowAction,
     },
 
+    /// Check or inspect fleet.yaml
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+
+    /// List flow, node and rebuild locks, or break one
+    Locks {
+        #[command(subcommand)]
+        action: Option<LocksAction>,
+
+        /// Include lock files nobody holds
+        #[arg(long)]
+        all: bool,
+    },
+
     /// Manage secrets (provision from 1Password, clean local files)
     Secrets {
         #[command(subcommand)]
@@ -245,6 +347,64 @@ enum SecretsAction {
     List,
 }
 
+#[derive(Subcommand)]
+enum HostkeysAction {
+    /// Record keys of nodes not yet known; report changed or mismatched ones
+    Scan {
+        /// Target nodes (names or @tag)
+        targets: Vec<String>,
+
+        /// Scan all nodes (default if no targets given)
+        #[arg(long)]
+        all: bool,
+    },
+
+    /// Check nodes' keys against known_hosts and the registry, changing nothing
+    Verify {
+        /// Target nodes (names or @tag)
+        targets: Vec<String>,
+
+        /// Verify all nodes (default if no targets given)
+        #[arg(long)]
+        all: bool,
+    },
+
+    /// Replace recorded keys that changed, unless the registry contradicts them
+    Update {
+        /// Target nodes (names or @tag)
+        targets: Vec<String>,
+
+        /// Update all nodes
+        #[arg(long)]
+        all: bool,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigAction {
+    /// Load fleet.yaml and its includes strictly, and summarize them
+    Check,
+
+    /// Print each node's effective settings, hooks and secrets, and where each came from
+    Show {
+        /// Only this node
+        node: Option<String>,
+    },
+}
+
+#[derive(Subcommand)]
+enum LocksAction {
+    /// Remove a lock file (a held one only with --force)
+    Break {
+        /// flow:<name>, node:<name> or rebuild
+        lock: String,
+
+        /// Break it even though a process holds it
+        #[arg(long)]
+        force: bool,
+    },
+}
+
 #[derive(Subcommand)]
 enum FlowAction {
     /// List available flows
@@ -262,27 +422,128 @@ enum FlowAction {
         #[arg(long)]
         all: bool,
 
+        /// Flow parameter as name=value (repeatable)
+        #[arg(long = "param", value_name = "NAME=VALUE")]
+        params: Vec<String>,
+
+        /// Pre-approve an approval step by ID, for non-interactive runs (repeatable)
+        #[arg(long, value_name = "STEP")]
+        approve: Vec<String>,
+
         /// Print execution plan without running
         #[arg(long)]
         dry_run: bool,
     },
+
+    /// Print a flow's DAG as Graphviz DOT, Mermaid or JSON
+    Graph {
+        /// Flow name
+        name: String,
+
+        /// Output format
+        #[arg(long, value_enum, default_value = "dot")]
+        format: commands::flow_graph::GraphFormat,
+
+        /// Annotate steps with their status and duration in a recorded run
+        #[arg(long, value_name = "RUN_ID")]
+        run: Option<String>,
+    },
+
+    /// List recorded runs, newest first
+    Runs {
+        /// Only runs of this flow
+        name: Option<String>,
+
+        /// Show at most this many runs
+        #[arg(long, default_value_t = 20)]
+        limit: usize,
+
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Show one recorded run step by step
+    Show {
+        /// Run ID, or a unique prefix of one
+        run_id: String,
+
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Check flows without running them (all flows when no name is given)
+    Validate {
+        /// Flow name
+        name: Option<String>,
+
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+    },
 }
 
-fn load_config() -> config::FleetConfig {
+fn load_config(profile: Option<String>) -> Result<config::FleetConfig> {
     // Prefer local detection: walk up to find flake.nix
     let dir = std::env::current_dir()
         .ok()
         .and_then(|cwd| commands::rebuild::find_flake_root(&cwd).ok())
         .or_else(|| std::env::var("FLEET_FLAKE_DIR").map(PathBuf::from).ok())
         .unwrap_or_else(|| PathBuf::from("."));
-    config::FleetConfig::load(&dir).unwrap_or_default()
+    // A broken fleet.yaml fails the command rather than reading as an empty
+    // one, where every flow would simply "not exist".
+    let profile = profile
+        .or_else(|| std::env::var("FLEET_PROFILE").ok())
+        .filter(|p| !p.is_empty());
+    config::FleetConfig::load(&dir, profile.as_deref()).context("invalid fleet configuration")
+}
+
+impl Commands {
+    /// Commands that never read fleet.yaml, and so keep working while it is
+    /// broken — `nix-credential` in particular runs from shell hooks.
+    fn reads_config(&self) -> bool {
+        !matches!(
+            self,
+            Commands::NixCredential { .. }
+                | Commands::Convergence { .. }
+                | Commands::Mcp
+                | Commands::WarmInputs { .. }
+        )
+    }
 }
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
-    let config = load_config();
+    let config = if cli.command.reads_config() {
+        load_config(cli.profile.clone())?
+    } else {
+        config::FleetConfig::default()
+    };
+    let locks = lock::Locks::from_cli(cli.lock_timeout, cli.no_wait);
+    let transport = transport::OpenSsh::default();
+    dispatch(
+        cli.command,
+        &config,
+        &locks,
+        &transport,
+        &runs::state_dir(),
+        &registry::load_registry,
+    )
+}
 
-    match cli.command {
+/// Run one command. Everything with a side outside this process — the
+/// config, locks, nodes and registry — comes in from `main`, so tests can
+/// drive the whole CLI against stand-ins.
+fn dispatch(
+    command: Commands,
+    config: &config::FleetConfig,
+    locks: &lock::Locks,
+    transport: &dyn transport::Transport,
+    state_dir: &Path,
+    registry: &dyn Fn() -> Result<registry::NodeRegistry>,
+) -> Result<()> {
+    match command {
         Commands::Deploy {
             targets,
             all,
@@ -290,16 +551,19 @@ fn main() -> Result<()> {
             show_trace,
             skip_checks,
         } => {
-            secrets::provision_for_command(&config, "deploy")?;
-            let reg = registry::load_registry()?;
+            secrets::provision_for_command(config, "deploy")?;
+            let reg = registry()?;
             let resolved = targeting::resolve(&reg, &targets, all)?;
-            for (name, node) in &resolved.nodes {
-                hooks::run_pre(&config, "deploy", name, node)?;
-            }
-            commands::deploy::run(&resolved, dry_run, show_trace, skip_checks)?;
-            for (name, node) in &resolved.nodes {
-                hooks::run_post(&config, "deploy", name, node);
-            }
+            let _locks = if dry_run {
+                Vec::new()
+            } else {
+                locks.nodes(&resolved.names(), "fleet deploy")?
+            };
+            hooks::around(
+                config,
+                &hooks::HookContext::new("deploy", &resolved).over(transport),
+                || commands::deploy::run(&resolved, dry_run, show_trace, skip_checks),
+            )?;
         }
 
         Commands::WarmInputs { dry_run } => {
@@ -317,70 +581,106 @@ fn main() -> Result<()> {
             all,
             show_trace,
         } => {
-            let reg = registry::load_registry()?;
+            let reg = registry()?;
             let resolved = targeting::resolve(&reg, &targets, all)?;
-            for (name, node) in &resolved.nodes {
-                hooks::run_pre(&config, "build", name, node)?;
-            }
-            commands::build::run(&resolved, show_trace)?;
-            for (name, node) in &resolved.nodes {
-                hooks::run_post(&config, "build", name, node);
-            }
+            hooks::around(
+                config,
+                &hooks::HookContext::new("build", &resolved).over(transport),
+                || commands::build::run(&resolved, show_trace),
+            )?;
         }
 
         Commands::Diff { targets, all } => {
-            let reg = registry::load_registry()?;
+            let reg = registry()?;
             let resolved = targeting::resolve(&reg, &targets, all)?;
-            for (name, node) in &resolved.nodes {
-                hooks::run_pre(&config, "diff", name, node)?;
-            }
-            commands::diff::run(&resolved, &config)?;
-            for (name, node) in &resolved.nodes {
-                hooks::run_post(&config, "diff", name, node);
-            }
+            hooks::around(
+                config,
+                &hooks::HookContext::new("diff", &resolved).over(transport),
+                || commands::diff::run(&resolved, config, transport),
+            )?;
         }
 
         Commands::Exec { targets, all, cmd } => {
-            let reg = registry::load_registry()?;
+            let reg = registry()?;
             let resolved = targeting::resolve(&reg, &targets, all)?;
-            for (name, node) in &resolved.nodes {
-                hooks::run_pre(&config, "exec", name, node)?;
-            }
-            commands::exec::run(&resolved, &cmd, &config)?;
-            for (name, node) in &resolved.nodes {
-                hooks::run_post(&config, "exec", name, node);
-            }
+            let _locks = locks.nodes(&resolved.names(), "fleet exec")?;
+            hooks::around(
+                config,
+                &hooks::HookContext::new("exec", &resolved).over(transport),
+                || commands::exec::run(&resolved, &cmd, config, transport),
+            )?;
+        }
+
+        Commands::Cp { from, to } => {
+            let transfer = commands::cp::Transfer::parse(&from, &to)?;
+            let reg = registry()?;
+            let resolved = targeting::resolve(&reg, &[transfer.selector().to_string()], false)?;
+            // Only a push changes the nodes
+            let _locks = match transfer {
+                commands::cp::Transfer::Push { .. } => {
+                    Some(locks.nodes(&resolved.names(), "fleet cp")?)
+                }
+                commands::cp::Transfer::Pull { .. } => None,
+            };
+            commands::cp::run(&transfer, &resolved, config, transport)?;
         }
 
         Commands::Status { targets, all } => {
-            let reg = registry::load_registry()?;
+            let reg = registry()?;
             let all = all || targets.is_empty();
             let resolved = targeting::resolve(&reg, &targets, all)?;
-            commands::status::run(&resolved, &config)?;
+            hooks::around(
+                config,
+                &hooks::HookContext::new("status", &resolved).over(transport),
+                || commands::status::run(&resolved, config, transport),
+            )?;
+        }
+
+        Commands::Logs {
+            targets,
+            all,
+            units,
+            since,
+            lines,
+            follow,
+            merge,
+            grep,
+            ignore_case,
+        } => {
+            let reg = registry()?;
+            let resolved = targeting::resolve(&reg, &targets, all)?;
+            let opts = commands::logs::LogOptions {
+                units,
+                since,
+                lines,
+                follow,
+                merge,
+                grep,
+                ignore_case,
+            };
+            commands::logs::run(&resolved, &opts, config, transport)?;
         }
 
         Commands::Rollback { targets, all } => {
-            let reg = registry::load_registry()?;
+            let reg = registry()?;
             let resolved = targeting::resolve(&reg, &targets, all)?;
-            for (name, node) in &resolved.nodes {
-                hooks::run_pre(&config, "rollback", name, node)?;
-            }
-            commands::rollback::run(&resolved, &config)?;
-            for (name, node) in &resolved.nodes {
-                hooks::run_post(&config, "rollback", name, node);
-            }
+            let _locks = locks.nodes(&resolved.names(), "fleet rollback")?;
+            hooks::around(
+                config,
+                &hooks::HookContext::new("rollback", &resolved).over(transport),
+                || commands::rollback::run(&resolved, false, config, transport),
+            )?;
         }
 
         Commands::Reboot { targets, all, yes } => {
-            let reg = registry::load_registry()?;
+            let reg = registry()?;
             let resolved = targeting::resolve(&reg, &targets, all)?;
-            for (name, node) in &resolved.nodes {
-                hooks::run_pre(&config, "reboot", name, node)?;
-            }
-            commands::reboot::run(&resolved, yes, &config)?;
-            for (name, node) in &resolved.nodes {
-                hooks::run_post(&config, "reboot", name, node);
-            }
+            let _locks = locks.nodes(&resolved.names(), "fleet reboot")?;
+            hooks::around(
+                config,
+                &hooks::HookContext::new("reboot", &resolved).over(transport),
+                || commands::reboot::run(&resolved, yes, config, transport),
+            )?;
         }
 
         Commands::Rebuild {
@@ -388,26 +688,62 @@ fn main() -> Result<()> {
             show_trace,
             nix_options,
         } => {
-            secrets::provision_for_command(&config, "rebuild")?;
-            commands::rebuild::rebuild(node.as_deref(), show_trace, &nix_options)?;
+            secrets::provision_for_command(config, "rebuild")?;
+            let rebuild = || commands::rebuild::rebuild(node.as_deref(), show_trace, &nix_options);
+            if config.hook("rebuild").is_empty() {
+                rebuild()?;
+            } else {
+                // This machine, as the registry knows it if it does
+                let name = match &node {
+                    Some(n) => n.clone(),
+                    None => commands::rebuild::get_hostname()?,
+                };
+                let known = registry().ok().and_then(|reg| reg.get(&name).cloned());
+                let local = known.unwrap_or_else(|| registry::Node {
+                    system: String::new(),
+                    hostname: name.clone(),
+                    ssh_user: std::env::var("USER").unwrap_or_default(),
+                    tags: vec![],
+                    host_key: None,
+                });
+                let resolved = targeting::ResolvedTargets {
+                    nodes: vec![(name, local)],
+                };
+                hooks::around(
+                    config,
+                    &hooks::HookContext::new("rebuild", &resolved),
+                    rebuild,
+                )?;
+            }
         }
 
-        Commands::Ssh { node } => {
-            let reg = registry::load_registry()?;
-            let resolved = targeting::resolve(&reg, &[node], false)?;
-            commands::ssh::run(&resolved, &config)?;
+        Commands::Ssh { targets, sync, cmd } => {
+            let reg = registry()?;
+            let resolved = targeting::resolve(&reg, &targets, false)?;
+            let command = (!cmd.is_empty()).then(|| transport::exec_command_line(&cmd));
+            // Only pre-hooks and on_failure: a session that starts replaces
+            // fleet, so nothing runs after it
+            hooks::around(
+                config,
+                &hooks::HookContext::new("ssh", &resolved).over(transport),
+                || commands::ssh::run(&resolved, command.as_deref(), sync, config, transport),
+            )?;
         }
 
         Commands::Info { json } => {
-            let reg = registry::load_registry()?;
+            let reg = registry()?;
             commands::info::run(&reg, json)?;
         }
 
         Commands::Ping { targets, all } => {
-            let reg = registry::load_registry()?;
+            let reg = registry()?;
             let all = all || targets.is_empty();
             let resolved = targeting::resolve(&reg, &targets, all)?;
-            commands::ping::run(&resolved, &config)?;
+            hooks::around(
+                config,
+                &hooks::HookContext::new("ping", &resolved).over(transport),
+                || commands::ping::run(&resolved, config, transport),
+            )?;
         }
 
         Commands::NixCredential { check, quiet, root } => {
@@ -416,14 +752,14 @@ fn main() -> Result<()> {
 
         Commands::Secrets { action } => match action {
             SecretsAction::Sync { name } => match name {
-                Some(n) => secrets::sync_secret(&config, &n)?,
-                None => secrets::sync_all(&config)?,
+                Some(n) => secrets::sync_secret(config, &n)?,
+                None => secrets::sync_all(config)?,
             },
             SecretsAction::Clean { name } => match name {
-                Some(n) => secrets::clean_secret(&config, &n)?,
+                Some(n) => secrets::clean_secret(config, &n)?,
                 None => {
                     for secret_name in config.secrets.keys() {
-                        secrets::clean_secret(&config, secret_name)?;
+                        secrets::clean_secret(config, secret_name)?;
                     }
                 }
             },
@@ -450,19 +786,91 @@ fn main() -> Result<()> {
             }
         },
 
+        Commands::Hostkeys { action } => {
+            let (mode, targets, all) = match action {
+                HostkeysAction::Scan { targets, all } => {
+                    (commands::hostkeys::Mode::Scan, targets, all)
+                }
+                HostkeysAction::Verify { targets, all } => {
+                    (commands::hostkeys::Mode::Verify, targets, all)
+                }
+                HostkeysAction::Update { targets, all } => {
+                    // Accepting new keys is never a default
+                    (commands::hostkeys::Mode::Update, targets, all)
+                }
+            };
+            let reg = registry()?;
+            let all = all || (targets.is_empty() && mode != commands::hostkeys::Mode::Update);
+            let resolved = targeting::resolve(&reg, &targets, all)?;
+            let known_hosts = hostkeys::known_hosts_path(state_dir);
+            commands::hostkeys::run(&resolved, mode, &known_hosts, config, transport)?;
+        }
+
+        Commands::Config { action } => match action {
+            ConfigAction::Check => commands::config::check(config)?,
+            ConfigAction::Show { node } => {
+                let reg = registry().unwrap_or_default();
+                commands::config::show(config, &reg, node.as_deref())?
+            }
+        },
+
+        Commands::Locks { action, all } => match action {
+            None => commands::locks::list(locks, all)?,
+            Some(LocksAction::Break { lock, force }) => {
+                commands::locks::break_lock(locks, &lock, force)?
+            }
+        },
+
         Commands::Flow { action } => match action {
             FlowAction::List => {
-                commands::flow::list(&config)?;
+                commands::flow::list(config)?;
             }
             FlowAction::Run {
                 name,
                 targets,
                 all,
+                params,
+                approve,
                 dry_run,
             } => {
                 // Registry is optional — Pangea-only flows don't need node targets
-                let reg = registry::load_registry().unwrap_or_default();
-                commands::flow::run(&config, &reg, &name, &targets, all, dry_run)?;
+                let reg = registry().unwrap_or_default();
+                let opts = commands::flow::RunOptions {
+                    targets,
+                    all,
+                    params,
+                    approve,
+                    dry_run,
+                };
+                commands::flow::run(
+                    config,
+                    &reg,
+                    locks,
+                    transport,
+                    &runs::runs_dir(state_dir),
+                    &name,
+                    &opts,
+                )?;
+            }
+            FlowAction::Graph { name, format, run } => {
+                let reg = registry().unwrap_or_default();
+                let runs_dir = runs::runs_dir(state_dir);
+                commands::flow_graph::run(config, &reg, &name, format, run.as_deref(), &runs_dir)?;
+            }
+            FlowAction::Runs { name, limit, json } => {
+                commands::flow_runs::list(
+                    &runs::runs_dir(state_dir),
+                    name.as_deref(),
+                    limit,
+                    json,
+                )?;
+            }
+            FlowAction::Show { run_id, json } => {
+                commands::flow_runs::show(&runs::runs_dir(state_dir), &run_id, json)?;
+            }
+            FlowAction::Validate { name, json } => {
+                let reg = registry().unwrap_or_default();
+                commands::flow_validate::run(config, &reg, name.as_deref(), json)?;
             }
         },
     }
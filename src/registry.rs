@@ -9,6 +9,10 @@ pub struct Node {
     #[serde(rename = "sshUser")]
     pub ssh_user: String,
     pub tags: Vec<String>,
+    /// The host key the node must present, `<type> <base64>` as in a
+    /// `.pub` file — checked by `fleet hostkeys`.
+    #[serde(rename = "hostKey", default, skip_serializing_if = "Option::is_none")]
+    pub host_key: Option<String>,
 }
 
 pub type NodeRegistry = HashMap<String, Node>;
@@ -0,0 +1,421 @@
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::Mutex;
+
+use crate::commands::utils::log_warning;
+use crate::condition::StepStatus;
+use crate::config::RunsConfig;
+
+/// How a whole flow run ended. `Running` is what a record says while the
+/// run is in progress — and forever after, if fleet was killed mid-run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum RunStatus {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// One `fleet flow run`, as persisted under the state directory.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RunRecord {
+    /// `<start epoch secs>-<pid>`, so file names sort chronologically.
+    pub id: String,
+    pub flow: String,
+    pub params: BTreeMap<String, String>,
+    /// CLI targets, for steps without their own.
+    pub targets: Vec<String>,
+    pub all: bool,
+    pub user: String,
+    /// `HEAD` of the flake repository, if it is a git checkout.
+    pub git_rev: Option<String>,
+    /// Whether tracked files differed from `git_rev`.
+    pub git_dirty: bool,
+    /// Epoch seconds.
+    pub started_at: u64,
+    pub finished_at: Option<u64>,
+    #[serde(default)]
+    pub duration_ms: Option<u64>,
+    pub status: RunStatus,
+    pub error: Option<String>,
+    pub steps: Vec<StepRecord>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StepRecord {
+    /// Step ID; steps of a sub-flow are `<flow step>.<sub step>`.
+    pub id: String,
+    pub action: String,
+    pub status: StepStatus,
+    /// Epoch seconds.
+    pub started_at: u64,
+    pub duration_ms: u64,
+    pub error: Option<String>,
+    /// Names of the outputs it produced. Values are not persisted — a
+    /// Pangea output can be a credential — except an approval's, which are
+    /// the audit trail of who let the run continue.
+    pub outputs: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub approval: Option<BTreeMap<String, serde_json::Value>>,
+    /// The failed steps that kept this one from running.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub blocked_by: Vec<String>,
+}
+
+/// Where fleet keeps state between runs: `$FLEET_STATE_DIR`, else
+/// `$XDG_STATE_HOME/fleet`, else `~/.local/state/fleet`.
+pub fn state_dir() -> PathBuf {
+    std::env::var_os("FLEET_STATE_DIR")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("XDG_STATE_HOME").map(|d| PathBuf::from(d).join("fleet")))
+        .unwrap_or_else(|| {
+            let home = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+            PathBuf::from(home).join(".local/state/fleet")
+        })
+}
+
+/// Where run records live: `runs/` in the state directory.
+pub fn runs_dir(state_dir: &Path) -> PathBuf {
+    state_dir.join("runs")
+}
+
+pub fn now_epoch() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+/// `850ms`, `3.2s`, `4m12s`, `1h03m`.
+pub fn format_duration_ms(ms: u64) -> String {
+    match ms {
+        0..1_000 => format!("{}ms", ms),
+        1_000..60_000 => format!("{:.1}s", ms as f64 / 1000.0),
+        60_000..3_600_000 => format!("{}m{:02}s", ms / 60_000, ms / 1000 % 60),
+        _ => format!("{}h{:02}m", ms / 3_600_000, ms / 60_000 % 60),
+    }
+}
+
+/// `YYYY-MM-DD HH:MM:SS` in UTC, from epoch seconds.
+pub fn format_utc(epoch: u64) -> String {
+    let (days, secs) = (epoch / 86_400, epoch % 86_400);
+    // Civil-from-days (Howard Hinnant), valid for any date after 1970
+    let z = days as i64 + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60
+    )
+}
+
+/// `HEAD` of the repository at `dir` and whether tracked files are dirty.
+pub fn git_state(dir: &Path) -> (Option<String>, bool) {
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(args)
+            .output()
+            .ok()
+            .filter(|o| o.status.success())
+            .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+    };
+    let rev = git(&["rev-parse", "HEAD"]);
+    let dirty = rev.is_some()
+        && git(&["status", "--porcelain", "--untracked-files=no"]).is_some_and(|s| !s.is_empty());
+    (rev, dirty)
+}
+
+/// Keeps a run's record on disk up to date as it progresses. Shared by the
+/// threads of a concurrent fan-out, hence the lock.
+pub struct Recorder {
+    dir: PathBuf,
+    record: Mutex<RunRecord>,
+    started: std::time::Instant,
+}
+
+impl Recorder {
+    /// Start recording; the record is written immediately, as `running`.
+    pub fn start(dir: PathBuf, record: RunRecord) -> Self {
+        let recorder = Recorder {
+            dir,
+            record: Mutex::new(record),
+            started: std::time::Instant::now(),
+        };
+        recorder.save();
+        recorder
+    }
+
+    pub fn step(&self, step: StepRecord) {
+        self.with(|r| r.steps.push(step));
+    }
+
+    pub fn finish(&self, error: Option<String>) {
+        self.with(|r| {
+            r.finished_at = Some(now_epoch());
+            r.duration_ms = Some(self.started.elapsed().as_millis() as u64);
+            r.status = if error.is_some() {
+                RunStatus::Failed
+            } else {
+                RunStatus::Succeeded
+            };
+            r.error = error;
+        });
+    }
+
+    pub fn id(&self) -> String {
+        self.record
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .id
+            .clone()
+    }
+
+    fn with(&self, f: impl FnOnce(&mut RunRecord)) {
+        f(&mut self.record.lock().unwrap_or_else(|e| e.into_inner()));
+        self.save();
+    }
+
+    /// History is a convenience: failing to write it warns, it never fails
+    /// the run.
+    fn save(&self) {
+        let record = self.record.lock().unwrap_or_else(|e| e.into_inner());
+        if let Err(e) = write(&self.dir, &record) {
+            log_warning(&format!("Could not save run record: {:#}", e));
+        }
+    }
+}
+
+fn write(dir: &Path, record: &RunRecord) -> Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("creating {}", dir.display()))?;
+    let path = dir.join(format!("{}.json", record.id));
+    // Write-then-rename, so a reader never sees half a record
+    let tmp = path.with_extension("json.tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(record)?)
+        .with_context(|| format!("writing {}", tmp.display()))?;
+    fs::rename(&tmp, &path).with_context(|| format!("writing {}", path.display()))?;
+    Ok(())
+}
+
+/// Every readable record, oldest first. Unreadable files are skipped with
+/// a warning rather than hiding the rest of the history.
+pub fn load_all(dir: &Path) -> Result<Vec<RunRecord>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e).with_context(|| format!("reading {}", dir.display())),
+    };
+    let mut records = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        if path.extension().is_none_or(|ext| ext != "json") {
+            continue;
+        }
+        match fs::read(&path)
+            .map_err(anyhow::Error::from)
+            .and_then(|data| Ok(serde_json::from_slice::<RunRecord>(&data)?))
+        {
+            Ok(record) => records.push(record),
+            Err(e) => log_warning(&format!("Skipping {}: {:#}", path.display(), e)),
+        }
+    }
+    records.sort_by_key(|r| (r.started_at, r.id.clone()));
+    Ok(records)
+}
+
+/// The record whose ID is `id`, or uniquely starts with it.
+pub fn find(dir: &Path, id: &str) -> Result<RunRecord> {
+    let mut matches: Vec<RunRecord> = load_all(dir)?
+        .into_iter()
+        .filter(|r| r.id.starts_with(id))
+        .collect();
+    if let Some(exact) = matches.iter().position(|r| r.id == id) {
+        return Ok(matches.swap_remove(exact));
+    }
+    match matches.len() {
+        0 => bail!("No run '{}' in {}", id, dir.display()),
+        1 => Ok(matches.remove(0)),
+        _ => bail!(
+            "Run ID '{}' is ambiguous: {}",
+            id,
+            matches
+                .iter()
+                .map(|r| r.id.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        ),
+    }
+}
+
+/// Delete finished records beyond the retention limits: more than `keep`
+/// per flow, or older than `max_age_days`. Records still `running` are
+/// left alone unless they are past the age limit — a killed run never
+/// finishes, but one in progress must not lose its file.
+pub fn prune(dir: &Path, limits: &RunsConfig) -> Result<usize> {
+    let records = load_all(dir)?;
+    let cutoff = limits
+        .max_age_days
+        .map(|days| now_epoch().saturating_sub(days * 86_400));
+
+    let mut per_flow: BTreeMap<&str, Vec<&RunRecord>> = BTreeMap::new();
+    for record in &records {
+        per_flow.entry(&record.flow).or_default().push(record);
+    }
+
+    let mut removed = 0;
+    for runs in per_flow.values() {
+        let excess = runs.len().saturating_sub(limits.keep);
+        for (i, record) in runs.iter().enumerate() {
+            let too_old = cutoff.is_some_and(|c| record.started_at < c);
+            let over_count = i < excess && record.status != RunStatus::Running;
+            if too_old || over_count {
+                fs::remove_file(dir.join(format!("{}.json", record.id)))?;
+                removed += 1;
+            }
+        }
+    }
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(id: &str, flow: &str, started_at: u64, status: RunStatus) -> RunRecord {
+        RunRecord {
+            id: id.to_string(),
+            flow: flow.to_string(),
+            params: BTreeMap::new(),
+            targets: Vec::new(),
+            all: false,
+            user: "ops".to_string(),
+            git_rev: None,
+            git_dirty: false,
+            started_at,
+            finished_at: None,
+            duration_ms: None,
+            status,
+            error: None,
+            steps: Vec::new(),
+        }
+    }
+
+    /// A scratch runs directory, removed on drop.
+    struct Dir(PathBuf);
+
+    impl Dir {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("fleet-runs-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            Dir(dir)
+        }
+    }
+
+    impl Drop for Dir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn recorder_writes_through() {
+        let dir = Dir::new("recorder");
+        let recorder = Recorder::start(
+            dir.0.clone(),
+            record("100-1", "deploy", 100, RunStatus::Running),
+        );
+        assert_eq!(load_all(&dir.0).unwrap()[0].status, RunStatus::Running);
+
+        recorder.step(StepRecord {
+            id: "build".to_string(),
+            action: "build".to_string(),
+            status: StepStatus::Failed,
+            started_at: 100,
+            duration_ms: 1500,
+            error: Some("boom".to_string()),
+            outputs: Vec::new(),
+            approval: None,
+            blocked_by: Vec::new(),
+        });
+        recorder.finish(Some("Step 'build' failed".to_string()));
+
+        let saved = find(&dir.0, "100").unwrap();
+        assert_eq!(saved.status, RunStatus::Failed);
+        assert_eq!(saved.steps[0].status, StepStatus::Failed);
+        assert_eq!(saved.steps[0].duration_ms, 1500);
+        assert!(saved.finished_at.is_some());
+    }
+
+    #[test]
+    fn find_by_prefix() {
+        let dir = Dir::new("find");
+        for id in ["100-1", "100-2", "200-1"] {
+            write(&dir.0, &record(id, "f", 0, RunStatus::Succeeded)).unwrap();
+        }
+        assert_eq!(find(&dir.0, "200").unwrap().id, "200-1");
+        assert_eq!(find(&dir.0, "100-2").unwrap().id, "100-2");
+        let err = find(&dir.0, "100").unwrap_err().to_string();
+        assert!(err.contains("ambiguous: 100-1, 100-2"), "{err}");
+        assert!(find(&dir.0, "300").is_err());
+    }
+
+    #[test]
+    fn durations() {
+        assert_eq!(format_duration_ms(850), "850ms");
+        assert_eq!(format_duration_ms(3_240), "3.2s");
+        assert_eq!(format_duration_ms(252_000), "4m12s");
+        assert_eq!(format_duration_ms(3_780_000), "1h03m");
+    }
+
+    #[test]
+    fn utc_dates() {
+        assert_eq!(format_utc(0), "1970-01-01 00:00:00");
+        assert_eq!(format_utc(951_782_400), "2000-02-29 00:00:00");
+        assert_eq!(format_utc(1_792_324_800), "2026-10-18 12:00:00");
+    }
+
+    #[test]
+    fn prune_keeps_newest_per_flow_and_running_records() {
+        let dir = Dir::new("prune");
+        let now = now_epoch();
+        for (id, flow, age_days, status) in [
+            ("1", "a", 10, RunStatus::Succeeded),
+            ("2", "a", 9, RunStatus::Running),
+            ("3", "a", 8, RunStatus::Failed),
+            ("4", "a", 7, RunStatus::Succeeded),
+            ("5", "b", 40, RunStatus::Succeeded),
+            ("6", "b", 1, RunStatus::Succeeded),
+        ] {
+            let r = record(id, flow, now - age_days * 86_400, status);
+            write(&dir.0, &r).unwrap();
+        }
+
+        let limits = RunsConfig {
+            keep: 2,
+            max_age_days: Some(30),
+        };
+        assert_eq!(prune(&dir.0, &limits).unwrap(), 2);
+        let left: Vec<_> = load_all(&dir.0)
+            .unwrap()
+            .into_iter()
+            .map(|r| r.id)
+            .collect();
+        assert_eq!(left, ["2", "3", "4", "6"]);
+    }
+}
@@ -88,6 +88,9 @@ fn provision_secret(name: &str, secret: &SecretDef, op_cmd: Option<&str>) -> Res
     Ok(())
 }
 
+/// The commands that provision secrets listing them in `provision_before`.
+pub const PROVISIONING_COMMANDS: &[&str] = &["deploy", "rebuild"];
+
 /// Provision all secrets that are configured to run before the given command,
 /// but only if their target file does not already exist.
 pub fn provision_for_command(config: &FleetConfig, command_name: &str) -> Result<()> {
@@ -0,0 +1,546 @@
+use anyhow::{bail, Context, Result};
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{BufRead, BufReader, Read};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::OnceLock;
+
+use crate::commands::utils::log_warning;
+use crate::config::{FleetConfig, ResolvedSsh};
+use crate::hostkeys;
+use crate::registry::Node;
+
+/// How long an idle multiplexed connection outlives the last command that
+/// used it — long enough for `status`'s four queries or a flow's next step,
+/// short enough not to linger after fleet is done.
+const CONTROL_PERSIST_SECS: u32 = 60;
+
+/// Where a remote command goes: a node, or any `[user@]host`.
+#[derive(Debug, Clone)]
+pub struct Target {
+    /// What messages call it — the node name.
+    pub name: String,
+    /// `user@host` (or just `host`), as ssh takes it.
+    pub destination: String,
+    pub ssh: ResolvedSsh,
+}
+
+impl Target {
+    /// A registry node, with its resolved SSH settings.
+    pub fn node(config: &FleetConfig, name: &str, node: &Node) -> Self {
+        Self {
+            name: name.to_string(),
+            destination: format!("{}@{}", node.ssh_user, node.hostname),
+            ssh: config.resolve_ssh(name, &node.tags),
+        }
+    }
+
+    /// A host that is not a node — a build machine, say — as `[user@]host`.
+    pub fn host(config: &FleetConfig, destination: &str) -> Self {
+        Self {
+            name: destination.to_string(),
+            destination: destination.to_string(),
+            ssh: config.resolve_ssh(destination, &[]),
+        }
+    }
+}
+
+/// What a remote command left behind.
+#[derive(Debug, Clone, Default)]
+pub struct Output {
+    /// Exit code; `None` when killed by a signal.
+    pub code: Option<i32>,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl Output {
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+}
+
+/// How fleet reaches its nodes. Every remote call goes through one of
+/// these, so the connection handling lives in one place — and tests can
+/// stand in for the network. `Sync`, as a flow runs a level's steps on
+/// threads of their own.
+pub trait Transport: Sync {
+    /// Run `command`, a shell command line, on the target and capture its
+    /// output. Errors only when the command could not be started at all; a
+    /// remote failure is an `Output` with a non-zero code.
+    fn run(&self, target: &Target, command: &str) -> Result<Output>;
+
+    /// Hand the terminal to an interactive session on the target, running
+    /// `command` or a login shell. Returns only if that could not start.
+    fn interactive(&self, target: &Target, command: Option<&str>) -> anyhow::Error;
+
+    /// `run`, handing each line of stdout to `line` as it arrives rather
+    /// than capturing it — for output that may never end, like
+    /// `journalctl -f`. The returned `Output` has no stdout.
+    fn stream(&self, target: &Target, command: &str, line: &mut dyn FnMut(&str)) -> Result<Output>;
+
+    /// Copy the local file `from` to `to` on the target. A failed copy is
+    /// an `Output` with a non-zero code, as with `run`.
+    fn push(&self, target: &Target, from: &Path, to: &str) -> Result<Output>;
+
+    /// Copy `from` on the target to the local path `to`.
+    fn pull(&self, target: &Target, from: &str, to: &Path) -> Result<Output>;
+
+    /// The host key the target presents, as known_hosts lines in stdout —
+    /// reached the way every other connection to it is, jump hosts and
+    /// all, but trusting nothing already recorded for it. Of type `kind`
+    /// if given, else whichever ssh prefers.
+    fn host_keys(&self, target: &Target, kind: Option<&str>) -> Result<Output>;
+
+    /// The command line `interactive` would run, as words — for starting
+    /// the session somewhere else, like a terminal multiplexer's pane.
+    fn login_command(&self, target: &Target, command: Option<&str>) -> Vec<String>;
+
+    /// Hand the terminal to `program` on this machine. Returns only if it
+    /// could not start.
+    fn hand_over(&self, program: &str, args: &[&str]) -> anyhow::Error;
+
+    /// Run `program` on this machine, on behalf of a remote operation — the
+    /// local half of one, like `diff`'s `nix build` — and capture its output.
+    fn local(&self, program: &str, args: &[&str]) -> Result<Output>;
+
+    /// `run`, failing unless the command succeeds: its trimmed stdout, or
+    /// an error carrying its stderr.
+    fn run_ok(&self, target: &Target, command: &str) -> Result<String> {
+        let output = self.run(target, command)?;
+        if !output.success() {
+            bail!("Command failed: {}", output.stderr.trim());
+        }
+        Ok(output.stdout.trim().to_string())
+    }
+
+    /// Whether the target accepts a connection and runs a command.
+    fn check(&self, target: &Target) -> bool {
+        self.run(target, "true").is_ok_and(|o| o.success())
+    }
+}
+
+/// The system `ssh`, sharing one connection per node between commands
+/// through ControlMaster sockets in a fleet-owned directory.
+#[derive(Default)]
+pub struct OpenSsh {
+    /// Where control sockets live, set up on first use; `None` when no safe
+    /// directory could be had, and every command connects afresh.
+    control_dir: OnceLock<Option<PathBuf>>,
+    /// The known_hosts file, its directory made on first use.
+    known_hosts: OnceLock<PathBuf>,
+}
+
+impl OpenSsh {
+    fn control_dir(&self) -> Option<&PathBuf> {
+        self.control_dir
+            .get_or_init(|| {
+                let dir = control_dir();
+                match prepare_control_dir(&dir) {
+                    Ok(()) => Some(dir),
+                    Err(e) => {
+                        log_warning(&format!("SSH connection sharing disabled: {:#}", e));
+                        None
+                    }
+                }
+            })
+            .as_ref()
+    }
+
+    fn known_hosts(&self) -> &Path {
+        self.known_hosts.get_or_init(|| {
+            let path = hostkeys::known_hosts_path(&crate::runs::state_dir());
+            // ssh records a new host's key (with accept-new) but will not
+            // create the directory to hold it
+            if let Some(dir) = path.parent() {
+                let _ = fs::create_dir_all(dir);
+            }
+            path
+        })
+    }
+
+    /// `-o` options for the target's settings, in precedence order — ssh
+    /// keeps the FIRST value it sees for each — so the config's `options`
+    /// can override fleet's own defaults below them.
+    fn options(&self, target: &Target, batch: bool) -> Vec<String> {
+        let ssh = &target.ssh;
+        let mut options = vec![
+            format!("ConnectTimeout={}", ssh.connect_timeout),
+            format!("StrictHostKeyChecking={}", ssh.strict_host_key),
+        ];
+        let mut configured: Vec<_> = ssh.options.iter().collect();
+        configured.sort();
+        options.extend(configured.into_iter().map(|(k, v)| format!("{}={}", k, v)));
+        // Fleet's own known_hosts, kept in plain names so `fleet hostkeys`
+        // can read back what ssh recorded — first, as ssh records new keys
+        // in the first file. The user's own stays trusted after it.
+        options.push(format!(
+            "UserKnownHostsFile={} {}",
+            ssh_path(self.known_hosts()),
+            USER_KNOWN_HOSTS
+        ));
+        options.push("HashKnownHosts=no".to_string());
+        if batch {
+            // Never stop to ask for a password or passphrase nobody will type
+            options.push("BatchMode=yes".to_string());
+        }
+        if let Some(dir) = self.control_dir() {
+            options.push("ControlMaster=auto".to_string());
+            // %C hashes host, port, user and jump host into a short name,
+            // well inside the socket path limit.
+            options.push(format!("ControlPath={}/%C", dir.display()));
+            options.push(format!("ControlPersist={}", CONTROL_PERSIST_SECS));
+        }
+        options
+            .into_iter()
+            .flat_map(|o| ["-o".to_string(), o])
+            .collect()
+    }
+
+    /// `ssh` with the target's settings.
+    fn command(&self, target: &Target, batch: bool, tty: bool) -> Command {
+        let mut cmd = Command::new("ssh");
+        cmd.args(self.options(target, batch));
+        if tty {
+            cmd.arg("-t");
+        }
+        cmd.arg(&target.destination);
+        cmd
+    }
+
+    /// `scp` with the target's settings, over the same shared connection.
+    fn copy(&self, target: &Target, from: &OsStr, to: &OsStr) -> Result<Output> {
+        let output = Command::new("scp")
+            .args(self.options(target, true))
+            .arg("-q")
+            .arg(from)
+            .arg(to)
+            .stdin(Stdio::null())
+            .output()
+            .with_context(|| format!("Failed to run scp for {}", target.name))?;
+        Ok(Output {
+            code: output.status.code(),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        })
+    }
+}
+
+impl Transport for OpenSsh {
+    fn run(&self, target: &Target, command: &str) -> Result<Output> {
+        let output = self
+            .command(target, true, false)
+            .arg(command)
+            .stdin(Stdio::null())
+            .output()
+            .with_context(|| format!("Failed to run ssh for {}", target.name))?;
+        Ok(Output {
+            code: output.status.code(),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        })
+    }
+
+    fn interactive(&self, target: &Target, command: Option<&str>) -> anyhow::Error {
+        // A remote command gets no tty unless asked for one
+        let mut cmd = self.command(target, false, command.is_some());
+        cmd.args(command);
+        anyhow::anyhow!("Failed to exec ssh: {}", cmd.exec())
+    }
+
+    fn stream(&self, target: &Target, command: &str, line: &mut dyn FnMut(&str)) -> Result<Output> {
+        let mut child = self
+            .command(target, true, false)
+            .arg(command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to run ssh for {}", target.name))?;
+        // Drained on a thread of its own, so a chatty stderr cannot fill its
+        // pipe and stall the stdout we are reading
+        let mut stderr = child.stderr.take().expect("stderr is piped");
+        let stderr = std::thread::spawn(move || {
+            let mut text = String::new();
+            let _ = stderr.read_to_string(&mut text);
+            text
+        });
+        let stdout = child.stdout.take().expect("stdout is piped");
+        for text in BufReader::new(stdout).split(b'\n') {
+            let text = text.with_context(|| format!("Failed to read from {}", target.name))?;
+            line(&String::from_utf8_lossy(&text));
+        }
+        let status = child.wait()?;
+        Ok(Output {
+            code: status.code(),
+            stdout: String::new(),
+            stderr: stderr.join().unwrap_or_default(),
+        })
+    }
+
+    fn push(&self, target: &Target, from: &Path, to: &str) -> Result<Output> {
+        let remote = format!("{}:{}", target.destination, to);
+        self.copy(target, from.as_os_str(), OsStr::new(&remote))
+    }
+
+    fn pull(&self, target: &Target, from: &str, to: &Path) -> Result<Output> {
+        let remote = format!("{}:{}", target.destination, from);
+        self.copy(target, OsStr::new(&remote), to.as_os_str())
+    }
+
+    fn host_keys(&self, target: &Target, kind: Option<&str>) -> Result<Output> {
+        let dir = private_dir(self.control_dir(), &format!("hostkeys-{}", target.name))?;
+        let file = dir.join("known_hosts");
+        // Ahead of the target's own options, as ssh keeps the first value:
+        // a fresh connection that records whatever key it is shown in a
+        // file of its own. ssh records it before authenticating, so a
+        // login that then fails still leaves the key behind.
+        let mut overrides = vec![
+            "StrictHostKeyChecking=accept-new".to_string(),
+            format!("UserKnownHostsFile={}", ssh_path(&file)),
+            "GlobalKnownHostsFile=/dev/null".to_string(),
+            "CheckHostIP=no".to_string(),
+            "ControlPath=none".to_string(),
+        ];
+        if let Some(kind) = kind {
+            overrides.push(format!("HostKeyAlgorithms={}", host_key_algorithms(kind)));
+        }
+        let output = Command::new("ssh")
+            .args(overrides.into_iter().flat_map(|o| ["-o".to_string(), o]))
+            .args(self.options(target, true))
+            .arg(&target.destination)
+            .arg("true")
+            .stdin(Stdio::null())
+            .output()
+            .with_context(|| format!("Failed to run ssh for {}", target.name))?;
+        let recorded = fs::read_to_string(&file).unwrap_or_default();
+        let _ = fs::remove_dir_all(&dir);
+        Ok(Output {
+            code: output.status.code(),
+            stdout: recorded,
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        })
+    }
+
+    fn login_command(&self, target: &Target, command: Option<&str>) -> Vec<String> {
+        let mut cmd = self.command(target, false, command.is_some());
+        cmd.args(command);
+        std::iter::once(cmd.get_program())
+            .chain(cmd.get_args())
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    fn hand_over(&self, program: &str, args: &[&str]) -> anyhow::Error {
+        let err = Command::new(program).args(args).exec();
+        anyhow::anyhow!("Failed to exec {}: {}", program, err)
+    }
+
+    fn local(&self, program: &str, args: &[&str]) -> Result<Output> {
+        let output = Command::new(program)
+            .args(args)
+            .stdin(Stdio::null())
+            .output()
+            .with_context(|| format!("Failed to execute: {} {}", program, args.join(" ")))?;
+        Ok(Output {
+            code: output.status.code(),
+            stdout: String::from_utf8_lossy(&output.stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        })
+    }
+}
+
+/// The algorithms that have a node present its key of type `kind`: an RSA
+/// key signs with SHA-2, as current ssh refuses plain `ssh-rsa`.
+fn host_key_algorithms(kind: &str) -> &str {
+    match kind {
+        "ssh-rsa" => "rsa-sha2-512,rsa-sha2-256,ssh-rsa",
+        kind => kind,
+    }
+}
+
+/// The user's own known_hosts, which ssh expands `~` in.
+const USER_KNOWN_HOSTS: &str = "~/.ssh/known_hosts";
+
+/// A path as an ssh option value, which ssh splits at whitespace unless
+/// quoted.
+fn ssh_path(path: &Path) -> String {
+    let path = path.display().to_string();
+    if path.contains(char::is_whitespace) {
+        format!("\"{}\"", path)
+    } else {
+        path
+    }
+}
+
+/// `FLEET_SSH_CONTROL_DIR`, else `$XDG_RUNTIME_DIR/fleet/ssh`, else a
+/// per-user directory under /tmp.
+fn control_dir() -> PathBuf {
+    if let Some(dir) = std::env::var_os("FLEET_SSH_CONTROL_DIR") {
+        return PathBuf::from(dir);
+    }
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(runtime) => PathBuf::from(runtime).join("fleet").join("ssh"),
+        None => std::env::temp_dir().join(format!("fleet-ssh-{}", uid())),
+    }
+}
+
+/// Make `dir` ours and private. A socket there is a logged-in session on a
+/// node, so a directory someone else created — a squatter in /tmp — is
+/// refused rather than used.
+fn prepare_control_dir(dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir).with_context(|| format!("failed to create {}", dir.display()))?;
+    let meta = fs::metadata(dir)?;
+    if meta.uid() != uid() {
+        bail!("{} belongs to uid {}, not us", dir.display(), meta.uid());
+    }
+    fs::set_permissions(dir, fs::Permissions::from_mode(0o700))
+        .with_context(|| format!("failed to restrict {}", dir.display()))
+}
+
+/// A new directory only we can enter, for a file ssh writes and fleet then
+/// trusts: inside the control directory when there is one, else the temp
+/// directory. Made fresh, so nobody can have put anything there first.
+fn private_dir(parent: Option<&PathBuf>, purpose: &str) -> Result<PathBuf> {
+    static MADE: AtomicU32 = AtomicU32::new(0);
+    let parent = parent.cloned().unwrap_or_else(std::env::temp_dir);
+    let nanos = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    let dir = parent.join(format!(
+        "fleet-{}-{}-{}-{}",
+        purpose,
+        std::process::id(),
+        MADE.fetch_add(1, Ordering::Relaxed),
+        nanos
+    ));
+    fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&dir)
+        .with_context(|| format!("failed to create {}", dir.display()))?;
+    Ok(dir)
+}
+
+fn uid() -> u32 {
+    // SAFETY: getuid() takes no arguments, cannot fail, and has no side effects.
+    unsafe { libc::getuid() }
+}
+
+/// `argv` as one POSIX shell command line, each word quoted as needed, so
+/// the remote shell sees exactly these words.
+pub fn shell_join(argv: &[String]) -> String {
+    argv.iter()
+        .map(|a| shell_quote(a))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// One word for a POSIX shell: as-is when it has nothing the shell would
+/// interpret, else single-quoted.
+pub fn shell_quote(word: &str) -> String {
+    let plain = !word.is_empty()
+        && word
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-./:=@%+,".contains(c));
+    if plain {
+        word.to_string()
+    } else {
+        format!("'{}'", word.replace('\'', r"'\''"))
+    }
+}
+
+/// What `fleet exec -- <cmd>` runs: a lone argument is a command line of
+/// its own (`fleet exec web -- 'journalctl -u nginx | tail'`), several are
+/// words, quoted so each arrives intact.
+pub fn exec_command_line(cmd: &[String]) -> String {
+    match cmd {
+        [line] => line.clone(),
+        words => shell_join(words),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn words(ws: &[&str]) -> Vec<String> {
+        ws.iter().map(|w| w.to_string()).collect()
+    }
+
+    #[test]
+    fn words_are_quoted_only_when_the_shell_would_touch_them() {
+        assert_eq!(shell_quote("nixos-rebuild"), "nixos-rebuild");
+        assert_eq!(shell_quote("/run/current-system"), "/run/current-system");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("my file"), "'my file'");
+        assert_eq!(shell_quote("$HOME"), "'$HOME'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+        assert_eq!(
+            shell_join(&words(&["grep", "-r", "a b", "/etc"])),
+            "grep -r 'a b' /etc"
+        );
+    }
+
+    #[test]
+    fn exec_takes_one_argument_as_a_command_line() {
+        assert_eq!(
+            exec_command_line(&words(&["journalctl -u nginx | tail"])),
+            "journalctl -u nginx | tail"
+        );
+        assert_eq!(
+            exec_command_line(&words(&["touch", "a b", ";", "reboot"])),
+            "touch 'a b' ';' reboot"
+        );
+    }
+
+    #[test]
+    fn config_options_come_before_fleet_defaults() {
+        let transport = OpenSsh {
+            control_dir: OnceLock::from(Some(PathBuf::from("/run/fleet/ssh"))),
+            known_hosts: OnceLock::from(PathBuf::from("/var/lib/fleet state/known_hosts")),
+        };
+        let mut config = FleetConfig::default();
+        config
+            .ssh
+            .options
+            .insert("BatchMode".to_string(), "no".to_string());
+        let node = Node {
+            system: "x86_64-linux".to_string(),
+            hostname: "10.0.0.1".to_string(),
+            ssh_user: "root".to_string(),
+            tags: vec![],
+            host_key: None,
+        };
+        let cmd = transport.command(&Target::node(&config, "web1", &node), true, false);
+        let args: Vec<String> = cmd
+            .get_args()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect();
+        let pos = |a: &str| args.iter().position(|x| x == a).unwrap();
+        assert!(pos("BatchMode=no") < pos("BatchMode=yes"));
+        assert!(args.contains(&"ControlPath=/run/fleet/ssh/%C".to_string()));
+        // Fleet's file, quoted, and then the user's: both are trusted
+        assert!(args.contains(
+            &"UserKnownHostsFile=\"/var/lib/fleet state/known_hosts\" ~/.ssh/known_hosts"
+                .to_string()
+        ));
+        assert_eq!(args.last().unwrap(), "root@10.0.0.1");
+    }
+
+    #[test]
+    fn scanned_keys_land_in_a_fresh_directory_only_we_can_enter() {
+        let a = private_dir(None, "test").unwrap();
+        let b = private_dir(None, "test").unwrap();
+        assert_ne!(a, b);
+        let meta = fs::symlink_metadata(&a).unwrap();
+        assert!(meta.is_dir());
+        assert_eq!(meta.mode() & 0o777, 0o700);
+        fs::remove_dir(a).unwrap();
+        fs::remove_dir(b).unwrap();
+    }
+}